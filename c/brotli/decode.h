@@ -66,6 +66,8 @@ typedef enum {
   BROTLI_ERROR_CODE(_, SUCCESS, 1) SEPARATOR                               \
   BROTLI_ERROR_CODE(_, NEEDS_MORE_INPUT, 2) SEPARATOR                      \
   BROTLI_ERROR_CODE(_, NEEDS_MORE_OUTPUT, 3) SEPARATOR                     \
+  /* Only with a work budget set from Rust */                              \
+  BROTLI_ERROR_CODE(_, YIELD, 4) SEPARATOR                                 \
                                                                            \
   /* Errors caused by invalid input */                                     \
   BROTLI_ERROR_CODE(_ERROR_FORMAT_, EXUBERANT_NIBBLE, -1) SEPARATOR        \
@@ -85,7 +87,9 @@ typedef enum {
   BROTLI_ERROR_CODE(_ERROR_FORMAT_, PADDING_2, -15) SEPARATOR              \
   BROTLI_ERROR_CODE(_ERROR_FORMAT_, DISTANCE, -16) SEPARATOR               \
                                                                            \
-  /* -17..-18 codes are reserved */                                        \
+  /* -17 code is reserved */                                               \
+                                                                           \
+  BROTLI_ERROR_CODE(_ERROR_, COMPOUND_DICTIONARY, -18) SEPARATOR           \
                                                                            \
   BROTLI_ERROR_CODE(_ERROR_, DICTIONARY_NOT_SET, -19) SEPARATOR            \
   BROTLI_ERROR_CODE(_ERROR_, INVALID_ARGUMENTS, -20) SEPARATOR             \
@@ -102,7 +106,13 @@ typedef enum {
   BROTLI_ERROR_CODE(_ERROR_ALLOC_, BLOCK_TYPE_TREES, -30) SEPARATOR        \
                                                                            \
   /* "Impossible" states */                                                \
-  BROTLI_ERROR_CODE(_ERROR_, UNREACHABLE, -31)
+  BROTLI_ERROR_CODE(_ERROR_, UNREACHABLE, -31) SEPARATOR                   \
+                                                                           \
+  /* Caps set from Rust with BrotliState::set_caps */                      \
+  BROTLI_ERROR_CODE(_ERROR_, CAP_METABLOCKS, -32) SEPARATOR                \
+  BROTLI_ERROR_CODE(_ERROR_, CAP_METADATA, -33) SEPARATOR                  \
+  BROTLI_ERROR_CODE(_ERROR_, CAP_TREES, -34) SEPARATOR                     \
+  BROTLI_ERROR_CODE(_ERROR_, CAP_INPUT_PER_OUTPUT, -35)
 
 /**
  * Error code for detailed logging / production debugging.
@@ -122,10 +132,10 @@ typedef enum {
  * The value of the last error code, negative integer.
  *
  * All other error code values are in the range from ::BROTLI_LAST_ERROR_CODE
- * to @c -1. There are also 4 other possible non-error codes @c 0 .. @c 3 in
+ * to @c -1. There are also 5 other possible non-error codes @c 0 .. @c 4 in
  * ::BrotliDecoderErrorCode enumeration.
  */
-#define BROTLI_LAST_ERROR_CODE BROTLI_DECODER_ERROR_UNREACHABLE
+#define BROTLI_LAST_ERROR_CODE BROTLI_DECODER_ERROR_CAP_INPUT_PER_OUTPUT
 
 /** Options to be used with ::BrotliDecoderSetParameter. */
 typedef enum BrotliDecoderParameter {
@@ -154,6 +164,32 @@ typedef enum BrotliDecoderParameter {
 BROTLI_DEC_API BROTLI_BOOL BrotliDecoderSetParameter(
     BrotliDecoderState* state, BrotliDecoderParameter param, uint32_t value);
 
+/** Kinds of dictionary accepted by ::BrotliDecoderAttachDictionary. */
+typedef enum BrotliSharedDictionaryType {
+  /** Raw LZ77 prefix dictionary. */
+  BROTLI_SHARED_DICTIONARY_RAW = 0,
+  /** Serialized shared dictionary; not supported by this decoder. */
+  BROTLI_SHARED_DICTIONARY_SERIALIZED = 1
+} BrotliSharedDictionaryType;
+
+/**
+ * Attaches a dictionary the stream was compressed against.
+ *
+ * Up to 15 raw dictionaries can be attached, in the order the encoder attached
+ * them, and only before decoding starts. @p data is copied, so it need not
+ * outlive the call.
+ *
+ * @param state decoder instance
+ * @param type dictionary type, only ::BROTLI_SHARED_DICTIONARY_RAW is accepted
+ * @param data_size length of @p data
+ * @param data dictionary contents
+ * @returns ::BROTLI_FALSE if the dictionary is not accepted
+ * @returns ::BROTLI_TRUE if the dictionary is attached
+ */
+BROTLI_DEC_API BROTLI_BOOL BrotliDecoderAttachDictionary(
+    BrotliDecoderState* state, BrotliSharedDictionaryType type,
+    size_t data_size, const uint8_t data[BROTLI_ARRAY_PARAM(data_size)]);
+
 /**
  * Creates an instance of ::BrotliDecoderState and initializes it.
  *
@@ -0,0 +1,107 @@
+// Measuring the memory a decoder really uses.
+//
+// CountingAllocator wraps any allocator and reports to an AllocCounter the caller keeps,
+// so the numbers stay readable while a state or reader owns the allocators. One counter
+// usually serves all three of a state's allocators, which makes it count bytes across
+// element types. What the memory is for is counted by the state itself, in DecodeStats.
+
+use core;
+use core::cell::Cell;
+use core::marker::PhantomData;
+use alloc::{Allocator, SliceWrapper};
+
+/// A snapshot of an `AllocCounter`. All sizes are in bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocStats {
+  /// Held right now.
+  pub current_bytes: usize,
+  /// Most ever held at once.
+  pub peak_bytes: usize,
+  /// Allocations made, not counting empty or failed ones.
+  pub allocations: u64,
+  /// Largest single allocation.
+  pub largest_bytes: usize,
+}
+
+/// Tallies what the `CountingAllocator`s reporting to it allocate and free.
+#[derive(Debug, Default)]
+pub struct AllocCounter {
+  stats: Cell<AllocStats>,
+}
+
+impl AllocCounter {
+  pub fn new() -> Self {
+    AllocCounter::default()
+  }
+
+  pub fn stats(&self) -> AllocStats {
+    self.stats.get()
+  }
+
+  /// Starts measuring anew from what is held right now, as before a new stream.
+  pub fn reset(&self) {
+    let current_bytes = self.stats.get().current_bytes;
+    self.stats.set(AllocStats {
+      current_bytes,
+      peak_bytes: current_bytes,
+      allocations: 0,
+      largest_bytes: 0,
+    });
+  }
+
+  fn allocated(&self, bytes: usize) {
+    if bytes == 0 {
+      return;
+    }
+    let mut stats = self.stats.get();
+    stats.current_bytes += bytes;
+    stats.peak_bytes = core::cmp::max(stats.peak_bytes, stats.current_bytes);
+    stats.allocations += 1;
+    stats.largest_bytes = core::cmp::max(stats.largest_bytes, bytes);
+    self.stats.set(stats);
+  }
+
+  fn freed(&self, bytes: usize) {
+    let mut stats = self.stats.get();
+    stats.current_bytes = stats.current_bytes.saturating_sub(bytes);
+    self.stats.set(stats);
+  }
+}
+
+/// Allocates from `inner` and reports every allocation and free to a counter.
+pub struct CountingAllocator<'a, T, A: Allocator<T>> {
+  inner: A,
+  counter: &'a AllocCounter,
+  element: PhantomData<T>,
+}
+
+impl<'a, T, A: Allocator<T>> CountingAllocator<'a, T, A> {
+  pub fn new(inner: A, counter: &'a AllocCounter) -> Self {
+    CountingAllocator {
+      inner,
+      counter,
+      element: PhantomData,
+    }
+  }
+
+  pub fn counter(&self) -> &'a AllocCounter {
+    self.counter
+  }
+
+  pub fn into_inner(self) -> A {
+    self.inner
+  }
+}
+
+impl<'a, T, A: Allocator<T>> Allocator<T> for CountingAllocator<'a, T, A> {
+  type AllocatedMemory = A::AllocatedMemory;
+  fn alloc_cell(&mut self, len: usize) -> A::AllocatedMemory {
+    let mem = self.inner.alloc_cell(len);
+    self.counter.allocated(core::mem::size_of_val(mem.slice()));
+    mem
+  }
+  fn free_cell(&mut self, data: A::AllocatedMemory) {
+    self.counter.freed(core::mem::size_of_val(data.slice()));
+    self.inner.free_cell(data)
+  }
+}
@@ -0,0 +1,249 @@
+// Memory for decoding without a heap.
+//
+// Everything the decoder allocates is one of a few kinds, each with a largest size set by
+// the window and by the format, and only one of each kind is live at a time:
+//   u8:          the ringbuffer, an owned custom dictionary until it is copied into the
+//                ringbuffer, and the context modes, literal context map and distance
+//                context map of the current metablock
+//   u32:         where each tree starts, for the three tree groups of the current metablock
+//   HuffmanCode: the context map table, the block type and block length trees, and the
+//                tables of the three tree groups
+// DecoderArena cuts one slot per kind out of the memory it is given and hands out the
+// smallest free slot an allocation fits in, so it never has to split or merge memory and
+// the decoder always finds a slot.
+
+use core;
+use core::cell::RefCell;
+use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
+use decode::{kBrotliLargeMaxWbits, kBrotliLargeMinWbits, kDistanceContextBits,
+             kRingBufferWriteAheadSlack};
+use dictionary::kBrotliMaxDictionaryWordLength;
+use huffman::{BROTLI_HUFFMAN_MAX_TABLE_SIZE, HuffmanCode};
+use state::kLiteralContextBits;
+
+#[allow(non_upper_case_globals)]
+const kMaxBlockTypes: usize = 256;
+#[allow(non_upper_case_globals)]
+const kMaxArenaSlots: usize = 6;
+
+/// Worst-case memory of one decoder, in elements of each type it allocates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryRequirement {
+  pub u8_len: usize,
+  pub u32_len: usize,
+  pub huffman_code_len: usize,
+}
+
+impl MemoryRequirement {
+  /// The total in bytes.
+  pub const fn bytes(&self) -> usize {
+    self.u8_len + self.u32_len * core::mem::size_of::<u32>() +
+    self.huffman_code_len * core::mem::size_of::<HuffmanCode>()
+  }
+}
+
+const fn u8_slots(max_window_bits: u32, dictionary_size: usize) -> [usize; kMaxArenaSlots] {
+  let window_bits = if max_window_bits < kBrotliLargeMinWbits {
+    kBrotliLargeMinWbits
+  } else if max_window_bits > kBrotliLargeMaxWbits {
+    kBrotliLargeMaxWbits
+  } else {
+    max_window_bits
+  };
+  [(1usize << window_bits) + kRingBufferWriteAheadSlack as usize +
+   kBrotliMaxDictionaryWordLength as usize,
+   dictionary_size,
+   kMaxBlockTypes,
+   kMaxBlockTypes << kLiteralContextBits,
+   kMaxBlockTypes << kDistanceContextBits,
+   0]
+}
+
+const fn u32_slots() -> [usize; kMaxArenaSlots] {
+  [kMaxBlockTypes, kMaxBlockTypes, kMaxBlockTypes, 0, 0, 0]
+}
+
+const fn huffman_slots() -> [usize; kMaxArenaSlots] {
+  let table = BROTLI_HUFFMAN_MAX_TABLE_SIZE as usize;
+  [table,
+   3 * table,
+   3 * table,
+   kMaxBlockTypes * table,
+   kMaxBlockTypes * table,
+   kMaxBlockTypes * table]
+}
+
+const fn slots_len(slots: [usize; kMaxArenaSlots]) -> usize {
+  let mut total = 0;
+  let mut index = 0;
+  while index < kMaxArenaSlots {
+    total += slots[index];
+    index += 1;
+  }
+  total
+}
+
+/// The most memory a `BrotliState` allocates while decoding streams with windows of up to
+/// `max_window_bits`, large windows included, from a state that copies a custom dictionary
+/// of up to `dictionary_size` bytes (0 if it borrows or shares its dictionary, or has none).
+///
+/// Dictionaries attached with `attach_dictionary_copy` and custom transforms are not
+/// counted.
+pub const fn memory_requirement(max_window_bits: u32, dictionary_size: usize) -> MemoryRequirement {
+  MemoryRequirement {
+    u8_len: slots_len(u8_slots(max_window_bits, dictionary_size)),
+    u32_len: slots_len(u32_slots()),
+    huffman_code_len: slots_len(huffman_slots()),
+  }
+}
+
+/// Memory handed out by a `DecoderArena`: the start of one of its slots.
+pub struct ArenaMemory<'a, T: 'a> {
+  slot: &'a mut [T],
+  len: usize,
+}
+
+impl<'a, T: 'a> Default for ArenaMemory<'a, T> {
+  fn default() -> Self {
+    ArenaMemory {
+      slot: &mut [],
+      len: 0,
+    }
+  }
+}
+
+impl<'a, T: 'a> SliceWrapper<T> for ArenaMemory<'a, T> {
+  fn slice(&self) -> &[T] {
+    &self.slot[..self.len]
+  }
+}
+
+impl<'a, T: 'a> SliceWrapperMut<T> for ArenaMemory<'a, T> {
+  fn slice_mut(&mut self) -> &mut [T] {
+    &mut self.slot[..self.len]
+  }
+}
+
+/// The slots of a `DecoderArena` for one element type, as an allocator of its own.
+pub struct ArenaAllocator<'a, T: 'a> {
+  // A slot in use leaves an empty entry behind, which it fills again when it comes back.
+  free: [&'a mut [T]; kMaxArenaSlots],
+}
+
+impl<'a, T: 'a + Clone + Default> ArenaAllocator<'a, T> {
+  fn new(mut buffer: &'a mut [T], sizes: [usize; kMaxArenaSlots]) -> Option<Self> {
+    let mut free: [&'a mut [T]; kMaxArenaSlots] = Default::default();
+    for (slot, size) in free.iter_mut().zip(sizes.iter()) {
+      if buffer.len() < *size {
+        return None;
+      }
+      let (head, tail) = core::mem::take(&mut buffer).split_at_mut(*size);
+      *slot = head;
+      buffer = tail;
+    }
+    Some(ArenaAllocator { free })
+  }
+}
+
+impl<'a, T: 'a + Clone + Default> Allocator<T> for ArenaAllocator<'a, T> {
+  type AllocatedMemory = ArenaMemory<'a, T>;
+  fn alloc_cell(&mut self, len: usize) -> ArenaMemory<'a, T> {
+    if len == 0 {
+      return ArenaMemory::default();
+    }
+    let mut best: Option<usize> = None;
+    let mut best_len = usize::MAX;
+    for (index, slot) in self.free.iter().enumerate() {
+      if slot.len() >= len && slot.len() < best_len {
+        best = Some(index);
+        best_len = slot.len();
+      }
+    }
+    match best {
+      Some(index) => {
+        let slot = core::mem::take(&mut self.free[index]);
+        for item in slot[..len].iter_mut() {
+          *item = T::default();
+        }
+        ArenaMemory { slot, len }
+      }
+      None => ArenaMemory::default(),
+    }
+  }
+
+  fn free_cell(&mut self, mem: ArenaMemory<'a, T>) {
+    if mem.slot.is_empty() {
+      return;
+    }
+    if let Some(entry) = self.free.iter_mut().find(|slot| slot.is_empty()) {
+      *entry = mem.slot;
+    }
+  }
+}
+
+/// Allocates for one `BrotliState` at a time out of buffers sized by
+/// `memory_requirement`, without a heap. `&arena` is each of the state's three allocators.
+///
+/// The state never runs out of memory while its streams' windows and its copied custom
+/// dictionary are no larger than the arena was set up for. A larger window fails with an
+/// allocation error instead.
+pub struct DecoderArena<'a> {
+  u8_pool: RefCell<ArenaAllocator<'a, u8>>,
+  u32_pool: RefCell<ArenaAllocator<'a, u32>>,
+  huffman_pool: RefCell<ArenaAllocator<'a, HuffmanCode>>,
+}
+
+impl<'a> DecoderArena<'a> {
+  /// Sets up the buffers for the same `max_window_bits` and `dictionary_size` given to
+  /// `memory_requirement`. Returns None if a buffer is shorter than it asks for; memory
+  /// beyond that is left unused.
+  pub fn new(max_window_bits: u32,
+             dictionary_size: usize,
+             u8_buffer: &'a mut [u8],
+             u32_buffer: &'a mut [u32],
+             huffman_buffer: &'a mut [HuffmanCode])
+             -> Option<Self> {
+    Some(DecoderArena {
+      u8_pool: RefCell::new(ArenaAllocator::new(u8_buffer, u8_slots(max_window_bits, dictionary_size))?),
+      u32_pool: RefCell::new(ArenaAllocator::new(u32_buffer, u32_slots())?),
+      huffman_pool: RefCell::new(ArenaAllocator::new(huffman_buffer, huffman_slots())?),
+    })
+  }
+
+  /// Splits the arena into an allocator per element type, for a state that owns its
+  /// allocators.
+  pub fn into_allocators(self)
+                         -> (ArenaAllocator<'a, u8>, ArenaAllocator<'a, u32>, ArenaAllocator<'a, HuffmanCode>) {
+    (self.u8_pool.into_inner(), self.u32_pool.into_inner(), self.huffman_pool.into_inner())
+  }
+}
+
+impl<'a> Allocator<u8> for &DecoderArena<'a> {
+  type AllocatedMemory = ArenaMemory<'a, u8>;
+  fn alloc_cell(&mut self, len: usize) -> ArenaMemory<'a, u8> {
+    self.u8_pool.borrow_mut().alloc_cell(len)
+  }
+  fn free_cell(&mut self, data: ArenaMemory<'a, u8>) {
+    self.u8_pool.borrow_mut().free_cell(data)
+  }
+}
+
+impl<'a> Allocator<u32> for &DecoderArena<'a> {
+  type AllocatedMemory = ArenaMemory<'a, u32>;
+  fn alloc_cell(&mut self, len: usize) -> ArenaMemory<'a, u32> {
+    self.u32_pool.borrow_mut().alloc_cell(len)
+  }
+  fn free_cell(&mut self, data: ArenaMemory<'a, u32>) {
+    self.u32_pool.borrow_mut().free_cell(data)
+  }
+}
+
+impl<'a> Allocator<HuffmanCode> for &DecoderArena<'a> {
+  type AllocatedMemory = ArenaMemory<'a, HuffmanCode>;
+  fn alloc_cell(&mut self, len: usize) -> ArenaMemory<'a, HuffmanCode> {
+    self.huffman_pool.borrow_mut().alloc_cell(len)
+  }
+  fn free_cell(&mut self, data: ArenaMemory<'a, HuffmanCode>) {
+    self.huffman_pool.borrow_mut().free_cell(data)
+  }
+}
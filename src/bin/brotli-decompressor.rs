@@ -82,6 +82,10 @@ impl<T: core::clone::Clone> alloc_no_stdlib::Allocator<T> for HeapAllocator<T> {
 #[allow(unused_imports)]
 use alloc_no_stdlib::{SliceWrapper,SliceWrapperMut, StackAllocator, AllocatedStackMemory, Allocator, bzero};
 use brotli_decompressor::HuffmanCode;
+use brotli_decompressor::{BrotliResult, BrotliState, Provenance, ProvenanceRecorder, ProvenanceSink,
+                          ProvenanceSpan};
+#[cfg(feature="std")]
+use brotli_decompressor::{DictionaryDecompressor, DictionaryStore};
 
 use std::io::{self, Error, ErrorKind, Read, Write};
 
@@ -271,6 +275,26 @@ impl<R: Read> BrotliDecompressor<R> {
                                                          io::Error::new(ErrorKind::InvalidData,
                                                                         "Invalid Data")))
   }
+  pub fn new_with_custom_dict(r: R, buffer_size: usize, dict: Vec<u8>) -> Self {
+    let mut alloc_u8 = HeapAllocator::<u8> { default_value: 0 };
+    let buffer = alloc_u8.alloc_cell(buffer_size);
+    let alloc_u32 = HeapAllocator::<u32> { default_value: 0 };
+    let alloc_hc = HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() };
+    BrotliDecompressor::<R>(
+          brotli_decompressor::DecompressorCustomIo::<Error,
+                                 IntoIoReader<R>,
+                                 Rebox<u8>,
+                                 HeapAllocator<u8>, HeapAllocator<u32>, HeapAllocator<HuffmanCode> >
+                                 ::new_with_custom_dictionary(IntoIoReader::<R>(r),
+                                                              buffer,
+                                                              alloc_u8, alloc_u32, alloc_hc,
+                                                              Rebox::<u8>::from(dict),
+                                                              io::Error::new(ErrorKind::InvalidData,
+                                                                             "Invalid Data")))
+  }
+  pub fn skip_output(&mut self, n: usize) {
+    self.0.skip_output(n)
+  }
 }
 
 impl<R: Read> Read for BrotliDecompressor<R> {
@@ -279,6 +303,157 @@ impl<R: Read> Read for BrotliDecompressor<R> {
   }
 }
 
+// Writes only bytes [offset, offset + length) of the decompressed stream, and stops
+// reading the input once they are out.
+pub fn decompress_range<InputType, OutputType>(r: &mut InputType,
+                                               w: &mut OutputType,
+                                               buffer_size: usize,
+                                               dict: Vec<u8>,
+                                               offset: usize,
+                                               length: Option<u64>)
+                                               -> Result<u64, io::Error>
+  where InputType: Read,
+        OutputType: Write
+{
+  let mut decompressor = BrotliDecompressor::new_with_custom_dict(r, buffer_size, dict);
+  decompressor.skip_output(offset);
+  match length {
+    Some(length) => io::copy(&mut decompressor.take(length), w),
+    None => io::copy(&mut decompressor, w),
+  }
+}
+
+// Like decompress_range, for streams that may be framed with the hash or id of a
+// dictionary in store.
+#[cfg(feature="std")]
+pub fn decompress_with_store<InputType, OutputType>(r: &mut InputType,
+                                                    w: &mut OutputType,
+                                                    buffer_size: usize,
+                                                    store: &DictionaryStore,
+                                                    offset: usize,
+                                                    length: Option<u64>)
+                                                    -> Result<u64, io::Error>
+  where InputType: Read,
+        OutputType: Write
+{
+  let mut decompressor = DictionaryDecompressor::new(r, buffer_size, store)?;
+  decompressor.skip_output(offset);
+  match length {
+    Some(length) => io::copy(&mut decompressor.take(length), w),
+    None => io::copy(&mut decompressor, w),
+  }
+}
+
+// Receives the spans of write_provenance and writes each as a line of JSON.
+struct JsonLines<'a, OutputType: Write + 'a> {
+  w: &'a mut OutputType,
+  error: Option<io::Error>,
+}
+
+impl<'a, OutputType: Write> ProvenanceSink for JsonLines<'a, OutputType> {
+  fn span(&mut self, span: &ProvenanceSpan) {
+    if self.error.is_some() {
+      return;
+    }
+    let source = match span.source {
+      Provenance::Literal => "\"source\":\"literal\"".to_string(),
+      Provenance::Uncompressed => "\"source\":\"uncompressed\"".to_string(),
+      Provenance::Copy { distance } => format!("\"source\":\"copy\",\"distance\":{}", distance),
+      Provenance::CustomDictionary { distance, offset } => {
+        format!("\"source\":\"custom_dictionary\",\"distance\":{},\"offset\":{}", distance, offset)
+      }
+      Provenance::CompoundDictionary { distance, offset } => {
+        format!("\"source\":\"compound_dictionary\",\"distance\":{},\"offset\":{}", distance, offset)
+      }
+      Provenance::StaticDictionary { word_len, word_idx, transform } => {
+        format!("\"source\":\"static_dictionary\",\"word_len\":{},\"word_idx\":{},\"transform\":{}",
+                word_len, word_idx, transform)
+      }
+    };
+    if let Err(e) = writeln!(self.w,
+                             "{{\"output_start\":{},\"len\":{},{},\"bit_start\":{},\"bit_end\":{}}}",
+                             span.output_start, span.len, source, span.bit_start, span.bit_end) {
+      self.error = Some(e);
+    }
+  }
+}
+
+// Writes where each run of the decompressed stream comes from as JSON lines, in place
+// of the decompressed stream itself.
+pub fn write_provenance<InputType, OutputType>(r: &mut InputType,
+                                               w: &mut OutputType,
+                                               buffer_size: usize,
+                                               dict: Vec<u8>)
+                                               -> Result<(), io::Error>
+  where InputType: Read,
+        OutputType: Write
+{
+  let mut brotli_state =
+    BrotliState::new_with_custom_dictionary(HeapAllocator::<u8> { default_value: 0 },
+                                            HeapAllocator::<u32> { default_value: 0 },
+                                            HeapAllocator::<HuffmanCode> {
+                                              default_value: HuffmanCode::default(),
+                                            },
+                                            Rebox::<u8>::from(dict));
+  let mut recorder = ProvenanceRecorder::new(JsonLines { w, error: None });
+  let mut input = vec![0u8; buffer_size];
+  let mut output = vec![0u8; buffer_size];
+  let mut available_in: usize = 0;
+  let mut input_offset: usize = 0;
+  let mut total_out: usize = 0;
+  let mut result = BrotliResult::NeedsMoreInput;
+  loop {
+    match result {
+      BrotliResult::NeedsMoreInput => {
+        input_offset = 0;
+        available_in = loop {
+          match r.read(&mut input[..]) {
+            Err(e) => {
+              if let ErrorKind::Interrupted = e.kind() {
+                continue;
+              }
+              return Err(e);
+            }
+            Ok(size) => break size,
+          }
+        };
+        if available_in == 0 {
+          return Err(Error::new(ErrorKind::UnexpectedEof, "Unexpected EOF"));
+        }
+      }
+      BrotliResult::NeedsMoreOutput => {}
+      BrotliResult::ResultSuccess => break,
+      BrotliResult::ResultFailure => return Err(Error::new(ErrorKind::InvalidData, "Invalid Data")),
+    }
+    let mut available_out = output.len();
+    let mut output_offset: usize = 0;
+    result = brotli_decompressor::BrotliDecompressStreamWithProvenance(&mut available_in,
+                                                                       &mut input_offset,
+                                                                       &input[..],
+                                                                       &mut available_out,
+                                                                       &mut output_offset,
+                                                                       &mut output[..],
+                                                                       &mut total_out,
+                                                                       &mut brotli_state,
+                                                                       &mut recorder);
+    if let Some(e) = recorder.get_mut().error.take() {
+      return Err(e);
+    }
+  }
+  let mut json_lines = recorder.into_inner();
+  match json_lines.error.take() {
+    Some(e) => Err(e),
+    None => Ok(()),
+  }
+}
+
+fn parse_size(argument: &str, value: &str) -> usize {
+  match value.parse::<usize>() {
+    Ok(size) => size,
+    Err(_) => panic!("Invalid number in {}", argument),
+  }
+}
+
 #[cfg(test)]
 fn writeln0<OutputType: Write>(strm: &mut OutputType,
                                data: &str)
@@ -300,6 +475,11 @@ fn main() {
   let mut double_dash = false;
   let mut input: Option<File> = None;
   let mut output: Option<File> = None;
+  let mut offset: Option<usize> = None;
+  let mut length: Option<u64> = None;
+  let mut provenance = false;
+  #[cfg(feature="std")]
+  let mut store: Option<DictionaryStore> = None;
   for argument in env::args().skip(1) {
     if argument == "--" {
       double_dash = true;
@@ -310,6 +490,27 @@ fn main() {
       dict_file.read_to_end(&mut dictionary).unwrap();
       continue;
     }
+    if argument.starts_with("--dict-dir=") && !double_dash {
+      #[cfg(feature="std")]
+      {
+        store = Some(DictionaryStore::load_dir(&Path::new(&argument[11..])).unwrap());
+        continue;
+      }
+      #[cfg(not(feature="std"))]
+      panic!("--dict-dir needs the dictionary store, which is only built with the std feature");
+    }
+    if argument.starts_with("--offset=") && !double_dash {
+      offset = Some(parse_size(&argument, &argument[9..]));
+      continue;
+    }
+    if argument == "--provenance" && !double_dash {
+      provenance = true;
+      continue;
+    }
+    if argument.starts_with("--length=") && !double_dash {
+      length = Some(parse_size(&argument, &argument[9..]) as u64);
+      continue;
+    }
     if input.is_none() {
        input = Some(File::open(&Path::new(&argument)).unwrap());
     } else if output.is_none() {
@@ -318,6 +519,39 @@ fn main() {
        panic!("Cannot specify more than 2 filename args (input, output)")
     }
   }
+  #[cfg(feature="std")]
+  if let Some(store) = store {
+    if provenance || !dictionary.is_empty() {
+      panic!("--dict-dir picks the dictionary itself and cannot be combined with -dict= or --provenance");
+    }
+    let offset = offset.unwrap_or(0);
+    match (input, output) {
+      (None, _) => decompress_with_store(&mut io::stdin(), &mut io::stdout(), 65536, &store, offset, length),
+      (Some(mut input), None) => decompress_with_store(&mut input, &mut io::stdout(), 65536, &store, offset, length),
+      (Some(mut input), Some(mut output)) => decompress_with_store(&mut input, &mut output, 65536, &store, offset, length),
+    }.unwrap();
+    return;
+  }
+  if provenance {
+    if offset.is_some() || length.is_some() {
+      panic!("--provenance describes the whole stream and cannot be combined with --offset= or --length=");
+    }
+    match (input, output) {
+      (None, _) => write_provenance(&mut io::stdin(), &mut io::stdout(), 65536, dictionary),
+      (Some(mut input), None) => write_provenance(&mut input, &mut io::stdout(), 65536, dictionary),
+      (Some(mut input), Some(mut output)) => write_provenance(&mut input, &mut output, 65536, dictionary),
+    }.unwrap();
+    return;
+  }
+  if offset.is_some() || length.is_some() {
+    let offset = offset.unwrap_or(0);
+    match (input, output) {
+      (None, _) => decompress_range(&mut io::stdin(), &mut io::stdout(), 65536, dictionary, offset, length),
+      (Some(mut input), None) => decompress_range(&mut input, &mut io::stdout(), 65536, dictionary, offset, length),
+      (Some(mut input), Some(mut output)) => decompress_range(&mut input, &mut output, 65536, dictionary, offset, length),
+    }.unwrap();
+    return;
+  }
   if input.is_none() {
     decompress(&mut io::stdin(), &mut io::stdout(), 65536, dictionary).unwrap();
   } else {
@@ -4,12 +4,41 @@ use std::io;
 #[cfg(feature="std")]
 use std::io::{Read,Write};
 use core::cmp;
+use core::cell::Cell;
+use core::pin::Pin;
 use super::brotli_decompressor::BrotliResult;
 use super::brotli_decompressor::BrotliDecompressStream;
+use super::brotli_decompressor::{BrotliDecompressStreamToSink, CommandSink};
 #[cfg(feature="std")]
-use super::brotli_decompressor::{Decompressor, DecompressorWriter};
+use super::brotli_decompressor::{Decompressor, DecompressorWriter, PipelinedDecompressor,
+                                 SeekableDecompressor, SeekTable, ThreadedDecompressor};
+#[cfg(feature="parallel")]
+use super::brotli_decompressor::ParallelDecompressor;
+#[cfg(feature="std")]
+use super::brotli_decompressor::{decompress_into, decompress_to_vec};
+use super::brotli_decompressor::{decompress_into_custom_alloc, decompress_to_vec_custom_alloc,
+                                 DecompressError, DecompressLimits};
 use super::brotli_decompressor::BrotliState;
 use super::brotli_decompressor::HuffmanCode;
+use super::brotli_decompressor::{dictionary, huffman, transform};
+use super::brotli_decompressor::{BitReader, DecodeStats};
+use super::brotli_decompressor::{HeaderConfidence, HeaderInfo, sniff_header};
+use super::brotli_decompressor::DecompressorOptions;
+#[cfg(feature="std")]
+use super::brotli_decompressor::{decompress_into_with_options, decompress_to_vec_with_options};
+use super::brotli_decompressor::BrotliDecompressStreamWithDictionary;
+#[cfg(feature="unsafe")]
+use super::brotli_decompressor::StaticDecompressor;
+use super::brotli_decompressor::{AllocCounter, CountingAllocator, FailingAllocator, FaultInjector};
+use super::brotli_decompressor::{BudgetedAllocator, MemoryBudget, WorkBudget};
+use super::brotli_decompressor::{BrotliDecompressStreamWithBudget, BudgetedResult};
+use super::brotli_decompressor::DecodeCaps;
+#[cfg(feature="std")]
+use super::brotli_decompressor::{DictionaryDecompressor, DictionaryStore};
+#[cfg(feature="std")]
+use super::brotli_decompressor::{BrotliDecompressStreamWithProvenance, Provenance, ProvenanceRecorder,
+                                 ProvenanceSink, ProvenanceSpan};
+use super::brotli_decompressor::ffi::BrotliDecoderErrorCode;
 use super::HeapAllocator;
 
 #[allow(unused_imports)]
@@ -325,6 +354,1538 @@ fn test_reader_uni() {
 }
 
 
+#[cfg(feature="std")]
+fn threaded_reader_helper(in_buf: &[u8], desired_buf: &[u8], bufsize: usize, chunk_size: usize) {
+  let mut rdec = ThreadedDecompressor::new_with_chunk_size(UnlimitedBuffer::new(in_buf),
+                                                           bufsize,
+                                                           chunk_size);
+  let mut output = Vec::<u8>::new();
+  let mut cmp = [0u8; 178];
+  loop {
+    match rdec.read(&mut cmp[..]) {
+      Ok(size) => {
+        if size == 0 {
+          break;
+        }
+        output.extend(&cmp[..size]);
+      }
+      Err(e) => panic!("Error {:?}", e),
+    }
+  }
+  assert_eq!(output.len(), desired_buf.len());
+  assert_eq!(&output[..], desired_buf);
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_threaded_reader_alice29() {
+  threaded_reader_helper(ALICE29_BR, ALICE29, 4096, 4096);
+  threaded_reader_helper(ALICE29_BR, ALICE29, 1, 7);
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_threaded_reader_empty() {
+  threaded_reader_helper(include_bytes!("../../testdata/empty.compressed"), &[], 4096, 4096);
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_threaded_reader_invalid_data() {
+  let mut rdec = ThreadedDecompressor::new(UnlimitedBuffer::new(
+    include_bytes!("../../testdata/borked.compressed")), 4096);
+  let mut output = Vec::<u8>::new();
+  match rdec.read_to_end(&mut output) {
+    Ok(_) => panic!("borked input decoded successfully"),
+    Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+  }
+  // the failure is sticky
+  assert!(rdec.read(&mut [0u8; 16][..]).is_err());
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_threaded_reader_truncated() {
+  let mut rdec = ThreadedDecompressor::new(UnlimitedBuffer::new(&ALICE29_BR[..ALICE29_BR.len() / 2]),
+                                           4096);
+  let mut output = Vec::<u8>::new();
+  // like Decompressor, a truncated stream simply ends early
+  let _ = rdec.read_to_end(&mut output);
+  assert!(output.len() < ALICE29.len());
+  assert_eq!(&output[..], &ALICE29[..output.len()]);
+}
+
+#[cfg(feature="std")]
+struct PanickingReader(usize, usize); // offset, offset at which to panic
+
+#[cfg(feature="std")]
+impl io::Read for PanickingReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.0 == self.1 {
+      panic!("reader exploded");
+    }
+    let to_copy = cmp::min(cmp::min(buf.len(), 1024), self.1 - self.0);
+    buf[..to_copy].clone_from_slice(&ALICE29_BR[self.0..self.0 + to_copy]);
+    self.0 += to_copy;
+    Ok(to_copy)
+  }
+}
+
+#[test]
+#[cfg(feature="std")]
+#[should_panic(expected = "reader exploded")]
+fn test_threaded_reader_propagates_panic() {
+  let mut rdec = ThreadedDecompressor::new(PanickingReader(0, ALICE29_BR.len()), 4096);
+  let mut output = Vec::<u8>::new();
+  let _ = rdec.read_to_end(&mut output);
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_threaded_reader_early_drop() {
+  let mut rdec = ThreadedDecompressor::new_with_chunk_size(UnlimitedBuffer::new(ALICE29_BR), 4096, 1024);
+  let mut cmp = [0u8; 100];
+  let size = rdec.read(&mut cmp[..]).unwrap();
+  assert_eq!(&cmp[..size], &ALICE29[..size]);
+  // dropping with chunks still in flight must join the worker without hanging
+}
+
+
+// Rebuilds the output from the command stream, keeping all of it as the history.
+struct VecSink {
+  output: Vec<u8>,
+  window_size: usize,
+  alternate: bool,
+  deferral_requests: Cell<usize>,
+  history_restores: usize,
+}
+
+impl CommandSink for VecSink {
+  fn defer_copies(&self) -> bool {
+    let requests = self.deferral_requests.get();
+    self.deferral_requests.set(requests + 1);
+    // alternating forces the decoder to fetch the history back from the sink
+    !self.alternate || requests % 2 == 0
+  }
+  fn ring_buffer_allocated(&mut self, ring: &[u8]) {
+    self.window_size = ring.len();
+  }
+  fn literals(&mut self, data: &[u8]) {
+    self.output.extend_from_slice(data);
+  }
+  fn copy(&mut self, len: u32, distance: u32) {
+    for _ in 0..len {
+      let byte = self.output[self.output.len() - distance as usize];
+      self.output.push(byte);
+    }
+  }
+  fn restore_history(&mut self, ring: &mut [u8]) {
+    self.history_restores += 1;
+    let mask = self.window_size - 1;
+    for (pos, byte) in self.output.iter().enumerate().rev().take(self.window_size) {
+      ring[pos & mask] = *byte;
+    }
+  }
+}
+
+// Decodes through BrotliDecompressStream into a single reused output buffer of
+// output_size bytes, so large buffers let the decoder use it as the window.
+fn output_window_helper(in_buf: &[u8],
+                        desired_buf: &[u8],
+                        input_buffer_limit: usize,
+                        output_size: usize) {
+  let mut brotli_state =
+    BrotliState::new(HeapAllocator::<u8> { default_value: 0 },
+                     HeapAllocator::<u32> { default_value: 0 },
+                     HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() });
+  let mut output = vec![0u8; output_size];
+  let mut decoded = Vec::<u8>::new();
+  let mut total_out = 0usize;
+  let mut consumed = 0usize;
+  let mut output_offset = 0usize;
+  loop {
+    let chunk_end = cmp::min(consumed + input_buffer_limit, in_buf.len());
+    let mut available_in = chunk_end - consumed;
+    let mut input_offset = 0usize;
+    let mut available_out = output_size - output_offset;
+    let result = BrotliDecompressStream(&mut available_in,
+                                        &mut input_offset,
+                                        &in_buf[consumed..chunk_end],
+                                        &mut available_out,
+                                        &mut output_offset,
+                                        &mut output[..],
+                                        &mut total_out,
+                                        &mut brotli_state);
+    consumed += input_offset;
+    match result {
+      BrotliResult::ResultSuccess => break,
+      BrotliResult::NeedsMoreInput => assert!(consumed < in_buf.len()),
+      BrotliResult::NeedsMoreOutput => {
+        decoded.extend_from_slice(&output[..output_offset]);
+        output_offset = 0;
+      }
+      BrotliResult::ResultFailure => panic!("decoding failed"),
+    }
+  }
+  decoded.extend_from_slice(&output[..output_offset]);
+  assert_eq!(total_out, desired_buf.len());
+  assert_eq!(decoded.len(), desired_buf.len());
+  assert!(decoded == desired_buf);
+}
+
+#[test]
+fn test_output_window_matches_output() {
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let alice_br = include_bytes!("../../testdata/alice29.txt.compressed");
+  // whole stream in one call, window in the output throughout
+  output_window_helper(alice_br, alice, alice_br.len(), alice.len() + (1 << 22) + 128);
+  // input runs out mid-stream, so the window moves into a ringbuffer between calls
+  output_window_helper(alice_br, alice, 997, alice.len() + (1 << 22) + 128);
+  // a single last metablock that just fits
+  output_window_helper(include_bytes!("../../testdata/quickfox_repeated.compressed"),
+                       include_bytes!("../../testdata/quickfox_repeated"),
+                       1 << 20, 176128 + 67);
+  output_window_helper(include_bytes!("../../testdata/10x10y.compressed"),
+                       include_bytes!("../../testdata/10x10y"), 1 << 20, 20 + 67);
+  output_window_helper(include_bytes!("../../testdata/x.compressed"),
+                       include_bytes!("../../testdata/x"), 1, 1 << 23);
+  output_window_helper(include_bytes!("../../testdata/empty.compressed"), &[], 1 << 20, 1 << 23);
+  // the output fills up mid-stream and the window moves into a ringbuffer
+  let reset = include_bytes!("../../testdata/metablock_reset");
+  let reset_br = include_bytes!("../../testdata/metablock_reset.compressed");
+  output_window_helper(reset_br, reset, 1 << 20, 4096);
+  output_window_helper(reset_br, reset, 1 << 20, 1024 + 66);
+  output_window_helper(reset_br, reset, 4093, 100000);
+  output_window_helper(include_bytes!("../../testdata/fuzz502.compressed"),
+                       include_bytes!("../../testdata/fuzz502"), 1 << 20, 65536 + 66);
+  output_window_helper(include_bytes!("../../testdata/random_org_10k.bin.compressed"),
+                       include_bytes!("../../testdata/random_org_10k.bin"), 777, 65536 + 100);
+}
+
+fn stream_to_sink_helper(in_buf: &[u8],
+                         desired_buf: &[u8],
+                         input_buffer_limit: usize,
+                         alternate: bool)
+                         -> VecSink {
+  let mut sink = VecSink {
+    output: Vec::new(),
+    window_size: 0,
+    alternate,
+    deferral_requests: Cell::new(0),
+    history_restores: 0,
+  };
+  let mut brotli_state =
+    BrotliState::new(HeapAllocator::<u8> { default_value: 0 },
+                     HeapAllocator::<u32> { default_value: 0 },
+                     HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() });
+  let mut total_out = 0usize;
+  let mut consumed = 0usize;
+  loop {
+    let chunk_end = cmp::min(consumed + input_buffer_limit, in_buf.len());
+    let mut available_in = chunk_end - consumed;
+    let mut input_offset = 0usize;
+    let result = BrotliDecompressStreamToSink(&mut available_in,
+                                              &mut input_offset,
+                                              &in_buf[consumed..chunk_end],
+                                              &mut total_out,
+                                              &mut brotli_state,
+                                              &mut sink);
+    consumed += input_offset;
+    match result {
+      BrotliResult::ResultSuccess => break,
+      BrotliResult::NeedsMoreInput => assert!(consumed < in_buf.len()),
+      BrotliResult::NeedsMoreOutput => panic!("sink decoding never needs output space"),
+      BrotliResult::ResultFailure => panic!("decoding failed"),
+    }
+  }
+  assert_eq!(sink.output.len(), desired_buf.len());
+  assert!(sink.output == desired_buf);
+  sink
+}
+
+#[test]
+fn test_stream_to_sink_matches_output() {
+  stream_to_sink_helper(ALICE29_BR, ALICE29, 65536, false);
+  stream_to_sink_helper(ALICE29_BR, ALICE29, 1, false);
+  stream_to_sink_helper(include_bytes!("../../testdata/random_then_unicode.compressed"),
+                        include_bytes!("../../testdata/random_then_unicode"),
+                        517,
+                        false);
+  stream_to_sink_helper(include_bytes!("../../testdata/backward65536.compressed"),
+                        include_bytes!("../../testdata/backward65536"),
+                        4096,
+                        false);
+  stream_to_sink_helper(include_bytes!("../../testdata/zeros.compressed"),
+                        include_bytes!("../../testdata/zeros"),
+                        4096,
+                        false);
+  stream_to_sink_helper(include_bytes!("../../testdata/empty.compressed"), &[], 4096, false);
+}
+
+#[test]
+fn test_stream_to_sink_restores_history() {
+  // metablock_reset wraps its 1024 byte window many times over seven metablocks
+  let sink = stream_to_sink_helper(include_bytes!("../../testdata/metablock_reset.compressed"),
+                                   include_bytes!("../../testdata/metablock_reset"),
+                                   1000,
+                                   true);
+  assert!(sink.history_restores > 0);
+  stream_to_sink_helper(include_bytes!("../../testdata/metablock_reset.compressed"),
+                        include_bytes!("../../testdata/metablock_reset"),
+                        1,
+                        false);
+}
+
+#[cfg(feature="std")]
+fn pipelined_reader_helper(in_buf: &[u8], desired_buf: &[u8], bufsize: usize, read_size: usize) {
+  let mut rdec = PipelinedDecompressor::new(UnlimitedBuffer::new(in_buf), bufsize);
+  let mut output = Vec::<u8>::new();
+  let mut cmp = vec![0u8; read_size];
+  loop {
+    match rdec.read(&mut cmp[..]) {
+      Ok(size) => {
+        if size == 0 {
+          break;
+        }
+        output.extend(&cmp[..size]);
+      }
+      Err(e) => panic!("Error {:?}", e),
+    }
+  }
+  assert_eq!(output.len(), desired_buf.len());
+  assert!(output == desired_buf);
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_pipelined_reader_matches_output() {
+  pipelined_reader_helper(ALICE29_BR, ALICE29, 4096, 65536);
+  pipelined_reader_helper(ALICE29_BR, ALICE29, 1, 177);
+  pipelined_reader_helper(include_bytes!("../../testdata/asyoulik.txt.compressed"),
+                          include_bytes!("../../testdata/asyoulik.txt"),
+                          4096,
+                          4096);
+  pipelined_reader_helper(include_bytes!("../../testdata/random_then_unicode.compressed"),
+                          include_bytes!("../../testdata/random_then_unicode"),
+                          4096,
+                          1000);
+  pipelined_reader_helper(include_bytes!("../../testdata/mapsdatazrh.compressed"),
+                          include_bytes!("../../testdata/mapsdatazrh"),
+                          65536,
+                          65536);
+  pipelined_reader_helper(include_bytes!("../../testdata/backward65536.compressed"),
+                          include_bytes!("../../testdata/backward65536"),
+                          4096,
+                          4096);
+  pipelined_reader_helper(include_bytes!("../../testdata/metablock_reset.compressed"),
+                          include_bytes!("../../testdata/metablock_reset"),
+                          4096,
+                          4096);
+  pipelined_reader_helper(include_bytes!("../../testdata/zeros.compressed"),
+                          include_bytes!("../../testdata/zeros"),
+                          65536,
+                          1 << 20);
+  pipelined_reader_helper(include_bytes!("../../testdata/x.compressed"),
+                          include_bytes!("../../testdata/x"),
+                          4096,
+                          1);
+  pipelined_reader_helper(include_bytes!("../../testdata/empty.compressed"), &[], 4096, 4096);
+}
+
+// Times NUM_BENCHMARK_ITERATIONS reads of the whole stream through the readers that
+// new_reader makes, printing the total as decompress_internal does.
+#[cfg(feature="std")]
+fn benchmark_reader<R, F>(label: &str, in_buf: &[u8], desired_buf: &[u8], new_reader: F) -> Duration
+  where R: Read,
+        F: Fn(UnlimitedBuffer) -> R
+{
+  let mut total = Duration::new(0, 0);
+  let mut timing_error = false;
+  let mut output = vec![0u8; desired_buf.len() + 1];
+  for _i in 0..NUM_BENCHMARK_ITERATIONS {
+    let start = now();
+    let mut rdec = new_reader(UnlimitedBuffer::new(in_buf));
+    let mut size = 0;
+    loop {
+      match rdec.read(&mut output[size..]) {
+        Ok(0) => break,
+        Ok(n) => size += n,
+        Err(e) => panic!("Error {:?}", e),
+      }
+    }
+    let (delta, err) = elapsed(start);
+    timing_error |= err;
+    total = total + delta;
+    assert!(output[..size] == desired_buf[..]);
+  }
+  if timing_error {
+    let _r = super::writeln0(&mut io::stderr(), "Timing error");
+  } else {
+    let _r = super::writeln_time(&mut io::stderr(),
+                                 label,
+                                 NUM_BENCHMARK_ITERATIONS as u64,
+                                 total.as_secs(),
+                                 total.subsec_nanos());
+  }
+  total
+}
+
+// metablock_reset codes its literals without context modeling, so the worker leaves
+// every copy to the reader; alice29 and lcet10 use context modeling, so the worker
+// applies the copies as well and the reader only takes over the output.
+#[test]
+#[cfg(feature="std")]
+fn benchmark_pipelined_reader() {
+  for &(in_buf, desired_buf) in [(&include_bytes!("../../testdata/metablock_reset.compressed")[..],
+                                  &include_bytes!("../../testdata/metablock_reset")[..]),
+                                 (ALICE29_BR, ALICE29),
+                                 (&include_bytes!("../../testdata/lcet10.txt.compressed")[..],
+                                  &include_bytes!("../../testdata/lcet10.txt")[..])].iter() {
+    benchmark_reader("Iterations; Time (Decompressor)", in_buf, desired_buf,
+                     |r| Decompressor::new(r, 65536));
+    benchmark_reader("Iterations; Time (ThreadedDecompressor)", in_buf, desired_buf,
+                     |r| ThreadedDecompressor::new(r, 65536));
+    benchmark_reader("Iterations; Time (PipelinedDecompressor)", in_buf, desired_buf,
+                     |r| PipelinedDecompressor::new(r, 65536));
+  }
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_pipelined_reader_invalid_data() {
+  let mut rdec = PipelinedDecompressor::new(UnlimitedBuffer::new(
+    include_bytes!("../../testdata/borked.compressed")), 4096);
+  let mut output = Vec::<u8>::new();
+  match rdec.read_to_end(&mut output) {
+    Ok(_) => panic!("borked input decoded successfully"),
+    Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+  }
+  // the bytes decoded before the error are handed out first, where Decompressor drops
+  // whatever the failing read had produced
+  let mut reference = Vec::<u8>::new();
+  let _ = Decompressor::new(UnlimitedBuffer::new(
+    include_bytes!("../../testdata/borked.compressed")), 4096).read_to_end(&mut reference);
+  assert!(output.len() >= reference.len());
+  assert!(output[..reference.len()] == reference[..]);
+  assert!(rdec.read(&mut [0u8; 16][..]).is_err());
+}
+
+#[test]
+#[cfg(feature="std")]
+#[should_panic(expected = "reader exploded")]
+fn test_pipelined_reader_propagates_panic() {
+  let mut rdec = PipelinedDecompressor::new(PanickingReader(0, ALICE29_BR.len() / 2), 4096);
+  let mut output = Vec::<u8>::new();
+  let _ = rdec.read_to_end(&mut output);
+}
+
+
+#[cfg(feature="std")]
+static SEEKABLE_FRAMES: [(&'static [u8], &'static [u8]); 5] = [
+  (include_bytes!("../../testdata/alice29.txt.compressed"), include_bytes!("../../testdata/alice29.txt")),
+  (include_bytes!("../../testdata/empty.compressed"), include_bytes!("../../testdata/empty")),
+  (include_bytes!("../../testdata/x.compressed"), include_bytes!("../../testdata/x")),
+  (include_bytes!("../../testdata/asyoulik.txt.compressed"), include_bytes!("../../testdata/asyoulik.txt")),
+  (include_bytes!("../../testdata/64x.compressed"), include_bytes!("../../testdata/64x")),
+];
+
+// Returns a seekable file built from SEEKABLE_FRAMES and its decompressed contents.
+#[cfg(feature="std")]
+fn seekable_file() -> (Vec<u8>, Vec<u8>) {
+  let mut file = Vec::<u8>::new();
+  let mut contents = Vec::<u8>::new();
+  for &(compressed, original) in SEEKABLE_FRAMES.iter() {
+    file.extend_from_slice(compressed);
+    contents.extend_from_slice(original);
+  }
+  let table = SeekTable::from_concatenated_frames(&file[..]).unwrap();
+  assert_eq!(table.len(), SEEKABLE_FRAMES.len());
+  for (entry, &(compressed, original)) in table.entries().iter().zip(SEEKABLE_FRAMES.iter()) {
+    assert_eq!(entry.compressed_size as usize, compressed.len());
+    assert_eq!(entry.decompressed_size as usize, original.len());
+  }
+  table.write_to(&mut file).unwrap();
+  (file, contents)
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_seekable_read_all() {
+  let (file, contents) = seekable_file();
+  let mut rdec = SeekableDecompressor::new(io::Cursor::new(&file[..])).unwrap();
+  assert_eq!(rdec.seek_table().decompressed_size(), contents.len() as u64);
+  let mut output = Vec::<u8>::new();
+  rdec.read_to_end(&mut output).unwrap();
+  assert!(output == contents);
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_seekable_random_access() {
+  use std::io::{Seek, SeekFrom};
+  let (file, contents) = seekable_file();
+  let mut rdec = SeekableDecompressor::new(io::Cursor::new(&file[..])).unwrap();
+  let alice_len = ALICE29.len() as u64;
+  // ranges inside one frame, across frame boundaries and past the end
+  for &(start, len) in [(0u64, 10usize), (1000, 5000), (alice_len - 3, 10), (alice_len + 1, 2),
+                        (contents.len() as u64 - 5, 100), (contents.len() as u64 + 7, 4)].iter() {
+    rdec.seek(SeekFrom::Start(start)).unwrap();
+    let mut output = Vec::<u8>::new();
+    rdec.by_ref().take(len as u64).read_to_end(&mut output).unwrap();
+    let begin = cmp::min(start as usize, contents.len());
+    let end = cmp::min(begin + len, contents.len());
+    assert!(output[..] == contents[begin..end]);
+  }
+  assert_eq!(rdec.seek(SeekFrom::End(-64)).unwrap(), contents.len() as u64 - 64);
+  let mut tail = [0u8; 64];
+  rdec.read_exact(&mut tail[..]).unwrap();
+  assert!(tail[..] == contents[contents.len() - 64..]);
+  assert_eq!(rdec.seek(SeekFrom::Current(-128)).unwrap(), contents.len() as u64 - 128);
+  assert!(rdec.seek(SeekFrom::Current(-(contents.len() as i64))).is_err());
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_seekable_table_is_valid_brotli() {
+  // tables small and large enough to need 1, 2 and 3 bytes of metadata length
+  for &num_frames in [1usize, 100, 10000].iter() {
+    let mut table = SeekTable::new();
+    for i in 0..num_frames {
+      table.push(i as u32 + 1, 2 * i as u32);
+    }
+    let encoded = table.to_bytes().unwrap();
+    let mut output = Vec::<u8>::new();
+    super::brotli_decompressor::BrotliDecompress(&mut &encoded[..], &mut output).unwrap();
+    assert_eq!(output.len(), 0);
+    let mut file = vec![0u8; table.compressed_size() as usize];
+    file.extend_from_slice(&encoded[..]);
+    assert!(SeekTable::read_from(&mut io::Cursor::new(&file[..])).unwrap() == table);
+  }
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_seekable_rejects_bad_table() {
+  match SeekableDecompressor::new(io::Cursor::new(ALICE29_BR)) {
+    Ok(_) => panic!("plain brotli stream accepted as seekable"),
+    Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+  }
+  let (mut file, _) = seekable_file();
+  file.remove(0);
+  match SeekableDecompressor::new(io::Cursor::new(&file[..])) {
+    Ok(_) => panic!("seek table accepted for the wrong file size"),
+    Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+  }
+  let mut truncated = Vec::<u8>::new();
+  truncated.extend_from_slice(&ALICE29_BR[..ALICE29_BR.len() / 2]);
+  match SeekTable::from_concatenated_frames(&truncated[..]) {
+    Ok(_) => panic!("truncated frame accepted"),
+    Err(e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+  }
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_seekable_rejects_frame_larger_than_entry() {
+  let mut file = Vec::<u8>::new();
+  let mut table = SeekTable::new();
+  for &(compressed, original) in SEEKABLE_FRAMES.iter() {
+    file.extend_from_slice(compressed);
+    table.push(compressed.len() as u32, original.len() as u32);
+  }
+  // the first frame decodes to more than its entry allows
+  let mut understated = SeekTable::new();
+  for (i, entry) in table.entries().iter().enumerate() {
+    let shrink = if i == 0 { 1000 } else { 0 };
+    understated.push(entry.compressed_size, entry.decompressed_size - shrink);
+  }
+  understated.write_to(&mut file).unwrap();
+  let mut rdec = SeekableDecompressor::new(io::Cursor::new(&file[..])).unwrap();
+  let mut output = Vec::<u8>::new();
+  match rdec.read_to_end(&mut output) {
+    Ok(_) => panic!("frame larger than its seek table entry accepted"),
+    Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+  }
+  assert_eq!(output.len(), 0);
+  #[cfg(feature="parallel")]
+  {
+    match ParallelDecompressor::new(2).decompress_seekable(&file[..], &mut output) {
+      Ok(_) => panic!("frame larger than its seek table entry accepted"),
+      Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+    }
+    assert_eq!(output.len(), 0);
+  }
+}
+
+#[test]
+#[cfg(feature="parallel")]
+fn test_parallel_frames_matches_output() {
+  let mut input = Vec::<u8>::new();
+  let mut frame_sizes = Vec::<usize>::new();
+  let mut contents = Vec::<u8>::new();
+  for _ in 0..5 {
+    for &(compressed, original) in SEEKABLE_FRAMES.iter() {
+      input.extend_from_slice(compressed);
+      frame_sizes.push(compressed.len());
+      contents.extend_from_slice(original);
+    }
+  }
+  for num_threads in [0, 1, 2, 3, 8].iter() {
+    let mut output = Vec::<u8>::new();
+    let written = ParallelDecompressor::new(*num_threads)
+      .decompress_frames(&input[..], &frame_sizes[..], &mut output).unwrap();
+    assert_eq!(written, contents.len() as u64);
+    assert!(output == contents);
+  }
+  match ParallelDecompressor::new(2).decompress_frames(&input[1..], &frame_sizes[..], &mut io::sink()) {
+    Ok(_) => panic!("frame sizes longer than the input accepted"),
+    Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+  }
+}
+
+#[test]
+#[cfg(feature="parallel")]
+fn test_parallel_seekable_matches_output() {
+  let (file, contents) = seekable_file();
+  for num_threads in [1, 4].iter() {
+    let parallel = ParallelDecompressor::new(*num_threads);
+    let mut output = Vec::<u8>::new();
+    parallel.decompress_seekable(&file[..], &mut output).unwrap();
+    assert!(output == contents);
+    output.clear();
+    parallel.decompress_seekable_file(&mut io::Cursor::new(&file[..]), &mut output).unwrap();
+    assert!(output == contents);
+  }
+}
+
+#[test]
+#[cfg(feature="parallel")]
+fn test_parallel_reports_first_error_in_order() {
+  let truncated = &ALICE29_BR[..ALICE29_BR.len() / 2];
+  let frames: [&[u8]; 6] = [ALICE29_BR, include_bytes!("../../testdata/64x.compressed"), truncated,
+                            ALICE29_BR, b"\xff\xff\xff", ALICE29_BR];
+  let mut input = Vec::<u8>::new();
+  for frame in frames.iter() {
+    input.extend_from_slice(frame);
+  }
+  let frame_sizes: Vec<usize> = frames.iter().map(|frame| frame.len()).collect();
+  let mut expected = Vec::<u8>::new();
+  expected.extend_from_slice(ALICE29);
+  expected.extend_from_slice(include_bytes!("../../testdata/64x"));
+  for num_threads in [1, 3, 6].iter() {
+    let mut output = Vec::<u8>::new();
+    match ParallelDecompressor::new(*num_threads).decompress_frames(&input[..], &frame_sizes[..],
+                                                                    &mut output) {
+      Ok(_) => panic!("truncated frame accepted"),
+      Err(e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+    }
+    assert!(output == expected);
+  }
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_decompress_to_vec_matches_output() {
+  let alice = decompress_to_vec(ALICE29_BR, &DecompressLimits::default()).unwrap();
+  assert!(alice[..] == ALICE29[..]);
+  let mut limits = DecompressLimits::new();
+  limits.initial_capacity = 1;
+  let alice = decompress_to_vec(ALICE29_BR, &limits).unwrap();
+  assert!(alice[..] == ALICE29[..]);
+  limits.max_output_size = ALICE29.len();
+  let alice = decompress_to_vec(ALICE29_BR, &limits).unwrap();
+  assert!(alice[..] == ALICE29[..]);
+  assert_eq!(decompress_to_vec(include_bytes!("../../testdata/empty.compressed"),
+                               &DecompressLimits::default()).unwrap().len(), 0);
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_decompress_to_vec_errors() {
+  let mut limits = DecompressLimits::new();
+  limits.max_output_size = ALICE29.len() - 1;
+  assert_eq!(decompress_to_vec(ALICE29_BR, &limits), Err(DecompressError::LimitExceeded));
+  limits.initial_capacity = 1;
+  assert_eq!(decompress_to_vec(ALICE29_BR, &limits), Err(DecompressError::LimitExceeded));
+  let limits = DecompressLimits::default();
+  assert_eq!(decompress_to_vec(&ALICE29_BR[..ALICE29_BR.len() - 1], &limits),
+             Err(DecompressError::Truncated));
+  let mut trailing = ALICE29_BR.to_vec();
+  trailing.extend_from_slice(b"junk");
+  assert_eq!(decompress_to_vec(&trailing[..], &limits),
+             Err(DecompressError::TrailingData(ALICE29_BR.len())));
+  // with options too, unless they ask to leave trailing input alone
+  let options = DecompressorOptions::new().limits(limits);
+  assert_eq!(decompress_to_vec_with_options(&trailing[..], &options),
+             Err(DecompressError::TrailingData(ALICE29_BR.len())));
+  assert_eq!(decompress_to_vec_with_options(&trailing[..], &options.strict(false)).map(|v| v.len()),
+             Ok(ALICE29.len()));
+  match decompress_to_vec(include_bytes!("../../testdata/borked.compressed"), &limits) {
+    Err(DecompressError::Decoder(_)) => {}
+    other => panic!("corrupt stream accepted {:?}", other.map(|v| v.len())),
+  }
+  let e: io::Error = DecompressError::Truncated.into();
+  assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_decompress_into_fixed_buffer() {
+  let mut output = vec![0u8; ALICE29.len()];
+  assert_eq!(decompress_into(ALICE29_BR, &mut output[..]), Ok(ALICE29.len()));
+  assert!(output[..] == ALICE29[..]);
+  let mut output = vec![0u8; ALICE29.len() + 4096];
+  assert_eq!(decompress_into(ALICE29_BR, &mut output[..]), Ok(ALICE29.len()));
+  assert!(output[..ALICE29.len()] == ALICE29[..]);
+  let mut output = vec![0u8; ALICE29.len() - 1];
+  assert_eq!(decompress_into(ALICE29_BR, &mut output[..]), Err(DecompressError::OutputTooSmall));
+  let mut output = vec![0u8; ALICE29.len()];
+  assert_eq!(decompress_into(&ALICE29_BR[..ALICE29_BR.len() / 2], &mut output[..]),
+             Err(DecompressError::Truncated));
+  let mut trailing = ALICE29_BR.to_vec();
+  trailing.push(0);
+  assert_eq!(decompress_into(&trailing[..], &mut output[..]),
+             Err(DecompressError::TrailingData(ALICE29_BR.len())));
+  assert_eq!(decompress_into_with_options(&trailing[..], &mut output[..], &DecompressorOptions::default()),
+             Err(DecompressError::TrailingData(ALICE29_BR.len())));
+  assert_eq!(decompress_into_with_options(&trailing[..], &mut output[..], &DecompressorOptions::new().strict(false)),
+             Ok(ALICE29.len()));
+}
+
+#[test]
+fn test_oneshot_custom_alloc() {
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let alice_br = include_bytes!("../../testdata/alice29.txt.compressed");
+  let mut output = [0u8; 200000];
+  let size = decompress_into_custom_alloc(alice_br,
+                                          &mut output[..],
+                                          HeapAllocator::<u8> { default_value: 0 },
+                                          HeapAllocator::<u32> { default_value: 0 },
+                                          HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() });
+  assert_eq!(size, Ok(alice.len()));
+  assert!(output[..alice.len()] == alice[..]);
+  let mut limits = DecompressLimits::new();
+  limits.initial_capacity = 1000;
+  let (output, size) = decompress_to_vec_custom_alloc(alice_br,
+                                                      &limits,
+                                                      HeapAllocator::<u8> { default_value: 0 },
+                                                      HeapAllocator::<u32> { default_value: 0 },
+                                                      HeapAllocator::<HuffmanCode> {
+                                                        default_value: HuffmanCode::default(),
+                                                      }).unwrap();
+  assert_eq!(size, alice.len());
+  assert!(output.slice()[..size] == alice[..]);
+  let mut trailing = [0u8; 2];
+  trailing[..1].clone_from_slice(include_bytes!("../../testdata/empty.compressed"));
+  match decompress_to_vec_custom_alloc(&trailing[..],
+                                       &limits,
+                                       HeapAllocator::<u8> { default_value: 0 },
+                                       HeapAllocator::<u32> { default_value: 0 },
+                                       HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() }) {
+    Err(DecompressError::TrailingData(1)) => {}
+    _ => panic!("trailing data accepted"),
+  }
+}
+
+fn skip_output_helper(in_buf: &[u8], desired: &[u8], skips: &[usize], output_size: usize) {
+  let mut brotli_state =
+    BrotliState::new(HeapAllocator::<u8> { default_value: 0 },
+                     HeapAllocator::<u32> { default_value: 0 },
+                     HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() });
+  let mut output = vec![0u8; output_size];
+  let mut decoded = Vec::<u8>::new();
+  let mut expected = Vec::<u8>::new();
+  let mut expected_pos: usize = 0;
+  let mut available_in = in_buf.len();
+  let mut input_offset: usize = 0;
+  let mut total_out: usize = 0;
+  let mut skips = skips.iter();
+  loop {
+    // each call skips the next entry, then keeps what it writes
+    if let Some(skip) = skips.next() {
+      brotli_state.skip_output(*skip);
+      expected_pos = cmp::min(expected_pos + skip, desired.len());
+    }
+    let mut available_out = output_size;
+    let mut output_offset: usize = 0;
+    let result = BrotliDecompressStream(&mut available_in,
+                                        &mut input_offset,
+                                        in_buf,
+                                        &mut available_out,
+                                        &mut output_offset,
+                                        &mut output[..],
+                                        &mut total_out,
+                                        &mut brotli_state);
+    decoded.extend_from_slice(&output[..output_offset]);
+    expected.extend_from_slice(&desired[expected_pos..expected_pos + output_offset]);
+    expected_pos += output_offset;
+    assert_eq!(total_out, expected_pos);
+    match result {
+      BrotliResult::NeedsMoreOutput => {}
+      BrotliResult::ResultSuccess => break,
+      _ => panic!("unexpected result"),
+    }
+  }
+  assert_eq!(expected_pos, desired.len());
+  assert!(decoded == expected);
+}
+
+#[test]
+fn test_skip_output_matches_output() {
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let alice_br = include_bytes!("../../testdata/alice29.txt.compressed");
+  skip_output_helper(alice_br, alice, &[100000], 4096);
+  skip_output_helper(alice_br, alice, &[100000], alice.len() + 4096);
+  skip_output_helper(alice_br, alice, &[1, 70000, 0, 3, 65536], 1000);
+  skip_output_helper(alice_br, alice, &[alice.len()], 4096);
+  skip_output_helper(alice_br, alice, &[alice.len() + 10], 4096);
+  skip_output_helper(include_bytes!("../../testdata/metablock_reset.compressed"),
+                     include_bytes!("../../testdata/metablock_reset"), &[4000, 100, 65536], 257);
+  skip_output_helper(include_bytes!("../../testdata/random_org_10k.bin.compressed"),
+                     include_bytes!("../../testdata/random_org_10k.bin"), &[9000], 4096);
+  skip_output_helper(include_bytes!("../../testdata/empty.compressed"), &[], &[5], 16);
+}
+
+#[cfg(feature="std")]
+struct CountingReader<'a> {
+  data: &'a [u8],
+  read: usize,
+}
+
+#[cfg(feature="std")]
+impl<'a> Read for CountingReader<'a> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let size = cmp::min(buf.len(), self.data.len() - self.read);
+    buf[..size].clone_from_slice(&self.data[self.read..self.read + size]);
+    self.read += size;
+    Ok(size)
+  }
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_reader_skip_output_range() {
+  let ranges: [(usize, usize); 4] = [(0, 10), (1000, 5000), (100000, 51), (ALICE29.len() - 10, 10)];
+  for &(offset, length) in ranges.iter() {
+    let mut input = CountingReader { data: ALICE29_BR, read: 0 };
+    let mut output = Vec::<u8>::new();
+    {
+      let mut decompressor = Decompressor::new(&mut input, 1024);
+      decompressor.skip_output(offset);
+      decompressor.take(length as u64).read_to_end(&mut output).unwrap();
+    }
+    assert!(output[..] == ALICE29[offset..offset + length]);
+    if offset + length < ALICE29.len() / 2 {
+      assert!(input.read < ALICE29_BR.len() / 2);
+    }
+  }
+  let mut output = Vec::<u8>::new();
+  let mut decompressor = Decompressor::new(ALICE29_BR, 4096);
+  decompressor.skip_output(ALICE29.len() + 1);
+  assert_eq!(decompressor.read_to_end(&mut output).unwrap(), 0);
+  let mut output = Vec::<u8>::new();
+  super::decompress_range(&mut io::Cursor::new(ALICE29_BR), &mut output, 4096, Vec::new(), 12345, Some(678)).unwrap();
+  assert!(output[..] == ALICE29[12345..12345 + 678]);
+}
+
+#[test]
+fn test_dictionary_words() {
+  assert_eq!(dictionary::num_words(3), 0);
+  assert_eq!(dictionary::num_words(4), 1024);
+  assert_eq!(dictionary::num_words(25), 0);
+  assert_eq!(dictionary::word(4, 0), b"time");
+  assert_eq!(dictionary::word(4, 1), b"down");
+  let mut count = 0;
+  let mut size = 0;
+  for (len, idx, word) in dictionary::words() {
+    assert_eq!(word.len(), len);
+    assert_eq!(dictionary::word(len, idx), word);
+    count += 1;
+    size += len;
+  }
+  assert_eq!(count, (4..25).map(dictionary::num_words).sum::<usize>());
+  assert_eq!(size, dictionary::kBrotliDictionary.len());
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_transform_apply() {
+  assert_eq!(&transform::apply(0, b"time")[..], b"time");
+  assert_eq!(&transform::apply(1, b"time")[..], b"time ");
+  assert_eq!(&transform::apply(5, b"time")[..], b"time the ");
+  assert_eq!(&transform::apply(9, b"time")[..], b"Time");
+  assert_eq!(&transform::apply(44, b"time")[..], b"TIME");
+  assert_eq!(&transform::apply(3, b"time")[..], b"ime");
+  assert_eq!(&transform::apply(12, b"time")[..], b"tim");
+  let mut dst = [0u8; 4 + transform::kMaxTransformOverhead];
+  let len = transform::apply_into(10, b"time", &mut dst[..]);
+  assert_eq!(&dst[..len], b"time and ");
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_transform_reverse_lookup() {
+  let mut words = 0;
+  for (len, idx, word) in dictionary::words().step_by(1499) {
+    for t in 0..transform::kNumTransforms as usize {
+      let transformed = transform::apply(t, word);
+      let reference = transform::DictionaryReference { len, idx, transform: t };
+      let found: Vec<transform::DictionaryReference> = transform::reverse_lookup(&transformed[..]).collect();
+      assert!(found.contains(&reference));
+      for other in found.iter() {
+        assert!(transform::apply(other.transform, dictionary::word(other.len, other.idx)) == transformed);
+      }
+    }
+    words += 1;
+  }
+  assert!(words > 5);
+  assert_eq!(transform::reverse_lookup(b"\xff\xfe\xfd").count(), 0);
+  assert!(transform::reverse_lookup(b"").count() > 1000);
+}
+
+struct BitWriter {
+  data: Vec<u8>,
+  bit_pos: usize,
+}
+
+impl BitWriter {
+  fn write(&mut self, value: u32, nbits: usize) {
+    for i in 0..nbits {
+      if self.bit_pos % 8 == 0 {
+        self.data.push(0);
+      }
+      *self.data.last_mut().unwrap() |= (((value >> i) & 1) as u8) << (self.bit_pos % 8);
+      self.bit_pos += 1;
+    }
+  }
+}
+
+// A stream of one metablock of mlen bytes whose only command copies the 4-byte static
+// dictionary word word_idx through transform transform_idx.
+fn dictionary_reference_stream(word_idx: u32, transform_idx: u32, mlen: u32) -> Vec<u8> {
+  let mut w = BitWriter { data: Vec::new(), bit_pos: 0 };
+  w.write(0, 1); // WBITS 16
+  w.write(1, 1); // ISLAST
+  w.write(0, 1); // ISLASTEMPTY
+  w.write(0, 2); // MNIBBLES 4
+  w.write(mlen - 1, 16);
+  w.write(0, 3); // one block type per category
+  w.write(0, 6); // NPOSTFIX, NDIRECT
+  w.write(0, 2); // literal context mode
+  w.write(0, 2); // one literal and one distance tree
+  // simple prefix codes with one symbol each: literal, insert&copy, distance
+  let value = (transform_idx << 10) + word_idx;
+  let mut code = 0;
+  while (((2 + (code & 1)) << (1 + (code >> 1))) - 4) + (1 << (1 + (code >> 1))) <= value {
+    code += 1;
+  }
+  let nbits = 1 + (code >> 1) as usize;
+  let extra = value - (((2 + (code & 1)) << nbits) - 4);
+  w.write(1, 2);
+  w.write(0, 2);
+  w.write(b'a' as u32, 8);
+  w.write(1, 2);
+  w.write(0, 2);
+  w.write(130, 10); // no insert, copy 4, explicit distance
+  w.write(1, 2);
+  w.write(0, 2);
+  w.write(16 + code, 6);
+  w.write(extra, nbits);
+  w.data
+}
+
+fn decode_with_transforms(input: &[u8], prefix_suffix: &[u8], triplets: &[u8], params: &[u8])
+                          -> Result<Vec<u8>, BrotliDecoderErrorCode> {
+  let mut brotli_state =
+    BrotliState::new(HeapAllocator::<u8> { default_value: 0 },
+                     HeapAllocator::<u32> { default_value: 0 },
+                     HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() });
+  if !triplets.is_empty() {
+    assert!(brotli_state.set_custom_transforms(prefix_suffix.to_vec().into(),
+                                               triplets.to_vec().into(),
+                                               params.to_vec().into()));
+  }
+  let mut output = [0u8; 256];
+  let mut available_in = input.len();
+  let mut input_offset: usize = 0;
+  let mut available_out = output.len();
+  let mut output_offset: usize = 0;
+  let mut total_out: usize = 0;
+  match BrotliDecompressStream(&mut available_in,
+                               &mut input_offset,
+                               input,
+                               &mut available_out,
+                               &mut output_offset,
+                               &mut output[..],
+                               &mut total_out,
+                               &mut brotli_state) {
+    BrotliResult::ResultSuccess => Ok(output[..output_offset].to_vec()),
+    _ => Err(brotli_state.error_code),
+  }
+}
+
+#[test]
+fn test_custom_transforms() {
+  assert_eq!(decode_with_transforms(&dictionary_reference_stream(0, 0, 4)[..], &[], &[], &[]),
+             Ok(b"time".to_vec()));
+  assert_eq!(decode_with_transforms(&dictionary_reference_stream(1, 5, 9)[..], &[], &[], &[]),
+             Ok(b"down the ".to_vec()));
+  let prefix_suffix = b"\0<\0>\0";
+  let triplets = [1, transform::kIdentity, 3,
+                  1, transform::kUppercaseAll, 0,
+                  0, transform::kShiftAll, 3,
+                  3, transform::kShiftFirst, 0];
+  let params = [0, 0, 0, 0, 1, 0, 0xfe, 0xff];
+  let decode = |word_idx, transform_idx, mlen| {
+    decode_with_transforms(&dictionary_reference_stream(word_idx, transform_idx, mlen)[..],
+                           &prefix_suffix[..], &triplets[..], &params[..])
+  };
+  assert_eq!(decode(0, 0, 6), Ok(b"<time>".to_vec()));
+  assert_eq!(decode(1, 1, 5), Ok(b"<DOWN".to_vec()));
+  assert_eq!(decode(0, 2, 5), Ok(b"ujnf>".to_vec()));
+  assert_eq!(decode(0, 3, 5), Ok(b">rime".to_vec()));
+  assert_eq!(decode(0, 4, 4), Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_TRANSFORM));
+  assert_eq!(decode_with_transforms(&dictionary_reference_stream(0, 121, 4)[..], &[], &[], &[]),
+             Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_TRANSFORM));
+}
+
+#[test]
+fn test_custom_transforms_validation() {
+  assert!(transform::kRfcTransforms.is_valid());
+  let table = transform::TransformTable { prefix_suffix: b"\0ab\0", triplets: &[1, 0, 0], params: &[] };
+  assert!(table.is_valid());
+  assert_eq!(table.num_transforms(), 1);
+  assert_eq!(table.prefix(0), b"ab");
+  let invalid: [(&[u8], &[u8], &[u8]); 6] = [(b"\0", &[], &[]),
+                                             (b"\0", &[0, 0], &[]),
+                                             (b"\0ab", &[1, 0, 0], &[]),
+                                             (b"\0", &[0, 23, 0], &[]),
+                                             (b"\0", &[0, transform::kShiftAll, 0], &[]),
+                                             (b"\0", &[0, 0, 0], &[0])];
+  for &(prefix_suffix, triplets, params) in invalid.iter() {
+    let table = transform::TransformTable { prefix_suffix, triplets, params };
+    assert!(!table.is_valid());
+  }
+  let mut long_affix = vec![b'x'; 39];
+  long_affix.push(0);
+  assert!(!transform::TransformTable { prefix_suffix: &long_affix[..], triplets: &[0, 0, 0], params: &[] }.is_valid());
+  let mut brotli_state =
+    BrotliState::new(HeapAllocator::<u8> { default_value: 0 },
+                     HeapAllocator::<u32> { default_value: 0 },
+                     HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() });
+  assert!(!brotli_state.set_custom_transforms(vec![0u8].into(), vec![0u8, 0].into(), Vec::new().into()));
+  assert_eq!(brotli_state.transforms().num_transforms(), transform::kNumTransforms as usize);
+}
+
+// Bits of canonical prefix codes, fed least significant bit first.
+struct CodeReader {
+  bits: Vec<u8>,
+  pos: usize,
+}
+
+impl CodeReader {
+  // Writes the canonical code of each symbol, most significant code bit first.
+  fn new(code_lengths: &[u8], symbols: &[usize]) -> Self {
+    let mut next_code = [0u32; 16];
+    let mut code = 0u32;
+    for len in 1..16 {
+      code = (code + code_lengths.iter().filter(|l| **l as usize == len - 1 && len > 1).count() as u32) << 1;
+      next_code[len] = code;
+    }
+    let mut codes = vec![0u32; code_lengths.len()];
+    for (symbol, len) in code_lengths.iter().enumerate() {
+      if *len != 0 {
+        codes[symbol] = next_code[*len as usize];
+        next_code[*len as usize] += 1;
+      }
+    }
+    let mut bits = Vec::new();
+    for symbol in symbols.iter() {
+      let len = code_lengths[*symbol];
+      for i in (0..len).rev() {
+        bits.push(((codes[*symbol] >> i) & 1) as u8);
+      }
+    }
+    CodeReader { bits, pos: 0 }
+  }
+}
+
+impl huffman::BitSource for CodeReader {
+  fn peek_bits(&mut self, n_bits: u32) -> u32 {
+    (0..n_bits as usize).fold(0, |acc, i| acc | (*self.bits.get(self.pos + i).unwrap_or(&0) as u32) << i)
+  }
+  fn skip_bits(&mut self, n_bits: u32) -> bool {
+    if self.pos + n_bits as usize > self.bits.len() {
+      return false;
+    }
+    self.pos += n_bits as usize;
+    true
+  }
+}
+
+fn huffman_round_trip(code_lengths: &[u8], root_bits: u32) {
+  let size = huffman::table_size(code_lengths, root_bits).unwrap();
+  let mut codes = vec![HuffmanCode::default(); size + 3];
+  assert_eq!(huffman::build_table(code_lengths, root_bits, &mut codes[..]), Ok(size));
+  let table = huffman::HuffmanTable::new(&codes[..size], root_bits).unwrap();
+  let symbols: Vec<usize> = (0..3).flat_map(|_| (0..code_lengths.len()).filter(|s| code_lengths[*s] != 0))
+                                  .collect();
+  let mut reader = CodeReader::new(code_lengths, &symbols[..]);
+  for symbol in symbols.iter() {
+    assert_eq!(table.read_symbol(&mut reader), Some(*symbol as u16));
+  }
+  assert_eq!(reader.pos, reader.bits.len());
+}
+
+#[test]
+fn test_huffman_build_and_read() {
+  huffman_round_trip(&[2, 2, 2, 2], 8);
+  huffman_round_trip(&[1, 0, 2, 3, 3], 2);
+  // lengths past root_bits go to second level tables
+  let mut skewed = vec![0u8; 300];
+  for (len, symbol) in (1..15u8).zip((0..300).step_by(21)) {
+    skewed[symbol] = len;
+  }
+  skewed[299] = 14;
+  huffman_round_trip(&skewed[..], 8);
+  huffman_round_trip(&skewed[..], 6);
+  let flat = vec![9u8; 512];
+  huffman_round_trip(&flat[..], 8);
+  assert_eq!(huffman::table_size(&flat[..], 8), Ok(256 + 256 * 2));
+  // a lone symbol takes no bits
+  let mut codes = [HuffmanCode::default(); 4];
+  assert_eq!(huffman::build_table(&[0, 0, 5], 2, &mut codes[..]), Ok(4));
+  let table = huffman::HuffmanTable::new(&codes[..], 2).unwrap();
+  assert_eq!(table.lookup(3), (2, 0));
+  assert_eq!(table.read_symbol(&mut CodeReader { bits: Vec::new(), pos: 0 }), Some(2));
+}
+
+#[test]
+fn test_huffman_table_entries() {
+  let code_lengths = [1u8, 2, 4, 4, 4, 5, 5];
+  let size = huffman::table_size(&code_lengths[..], 2).unwrap();
+  assert_eq!(size, 4 + 4 + 4);
+  let mut codes = vec![HuffmanCode::default(); size];
+  huffman::build_table(&code_lengths[..], 2, &mut codes[..]).unwrap();
+  let table = huffman::HuffmanTable::new(&codes[..], 2).unwrap();
+  assert_eq!(table.entry(0), huffman::TableEntry::Symbol { symbol: 0, length: 1 });
+  assert_eq!(table.entry(2), huffman::TableEntry::Symbol { symbol: 0, length: 1 });
+  assert_eq!(table.entry(1), huffman::TableEntry::Symbol { symbol: 1, length: 2 });
+  let mut lengths = Vec::new();
+  for index in 0..4 {
+    if let huffman::TableEntry::SubTable { offset, bits } = table.entry(index) {
+      assert!(offset >= 4 && offset + (1 << bits) <= size);
+      for sub in offset..offset + (1 << bits) {
+        if let huffman::TableEntry::Symbol { symbol, length } = table.entry(sub) {
+          assert_eq!(length, code_lengths[symbol as usize] as u32);
+          lengths.push(symbol);
+        }
+      }
+    }
+  }
+  lengths.sort();
+  lengths.dedup();
+  assert_eq!(lengths, vec![2, 3, 4, 5, 6]);
+  // 0b10111 reversed: the code 1110 continues in a subtable
+  assert_eq!(table.lookup(0b0111), (4, 4));
+}
+
+#[test]
+fn test_huffman_build_errors() {
+  let mut codes = [HuffmanCode::default(); 256];
+  assert_eq!(huffman::build_table(&[1, 1, 1], 8, &mut codes[..]),
+             Err(huffman::HuffmanTableError::Oversubscribed));
+  assert_eq!(huffman::build_table(&[1, 2], 8, &mut codes[..]),
+             Err(huffman::HuffmanTableError::Incomplete));
+  assert_eq!(huffman::build_table(&[1, 16], 8, &mut codes[..]),
+             Err(huffman::HuffmanTableError::CodeLengthTooLong));
+  assert_eq!(huffman::build_table(&[1, 1], 0, &mut codes[..]),
+             Err(huffman::HuffmanTableError::InvalidRootBits));
+  assert_eq!(huffman::build_table(&[1, 1], 9, &mut codes[..]),
+             Err(huffman::HuffmanTableError::InvalidRootBits));
+  let mut deep = (1..16u8).collect::<Vec<u8>>();
+  assert_eq!(huffman::table_size(&deep[..], 4), Err(huffman::HuffmanTableError::Incomplete));
+  deep.push(15);
+  assert_eq!(huffman::table_size(&deep[..], 4), Err(huffman::HuffmanTableError::InvalidRootBits));
+  assert!(huffman::table_size(&deep[..], 7).is_ok());
+  assert_eq!(huffman::build_table(&vec![9u8; 512][..], 8, &mut codes[..]),
+             Err(huffman::HuffmanTableError::TableTooSmall));
+  // one more symbol than a u16 count holds, all of the same length
+  assert_eq!(huffman::table_size(&vec![15u8; 1 << 16][..], 8),
+             Err(huffman::HuffmanTableError::AlphabetTooLarge));
+  assert_eq!(huffman::table_size(&vec![15u8; 0xffff][..], 8),
+             Err(huffman::HuffmanTableError::Oversubscribed));
+}
+
+#[test]
+fn test_huffman_table_checked() {
+  let code_lengths = [1u8, 2, 4, 4, 4, 5, 5];
+  let mut codes = [HuffmanCode::default(); 12];
+  huffman::build_table(&code_lengths[..], 2, &mut codes[..]).unwrap();
+  assert!(huffman::HuffmanTable::new(&codes[..], 2).is_ok());
+  assert_eq!(huffman::HuffmanTable::new(&codes[..3], 2).unwrap_err(),
+             huffman::HuffmanTableError::TableTooSmall);
+  assert_eq!(huffman::HuffmanTable::new(&codes[..], 0).unwrap_err(),
+             huffman::HuffmanTableError::InvalidRootBits);
+  // the second level tables are cut off
+  assert_eq!(huffman::HuffmanTable::new(&codes[..8], 2).unwrap_err(),
+             huffman::HuffmanTableError::InvalidTable);
+  let mut malformed = [HuffmanCode::default(); 4];
+  malformed[1] = HuffmanCode { bits: 4, value: 1 };
+  assert_eq!(huffman::HuffmanTable::new(&malformed[..], 2).unwrap_err(),
+             huffman::HuffmanTableError::InvalidTable);
+  malformed[1] = HuffmanCode { bits: 16, value: 0 };
+  assert_eq!(huffman::HuffmanTable::new(&malformed[..], 2).unwrap_err(),
+             huffman::HuffmanTableError::InvalidTable);
+}
+
+fn decode_stats(input: &[u8], dict: &[u8]) -> (Vec<u8>, DecodeStats) {
+  let mut brotli_state =
+    BrotliState::new_with_custom_dictionary(HeapAllocator::<u8> { default_value: 0 },
+                                            HeapAllocator::<u32> { default_value: 0 },
+                                            HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() },
+                                            dict.to_vec().into());
+  brotli_state.enable_stats();
+  let mut output = [0u8; 256];
+  let mut available_in = input.len();
+  let mut input_offset: usize = 0;
+  let mut available_out = output.len();
+  let mut output_offset: usize = 0;
+  let mut total_out: usize = 0;
+  match BrotliDecompressStream(&mut available_in,
+                               &mut input_offset,
+                               input,
+                               &mut available_out,
+                               &mut output_offset,
+                               &mut output[..],
+                               &mut total_out,
+                               &mut brotli_state) {
+    BrotliResult::ResultSuccess => {}
+    _ => panic!("unexpected result"),
+  }
+  (output[..output_offset].to_vec(), brotli_state.take_stats().unwrap())
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_decode_stats_reader() {
+  let input = include_bytes!("../../testdata/alice29.txt.compressed");
+  let mut reader = Decompressor::new(&input[..], 4096);
+  assert!(reader.stats().is_none());
+  reader.enable_stats();
+  let mut output = Vec::new();
+  reader.read_to_end(&mut output).unwrap();
+  let stats = reader.stats().unwrap();
+  assert_eq!(output, &include_bytes!("../../testdata/alice29.txt")[..]);
+  assert_eq!(stats.output_bytes(), output.len() as u64);
+  assert!(stats.compressed_metablocks > 0);
+  assert_eq!(stats.uncompressed_metablocks + stats.metadata_metablocks, 0);
+  assert!(stats.literal_bytes > 0 && stats.copied_bytes > 0);
+  assert!(stats.distance_cache_hits.iter().sum::<u64>() > 0);
+  assert!(stats.huffman_trees.iter().all(|trees| *trees >= stats.compressed_metablocks));
+  assert!(stats.peak_huffman_table_bytes > 0 && stats.peak_huffman_table_bytes <= stats.huffman_table_bytes);
+  assert_eq!(stats.custom_dictionary_references, 0);
+}
+
+#[test]
+fn test_decode_stats_references() {
+  let (output, stats) = decode_stats(&dictionary_reference_stream(1, 5, 9)[..], &[]);
+  assert_eq!(output, b"down the ");
+  assert_eq!(stats.compressed_metablocks, 1);
+  assert_eq!(stats.total_dictionary_references(), 1);
+  assert_eq!(stats.dictionary_references[5], 1);
+  assert_eq!(stats.dictionary_bytes, 9);
+  assert_eq!(stats.copied_bytes + stats.literal_bytes, 0);
+  assert_eq!(stats.huffman_trees, [1, 1, 1]);
+  assert_eq!(stats.block_switches, [0, 0, 0]);
+  // the same distance falls within a custom dictionary of 8 bytes
+  let (output, stats) = decode_stats(&dictionary_reference_stream(3, 0, 4)[..], b"abcdefgh");
+  assert_eq!(output, b"efgh");
+  assert_eq!(stats.total_dictionary_references(), 0);
+  assert_eq!(stats.copied_bytes, 4);
+  assert_eq!(stats.custom_dictionary_references, 1);
+}
+
+// Two bytes of metadata, "hello" in an uncompressed metablock and an empty last one.
+fn uncompressed_stream() -> Vec<u8> {
+  let mut w = BitWriter { data: Vec::new(), bit_pos: 0 };
+  w.write(0, 1); // WBITS 16
+  w.write(0, 1); // ISLAST
+  w.write(3, 2); // metadata
+  w.write(0, 1); // reserved
+  w.write(1, 2); // MSKIPBYTES
+  w.write(1, 8); // two bytes of metadata
+  w.bit_pos = (w.bit_pos + 7) & !7;
+  w.write(0xabcd, 16);
+  w.write(0, 1); // ISLAST
+  w.write(0, 2); // MNIBBLES 4
+  w.write(4, 16);
+  w.write(1, 1); // ISUNCOMPRESSED
+  w.bit_pos = (w.bit_pos + 7) & !7;
+  for byte in b"hello".iter() {
+    w.write(*byte as u32, 8);
+  }
+  w.write(3, 2); // ISLAST, ISLASTEMPTY
+  w.data
+}
+
+#[test]
+fn test_decode_stats_uncompressed_and_metadata() {
+  let (output, stats) = decode_stats(&uncompressed_stream()[..], &[]);
+  assert_eq!(output, b"hello");
+  assert_eq!(stats.metadata_metablocks, 1);
+  assert_eq!(stats.uncompressed_metablocks, 1);
+  assert_eq!(stats.empty_metablocks, 1);
+  assert_eq!(stats.compressed_metablocks, 0);
+  assert_eq!(stats.uncompressed_bytes, 5);
+  assert_eq!(stats.output_bytes(), 5);
+  assert_eq!(stats.huffman_table_bytes, 0);
+}
+
+#[cfg(feature="std")]
+fn decode_provenance(input: &[u8], dict: &[u8], chunk_size: usize) -> (Vec<u8>, Vec<ProvenanceSpan>) {
+  let mut brotli_state =
+    BrotliState::new_with_custom_dictionary(HeapAllocator::<u8> { default_value: 0 },
+                                            HeapAllocator::<u32> { default_value: 0 },
+                                            HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() },
+                                            dict.to_vec().into());
+  let mut spans = Vec::new();
+  let mut output = Vec::new();
+  let mut buffer = [0u8; 1000];
+  let mut total_out: usize = 0;
+  for chunk in input.chunks(chunk_size) {
+    let mut available_in = chunk.len();
+    let mut input_offset: usize = 0;
+    loop {
+      let mut available_out = buffer.len();
+      let mut output_offset: usize = 0;
+      let result = BrotliDecompressStreamWithProvenance(&mut available_in,
+                                                        &mut input_offset,
+                                                        chunk,
+                                                        &mut available_out,
+                                                        &mut output_offset,
+                                                        &mut buffer[..],
+                                                        &mut total_out,
+                                                        &mut brotli_state,
+                                                        &mut spans);
+      output.extend_from_slice(&buffer[..output_offset]);
+      match result {
+        BrotliResult::NeedsMoreOutput => continue,
+        BrotliResult::ResultFailure => panic!("unexpected result"),
+        _ => break,
+      }
+    }
+  }
+  (output, spans)
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_provenance_explains_output() {
+  let input = &include_bytes!("../../testdata/alice29.txt.compressed")[..];
+  let (output, spans) = decode_provenance(input, &[], input.len());
+  assert_eq!(output, &include_bytes!("../../testdata/alice29.txt")[..]);
+  let mut output_pos = 0;
+  let mut bit_pos = 0;
+  for span in spans.iter() {
+    assert_eq!(span.output_start, output_pos);
+    assert!(span.len > 0);
+    assert!(bit_pos <= span.bit_start && span.bit_start <= span.bit_end);
+    let start = span.output_start as usize;
+    let end = start + span.len as usize;
+    match span.source {
+      Provenance::Copy { distance } => {
+        for pos in start..end {
+          assert_eq!(output[pos], output[pos - distance as usize]);
+        }
+      }
+      Provenance::StaticDictionary { word_len, word_idx, transform } => {
+        assert_eq!(transform::apply(transform as usize, dictionary::word(word_len as usize, word_idx as usize)),
+                   &output[start..end]);
+      }
+      Provenance::Literal => {}
+      _ => panic!("unexpected source"),
+    }
+    output_pos += span.len;
+    bit_pos = span.bit_end;
+  }
+  assert_eq!(output_pos, output.len() as u64);
+  assert!(bit_pos <= 8 * input.len() as u64);
+  assert!(spans.iter().any(|span| if let Provenance::StaticDictionary { .. } = span.source { true } else { false }));
+  // bit positions do not depend on how the input is split
+  for chunk_size in [1, 7, 4096].iter() {
+    assert_eq!(decode_provenance(input, &[], *chunk_size).1, spans);
+  }
+  let mut recorder = ProvenanceRecorder::new(Vec::new());
+  for span in spans.iter() {
+    recorder.span(span);
+  }
+  let merged = recorder.into_inner();
+  assert!(merged.len() <= spans.len());
+  assert_eq!(merged.iter().map(|span| span.len).sum::<u64>(), output.len() as u64);
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_provenance_sources() {
+  let stream = dictionary_reference_stream(1, 5, 9);
+  let (output, spans) = decode_provenance(&stream[..], &[], stream.len());
+  assert_eq!(output, b"down the ");
+  assert_eq!(spans.len(), 1);
+  assert_eq!(spans[0].source, Provenance::StaticDictionary { word_len: 4, word_idx: 1, transform: 5 });
+  assert_eq!((spans[0].output_start, spans[0].len), (0, 9));
+  // codes of a single symbol take no bits, leaving the 11 extra bits of the distance
+  assert_eq!(spans[0].bit_end - spans[0].bit_start, 11);
+  assert_eq!((spans[0].bit_end + 7) / 8, stream.len() as u64);
+  let stream = dictionary_reference_stream(3, 0, 4);
+  let (output, spans) = decode_provenance(&stream[..], b"abcdefgh", 1);
+  assert_eq!(output, b"efgh");
+  assert_eq!(spans.len(), 1);
+  assert_eq!(spans[0].source, Provenance::CustomDictionary { distance: 4, offset: 4 });
+  let stream = uncompressed_stream();
+  let (output, spans) = decode_provenance(&stream[..], &[], 2);
+  assert_eq!(output, b"hello");
+  // each call describes the bytes it copied
+  assert_eq!(spans.len(), 3);
+  let mut recorder = ProvenanceRecorder::new(Vec::new());
+  for span in spans.iter() {
+    recorder.span(span);
+  }
+  assert_eq!(recorder.into_inner(), vec![ProvenanceSpan { output_start: 0, len: 5, source: Provenance::Uncompressed,
+                                          bit_start: 7 * 8, bit_end: 12 * 8 }]);
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_write_provenance_json_lines() {
+  let input = &include_bytes!("../../testdata/alice29.txt.compressed")[..];
+  let mut json = Vec::new();
+  super::write_provenance(&mut &input[..], &mut json, 4096, Vec::new()).unwrap();
+  let json = String::from_utf8(json).unwrap();
+  let lines: Vec<&str> = json.lines().collect();
+  assert!(lines[0].starts_with("{\"output_start\":0,\"len\":"));
+  assert!(lines.iter().all(|line| line.starts_with('{') && line.ends_with('}')));
+  assert!(lines.iter().any(|line| line.contains("\"source\":\"copy\",\"distance\":")));
+  assert!(lines.iter().any(|line| line.contains("\"source\":\"static_dictionary\",\"word_len\":")));
+  let (_, spans) = decode_provenance(input, &[], input.len());
+  let mut recorder = ProvenanceRecorder::new(Vec::new());
+  for span in spans.iter() {
+    recorder.span(span);
+  }
+  assert_eq!(lines.len(), recorder.into_inner().len());
+  assert!(super::write_provenance(&mut &input[..input.len() - 10], &mut Vec::new(), 4096, Vec::new()).is_err());
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_provenance_recorder_merges_runs() {
+  let span = |output_start, len, source| ProvenanceSpan { output_start, len, source, bit_start: output_start, bit_end: output_start + len };
+  let mut recorder = ProvenanceRecorder::new(Vec::new());
+  recorder.span(&span(0, 2, Provenance::Literal));
+  recorder.span(&span(2, 3, Provenance::Literal));
+  recorder.span(&span(5, 4, Provenance::Copy { distance: 2 }));
+  recorder.span(&span(9, 4, Provenance::Copy { distance: 2 }));
+  recorder.span(&span(13, 4, Provenance::Copy { distance: 3 }));
+  let word = Provenance::StaticDictionary { word_len: 4, word_idx: 0, transform: 0 };
+  recorder.span(&span(17, 4, word));
+  recorder.span(&span(21, 4, word));
+  recorder.span(&span(25, 1, Provenance::Uncompressed));
+  assert_eq!(recorder.get_ref().len(), 5);
+  assert_eq!(recorder.into_inner(),
+             vec![span(0, 5, Provenance::Literal),
+                  span(5, 8, Provenance::Copy { distance: 2 }),
+                  span(13, 4, Provenance::Copy { distance: 3 }),
+                  span(17, 4, word),
+                  span(21, 4, word),
+                  span(25, 1, Provenance::Uncompressed)]);
+}
+
+#[test]
+fn test_bit_reader_slice() {
+  let data = [0xb5u8, 0x0f, 0x00, 0xf0, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc];
+  let mut reader = BitReader::new(&data[..]);
+  assert_eq!(reader.available_bits(), 80);
+  assert_eq!(reader.peek_bits(3), Some(0b101));
+  assert_eq!(reader.read_bits(3), Some(0b101));
+  assert_eq!(reader.read_bits(5), Some(0b10110));
+  assert!(reader.is_byte_aligned());
+  assert_eq!(reader.read_bits(4), Some(0xf));
+  assert!(reader.align_to_byte());
+  assert_eq!(reader.bit_position(), 16);
+  assert!(reader.skip_bits(12));
+  assert!(!reader.align_to_byte());
+  assert_eq!(reader.read_bits(32), Some(0x78563412));
+  assert_eq!(reader.read_bits(0), Some(0));
+  assert_eq!(reader.read_bits(17), None);
+  assert_eq!(reader.bit_position(), 64);
+  assert!(!reader.skip_bits(17));
+  assert!(reader.skip_bits(16));
+  assert_eq!(reader.available_bits(), 0);
+  assert_eq!(reader.peek_bits(1), None);
+  let mut owned = BitReader::new(vec![0xffu8; 100]);
+  assert!(owned.skip_bits(790));
+  assert_eq!(owned.read_bits(10), Some(0x3ff));
+  assert_eq!(owned.into_inner().len(), 100);
+}
+
+#[test]
+fn test_bit_reader_chunks() {
+  let data: Vec<u8> = (0..64u32).map(|i| (i * 37 + 11) as u8).collect();
+  let field_bits = |i: usize| ((i * 7) % 33) as u32;
+  let mut whole = BitReader::new(&data[..]);
+  let mut expected = Vec::new();
+  while let Some(bits) = whole.read_bits(field_bits(expected.len())) {
+    expected.push(bits);
+  }
+  for chunk_size in 1..9 {
+    let mut chunks = data.chunks(chunk_size);
+    let mut reader = BitReader::new(chunks.next().unwrap());
+    let mut actual = Vec::new();
+    while actual.len() < expected.len() {
+      let n_bits = field_bits(actual.len());
+      let checkpoint = reader.checkpoint();
+      // a field of two parts is read whole or not at all
+      let low = reader.read_bits(n_bits / 2);
+      let high = reader.read_bits(n_bits - n_bits / 2);
+      if let (Some(low), Some(high)) = (low, high) {
+        actual.push(low | high.checked_shl(n_bits / 2).unwrap_or(0));
+        continue;
+      }
+      assert!(reader.rollback(&checkpoint));
+      assert_eq!(reader.checkpoint(), checkpoint);
+      assert!(reader.next_chunk(chunks.next().unwrap()).is_ok());
+      assert!(!reader.rollback(&checkpoint));
+    }
+    assert_eq!(actual, expected);
+  }
+  // more than 8 unread bytes beyond the buffered bits are not carried over
+  let mut reader = BitReader::new(&data[..20]);
+  assert_eq!(reader.read_bits(8), Some(data[0] as u32));
+  assert_eq!(reader.next_chunk(&data[20..]).err(), Some(&data[20..]));
+  assert!(reader.skip_bits(8 * 18));
+  assert_eq!(reader.next_chunk(&data[20..]), Ok(&data[..20]));
+  assert_eq!(reader.read_bits(16), Some(data[19] as u32 | (data[20] as u32) << 8));
+}
+
+#[test]
+fn test_bit_reader_huffman_source() {
+  let code_lengths = [1u8, 2, 4, 4, 4, 5, 5];
+  let mut codes = vec![HuffmanCode::default(); 12];
+  huffman::build_table(&code_lengths[..], 2, &mut codes[..]).unwrap();
+  let table = huffman::HuffmanTable::new(&codes[..], 2).unwrap();
+  let symbols = [6usize, 0, 2, 5, 1, 1, 4, 3];
+  let code_reader = CodeReader::new(&code_lengths[..], &symbols[..]);
+  let mut bytes = vec![0u8; (code_reader.bits.len() + 7) / 8];
+  for (i, bit) in code_reader.bits.iter().enumerate() {
+    bytes[i / 8] |= bit << (i % 8);
+  }
+  let mut reader = BitReader::new(&bytes[..]);
+  for symbol in symbols.iter() {
+    assert_eq!(table.read_symbol(&mut reader), Some(*symbol as u16));
+  }
+  assert_eq!(reader.bit_position(), code_reader.bits.len() as u64);
+}
+
 #[cfg(feature="std")]
 fn writer_helper(mut in_buf: &[u8], desired_out_buf: &[u8], buf_size: usize) {
   let mut output = UnlimitedBuffer::new(&[]);
@@ -955,3 +2516,1330 @@ fn test_large_window() {
                                         1,
                                         16384);
 }
+
+#[test]
+fn test_sniff_header_streams() {
+  let alice = &include_bytes!("../../testdata/alice29.txt.compressed")[..];
+  let info = sniff_header(alice);
+  assert_eq!(info,
+             HeaderInfo {
+               valid: true,
+               window_bits: 22,
+               large_window: false,
+               empty: false,
+               confidence: HeaderConfidence::Medium,
+             });
+  assert_eq!(info.window_size(), 1 << 22);
+  assert_eq!(sniff_header(&alice[..1]).confidence, HeaderConfidence::Low);
+  for empty in [&include_bytes!("../../testdata/empty.compressed")[..],
+                &include_bytes!("../../testdata/empty.compressed.18")[..]].iter() {
+    let info = sniff_header(empty);
+    assert!(info.valid && info.empty);
+    assert_eq!(info.confidence, HeaderConfidence::High);
+  }
+  let stream = uncompressed_stream();
+  let info = sniff_header(&stream[..]);
+  assert!(info.valid && !info.empty);
+  assert_eq!((info.window_bits, info.confidence), (16, HeaderConfidence::High));
+  let mut last = HeaderConfidence::None;
+  for len in 0..stream.len() {
+    let info = sniff_header(&stream[..len]);
+    assert_eq!(info.valid, len != 0);
+    assert!(info.confidence >= last);
+    last = info.confidence;
+  }
+}
+
+#[test]
+fn test_sniff_header_rejects() {
+  assert!(!sniff_header(&[]).valid);
+  // WBITS of 0x11 followed by a set bit.
+  assert_eq!(sniff_header(&[0x91]).confidence, HeaderConfidence::None);
+  // The empty stream with a stray padding bit.
+  assert!(!sniff_header(&[0x0e]).valid);
+  // A metadata metablock with its reserved bit set.
+  assert!(!sniff_header(&[0x1c, 0x00]).valid);
+  let info = sniff_header(&[0x11]);
+  assert!(info.valid && info.large_window);
+  assert_eq!((info.window_bits, info.confidence), (30, HeaderConfidence::Low));
+  // Large window of 2^9 bytes, below the smallest allowed.
+  assert!(!sniff_header(&[0x11, 0x09]).valid);
+  let info = sniff_header(&[0x11, 0x1c | 0xc0]);
+  assert!(info.valid && info.large_window && info.empty);
+  assert_eq!(info.window_bits, 28);
+}
+
+// An empty stream that signals a large window of 2^28 bytes.
+const kLargeWindowEmptyStream: [u8; 2] = [0x11, 0xdc];
+
+#[test]
+fn test_reset_keeps_large_window() {
+  let quickfox = include_bytes!("../../testdata/quickfox_repeated.compressed");
+  let mut state = BrotliState::new(HeapAllocator::<u8> { default_value: 0 },
+                                   HeapAllocator::<u32> { default_value: 0 },
+                                   HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() });
+  assert!(decode_in_chunks(&quickfox[..], 4096, &mut state).is_ok());
+  state.reset();
+  assert_eq!(decode_in_chunks(&kLargeWindowEmptyStream[..], 4096, &mut state), Ok(Vec::new()));
+  // and a state that refuses large windows keeps refusing them
+  state.set_large_window(false);
+  state.reset();
+  assert_eq!(decode_in_chunks(&kLargeWindowEmptyStream[..], 4096, &mut state),
+             Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_WINDOW_BITS));
+  state.reset();
+  assert!(decode_in_chunks(&quickfox[..], 4096, &mut state).is_ok());
+  state.reset();
+  assert_eq!(decode_in_chunks(&kLargeWindowEmptyStream[..], 4096, &mut state),
+             Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_WINDOW_BITS));
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_options_one_shot() {
+  let alice = &include_bytes!("../../testdata/alice29.txt.compressed")[..];
+  let expected = &include_bytes!("../../testdata/alice29.txt")[..];
+  let options = DecompressorOptions::new();
+  assert_eq!(&decompress_to_vec_with_options(alice, &options).unwrap()[..], expected);
+  let mut trailing = alice.to_vec();
+  trailing.extend_from_slice(b"trailing");
+  assert_eq!(decompress_to_vec_with_options(&trailing[..], &options),
+             Err(DecompressError::TrailingData(alice.len())));
+  assert_eq!(decompress_to_vec_with_options(&trailing[..], &options.strict(false)).unwrap().len(),
+             expected.len());
+  let limited = options.max_output_size(expected.len() - 1);
+  assert_eq!(decompress_to_vec_with_options(alice, &limited),
+             Err(DecompressError::LimitExceeded));
+  let mut output = vec![0u8; expected.len() + 10];
+  assert_eq!(decompress_into_with_options(alice, &mut output[..], &limited),
+             Err(DecompressError::LimitExceeded));
+  assert_eq!(decompress_into_with_options(alice, &mut output[..], &options),
+             Ok(expected.len()));
+  assert_eq!(decompress_to_vec_with_options(&kLargeWindowEmptyStream[..], &options),
+             Ok(Vec::new()));
+  match decompress_to_vec_with_options(&kLargeWindowEmptyStream[..], &options.large_window(false)) {
+    Err(DecompressError::Decoder(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_WINDOW_BITS)) => {}
+    other => panic!("{:?}", other),
+  }
+  let stream = dictionary_reference_stream(3, 0, 4);
+  assert_eq!(decompress_to_vec_with_options(&stream[..], &options.custom_dictionary(b"abcdefgh")),
+             Ok(b"efgh".to_vec()));
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_options_reader() {
+  let alice = &include_bytes!("../../testdata/alice29.txt.compressed")[..];
+  let expected = &include_bytes!("../../testdata/alice29.txt")[..];
+  let mut trailing = alice.to_vec();
+  trailing.extend_from_slice(b"trailing");
+  let options = DecompressorOptions::new().buffer_size(17);
+  let mut output = Vec::new();
+  Decompressor::with_options(&trailing[..], &options.strict(false)).read_to_end(&mut output).unwrap();
+  assert_eq!(&output[..], expected);
+  output.clear();
+  let mut reader = Decompressor::with_options(&trailing[..], &options);
+  assert_eq!(reader.read_to_end(&mut output).unwrap_err().kind(), io::ErrorKind::InvalidData);
+  output.clear();
+  Decompressor::with_options(alice, &options).read_to_end(&mut output).unwrap();
+  assert_eq!(&output[..], expected);
+  output.clear();
+  let mut reader = Decompressor::with_options(alice, &options.max_output_size(1000));
+  assert_eq!(reader.read_to_end(&mut output).unwrap_err().kind(), io::ErrorKind::InvalidData);
+  assert_eq!(&output[..], &expected[..1000]);
+  output.clear();
+  // skipped bytes are not delivered, so they do not count towards the limit
+  let mut reader = Decompressor::with_options(alice, &options.max_output_size(1000));
+  reader.skip_output(100000);
+  assert_eq!(reader.read_to_end(&mut output).unwrap_err().kind(), io::ErrorKind::InvalidData);
+  assert_eq!(&output[..], &expected[100000..101000]);
+  output.clear();
+  let mut reader = Decompressor::with_options(alice, &options.max_output_size(1000));
+  reader.skip_output(expected.len() - 1000);
+  reader.read_to_end(&mut output).unwrap();
+  assert_eq!(&output[..], &expected[expected.len() - 1000..]);
+  output.clear();
+  Decompressor::with_options(alice, &options.max_output_size(expected.len()))
+    .read_to_end(&mut output)
+    .unwrap();
+  assert_eq!(&output[..], expected);
+  let stream = dictionary_reference_stream(3, 0, 4);
+  output.clear();
+  Decompressor::with_options(&stream[..], &options.custom_dictionary(b"abcdefgh"))
+    .read_to_end(&mut output)
+    .unwrap();
+  assert_eq!(&output[..], b"efgh");
+  let mut reader = Decompressor::with_options(&kLargeWindowEmptyStream[..], &options.large_window(false));
+  assert!(reader.read_to_end(&mut output).is_err());
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_options_writer() {
+  let alice = &include_bytes!("../../testdata/alice29.txt.compressed")[..];
+  let expected = &include_bytes!("../../testdata/alice29.txt")[..];
+  let options = DecompressorOptions::new().buffer_size(1000);
+  {
+    let mut writer = DecompressorWriter::with_options(Vec::new(), &options);
+    writer.write_all(alice).unwrap();
+    assert_eq!(&writer.get_ref()[..], expected);
+    assert_eq!(writer.write(b"trailing").unwrap_err().kind(), io::ErrorKind::InvalidData);
+  }
+  {
+    let mut writer = DecompressorWriter::with_options(Vec::new(), &options.strict(false));
+    writer.write_all(alice).unwrap();
+    writer.write_all(b"trailing").unwrap();
+    assert_eq!(&writer.get_ref()[..], expected);
+  }
+  {
+    let mut writer = DecompressorWriter::with_options(Vec::new(), &options.max_output_size(5000));
+    assert_eq!(writer.write_all(alice).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    // like the reader, the writer hands over the output up to the limit
+    assert_eq!(&writer.get_ref()[..], &expected[..5000]);
+  }
+  {
+    let mut writer = DecompressorWriter::with_options(Vec::new(), &options.max_output_size(expected.len()));
+    writer.write_all(alice).unwrap();
+    assert_eq!(&writer.get_ref()[..], expected);
+  }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_options_ffi() {
+  use super::brotli_decompressor::ffi;
+  use super::brotli_decompressor::ffi::interface::{BrotliDecoderParameter, BrotliDecoderResult};
+  let stream = dictionary_reference_stream(3, 0, 4);
+  for &(input, large_window, dict, ok) in [(&kLargeWindowEmptyStream[..], true, None, true),
+                                           (&kLargeWindowEmptyStream[..], false, None, false),
+                                           (&stream[..], true, Some(&b"abcdefgh"[..]), true)]
+    .iter() {
+    let mut options = DecompressorOptions::new();
+    options.custom_dictionary = dict;
+    let mut output = [0u8; 16];
+    unsafe {
+      let state = ffi::BrotliDecoderCreateInstanceWithOptions(None, None, core::ptr::null_mut(), &options);
+      ffi::BrotliDecoderSetParameter(state,
+                                     BrotliDecoderParameter::BROTLI_DECODER_PARAM_LARGE_WINDOW,
+                                     large_window as u32);
+      let mut available_in = input.len();
+      let mut next_in = input.as_ptr();
+      let mut available_out = output.len();
+      let mut next_out = output.as_mut_ptr();
+      let mut total_out = 0;
+      let result = ffi::BrotliDecoderDecompressStream(state,
+                                                      &mut available_in,
+                                                      &mut next_in,
+                                                      &mut available_out,
+                                                      &mut next_out,
+                                                      &mut total_out);
+      ffi::BrotliDecoderDestroyInstance(state);
+      match result {
+        BrotliDecoderResult::BROTLI_DECODER_RESULT_SUCCESS => assert!(ok),
+        _ => assert!(!ok),
+      }
+      if dict.is_some() {
+        assert_eq!(&output[..total_out], b"efgh");
+      }
+    }
+  }
+}
+
+fn compound_dictionary_expected() -> Vec<u8> {
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let mut expected = Vec::new();
+  for &(start, end) in [(40000, 41000), (5000, 9000), (30000, 33000), (19000, 21000), (41000, 45000)]
+    .iter() {
+    expected.extend_from_slice(&alice[start..end]);
+  }
+  expected
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_compound_dictionary_options() {
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let stream = include_bytes!("../../testdata/alice29.txt.compound.br");
+  let dicts = [&alice[..20000], &alice[20000..40000]];
+  let options = DecompressorOptions::new().compound_dictionaries(&dicts[..]);
+  let output = decompress_to_vec_with_options(stream, &options).unwrap();
+  assert!(output == compound_dictionary_expected());
+}
+
+#[test]
+fn test_compound_dictionary_options_rejected() {
+  let stream = include_bytes!("../../testdata/alice29.txt.compound.br");
+  let dicts = [&b"dictionary"[..]; dictionary::kMaxCompoundDictionaries + 1];
+  let options = DecompressorOptions::new().compound_dictionaries(&dicts[..]);
+  let mut state = BrotliState::new_with_options(HeapAllocator::<u8> { default_value: 0 },
+                                                HeapAllocator::<u32> { default_value: 0 },
+                                                HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() },
+                                                &options);
+  for _ in 0..2 {
+    assert_eq!(decode_in_chunks(&stream[..], 4096, &mut state),
+               Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_COMPOUND_DICTIONARY));
+    state.reset();
+  }
+  // a dictionary that could not be copied is no more left out than one too many
+  let injector = FaultInjector::fail_nth(1);
+  let options = DecompressorOptions::new().compound_dictionaries(&dicts[..1]);
+  let mut state = BrotliState::new_with_options(FailingAllocator::new(HeapAllocator::<u8> { default_value: 0 }, &injector),
+                                                HeapAllocator::<u32> { default_value: 0 },
+                                                HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() },
+                                                &options);
+  assert!(injector.failed());
+  assert_eq!(decode_in_chunks(&stream[..], 4096, &mut state),
+             Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_COMPOUND_DICTIONARY));
+}
+
+fn decode_with_compound_dictionary(input: &[u8], dicts: &[&[u8]])
+                                   -> Result<Vec<u8>, BrotliDecoderErrorCode> {
+  let mut brotli_state =
+    BrotliState::new(HeapAllocator::<u8> { default_value: 0 },
+                     HeapAllocator::<u32> { default_value: 0 },
+                     HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() });
+  for dict in dicts.iter() {
+    assert!(brotli_state.attach_dictionary_copy(dict));
+  }
+  let mut output = [0u8; 256];
+  let mut available_in = input.len();
+  let mut input_offset: usize = 0;
+  let mut available_out = output.len();
+  let mut output_offset: usize = 0;
+  let mut total_out: usize = 0;
+  match BrotliDecompressStream(&mut available_in,
+                               &mut input_offset,
+                               input,
+                               &mut available_out,
+                               &mut output_offset,
+                               &mut output[..],
+                               &mut total_out,
+                               &mut brotli_state) {
+    BrotliResult::ResultSuccess => Ok(output[..output_offset].to_vec()),
+    _ => Err(brotli_state.error_code),
+  }
+}
+
+#[test]
+fn test_compound_dictionary_addresses() {
+  let dicts = [&b"abcd"[..], &b"efgh"[..]];
+  // the byte just beyond the window is the last one attached
+  assert_eq!(decode_with_compound_dictionary(&dictionary_reference_stream(3, 0, 4)[..], &dicts),
+             Ok(b"efgh".to_vec()));
+  assert_eq!(decode_with_compound_dictionary(&dictionary_reference_stream(6, 0, 4)[..], &dicts),
+             Ok(b"bcde".to_vec()));
+  // the static dictionary follows the attached ones
+  assert_eq!(decode_with_compound_dictionary(&dictionary_reference_stream(8, 0, 4)[..], &dicts),
+             Ok(b"time".to_vec()));
+  assert_eq!(decode_with_compound_dictionary(&dictionary_reference_stream(0, 0, 4)[..], &dicts),
+             Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_COMPOUND_DICTIONARY));
+  assert_eq!(decode_with_compound_dictionary(&dictionary_reference_stream(0, 0, 4)[..], &[]),
+             Ok(b"time".to_vec()));
+}
+
+#[test]
+fn test_compound_dictionary_chunks() {
+  let mut alloc = HeapAllocator::<u8> { default_value: 0 };
+  let mut dict = dictionary::CompoundDictionary::default();
+  for chunk in [&b"abc"[..], &b""[..], &b"defg"[..]].iter() {
+    let mut mem = alloc.alloc_cell(chunk.len());
+    mem.slice_mut().clone_from_slice(chunk);
+    assert!(dict.attach(mem).is_ok());
+  }
+  assert_eq!(dict.num_chunks(), 3);
+  assert_eq!(dict.total_size(), 7);
+  assert_eq!(dict.offset_of_address(0), 6);
+  let mut out = [0u8; 5];
+  assert!(dict.copy_to(1, &mut out));
+  assert_eq!(&out, b"bcdef");
+  assert!(dict.copy_to(7, &mut []));
+  assert!(!dict.copy_to(3, &mut out));
+  for _ in 3..dictionary::kMaxCompoundDictionaries {
+    assert!(dict.attach(alloc.alloc_cell(1)).is_ok());
+  }
+  assert!(dict.attach(alloc.alloc_cell(1)).is_err());
+  dict.free(&mut alloc);
+  assert_eq!(dict.total_size(), 0);
+}
+
+#[test]
+fn test_compound_dictionary_streaming() {
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let stream = include_bytes!("../../testdata/alice29.txt.compound.br");
+  let expected = compound_dictionary_expected();
+  let mut brotli_state =
+    BrotliState::new(HeapAllocator::<u8> { default_value: 0 },
+                     HeapAllocator::<u32> { default_value: 0 },
+                     HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() });
+  for dict in [&alice[..20000], &alice[20000..40000]].iter() {
+    let mut mem = brotli_state.alloc_u8.alloc_cell(dict.len());
+    mem.slice_mut().clone_from_slice(dict);
+    assert!(brotli_state.attach_dictionary(mem));
+  }
+  for round in 0..2 {
+    let mut output = [0u8; 100];
+    let mut decoded = Vec::new();
+    let mut consumed = 0;
+    let mut total_out = 0;
+    loop {
+      let chunk_end = cmp::min(consumed + 7, stream.len());
+      let mut available_in = chunk_end - consumed;
+      let mut input_offset = 0;
+      let mut available_out = output.len();
+      let mut output_offset = 0;
+      let result = BrotliDecompressStream(&mut available_in,
+                                          &mut input_offset,
+                                          &stream[consumed..chunk_end],
+                                          &mut available_out,
+                                          &mut output_offset,
+                                          &mut output[..],
+                                          &mut total_out,
+                                          &mut brotli_state);
+      consumed += input_offset;
+      decoded.extend_from_slice(&output[..output_offset]);
+      match result {
+        BrotliResult::ResultSuccess => break,
+        BrotliResult::NeedsMoreInput | BrotliResult::NeedsMoreOutput => {}
+        BrotliResult::ResultFailure => panic!("decoding failed"),
+      }
+      // attaching is only allowed before the stream starts
+      assert!(!brotli_state.attach_dictionary_copy(b"late"));
+    }
+    assert!(decoded == expected, "round {}", round);
+    // the dictionaries stay attached for the next stream
+    brotli_state.reset();
+  }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_compound_dictionary_ffi() {
+  use super::brotli_decompressor::ffi;
+  use super::brotli_decompressor::ffi::interface::{BrotliDecoderResult, BrotliSharedDictionaryType};
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let stream = include_bytes!("../../testdata/alice29.txt.compound.br");
+  let expected = compound_dictionary_expected();
+  let mut output = vec![0u8; expected.len()];
+  unsafe {
+    let state = ffi::BrotliDecoderCreateInstance(None, None, core::ptr::null_mut());
+    assert_eq!(ffi::BrotliDecoderAttachDictionary(
+      state, BrotliSharedDictionaryType::BROTLI_SHARED_DICTIONARY_SERIALIZED, 4, b"abcd".as_ptr()), 0);
+    for dict in [&alice[..20000], &alice[20000..40000]].iter() {
+      assert_eq!(ffi::BrotliDecoderAttachDictionary(
+        state, BrotliSharedDictionaryType::BROTLI_SHARED_DICTIONARY_RAW, dict.len(), dict.as_ptr()), 1);
+    }
+    let mut available_in = stream.len();
+    let mut next_in = stream.as_ptr();
+    let mut available_out = output.len();
+    let mut next_out = output.as_mut_ptr();
+    let mut total_out = 0;
+    let result = ffi::BrotliDecoderDecompressStream(state,
+                                                    &mut available_in,
+                                                    &mut next_in,
+                                                    &mut available_out,
+                                                    &mut next_out,
+                                                    &mut total_out);
+    ffi::BrotliDecoderDestroyInstance(state);
+    match result {
+      BrotliDecoderResult::BROTLI_DECODER_RESULT_SUCCESS => {}
+      _ => panic!("decoding failed"),
+    }
+    assert_eq!(total_out, expected.len());
+  }
+  assert!(output == expected);
+}
+
+#[test]
+fn test_compound_dictionary_reset_mid_copy() {
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let stream = include_bytes!("../../testdata/alice29.txt.compound.br");
+  let mut brotli_state =
+    BrotliState::new(HeapAllocator::<u8> { default_value: 0 },
+                     HeapAllocator::<u32> { default_value: 0 },
+                     HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() });
+  for dict in [&alice[..20000], &alice[20000..40000]].iter() {
+    assert!(brotli_state.attach_dictionary_copy(dict));
+  }
+  // a byte of output at a time, until the stream stops in the middle of a compound copy
+  let mut output = [0u8; 1];
+  let mut consumed = 0;
+  let mut total_out = 0;
+  while brotli_state.compound_copy.is_none() {
+    let mut available_in = stream.len() - consumed;
+    let mut input_offset = 0;
+    let mut available_out = output.len();
+    let mut output_offset = 0;
+    match BrotliDecompressStream(&mut available_in,
+                                 &mut input_offset,
+                                 &stream[consumed..],
+                                 &mut available_out,
+                                 &mut output_offset,
+                                 &mut output[..],
+                                 &mut total_out,
+                                 &mut brotli_state) {
+      BrotliResult::NeedsMoreOutput => {}
+      _ => panic!("the stream ended before a compound copy was cut short"),
+    }
+    consumed += input_offset;
+  }
+  brotli_state.reset();
+  // the run of x is one copy that overlaps itself, made where compound copies resume
+  let input = include_bytes!("../../testdata/64x.compressed");
+  let mut output = [0u8; 256];
+  let mut available_in = input.len();
+  let mut input_offset = 0;
+  let mut available_out = output.len();
+  let mut output_offset = 0;
+  let mut total_out = 0;
+  match BrotliDecompressStream(&mut available_in,
+                               &mut input_offset,
+                               &input[..],
+                               &mut available_out,
+                               &mut output_offset,
+                               &mut output[..],
+                               &mut total_out,
+                               &mut brotli_state) {
+    BrotliResult::ResultSuccess => {}
+    _ => panic!("decoding failed"),
+  }
+  assert_eq!(&output[..output_offset], &include_bytes!("../../testdata/64x")[..]);
+}
+
+fn decode_with_borrowed_dictionary(input: &[u8],
+                                   dict: &[u8],
+                                   brotli_state: &mut BrotliState<HeapAllocator<u8>,
+                                                                  HeapAllocator<u32>,
+                                                                  HeapAllocator<HuffmanCode>>)
+                                   -> Vec<u8> {
+  let mut output = [0u8; 256];
+  let mut available_in = input.len();
+  let mut input_offset: usize = 0;
+  let mut available_out = output.len();
+  let mut output_offset: usize = 0;
+  let mut total_out: usize = 0;
+  match BrotliDecompressStreamWithDictionary(&mut available_in,
+                                             &mut input_offset,
+                                             input,
+                                             &mut available_out,
+                                             &mut output_offset,
+                                             &mut output[..],
+                                             &mut total_out,
+                                             brotli_state,
+                                             dict) {
+    BrotliResult::ResultSuccess => output[..output_offset].to_vec(),
+    _ => panic!("unexpected result"),
+  }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_shared_dictionary() {
+  let stream = dictionary_reference_stream(3, 0, 4);
+  let dict = dictionary::SharedDictionary::prepare(b"abcdefgh");
+  let mut states: Vec<_> = (0..3).map(|_| {
+    BrotliState::new_with_shared_dictionary(HeapAllocator::<u8> { default_value: 0 },
+                                            HeapAllocator::<u32> { default_value: 0 },
+                                            HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() },
+                                            dict.clone())
+  }).collect();
+  match dict {
+    dictionary::SharedDictionary::Shared(ref bytes) => assert_eq!(std::sync::Arc::strong_count(bytes), 4),
+    _ => panic!("prepare shares its copy"),
+  }
+  for state in states.iter_mut() {
+    assert_eq!(decode_with_borrowed_dictionary(&stream[..], &[], state), b"efgh");
+    // the dictionary stays with the state for the next stream
+    state.reset();
+    assert_eq!(decode_with_borrowed_dictionary(&stream[..], &[], state), b"efgh");
+  }
+  let mut output = Vec::new();
+  Decompressor::with_options(&stream[..], &DecompressorOptions::new().shared_dictionary(&dict))
+    .read_to_end(&mut output)
+    .unwrap();
+  assert_eq!(&output[..], b"efgh");
+  let static_dict = dictionary::SharedDictionary::from(&b"abcdefgh"[..]);
+  assert_eq!(decompress_to_vec_with_options(&stream[..],
+                                            &DecompressorOptions::new().shared_dictionary(&static_dict)),
+             Ok(b"efgh".to_vec()));
+}
+
+#[test]
+fn test_borrowed_dictionary() {
+  let stream = dictionary_reference_stream(3, 0, 4);
+  // without a dictionary of its own, a state can borrow one for each call
+  let mut state = BrotliState::new(HeapAllocator::<u8> { default_value: 0 },
+                                   HeapAllocator::<u32> { default_value: 0 },
+                                   HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() });
+  assert_eq!(decode_with_borrowed_dictionary(&stream[..], b"abcdefgh", &mut state), b"efgh");
+  state.reset();
+  assert_eq!(decode_with_borrowed_dictionary(&stream[..], b"xyzw0123", &mut state), b"0123");
+  state.reset();
+  assert_eq!(decode_with_borrowed_dictionary(&stream[..], &[], &mut state), b"left");
+  // a dictionary the state owns only outlives the first stream when asked to
+  for &keep in [false, true].iter() {
+    let mut dict_mem = HeapAllocator::<u8> { default_value: 0 }.alloc_cell(8);
+    dict_mem.slice_mut().clone_from_slice(b"abcdefgh");
+    let mut state =
+      BrotliState::new_with_custom_dictionary(HeapAllocator::<u8> { default_value: 0 },
+                                              HeapAllocator::<u32> { default_value: 0 },
+                                              HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() },
+                                              dict_mem);
+    if keep {
+      state.keep_custom_dictionary();
+    }
+    assert_eq!(decode_with_borrowed_dictionary(&stream[..], &[], &mut state), b"efgh");
+    assert_eq!(state.custom_dict.slice().len(), if keep { 8 } else { 0 });
+    state.reset();
+    let expected: &[u8] = if keep { b"efgh" } else { b"left" };
+    assert_eq!(decode_with_borrowed_dictionary(&stream[..], &[], &mut state), expected);
+  }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_wrapper_dictionary() {
+  let stream = dictionary_reference_stream(3, 0, 4);
+  // a dictionary that lives no longer than the decoder, taking the place of the one in
+  // the options
+  let dict = b"abcdefgh".to_vec();
+  let options = DecompressorOptions::new().custom_dictionary(b"xyzw0123").buffer_size(1);
+  let mut output = Vec::new();
+  Decompressor::with_dictionary(&stream[..], &dict[..], &options)
+    .read_to_end(&mut output)
+    .unwrap();
+  assert_eq!(&output[..], b"efgh");
+  let shared: std::sync::Arc<[u8]> = dict.into();
+  let mut output = Vec::new();
+  {
+    let mut writer = DecompressorWriter::with_dictionary(&mut output, shared.clone(), &options);
+    for byte in stream.iter() {
+      writer.write_all(std::slice::from_ref(byte)).unwrap();
+    }
+  }
+  assert_eq!(&output[..], b"efgh");
+  assert_eq!(std::sync::Arc::strong_count(&shared), 1);
+  // an empty dictionary leaves the stream to decode without one
+  let mut output = Vec::new();
+  Decompressor::with_dictionary(&stream[..], &b""[..], &DecompressorOptions::new())
+    .read_to_end(&mut output)
+    .unwrap();
+  assert_eq!(&output[..], b"left");
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn test_sha256() {
+  assert_eq!(hex(&dictionary::sha256(b"")[..]),
+             "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+  assert_eq!(hex(&dictionary::sha256(b"abc")[..]),
+             "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+  // 56 and 64 bytes need a second padding block
+  assert_eq!(hex(&dictionary::sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")[..]),
+             "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+  assert_eq!(hex(&dictionary::sha256(&[b'a'; 64])[..]),
+             "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb");
+  assert_eq!(hex(&dictionary::sha256(include_bytes!("../../testdata/alice29.txt"))[..]),
+             "7467306ee0feed4971260f3c87421154a05be571d944e9cb021a5713700c38f0");
+  // fed in pieces that straddle block boundaries
+  let mut hasher = dictionary::Sha256::new();
+  for piece in include_bytes!("../../testdata/alice29.txt").chunks(37) {
+    hasher.update(piece);
+  }
+  assert_eq!(hex(&hasher.finish()[..]),
+             "7467306ee0feed4971260f3c87421154a05be571d944e9cb021a5713700c38f0");
+}
+
+#[cfg(feature="std")]
+fn framed_stream(dict: &[u8], stream: &[u8]) -> Vec<u8> {
+  let mut framed = super::brotli_decompressor::dictionary_store::dictionary_frame_header(dict).to_vec();
+  framed.extend_from_slice(stream);
+  framed
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_dictionary_store() {
+  let stream = dictionary_reference_stream(3, 0, 4);
+  let mut store = DictionaryStore::new();
+  let first = store.insert_with_id("first", b"abcdefgh");
+  store.insert_with_id("second", b"xyzw0123");
+  assert_eq!(store.insert(b"abcdefgh"), first);
+  assert_eq!(store.len(), 2);
+  let read = |input: Vec<u8>, store: &DictionaryStore| -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    DictionaryDecompressor::new(&input[..], 4096, store)?.read_to_end(&mut output)?;
+    Ok(output)
+  };
+  assert_eq!(read(framed_stream(b"abcdefgh", &stream[..]), &store).unwrap(), b"efgh");
+  assert_eq!(read(framed_stream(b"xyzw0123", &stream[..]), &store).unwrap(), b"0123");
+  // plain streams decode without a dictionary
+  assert_eq!(read(stream.clone(), &store).unwrap(), b"left");
+  assert_eq!(read(framed_stream(b"unknown", &stream[..]), &store).unwrap_err().kind(),
+             io::ErrorKind::NotFound);
+  assert_eq!(read(framed_stream(b"abcdefgh", &[])[..20].to_vec(), &store).unwrap_err().kind(),
+             io::ErrorKind::UnexpectedEof);
+  // a dictionary picked by id must be the one the frame names
+  let framed = framed_stream(b"abcdefgh", &stream[..]);
+  let mut output = Vec::new();
+  DictionaryDecompressor::with_dictionary(&framed[..], 4096, store.get_by_id("first").unwrap())
+    .unwrap()
+    .read_to_end(&mut output)
+    .unwrap();
+  assert_eq!(output, b"efgh");
+  match DictionaryDecompressor::with_dictionary(&framed[..], 4096, store.get_by_id("second").unwrap()) {
+    Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+    Ok(_) => panic!("mismatched dictionary accepted"),
+  }
+}
+
+#[cfg(feature="std")]
+fn id_framed_stream(id: &str, content: &[u8], stream: &[u8]) -> Vec<u8> {
+  let mut framed = super::brotli_decompressor::dictionary_store::dictionary_id_frame_header(id, content).unwrap();
+  framed.extend_from_slice(stream);
+  framed
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_dictionary_store_id_frame() {
+  let stream = dictionary_reference_stream(3, 0, 4);
+  let mut store = DictionaryStore::new();
+  store.insert_with_id("first", b"abcdefgh");
+  store.insert_with_id("second", b"xyzw0123");
+  let read = |input: Vec<u8>, store: &DictionaryStore| -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut decompressor = DictionaryDecompressor::new(&input[..], 4096, store)?;
+    assert_eq!(decompressor.dictionary_id(), Some("first"));
+    decompressor.read_to_end(&mut output)?;
+    Ok(output)
+  };
+  assert_eq!(read(id_framed_stream("first", b"efgh", &stream[..]), &store).unwrap(), b"efgh");
+  // the stream decodes against the wrong dictionary, but not to the content it was made from
+  let mut wrong = DictionaryStore::new();
+  wrong.insert_with_id("first", b"xyzw0123");
+  assert_eq!(read(id_framed_stream("first", b"efgh", &stream[..]), &wrong).unwrap_err().kind(),
+             io::ErrorKind::InvalidData);
+  assert_eq!(read(id_framed_stream("first", b"efgh", &stream[..]), &DictionaryStore::new()).unwrap_err().kind(),
+             io::ErrorKind::NotFound);
+  assert_eq!(read(id_framed_stream("first", b"efgh", &[])[..20].to_vec(), &store).unwrap_err().kind(),
+             io::ErrorKind::UnexpectedEof);
+  let framed = id_framed_stream("first", b"efgh", &stream[..]);
+  let mut output = Vec::new();
+  match DictionaryDecompressor::with_dictionary(&framed[..], 4096, store.get_by_id("second").unwrap())
+    .unwrap()
+    .read_to_end(&mut output) {
+    Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+    Ok(_) => panic!("mismatched dictionary accepted"),
+  }
+  assert_eq!(super::brotli_decompressor::dictionary_store::dictionary_id_frame_header("", b"").unwrap_err().kind(),
+             io::ErrorKind::InvalidInput);
+}
+
+#[test]
+#[cfg(feature="std")]
+fn test_dictionary_store_dir() {
+  let dir = std::env::temp_dir().join(format!("brotli-dict-dir-{}", std::process::id()));
+  std::fs::create_dir_all(dir.join("nested")).unwrap();
+  std::fs::write(dir.join("first"), b"abcdefgh").unwrap();
+  std::fs::write(dir.join("second"), b"xyzw0123").unwrap();
+  let store = DictionaryStore::load_dir(&dir).unwrap();
+  std::fs::remove_dir_all(&dir).unwrap();
+  assert_eq!(store.len(), 2);
+  assert_eq!(store.get_by_id("second").unwrap().hash, dictionary::sha256(b"xyzw0123"));
+  let framed = framed_stream(b"xyzw0123", &dictionary_reference_stream(3, 0, 4)[..]);
+  let mut output = Vec::new();
+  super::decompress_with_store(&mut &framed[..], &mut output, 4096, &store, 1, Some(2)).unwrap();
+  assert_eq!(output, b"12");
+}
+
+fn decode_in_chunks<AllocU8: Allocator<u8>, AllocU32: Allocator<u32>, AllocHC: Allocator<HuffmanCode>>
+  (input: &[u8],
+   chunk_size: usize,
+   brotli_state: &mut BrotliState<AllocU8, AllocU32, AllocHC>)
+   -> Result<Vec<u8>, BrotliDecoderErrorCode> {
+  let mut output = Vec::new();
+  let mut chunk = vec![0u8; chunk_size];
+  let mut available_in = input.len();
+  let mut input_offset: usize = 0;
+  let mut total_out: usize = 0;
+  loop {
+    let mut available_out = chunk.len();
+    let mut output_offset: usize = 0;
+    let result = BrotliDecompressStream(&mut available_in,
+                                        &mut input_offset,
+                                        input,
+                                        &mut available_out,
+                                        &mut output_offset,
+                                        &mut chunk[..],
+                                        &mut total_out,
+                                        brotli_state);
+    output.extend_from_slice(&chunk[..output_offset]);
+    match result {
+      BrotliResult::ResultSuccess => return Ok(output),
+      BrotliResult::NeedsMoreOutput => {}
+      BrotliResult::NeedsMoreInput => panic!("truncated input"),
+      BrotliResult::ResultFailure => return Err(brotli_state.error_code),
+    }
+  }
+}
+
+#[test]
+fn test_memory_requirement() {
+  let requirement = super::brotli_decompressor::memory_requirement(22, 0);
+  assert_eq!(requirement.u8_len, (1 << 22) + 42 + 24 + 256 + 16384 + 1024);
+  assert_eq!(requirement.u32_len, 3 * 256);
+  assert_eq!(requirement.huffman_code_len, 7 * 1080 + 3 * 256 * 1080);
+  assert_eq!(requirement.bytes(),
+             requirement.u8_len + 4 * requirement.u32_len +
+             core::mem::size_of::<HuffmanCode>() * requirement.huffman_code_len);
+  assert_eq!(super::brotli_decompressor::memory_requirement(22, 1000).u8_len, requirement.u8_len + 1000);
+  // no stream has a window outside 10..=30 bits
+  assert_eq!(super::brotli_decompressor::memory_requirement(0, 0),
+             super::brotli_decompressor::memory_requirement(10, 0));
+  assert_eq!(super::brotli_decompressor::memory_requirement(40, 0),
+             super::brotli_decompressor::memory_requirement(30, 0));
+}
+
+#[test]
+fn test_decoder_arena() {
+  let requirement = super::brotli_decompressor::memory_requirement(22, 0);
+  let mut u8_buffer = vec![0u8; requirement.u8_len];
+  let mut u32_buffer = vec![0u32; requirement.u32_len];
+  let mut hc_buffer = vec![HuffmanCode::default(); requirement.huffman_code_len];
+  assert!(super::brotli_decompressor::DecoderArena::new(22, 0,
+                                                       &mut u8_buffer[1..],
+                                                       &mut u32_buffer[..],
+                                                       &mut hc_buffer[..]).is_none());
+  let arena = super::brotli_decompressor::DecoderArena::new(22, 0,
+                                                            &mut u8_buffer[..],
+                                                            &mut u32_buffer[..],
+                                                            &mut hc_buffer[..]).unwrap();
+  let mut state = BrotliState::new(&arena, &arena, &arena);
+  let cases: [(&[u8], &[u8]); 6] = [
+    (include_bytes!("../../testdata/alice29.txt.compressed"), include_bytes!("../../testdata/alice29.txt")),
+    (include_bytes!("../../testdata/lcet10.txt.compressed"), include_bytes!("../../testdata/lcet10.txt")),
+    (include_bytes!("../../testdata/mapsdatazrh.compressed"), include_bytes!("../../testdata/mapsdatazrh")),
+    (include_bytes!("../../testdata/metablock_reset.compressed"), include_bytes!("../../testdata/metablock_reset")),
+    (include_bytes!("../../testdata/random_then_unicode.compressed"),
+     include_bytes!("../../testdata/random_then_unicode")),
+    (include_bytes!("../../testdata/empty.compressed"), include_bytes!("../../testdata/empty")),
+  ];
+  // one state decodes them all, with a ringbuffer and with the output as the window
+  for &chunk_size in [1usize << 12, 1 << 24].iter() {
+    for &(compressed, expected) in cases.iter() {
+      let output = decode_in_chunks(compressed, chunk_size, &mut state).unwrap();
+      assert!(output == expected);
+      state.reset();
+    }
+  }
+  state.BrotliStateCleanup();
+}
+
+#[test]
+fn test_decoder_arena_limits() {
+  // a custom dictionary copied into the state has a slot of its own
+  let stream = dictionary_reference_stream(3, 0, 4);
+  let requirement = super::brotli_decompressor::memory_requirement(16, 8);
+  let mut u8_buffer = vec![0u8; requirement.u8_len];
+  let mut u32_buffer = vec![0u32; requirement.u32_len];
+  let mut hc_buffer = vec![HuffmanCode::default(); requirement.huffman_code_len];
+  let arena = super::brotli_decompressor::DecoderArena::new(16, 8,
+                                                            &mut u8_buffer[..],
+                                                            &mut u32_buffer[..],
+                                                            &mut hc_buffer[..]).unwrap();
+  let mut dict = Allocator::<u8>::alloc_cell(&mut &arena, 8);
+  dict.slice_mut().clone_from_slice(b"abcdefgh");
+  let mut state = BrotliState::new_with_custom_dictionary(&arena, &arena, &arena, dict);
+  assert_eq!(decode_in_chunks(&stream[..], 256, &mut state), Ok(b"efgh".to_vec()));
+  state.reset();
+  // a window larger than the arena was set up for fails to get its ringbuffer
+  assert_eq!(decode_in_chunks(include_bytes!("../../testdata/alice29.txt.compressed"), 4096, &mut state),
+             Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_2));
+  state.BrotliStateCleanup();
+}
+
+#[cfg(feature="unsafe")]
+static mut STATIC_DECODER_16: StaticDecompressor<16> = StaticDecompressor::new();
+#[cfg(feature="unsafe")]
+static mut STATIC_DECODER_22: StaticDecompressor<22> = StaticDecompressor::new();
+
+#[test]
+#[cfg(feature="unsafe")]
+fn test_static_decompressor() {
+  // only this test touches the statics
+  let mut decoder_22 = Pin::static_mut(unsafe { &mut *core::ptr::addr_of_mut!(STATIC_DECODER_22) });
+  let mut decoder_16 = Pin::static_mut(unsafe { &mut *core::ptr::addr_of_mut!(STATIC_DECODER_16) });
+  let alice = include_bytes!("../../testdata/alice29.txt.compressed");
+  let random = include_bytes!("../../testdata/random_org_10k.bin.compressed");
+  assert!(decoder_22.state().is_none() && !decoder_22.is_used());
+  let mut output = Vec::new();
+  let mut chunk = [0u8; 4096];
+  let mut available_in = alice.len();
+  let mut input_offset = 0;
+  let mut total_out = 0;
+  loop {
+    let mut available_out = chunk.len();
+    let mut output_offset = 0;
+    let result = decoder_22.as_mut().decompress_stream(&mut available_in,
+                                                       &mut input_offset,
+                                                       &alice[..],
+                                                       &mut available_out,
+                                                       &mut output_offset,
+                                                       &mut chunk[..],
+                                                       &mut total_out);
+    output.extend_from_slice(&chunk[..output_offset]);
+    match result {
+      BrotliResult::NeedsMoreOutput => {}
+      BrotliResult::ResultSuccess => break,
+      _ => panic!("unexpected result"),
+    }
+  }
+  assert!(&output[..] == &include_bytes!("../../testdata/alice29.txt")[..]);
+  assert!(decoder_22.is_finished());
+  // the same decoder and memory serve the next stream
+  decoder_22.as_mut().reset();
+  assert_eq!(decode_in_chunks(&random[..], 1 << 20, unsafe { decoder_22.as_mut().state_mut() }),
+             Ok(include_bytes!("../../testdata/random_org_10k.bin").to_vec()));
+  decoder_22.as_mut().reset();
+  assert!(decode_in_chunks(&alice[..], 4096, unsafe { decoder_22.as_mut().state_mut() }).unwrap() ==
+          &include_bytes!("../../testdata/alice29.txt")[..]);
+
+  assert_eq!(decode_in_chunks(&random[..], 4096, unsafe { decoder_16.as_mut().state_mut() }),
+             Ok(include_bytes!("../../testdata/random_org_10k.bin").to_vec()));
+  decoder_16.as_mut().reset();
+  // the window of alice29 is 22 bits, rejected even when the output could hold it all
+  assert_eq!(decode_in_chunks(&alice[..], 1 << 20, unsafe { decoder_16.as_mut().state_mut() }),
+             Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_WINDOW_BITS));
+  assert_eq!(decoder_16.error_code(), BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_WINDOW_BITS);
+}
+
+#[test]
+fn test_counting_allocator() {
+  let counter = AllocCounter::new();
+  let mut state = BrotliState::new(CountingAllocator::new(HeapAllocator::<u8> { default_value: 0 }, &counter),
+                                   CountingAllocator::new(HeapAllocator::<u32> { default_value: 0 }, &counter),
+                                   CountingAllocator::new(HeapAllocator::<HuffmanCode> {
+                                                            default_value: HuffmanCode::default(),
+                                                          },
+                                                          &counter));
+  state.enable_stats();
+  let table_bytes = 1080 * core::mem::size_of::<HuffmanCode>();
+  assert_eq!(counter.stats().current_bytes, table_bytes);
+  let output = decode_in_chunks(include_bytes!("../../testdata/alice29.txt.compressed"), 4096, &mut state).unwrap();
+  assert!(&output[..] == &include_bytes!("../../testdata/alice29.txt")[..]);
+  let alloc_stats = counter.stats();
+  let decode_stats = state.stats().unwrap().clone();
+  // a single metablock lets the ringbuffer shrink to fit the stream
+  assert_eq!(decode_stats.ring_buffer_bytes, (1 << 18) + 66);
+  assert_eq!(alloc_stats.largest_bytes as u64, decode_stats.ring_buffer_bytes);
+  assert!(decode_stats.peak_tree_group_bytes > decode_stats.peak_huffman_table_bytes);
+  assert!(decode_stats.peak_context_map_bytes > 0);
+  assert!(alloc_stats.peak_bytes as u64 >=
+          decode_stats.ring_buffer_bytes + decode_stats.peak_tree_group_bytes +
+          decode_stats.peak_context_map_bytes);
+  assert!(alloc_stats.peak_bytes <= super::brotli_decompressor::memory_requirement(22, 0).bytes());
+  assert!(alloc_stats.allocations >= 4 + 6 * decode_stats.compressed_metablocks);
+  // the next stream is measured on its own
+  state.reset();
+  counter.reset();
+  assert_eq!(counter.stats().peak_bytes, table_bytes);
+  decode_in_chunks(include_bytes!("../../testdata/x.compressed"), 4096, &mut state).unwrap();
+  assert!(counter.stats().peak_bytes < alloc_stats.peak_bytes);
+  core::mem::drop(state);
+  assert_eq!(counter.stats().current_bytes, 0);
+
+  // wrappers take counting allocators like any other
+  let counter = AllocCounter::new();
+  let mut output = vec![0u8; 1 << 20];
+  let size = decompress_into_custom_alloc(include_bytes!("../../testdata/alice29.txt.compressed"),
+                                          &mut output[..],
+                                          CountingAllocator::new(HeapAllocator::<u8> { default_value: 0 }, &counter),
+                                          CountingAllocator::new(HeapAllocator::<u32> { default_value: 0 }, &counter),
+                                          CountingAllocator::new(HeapAllocator::<HuffmanCode> {
+                                                                   default_value: HuffmanCode::default(),
+                                                                 },
+                                                                 &counter)).unwrap();
+  assert!(&output[..size] == &include_bytes!("../../testdata/alice29.txt")[..]);
+  // the output was the window, so no ringbuffer
+  assert!(counter.stats().largest_bytes < 1 << 20);
+  assert_eq!(counter.stats().current_bytes, 0);
+}
+
+#[cfg(feature="std")]
+fn decode_with_faults(compressed: &[u8],
+                      injector: &FaultInjector,
+                      counter: &AllocCounter)
+                      -> Result<Vec<u8>, BrotliDecoderErrorCode> {
+  let mut state = BrotliState::new(CountingAllocator::new(FailingAllocator::new(HeapAllocator::<u8> { default_value: 0 },
+                                                                                injector),
+                                                          counter),
+                                   CountingAllocator::new(FailingAllocator::new(HeapAllocator::<u32> { default_value: 0 },
+                                                                                injector),
+                                                          counter),
+                                   CountingAllocator::new(FailingAllocator::new(HeapAllocator::<HuffmanCode> {
+                                                                                  default_value: HuffmanCode::default(),
+                                                                                },
+                                                                                injector),
+                                                          counter));
+  decode_in_chunks(compressed, 1 << 16, &mut state)
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_allocation_failure() {
+  let mut files = 0;
+  for entry in std::fs::read_dir("testdata").unwrap() {
+    let path = entry.unwrap().path();
+    if path.extension().map_or(true, |ext| ext != "compressed") {
+      continue;
+    }
+    let mut compressed = Vec::new();
+    std::fs::File::open(&path).unwrap().read_to_end(&mut compressed).unwrap();
+    let injector = FaultInjector::new();
+    let counter = AllocCounter::new();
+    let expected = decode_with_faults(&compressed[..], &injector, &counter);
+    assert!(!injector.failed());
+    assert_eq!(counter.stats().current_bytes, 0);
+    let allocations = injector.allocations();
+    assert!(allocations > 0);
+    for n in 1..allocations + 1 {
+      let injector = FaultInjector::fail_nth(n);
+      let counter = AllocCounter::new();
+      let result = decode_with_faults(&compressed[..], &injector, &counter);
+      assert!(injector.failed());
+      match result {
+        Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_CONTEXT_MODES) |
+        Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_TREE_GROUPS) |
+        Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_CONTEXT_MAP) |
+        Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_1) |
+        Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_2) |
+        Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_BLOCK_TYPE_TREES) => {}
+        // the context map table only fails the stream if it cannot be had again
+        ref other if n == 1 => assert!(*other == expected),
+        other => panic!("{:?}: allocation {} of {} failed with {:?}",
+                        path, n, allocations, other.map(|output| output.len())),
+      }
+      assert_eq!(counter.stats().current_bytes, 0);
+    }
+    files += 1;
+  }
+  assert!(files >= 20);
+}
+
+#[test]
+fn test_allocation_failure_custom_dictionary() {
+  // a custom dictionary that could not be copied fails the stream rather than being left out
+  let injector = FaultInjector::fail_nth(1);
+  let options = DecompressorOptions::new().custom_dictionary(b"dictionary");
+  let mut state = BrotliState::new_with_options(FailingAllocator::new(HeapAllocator::<u8> { default_value: 0 }, &injector),
+                                                HeapAllocator::<u32> { default_value: 0 },
+                                                HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() },
+                                                &options);
+  assert!(injector.failed());
+  assert_eq!(decode_in_chunks(include_bytes!("../../testdata/x.compressed"), 4096, &mut state),
+             Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_1));
+  // and so does every stream after it
+  state.reset();
+  assert_eq!(decode_in_chunks(include_bytes!("../../testdata/x.compressed"), 4096, &mut state),
+             Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_1));
+}
+
+type BudgetedState<'a> = BrotliState<BudgetedAllocator<'a, u8, HeapAllocator<u8>>,
+                                     BudgetedAllocator<'a, u32, HeapAllocator<u32>>,
+                                     BudgetedAllocator<'a, HuffmanCode, HeapAllocator<HuffmanCode>>>;
+
+fn budgeted_state(budget: &MemoryBudget) -> BudgetedState<'_> {
+  BrotliState::new(BudgetedAllocator::new(HeapAllocator::<u8> { default_value: 0 }, budget),
+                   BudgetedAllocator::new(HeapAllocator::<u32> { default_value: 0 }, budget),
+                   BudgetedAllocator::new(HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() },
+                                          budget))
+}
+
+fn is_alloc_error(code: BrotliDecoderErrorCode) -> bool {
+  (code as i32) <= BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_CONTEXT_MODES as i32 &&
+  (code as i32) >= BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_BLOCK_TYPE_TREES as i32
+}
+
+#[test]
+fn test_memory_budget() {
+  let compressed = include_bytes!("../../testdata/alice29.txt.compressed");
+  let expected = &include_bytes!("../../testdata/alice29.txt")[..];
+  let counter = AllocCounter::new();
+  let mut state = BrotliState::new(CountingAllocator::new(HeapAllocator::<u8> { default_value: 0 }, &counter),
+                                   CountingAllocator::new(HeapAllocator::<u32> { default_value: 0 }, &counter),
+                                   CountingAllocator::new(HeapAllocator::<HuffmanCode> {
+                                                            default_value: HuffmanCode::default(),
+                                                          },
+                                                          &counter));
+  decode_in_chunks(compressed, 4096, &mut state).unwrap();
+  let peak = counter.stats().peak_bytes;
+
+  // a budget is enough exactly when it covers the peak
+  let budget = MemoryBudget::new(peak);
+  let mut state = budgeted_state(&budget);
+  assert!(decode_in_chunks(compressed, 4096, &mut state).unwrap() == expected);
+  core::mem::drop(state);
+  assert_eq!(budget.used(), 0);
+  let budget = MemoryBudget::new(peak - 1);
+  let mut state = budgeted_state(&budget);
+  assert!(is_alloc_error(decode_in_chunks(compressed, 4096, &mut state).unwrap_err()));
+  core::mem::drop(state);
+  assert_eq!(budget.used(), 0);
+
+  // states drawing on one budget hold it together, and freed memory goes back to it
+  // (the second state holds its context map table from the start)
+  let budget = MemoryBudget::new(peak + 1080 * core::mem::size_of::<HuffmanCode>());
+  let mut first = budgeted_state(&budget);
+  let mut second = budgeted_state(&budget);
+  assert!(decode_in_chunks(compressed, 4096, &mut first).unwrap() == expected);
+  assert!(budget.used() > 0 && budget.available() < peak);
+  assert!(is_alloc_error(decode_in_chunks(compressed, 4096, &mut second).unwrap_err()));
+  core::mem::drop(first);
+  core::mem::drop(second);
+  assert_eq!(budget.used(), 0);
+  let mut third = budgeted_state(&budget);
+  assert!(decode_in_chunks(compressed, 4096, &mut third).unwrap() == expected);
+  core::mem::drop(third);
+}
+
+#[cfg(feature="std")]
+static SHARED_BUDGET: MemoryBudget = MemoryBudget::new(4 << 20);
+
+#[cfg(feature="std")]
+#[test]
+fn test_memory_budget_threads() {
+  let compressed = include_bytes!("../../testdata/alice29.txt.compressed");
+  let expected = &include_bytes!("../../testdata/alice29.txt")[..];
+  // decoders on many threads share a static budget, and each either decodes or fails cleanly
+  let workers: Vec<_> = (0..8).map(|_| std::thread::spawn(move || {
+    let mut decoded = 0;
+    for _ in 0..4 {
+      let mut state = budgeted_state(&SHARED_BUDGET);
+      match decode_in_chunks(compressed, 4096, &mut state) {
+        Ok(output) => {
+          assert!(output == expected);
+          decoded += 1;
+        }
+        Err(code) => assert!(is_alloc_error(code)),
+      }
+    }
+    decoded
+  })).collect();
+  let decoded = workers.into_iter().map(|worker| worker.join().unwrap()).sum::<u32>();
+  assert!(decoded > 0);
+  assert_eq!(SHARED_BUDGET.used(), 0);
+}
+
+// Decodes feeding at most input_chunk bytes per call and returns the output along with how
+// many calls yielded.
+fn decode_with_work_budget(input: &[u8],
+                           input_chunk: usize,
+                           output_chunk: usize,
+                           budget: WorkBudget)
+                           -> (Vec<u8>, usize) {
+  let mut brotli_state = BrotliState::new(HeapAllocator::<u8> { default_value: 0 },
+                                          HeapAllocator::<u32> { default_value: 0 },
+                                          HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() });
+  let mut output = Vec::new();
+  let mut chunk = vec![0u8; output_chunk];
+  let mut input_offset: usize = 0;
+  let mut total_out: usize = 0;
+  let mut yields = 0;
+  loop {
+    let mut available_in = cmp::min(input_chunk, input.len() - input_offset);
+    let end = input_offset + available_in;
+    let mut available_out = chunk.len();
+    let mut output_offset: usize = 0;
+    let result = BrotliDecompressStreamWithBudget(&mut available_in,
+                                                  &mut input_offset,
+                                                  &input[..end],
+                                                  &mut available_out,
+                                                  &mut output_offset,
+                                                  &mut chunk[..],
+                                                  &mut total_out,
+                                                  &mut brotli_state,
+                                                  budget);
+    output.extend_from_slice(&chunk[..output_offset]);
+    match result {
+      BudgetedResult::Done(BrotliResult::ResultSuccess) => return (output, yields),
+      BudgetedResult::Yield => yields += 1,
+      BudgetedResult::Done(BrotliResult::NeedsMoreOutput) => {}
+      BudgetedResult::Done(BrotliResult::NeedsMoreInput) => assert!(input_offset < input.len(), "truncated input"),
+      BudgetedResult::Done(BrotliResult::ResultFailure) =>
+        panic!("decoding failed with {:?}", brotli_state.error_code),
+    }
+  }
+}
+
+#[test]
+fn test_work_budget() {
+  let cases: [(&[u8], &[u8]); 6] = [
+    (include_bytes!("../../testdata/alice29.txt.compressed"), include_bytes!("../../testdata/alice29.txt")),
+    (include_bytes!("../../testdata/mapsdatazrh.compressed"), include_bytes!("../../testdata/mapsdatazrh")),
+    (include_bytes!("../../testdata/metablock_reset.compressed"), include_bytes!("../../testdata/metablock_reset")),
+    (include_bytes!("../../testdata/random_org_10k.bin.compressed"), include_bytes!("../../testdata/random_org_10k.bin")),
+    (include_bytes!("../../testdata/compressed_repeated.compressed"), include_bytes!("../../testdata/compressed_repeated")),
+    (include_bytes!("../../testdata/empty.compressed"), include_bytes!("../../testdata/empty")),
+  ];
+  let budgets = [WorkBudget::Commands(1), WorkBudget::Commands(100),
+                 WorkBudget::OutputBytes(1), WorkBudget::OutputBytes(4096)];
+  for &(compressed, expected) in cases.iter() {
+    for budget in budgets.iter() {
+      // a ringbuffer, the output as the window, and input running out between yields
+      for &(input_chunk, output_chunk) in [(1usize << 20, 1usize << 16), (1 << 20, 1 << 24), (7, 1 << 16)].iter() {
+        let (output, _) = decode_with_work_budget(compressed, input_chunk, output_chunk, *budget);
+        assert!(output == expected, "{:?} {} {}", budget, input_chunk, output_chunk);
+      }
+    }
+  }
+  let (compressed, expected) = cases[0];
+  let (_, few) = decode_with_work_budget(compressed, 1 << 20, 1 << 24, WorkBudget::Commands(100));
+  let (_, many) = decode_with_work_budget(compressed, 1 << 20, 1 << 24, WorkBudget::Commands(1));
+  assert!(few > 0 && many > 50 * few);
+  let (_, yields) = decode_with_work_budget(compressed, 1 << 20, 1 << 24, WorkBudget::OutputBytes(4096));
+  assert!(yields >= expected.len() / 8192);
+  // a budget that never runs out lets a single call decode it all
+  let (_, yields) = decode_with_work_budget(compressed, 1 << 20, 1 << 24, WorkBudget::OutputBytes(usize::MAX));
+  assert_eq!(yields, 0);
+
+  // the first metablock header alone is split over calls, between its context maps and trees
+  let mut brotli_state = BrotliState::new(HeapAllocator::<u8> { default_value: 0 },
+                                          HeapAllocator::<u32> { default_value: 0 },
+                                          HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() });
+  brotli_state.enable_stats();
+  let mut output = [0u8; 1 << 16];
+  let mut input_offset: usize = 0;
+  let mut total_out: usize = 0;
+  let mut header_yields = 0;
+  while total_out == 0 {
+    let mut available_in = compressed.len() - input_offset;
+    let mut available_out = output.len();
+    let mut output_offset: usize = 0;
+    match BrotliDecompressStreamWithBudget(&mut available_in,
+                                           &mut input_offset,
+                                           compressed,
+                                           &mut available_out,
+                                           &mut output_offset,
+                                           &mut output[..],
+                                           &mut total_out,
+                                           &mut brotli_state,
+                                           WorkBudget::Commands(1)) {
+      BudgetedResult::Yield => header_yields += 1,
+      _ => panic!("the header was decoded without yielding"),
+    }
+  }
+  let trees = brotli_state.stats().unwrap().huffman_trees;
+  assert!(trees.iter().sum::<u64>() >= 3);
+  // one yield before the second context map and before each tree
+  assert!(header_yields as u64 > trees.iter().sum::<u64>());
+}
+
+// A stream of `blocks` metadata metablocks of `len` bytes each, with no output.
+fn metadata_stream(blocks: usize, len: usize) -> Vec<u8> {
+  assert!(blocks > 0 && len > 0 && len <= 256);
+  let skip = (len - 1) as u8;
+  // a 16 bit window, then ISLAST = 0, MNIBBLES = 0, reserved, MSKIPBYTES = 1, MSKIPLEN - 1
+  let mut stream = vec![0x2c | (skip << 7), skip >> 1];
+  stream.extend(core::iter::repeat(0u8).take(len));
+  for _ in 1..blocks {
+    stream.push(0x16 | (skip << 6));
+    stream.push(skip >> 2);
+    stream.extend(core::iter::repeat(0u8).take(len));
+  }
+  // ISLAST = 1, ISLASTEMPTY = 1
+  stream.push(0x03);
+  stream
+}
+
+fn decode_with_caps(input: &[u8], caps: DecodeCaps) -> Result<Vec<u8>, BrotliDecoderErrorCode> {
+  let mut brotli_state = BrotliState::new(HeapAllocator::<u8> { default_value: 0 },
+                                          HeapAllocator::<u32> { default_value: 0 },
+                                          HeapAllocator::<HuffmanCode> { default_value: HuffmanCode::default() });
+  brotli_state.set_caps(caps);
+  decode_in_chunks(input, 1 << 16, &mut brotli_state)
+}
+
+// The start of a last metablock of 65536 bytes, up to its block type counts.
+fn many_trees_header() -> BitWriter {
+  let mut w = BitWriter { data: Vec::new(), bit_pos: 0 };
+  w.write(0, 1); // WBITS 16
+  w.write(1, 1); // ISLAST
+  w.write(0, 1); // ISLASTEMPTY
+  w.write(0, 2); // MNIBBLES 4
+  w.write(65535, 16);
+  w
+}
+
+#[test]
+fn test_decode_caps() {
+  let metadata = metadata_stream(100, 200);
+  assert_eq!(decode_with_caps(&metadata[..], DecodeCaps::new()), Ok(Vec::new()));
+  let caps = DecodeCaps { max_metablocks: 101, ..DecodeCaps::new() };
+  assert_eq!(decode_with_caps(&metadata[..], caps), Ok(Vec::new()));
+  let caps = DecodeCaps { max_metablocks: 100, ..DecodeCaps::new() };
+  assert_eq!(decode_with_caps(&metadata[..], caps),
+             Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_METABLOCKS));
+  let caps = DecodeCaps { max_metadata_bytes: 20000, ..DecodeCaps::new() };
+  assert_eq!(decode_with_caps(&metadata[..], caps), Ok(Vec::new()));
+  let caps = DecodeCaps { max_metadata_bytes: 19999, ..DecodeCaps::new() };
+  assert_eq!(decode_with_caps(&metadata[..], caps),
+             Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_METADATA));
+  // all input and no output goes over any ratio once past the allowance
+  let caps = DecodeCaps { max_input_per_output: 1000, ..DecodeCaps::new() };
+  assert_eq!(decode_with_caps(&metadata_stream(10, 200)[..], caps), Ok(Vec::new()));
+  assert_eq!(decode_with_caps(&metadata[..], caps),
+             Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_INPUT_PER_OUTPUT));
+
+  // real streams stay well within tight caps on the ratio
+  let cases: [(&[u8], &[u8]); 4] = [
+    (include_bytes!("../../testdata/alice29.txt.compressed"), include_bytes!("../../testdata/alice29.txt")),
+    (include_bytes!("../../testdata/metablock_reset.compressed"), include_bytes!("../../testdata/metablock_reset")),
+    (include_bytes!("../../testdata/random_org_10k.bin.compressed"), include_bytes!("../../testdata/random_org_10k.bin")),
+    (include_bytes!("../../testdata/empty.compressed"), include_bytes!("../../testdata/empty")),
+  ];
+  for &(compressed, expected) in cases.iter() {
+    let caps = DecodeCaps { max_input_per_output: 1, ..DecodeCaps::new() };
+    assert!(decode_with_caps(compressed, caps).unwrap() == expected);
+  }
+
+  let alice = include_bytes!("../../testdata/alice29.txt.compressed");
+  let caps = DecodeCaps { max_trees_per_group: 1, ..DecodeCaps::new() };
+  assert_eq!(decode_with_caps(alice, caps), Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_TREES));
+  let caps = DecodeCaps { max_trees_per_group: 256, max_metablocks: 1, ..DecodeCaps::new() };
+  assert!(decode_with_caps(alice, caps).unwrap() == &include_bytes!("../../testdata/alice29.txt")[..]);
+  // the counts are checked as they are read: these headers end right after them, and
+  // decode_with_caps panics if the decoder asks for more input
+  let caps = DecodeCaps { max_trees_per_group: 255, ..DecodeCaps::new() };
+  for &category in [0, 2].iter() {
+    let mut w = many_trees_header();
+    for _ in 0..category {
+      w.write(0, 1); // one block type
+    }
+    w.write(1, 1);
+    w.write(7, 3);
+    w.write(127, 7); // 256 block types
+    assert_eq!(decode_with_caps(&w.data[..], caps), Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_TREES));
+  }
+  let mut w = many_trees_header();
+  w.write(0, 3); // one block type per category
+  w.write(0, 6); // NPOSTFIX, NDIRECT
+  w.write(0, 2); // literal context mode
+  w.write(1, 1);
+  w.write(7, 3);
+  w.write(127, 7); // 256 literal trees
+  assert_eq!(decode_with_caps(&w.data[..], caps), Err(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_TREES));
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_decode_caps_options() {
+  let metadata = metadata_stream(100, 200);
+  // the caps carry through the options to the wrappers
+  let options = DecompressorOptions::new().caps(DecodeCaps { max_metablocks: 100, ..DecodeCaps::new() });
+  assert_eq!(decompress_to_vec_with_options(&metadata[..], &options),
+             Err(DecompressError::Decoder(BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_METABLOCKS)));
+  let mut output = Vec::new();
+  assert!(Decompressor::with_options(&metadata[..], &options).read_to_end(&mut output).is_err());
+}
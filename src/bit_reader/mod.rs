@@ -1,5 +1,7 @@
 #![allow(non_snake_case)]
+use core::cmp;
 use core::default::Default;
+use huffman::BitSource;
 macro_rules! xprintln (
   ($a : expr) => ();
   ($a : expr, $b : expr) => ();
@@ -449,6 +451,219 @@ pub fn BrotliWarmupBitReader(br: &mut BrotliBitReader, input: &[u8]) -> bool {
 }
 
 
+// Bytes of one chunk that BitReader::next_chunk keeps beyond the accumulator.
+const kCarryBytes: usize = 8;
+
+/// Reads a bitstream least significant bit first, as brotli does, from `input`: a slice it
+/// borrows or a buffer it owns, such as a `Vec<u8>`. A stream that arrives in chunks is fed
+/// with `next_chunk`, which carries the unread end of the previous chunk over.
+pub struct BitReader<Input: AsRef<[u8]>> {
+  input: Input,
+  next_in: usize,
+  carry: [u8; kCarryBytes],
+  carry_pos: usize,
+  carry_len: usize,
+  val: u64, // buffered bits, the next one lowest
+  bit_count: u32,
+  position: u64,
+  chunk: u64,
+}
+
+/// A position of a `BitReader` to return to with `BitReader::rollback`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BitReaderCheckpoint {
+  next_in: usize,
+  carry_pos: usize,
+  val: u64,
+  bit_count: u32,
+  position: u64,
+  chunk: u64,
+}
+
+impl<Input: AsRef<[u8]>> BitReader<Input> {
+  pub fn new(input: Input) -> Self {
+    BitReader {
+      input,
+      next_in: 0,
+      carry: [0; kCarryBytes],
+      carry_pos: 0,
+      carry_len: 0,
+      val: 0,
+      bit_count: 0,
+      position: 0,
+      chunk: 0,
+    }
+  }
+
+  fn next_byte(&mut self) -> Option<u8> {
+    if self.carry_pos < self.carry_len {
+      self.carry_pos += 1;
+      return Some(self.carry[self.carry_pos - 1]);
+    }
+    let byte = self.input.as_ref().get(self.next_in).cloned();
+    if byte.is_some() {
+      self.next_in += 1;
+    }
+    byte
+  }
+
+  fn fill(&mut self) {
+    while self.bit_count <= 56 {
+      match self.next_byte() {
+        Some(byte) => {
+          self.val |= (byte as u64) << self.bit_count;
+          self.bit_count += 8;
+        }
+        None => break,
+      }
+    }
+  }
+
+  fn drop_bits(&mut self, n_bits: u32) {
+    self.val = self.val.checked_shr(n_bits).unwrap_or(0);
+    self.bit_count -= n_bits;
+    self.position += n_bits as u64;
+  }
+
+  /// Returns the next `n_bits` bits, at most 32, without consuming them, or None if the
+  /// input ends first.
+  pub fn peek_bits(&mut self, n_bits: u32) -> Option<u32> {
+    assert!(n_bits <= 32);
+    if self.bit_count < n_bits {
+      self.fill();
+      if self.bit_count < n_bits {
+        return None;
+      }
+    }
+    Some(self.val as u32 & BitMask(n_bits))
+  }
+
+  /// Consumes and returns the next `n_bits` bits, at most 32, or returns None and consumes
+  /// nothing if the input ends first.
+  pub fn read_bits(&mut self, n_bits: u32) -> Option<u32> {
+    let bits = self.peek_bits(n_bits);
+    if bits.is_some() {
+      self.drop_bits(n_bits);
+    }
+    bits
+  }
+
+  /// Consumes `n_bits` bits, or returns false and consumes nothing if fewer are left.
+  pub fn skip_bits(&mut self, n_bits: u64) -> bool {
+    if n_bits > self.available_bits() {
+      return false;
+    }
+    let mut n_bits = n_bits;
+    while n_bits != 0 {
+      if self.bit_count == 0 {
+        self.fill();
+      }
+      let step = cmp::min(n_bits, self.bit_count as u64) as u32;
+      self.drop_bits(step);
+      n_bits -= step as u64;
+    }
+    true
+  }
+
+  /// Skips to the next byte boundary and returns whether the skipped bits were all zero,
+  /// as brotli requires of padding.
+  pub fn align_to_byte(&mut self) -> bool {
+    let pad_bits = ((8 - (self.position & 7)) & 7) as u32;
+    // the rest of a started byte is always buffered
+    let pad = self.val as u32 & BitMask(pad_bits);
+    self.drop_bits(pad_bits);
+    pad == 0
+  }
+
+  pub fn is_byte_aligned(&self) -> bool {
+    self.position & 7 == 0
+  }
+
+  /// Number of bits consumed since the reader was created.
+  pub fn bit_position(&self) -> u64 {
+    self.position
+  }
+
+  /// Number of bits left in the buffered bits, the carried over bytes and the current chunk.
+  pub fn available_bits(&self) -> u64 {
+    let unread = self.carry_len - self.carry_pos + self.input.as_ref().len() - self.next_in;
+    self.bit_count as u64 + ((unread as u64) << 3)
+  }
+
+  pub fn checkpoint(&self) -> BitReaderCheckpoint {
+    BitReaderCheckpoint {
+      next_in: self.next_in,
+      carry_pos: self.carry_pos,
+      val: self.val,
+      bit_count: self.bit_count,
+      position: self.position,
+      chunk: self.chunk,
+    }
+  }
+
+  /// Returns to `checkpoint`, unless `next_chunk` has replaced the chunk it was taken in;
+  /// then returns false and stays put. A parser can take a checkpoint, read a whole field
+  /// and on running out of input roll back before fetching the next chunk.
+  pub fn rollback(&mut self, checkpoint: &BitReaderCheckpoint) -> bool {
+    if checkpoint.chunk != self.chunk {
+      return false;
+    }
+    self.next_in = checkpoint.next_in;
+    self.carry_pos = checkpoint.carry_pos;
+    self.val = checkpoint.val;
+    self.bit_count = checkpoint.bit_count;
+    self.position = checkpoint.position;
+    true
+  }
+
+  /// Continues the stream with `input`. Up to 64 bits of the current chunk stay buffered
+  /// and up to 8 more unread bytes are carried over, much like the decoder carries the
+  /// start of a split value in its own buffer. Returns the previous chunk for reuse, or
+  /// hands `input` back if more of the current chunk is unread than that.
+  pub fn next_chunk(&mut self, input: Input) -> Result<Input, Input> {
+    self.fill();
+    let mut unread = [0u8; kCarryBytes];
+    let mut unread_len = 0;
+    {
+      let carry = &self.carry[self.carry_pos..self.carry_len];
+      let rest = &self.input.as_ref()[self.next_in..];
+      if carry.len() + rest.len() > kCarryBytes {
+        return Err(input);
+      }
+      for byte in carry.iter().chain(rest.iter()) {
+        unread[unread_len] = *byte;
+        unread_len += 1;
+      }
+    }
+    self.carry = unread;
+    self.carry_pos = 0;
+    self.carry_len = unread_len;
+    self.next_in = 0;
+    self.chunk += 1;
+    Ok(core::mem::replace(&mut self.input, input))
+  }
+
+  pub fn get_ref(&self) -> &Input {
+    &self.input
+  }
+
+  pub fn into_inner(self) -> Input {
+    self.input
+  }
+}
+
+impl<Input: AsRef<[u8]>> BitSource for BitReader<Input> {
+  fn peek_bits(&mut self, n_bits: u32) -> u32 {
+    if self.bit_count < n_bits {
+      self.fill();
+    }
+    // bits past the end of the input are zero in val
+    self.val as u32 & BitMask(n_bits)
+  }
+  fn skip_bits(&mut self, n_bits: u32) -> bool {
+    BitReader::skip_bits(self, n_bits as u64)
+  }
+}
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,146 @@
+// A limit on the memory of many decoders together.
+//
+// A MemoryBudget is shared by reference between the BudgetedAllocators of any number of
+// states. Each allocation takes its bytes out of the budget before it is made and each free
+// gives them back, so memory in use never exceeds the limit. An allocation that does not
+// fit comes back empty, as when the allocator itself runs out, and the decoder fails with
+// one of the BROTLI_DECODER_ERROR_ALLOC_* codes.
+//
+// With std the budget is counted with atomics, so decoders on different threads can share
+// a `static` one; without std it is a plain counter for decoders on one thread.
+
+use core;
+#[cfg(not(feature="std"))]
+use core::cell::Cell;
+use core::marker::PhantomData;
+#[cfg(feature="std")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::{Allocator, SliceWrapper};
+
+/// Bytes that the allocators drawing on it may hold at once, all together.
+#[derive(Debug)]
+pub struct MemoryBudget {
+  limit: usize,
+  #[cfg(feature="std")]
+  used: AtomicUsize,
+  #[cfg(not(feature="std"))]
+  used: Cell<usize>,
+}
+
+impl MemoryBudget {
+  pub const fn new(limit: usize) -> Self {
+    MemoryBudget {
+      limit,
+      #[cfg(feature="std")]
+      used: AtomicUsize::new(0),
+      #[cfg(not(feature="std"))]
+      used: Cell::new(0),
+    }
+  }
+
+  pub fn limit(&self) -> usize {
+    self.limit
+  }
+
+  /// Bytes held right now by the allocators drawing on the budget.
+  #[cfg(feature="std")]
+  pub fn used(&self) -> usize {
+    self.used.load(Ordering::Relaxed)
+  }
+
+  /// Bytes held right now by the allocators drawing on the budget.
+  #[cfg(not(feature="std"))]
+  pub fn used(&self) -> usize {
+    self.used.get()
+  }
+
+  pub fn available(&self) -> usize {
+    self.limit.saturating_sub(self.used())
+  }
+
+  /// Takes `bytes` out of the budget, or returns false and takes nothing if they do not fit.
+  #[cfg(feature="std")]
+  pub fn try_reserve(&self, bytes: usize) -> bool {
+    self.used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+      match used.checked_add(bytes) {
+        Some(total) if total <= self.limit => Some(total),
+        _ => None,
+      }
+    }).is_ok()
+  }
+
+  /// Takes `bytes` out of the budget, or returns false and takes nothing if they do not fit.
+  #[cfg(not(feature="std"))]
+  pub fn try_reserve(&self, bytes: usize) -> bool {
+    match self.used.get().checked_add(bytes) {
+      Some(total) if total <= self.limit => {
+        self.used.set(total);
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// Gives back `bytes` taken by `try_reserve`.
+  #[cfg(feature="std")]
+  pub fn release(&self, bytes: usize) {
+    let _ = self.used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+      Some(used.saturating_sub(bytes))
+    });
+  }
+
+  /// Gives back `bytes` taken by `try_reserve`.
+  #[cfg(not(feature="std"))]
+  pub fn release(&self, bytes: usize) {
+    self.used.set(self.used.get().saturating_sub(bytes));
+  }
+}
+
+/// Allocates from `inner` only what fits in a budget it shares with other allocators.
+pub struct BudgetedAllocator<'a, T, A: Allocator<T>> {
+  inner: A,
+  budget: &'a MemoryBudget,
+  element: PhantomData<T>,
+}
+
+impl<'a, T, A: Allocator<T>> BudgetedAllocator<'a, T, A> {
+  pub fn new(inner: A, budget: &'a MemoryBudget) -> Self {
+    BudgetedAllocator {
+      inner,
+      budget,
+      element: PhantomData,
+    }
+  }
+
+  pub fn budget(&self) -> &'a MemoryBudget {
+    self.budget
+  }
+
+  pub fn into_inner(self) -> A {
+    self.inner
+  }
+}
+
+impl<'a, T, A: Allocator<T>> Allocator<T> for BudgetedAllocator<'a, T, A> {
+  type AllocatedMemory = A::AllocatedMemory;
+  fn alloc_cell(&mut self, len: usize) -> A::AllocatedMemory {
+    if len == 0 {
+      return self.inner.alloc_cell(0);
+    }
+    let bytes = match len.checked_mul(core::mem::size_of::<T>()) {
+      Some(bytes) => bytes,
+      None => return A::AllocatedMemory::default(),
+    };
+    if !self.budget.try_reserve(bytes) {
+      return A::AllocatedMemory::default();
+    }
+    let mem = self.inner.alloc_cell(len);
+    // the budget is charged for what the allocator really handed out
+    self.budget.release(bytes - core::cmp::min(bytes, core::mem::size_of_val(mem.slice())));
+    mem
+  }
+  fn free_cell(&mut self, data: A::AllocatedMemory) {
+    self.budget.release(core::mem::size_of_val(data.slice()));
+    self.inner.free_cell(data)
+  }
+}
@@ -17,13 +17,15 @@ use super::bit_reader;
 use super::huffman;
 use super::state;
 use super::prefix;
+use super::pipeline::{CommandSink, NopCommandSink};
+use super::provenance::{Provenance, ProvenanceSink, ProvenanceSpan};
 
-use super::transform::{TransformDictionaryWord, kNumTransforms};
+use super::transform::TransformDictionaryWordWithTable;
 use state::{BlockTypeAndLengthState, BrotliRunningContextMapState, BrotliRunningDecodeUint8State,
             BrotliRunningHuffmanState, BrotliRunningMetablockHeaderState,
             BrotliRunningReadBlockLengthState, BrotliRunningState, BrotliRunningTreeGroupState,
             BrotliRunningUncompressedState, kLiteralContextBits,
-            BrotliDecoderErrorCode,
+            BrotliDecoderErrorCode, WorkBudget, kInputPerOutputAllowance,
 };
 use context::{kContextLookup};
 use ::dictionary::{kBrotliDictionary, kBrotliDictionaryOffsetsByLength,
@@ -36,9 +38,17 @@ pub enum BrotliResult {
   NeedsMoreOutput,
   ResultFailure,
 }
+
+/// Result of `BrotliDecompressStreamWithBudget`.
+pub enum BudgetedResult {
+  /// The call stopped for one of the reasons `BrotliDecompressStream` returns.
+  Done(BrotliResult),
+  /// The call did as much work as its budget allows; call again to go on.
+  Yield,
+}
 const kBrotliWindowGap: u32 = 16;
-const kBrotliLargeMinWbits: u32 = 10;
-const kBrotliLargeMaxWbits: u32 = 30;
+pub const kBrotliLargeMinWbits: u32 = 10;
+pub const kBrotliLargeMaxWbits: u32 = 30;
 const kBrotliMaxPostfix: usize = 3;
 const kBrotliMaxAllowedDistance: u32 = 0x7FFFFFFC;
 const kDefaultCodeLength: u32 = 8;
@@ -46,7 +56,7 @@ const kCodeLengthRepeatCode: u32 = 16;
 const kNumLiteralCodes: u16 = 256;
 const kNumInsertAndCopyCodes: u16 = 704;
 const kNumBlockLengthCodes: u32 = 26;
-const kDistanceContextBits: i32 = 2;
+pub const kDistanceContextBits: i32 = 2;
 const HUFFMAN_TABLE_BITS: u32 = 8;
 const HUFFMAN_TABLE_MASK: u32 = 0xff;
 const CODE_LENGTH_CODES: usize = 18;
@@ -93,6 +103,9 @@ macro_rules! SaveErrorCode {
         BrotliResult::NeedsMoreInput,
       BrotliDecoderErrorCode::BROTLI_DECODER_NEEDS_MORE_OUTPUT =>
         BrotliResult::NeedsMoreOutput,
+      // only budgeted calls yield, and they look at the error code
+      BrotliDecoderErrorCode::BROTLI_DECODER_YIELD =>
+        BrotliResult::NeedsMoreOutput,
       _ =>
         BrotliResult::ResultFailure,
     }
@@ -147,9 +160,9 @@ pub use state::BrotliState;
 
 /* Decodes WBITS by reading 1 - 7 bits, or 0x11 for "Large Window Brotli".
    Precondition: bit-reader accumulator has at least 8 bits. */
-fn DecodeWindowBits(s_large_window: &mut bool,
-                    s_window_bits:&mut u32,
-                    br: &mut bit_reader::BrotliBitReader) -> BrotliDecoderErrorCode {
+pub fn DecodeWindowBits(s_large_window: &mut bool,
+                        s_window_bits:&mut u32,
+                        br: &mut bit_reader::BrotliBitReader) -> BrotliDecoderErrorCode {
   let mut n: u32 = 0;
   let large_window = *s_large_window;
   *s_large_window = false;
@@ -238,9 +251,9 @@ fn DecodeVarLenUint8(substate_decode_uint8: &mut state::BrotliRunningDecodeUint8
   }
 }
 
-fn DecodeMetaBlockLength<AllocU8: alloc::Allocator<u8>,
-                         AllocU32: alloc::Allocator<u32>,
-                         AllocHC: alloc::Allocator<HuffmanCode>>
+pub fn DecodeMetaBlockLength<AllocU8: alloc::Allocator<u8>,
+                             AllocU32: alloc::Allocator<u32>,
+                             AllocHC: alloc::Allocator<HuffmanCode>>
   (s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
    input: &[u8])
    -> BrotliDecoderErrorCode {
@@ -1177,6 +1190,11 @@ fn HuffmanTreeGroupDecode<AllocU8: alloc::Allocator<u8>,
   for mut htree_iter in
       fast_mut!((htrees.slice_mut())[s.htree_index as usize ; (group_num_htrees as usize)])
     .iter_mut() {
+    // a group may hold hundreds of trees, so a call can stop between any two
+    if WorkBudgetSpent(s) {
+      result = BrotliDecoderErrorCode::BROTLI_DECODER_YIELD;
+      break;
+    }
     let mut table_size: u32 = 0;
     result = ReadHuffmanCode(u32::from(alphabet_size), u32::from(group_max_symbol),
                              hcodes.slice_mut(),
@@ -1191,6 +1209,7 @@ fn HuffmanTreeGroupDecode<AllocU8: alloc::Allocator<u8>,
     *htree_iter = s.htree_next_offset;
     s.htree_next_offset += table_size;
     s.htree_index += 1;
+    ChargeWork(s, 1, table_size as usize);
   }
   if group_index == 0 {
     mem::replace(&mut s.literal_hgroup.codes,
@@ -1254,6 +1273,10 @@ fn DecodeContextMapInner<AllocU8: alloc::Allocator<u8>,
         s.context_index = 0;
         BROTLI_LOG_UINT!(context_map_size);
         BROTLI_LOG_UINT!(*num_htrees);
+        // before any of the map is read or allocated
+        if *num_htrees > s.caps.max_trees_per_group {
+          return BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_TREES;
+        }
         *context_map_arg = s.alloc_u8.alloc_cell(context_map_size as usize);
         if (context_map_arg.slice().len() < context_map_size as usize) {
           return BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_CONTEXT_MAP;
@@ -1545,6 +1568,9 @@ fn DecodeLiteralBlockSwitchInternal<AllocU8: alloc::Allocator<u8>,
   if !DecodeBlockTypeAndLength(safe, &mut s.block_type_length_state, &mut s.br, 0, input) {
     return false;
   }
+  if let Some(ref mut stats) = s.stats {
+    stats.block_switches[0] += 1;
+  }
   PrepareLiteralDecoding(s);
   true
 }
@@ -1578,6 +1604,9 @@ fn DecodeCommandBlockSwitchInternal<AllocU8: alloc::Allocator<u8>,
   if (!DecodeBlockTypeAndLength(safe, &mut s.block_type_length_state, &mut s.br, 1, input)) {
     return false;
   }
+  if let Some(ref mut stats) = s.stats {
+    stats.block_switches[1] += 1;
+  }
   s.htree_command_index = fast!((s.block_type_length_state.block_type_rb)[3]) as u16;
   true
 }
@@ -1612,6 +1641,9 @@ fn DecodeDistanceBlockSwitchInternal<AllocU8: alloc::Allocator<u8>,
   if (!DecodeBlockTypeAndLength(safe, &mut s.block_type_length_state, &mut s.br, 2, input)) {
     return false;
   }
+  if let Some(ref mut stats) = s.stats {
+    stats.block_switches[2] += 1;
+  }
   s.dist_context_map_slice_index =
     (fast!((s.block_type_length_state.block_type_rb)[5]) << kDistanceContextBits) as usize;
   s.dist_htree_index = fast_slice!((s.dist_context_map)[s.dist_context_map_slice_index
@@ -1663,14 +1695,29 @@ fn WriteRingBuffer<'a,
   force: bool,
   s: &'a mut BrotliState<AllocU8, AllocU32, AllocHC>,
 ) -> (BrotliDecoderErrorCode, &'a [u8]) {
-  let to_write = UnwrittenBytes(s, true);
+  if (s.meta_block_remaining_len < 0) {
+    return (BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_BLOCK_LENGTH_1, &[]);
+  }
+  if s.flat_window {
+    // the bytes already sit in the caller's output, only the counters move
+    let num_written = s.pos as usize - s.partial_pos_out;
+    *output_offset += num_written;
+    *available_out -= num_written;
+    s.partial_pos_out += num_written;
+    *total_out = s.partial_pos_out;
+    return (BrotliDecoderErrorCode::BROTLI_DECODER_SUCCESS, &[]);
+  }
+  let mut to_write = UnwrittenBytes(s, true);
+  if s.skip_output_remaining != 0 {
+    let num_skipped = core::cmp::min(s.skip_output_remaining, to_write);
+    s.skip_output_remaining -= num_skipped;
+    s.partial_pos_out += num_skipped;
+    to_write -= num_skipped;
+  }
   let mut num_written = *available_out as usize;
   if (num_written > to_write) {
     num_written = to_write;
   }
-  if (s.meta_block_remaining_len < 0) {
-    return (BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_BLOCK_LENGTH_1, &[]);
-  }
   let start_index = (s.partial_pos_out & s.ringbuffer_mask as usize) as usize;
   let start = fast_slice!((s.ringbuffer)[start_index ; start_index + num_written as usize]);
   if let Some(output) = opt_output {
@@ -1713,15 +1760,117 @@ fn WrapRingBuffer<AllocU8: alloc::Allocator<u8>,
 
 }
 
+// Counts work against the budget of the current call, in whichever unit it is set in.
+fn ChargeWork<AllocU8: alloc::Allocator<u8>,
+              AllocU32: alloc::Allocator<u32>,
+              AllocHC: alloc::Allocator<HuffmanCode>>
+  (s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
+   commands: usize,
+   bytes: usize) {
+  match s.work_budget {
+    None => {}
+    Some(WorkBudget::OutputBytes(_)) => s.work_spent = s.work_spent.saturating_add(bytes),
+    Some(WorkBudget::Commands(_)) => s.work_spent = s.work_spent.saturating_add(commands),
+  }
+}
+
+// Whether the call should yield before the next command, metablock, context map or tree of
+// a tree group. A call that has not done any work yet goes on, so that every call makes
+// progress, and so does one reading from the internal buffer, which only stops once its
+// bytes are used up.
+fn WorkBudgetSpent<AllocU8: alloc::Allocator<u8>,
+                   AllocU32: alloc::Allocator<u32>,
+                   AllocHC: alloc::Allocator<HuffmanCode>>
+  (s: &BrotliState<AllocU8, AllocU32, AllocHC>)
+   -> bool {
+  let limit = match s.work_budget {
+    None => return false,
+    Some(WorkBudget::OutputBytes(limit)) | Some(WorkBudget::Commands(limit)) => limit,
+  };
+  s.work_spent != 0 && s.work_spent >= limit && s.buffer_length == 0
+}
+
+// Uses the caller's output as the window when it can hold everything the ringbuffer
+// would, so decoded bytes are written once instead of being copied out of the
+// ringbuffer. Positions then index the output directly and never wrap.
+fn StartFlatWindow<AllocU8: alloc::Allocator<u8>,
+                   AllocU32: alloc::Allocator<u32>,
+                   AllocHC: alloc::Allocator<HuffmanCode>>
+  (s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
+   output_offset: usize,
+   available_out: usize)
+   -> bool {
+  let slack = kRingBufferWriteAheadSlack as usize + kBrotliMaxDictionaryWordLength as usize;
+  // skipped bytes must not land in the caller's output
+  if s.custom_dict_size != 0 || s.skip_output_remaining != 0 || available_out <= slack {
+    return false;
+  }
+  let room = core::cmp::min(available_out - slack, 1 << 30);
+  // a last metablock ending exactly at the end of the room would be moved into a
+  // ringbuffer anyway
+  if room < 1 << s.window_bits &&
+     (s.is_last_metablock == 0 || s.meta_block_remaining_len as usize >= room) {
+    return false;
+  }
+  s.flat_window = true;
+  s.flat_window_offset = output_offset;
+  s.ringbuffer_size = room as i32;
+  s.ringbuffer_mask = -1;
+  true
+}
+
+// Moves the window out of the caller's output, once it is full or before returning to
+// the caller, into a regular ringbuffer. Every byte must have been written already.
+fn FlatWindowToRingBuffer<AllocU8: alloc::Allocator<u8>,
+                          AllocU32: alloc::Allocator<u32>,
+                          AllocHC: alloc::Allocator<HuffmanCode>>
+  (s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
+   output: &[u8])
+   -> bool {
+  let stream_pos = s.pos as usize; 
+  s.flat_window = false;
+  s.ringbuffer_size = 1 << s.window_bits;
+  s.ringbuffer_mask = s.ringbuffer_size - 1;
+  s.ringbuffer = s.alloc_u8
+    .alloc_cell(s.ringbuffer_size as usize + kRingBufferWriteAheadSlack as usize +
+                kBrotliMaxDictionaryWordLength as usize);
+  if s.ringbuffer.slice().is_empty() {
+    return false;
+  }
+  if let Some(ref mut stats) = s.stats {
+    stats.ring_buffer_bytes = s.ringbuffer.slice().len() as u64;
+  }
+  let size = s.ringbuffer_size as usize;
+  fast_mut!((s.ringbuffer.slice_mut())[size - 1]) = 0;
+  fast_mut!((s.ringbuffer.slice_mut())[size - 2]) = 0;
+  let history_start = stream_pos.saturating_sub(size);
+  let history = fast!((output)[s.flat_window_offset + history_start ;
+                               s.flat_window_offset + stream_pos]);
+  let ring_start = history_start & s.ringbuffer_mask as usize;
+  let first = core::cmp::min(history.len(), size - ring_start);
+  fast_mut!((s.ringbuffer.slice_mut())[ring_start ; ring_start + first])
+    .clone_from_slice(fast!((history)[0 ; first]));
+  fast_mut!((s.ringbuffer.slice_mut())[0 ; history.len() - first])
+    .clone_from_slice(fast!((history)[first ;]));
+  s.rb_roundtrips = stream_pos / size;
+  s.pos = (stream_pos % size) as i32;
+  if s.rb_roundtrips != 0 {
+    s.max_distance = s.max_backward_distance;
+  }
+  true
+}
+
 fn CopyUncompressedBlockToOutput<AllocU8: alloc::Allocator<u8>,
                                  AllocU32: alloc::Allocator<u32>,
-                                 AllocHC: alloc::Allocator<HuffmanCode>>
+                                 AllocHC: alloc::Allocator<HuffmanCode>,
+                                 S: CommandSink>
   (mut available_out: &mut usize,
    mut output: &mut [u8],
    mut output_offset: &mut usize,
    mut total_out: &mut usize,
    mut s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
-   input: &[u8])
+   input: &[u8],
+   sink: &mut S)
    -> BrotliDecoderErrorCode {
   // State machine
   loop {
@@ -1735,13 +1884,25 @@ fn CopyUncompressedBlockToOutput<AllocU8: alloc::Allocator<u8>,
           nbytes = s.ringbuffer_size - s.pos;
         }
         // Copy remaining bytes from s.br.buf_ to ringbuffer.
-        bit_reader::BrotliCopyBytes(fast_mut!((s.ringbuffer.slice_mut())[s.pos as usize;]),
+        let ring = if s.flat_window {
+          fast_mut!((output)[s.flat_window_offset ; *output_offset + *available_out])
+        } else {
+          s.ringbuffer.slice_mut()
+        };
+        bit_reader::BrotliCopyBytes(fast_mut!((ring)[s.pos as usize;]),
                                     &mut s.br,
                                     nbytes as u32,
                                     input);
         s.pos += nbytes;
         s.meta_block_remaining_len -= nbytes;
-        if s.pos < (1 << s.window_bits) {
+        if S::ACTIVE {
+          let pos = s.pos;
+          let ringbuffer = core::mem::take(&mut s.ringbuffer);
+          PipelineFlushLiterals(s, ringbuffer.slice(), pos, sink);
+          s.ringbuffer = ringbuffer;
+        }
+        let window_end = if s.flat_window { s.ringbuffer_size } else { 1 << s.window_bits };
+        if s.pos < window_end {
           if (s.meta_block_remaining_len == 0) {
             return BrotliDecoderErrorCode::BROTLI_DECODER_SUCCESS;
           }
@@ -1753,7 +1914,7 @@ fn CopyUncompressedBlockToOutput<AllocU8: alloc::Allocator<u8>,
       }
       BrotliRunningUncompressedState::BROTLI_STATE_UNCOMPRESSED_WRITE => {
         let (result, _) = WriteRingBuffer(&mut available_out,
-                                          Some(&mut output),
+                                          if S::ACTIVE { None } else { Some(&mut output) },
                                           &mut output_offset,
                                           &mut total_out,
                                           false,
@@ -1762,7 +1923,11 @@ fn CopyUncompressedBlockToOutput<AllocU8: alloc::Allocator<u8>,
           BrotliDecoderErrorCode::BROTLI_DECODER_SUCCESS => {}
           _ => return result,
         }
-        if s.ringbuffer_size == 1 << s.window_bits {
+        if s.flat_window {
+          if !FlatWindowToRingBuffer(s, output) {
+            return BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_1;
+          }
+        } else if s.ringbuffer_size == 1 << s.window_bits {
           s.max_distance = s.max_backward_distance;
         }
         s.substate_uncompressed = BrotliRunningUncompressedState::BROTLI_STATE_UNCOMPRESSED_NONE;
@@ -1771,16 +1936,65 @@ fn CopyUncompressedBlockToOutput<AllocU8: alloc::Allocator<u8>,
   }
 }
 
+// Hands the bytes placed in the ringbuffer since the last call, up to pos, to the sink.
+fn PipelineFlushLiterals<AllocU8: alloc::Allocator<u8>,
+                         AllocU32: alloc::Allocator<u32>,
+                         AllocHC: alloc::Allocator<HuffmanCode>,
+                         S: CommandSink>
+  (s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
+   ring: &[u8],
+   pos: i32,
+   sink: &mut S) {
+  let rb_start = s.rb_roundtrips * s.ringbuffer_size as usize;
+  let abs_pos = rb_start + pos as usize;
+  // a wrap copy is announced in full before pos catches up with it
+  if s.pipeline_emitted < abs_pos {
+    let start = s.pipeline_emitted - rb_start;
+    sink.literals(fast!((ring)[start ; pos as usize]));
+    s.pipeline_emitted = abs_pos;
+  }
+}
+
+// Copies may only be left to the sink when no literal of the metablock depends on
+// the preceding bytes, otherwise the ringbuffer has to hold the real history.
+fn PipelinePrepareMetablock<AllocU8: alloc::Allocator<u8>,
+                            AllocU32: alloc::Allocator<u32>,
+                            AllocHC: alloc::Allocator<HuffmanCode>,
+                            S: CommandSink>
+  (s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
+   sink: &mut S) {
+  let num_literal_types = fast!((s.block_type_length_state.num_block_types)[0]) as usize;
+  let mut all_trivial = true;
+  for i in 0..num_literal_types {
+    if (fast!((s.trivial_literal_contexts)[i >> 5]) >> (i & 31)) & 1 == 0 {
+      all_trivial = false;
+      break;
+    }
+  }
+  s.pipeline_defer_copies = all_trivial && sink.defer_copies();
+  if s.pipeline_defer_copies {
+    s.pipeline_history_stale = true;
+    return;
+  }
+  if s.pipeline_history_stale {
+    let size = s.ringbuffer_size as usize;
+    sink.restore_history(fast_slice_mut!((s.ringbuffer)[0 ; size]));
+    s.pipeline_history_stale = false;
+  }
+}
+
+// We need the slack region for the following reasons:
+// - doing up to two 16-byte copies for fast backward copying
+// - inserting transformed dictionary word (5 prefix + 24 base + 8 suffix)
+pub const kRingBufferWriteAheadSlack: i32 = 42;
+
 fn BrotliAllocateRingBuffer<AllocU8: alloc::Allocator<u8>,
                             AllocU32: alloc::Allocator<u32>,
                             AllocHC: alloc::Allocator<HuffmanCode>>
   (s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
-   input: &[u8])
+   input: &[u8],
+   dict: &[u8])
    -> bool {
-  // We need the slack region for the following reasons:
-  // - doing up to two 16-byte copies for fast backward copying
-  // - inserting transformed dictionary word (5 prefix + 24 base + 8 suffix)
-  const kRingBufferWriteAheadSlack: i32 = 42;
   let mut is_last = s.is_last_metablock;
   s.ringbuffer_size = 1 << s.window_bits;
 
@@ -1796,12 +2010,20 @@ fn BrotliAllocateRingBuffer<AllocU8: alloc::Allocator<u8>,
   }
   let max_dict_size = s.ringbuffer_size as usize - 16;
   {
+    // a dictionary passed with the call comes first, then one shared with other states
+    let full_dict = if !dict.is_empty() {
+      dict
+    } else if let Some(ref shared) = s.shared_dict {
+      shared.slice()
+    } else {
+      s.custom_dict.slice()
+    };
     let custom_dict = if s.custom_dict_size as usize > max_dict_size {
-      let cd = fast_slice!((s.custom_dict)[(s.custom_dict_size as usize - max_dict_size); s.custom_dict_size as usize]);
+      let cd = &full_dict[(s.custom_dict_size as usize - max_dict_size)..s.custom_dict_size as usize];
       s.custom_dict_size = max_dict_size as i32;
       cd
     } else {
-      fast_slice!((s.custom_dict)[0; s.custom_dict_size as usize])
+      &full_dict[..s.custom_dict_size as usize]
     };
 
     // We need at least 2 bytes of ring buffer size to get the last two
@@ -1822,6 +2044,9 @@ fn BrotliAllocateRingBuffer<AllocU8: alloc::Allocator<u8>,
     if (s.ringbuffer.slice().len() == 0) {
       return false;
     }
+    if let Some(ref mut stats) = s.stats {
+      stats.ring_buffer_bytes = s.ringbuffer.slice().len() as u64;
+    }
     fast_mut!((s.ringbuffer.slice_mut())[s.ringbuffer_size as usize - 1]) = 0;
     fast_mut!((s.ringbuffer.slice_mut())[s.ringbuffer_size as usize - 2]) = 0;
     if custom_dict.len() != 0 {
@@ -1829,7 +2054,7 @@ fn BrotliAllocateRingBuffer<AllocU8: alloc::Allocator<u8>,
       fast_mut!((s.ringbuffer.slice_mut())[offset ; offset + s.custom_dict_size as usize]).clone_from_slice(custom_dict);
     }
   }
-  if s.custom_dict.slice().len() != 0 {
+  if s.custom_dict.slice().len() != 0 && !s.keep_custom_dict {
     s.alloc_u8.free_cell(core::mem::replace(&mut s.custom_dict,
                          AllocU8::AllocatedMemory::default()));
   }
@@ -1940,6 +2165,9 @@ pub fn ReadDistanceInternal<AllocU8: alloc::Allocator<u8>,
   // looking up past distances from the s.ringbuffer.
   s.distance_context = 0;
   if ((s.distance_code as u64 & 0xfffffffffffffff0) == 0) {
+    if let Some(ref mut stats) = s.stats {
+      stats.distance_cache_hits[s.distance_code as usize] += 1;
+    }
     TakeDistanceFromRingBuffer(s);
     fast_mut!((s.block_type_length_state.block_length)[2]) -= 1;
     return true;
@@ -2177,12 +2405,45 @@ pub fn BrotliDecoderGetErrorCode<AllocU8: alloc::Allocator<u8>,
   s.error_code
 }
 
+// Position of the next unread bit in the whole compressed stream.
+fn StreamBitPosition<AllocU8: alloc::Allocator<u8>,
+                     AllocU32: alloc::Allocator<u32>,
+                     AllocHC: alloc::Allocator<HuffmanCode>>
+  (s: &BrotliState<AllocU8, AllocU32, AllocHC>)
+   -> u64 {
+  ((s.input_base + s.br.next_in as i64) as u64 * 8) - bit_reader::BrotliGetAvailableBits(&s.br) as u64
+}
+
+// Hands the sink the next len bytes of output, encoded by the bits since the last span.
+fn EmitProvenance<AllocU8: alloc::Allocator<u8>,
+                  AllocU32: alloc::Allocator<u32>,
+                  AllocHC: alloc::Allocator<HuffmanCode>,
+                  S: CommandSink>
+  (s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
+   sink: &mut S,
+   len: i32,
+   source: Provenance) {
+  let bit_end = StreamBitPosition(s);
+  sink.provenance(&ProvenanceSpan {
+    output_start: s.provenance_pos,
+    len: len as u64,
+    source,
+    bit_start: s.provenance_bit_start,
+    bit_end,
+  });
+  s.provenance_pos += len as u64;
+  s.provenance_bit_start = bit_end;
+}
+
 fn ProcessCommandsInternal<AllocU8: alloc::Allocator<u8>,
                            AllocU32: alloc::Allocator<u32>,
-                           AllocHC: alloc::Allocator<HuffmanCode>>
+                           AllocHC: alloc::Allocator<HuffmanCode>,
+                           S: CommandSink>
   (safe: bool,
    s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
-   input: &[u8])
+   flat_window: &mut [u8],
+   input: &[u8],
+   sink: &mut S)
    -> BrotliDecoderErrorCode {
   if (!CheckInputAmount(safe, &s.br, 28)) || (!WarmupBitReader(safe, &mut s.br, input)) {
     mark_unlikely();
@@ -2200,7 +2461,13 @@ fn ProcessCommandsInternal<AllocU8: alloc::Allocator<u8>,
   let mut saved_insert_copy_hgroup =
     core::mem::replace(&mut s.insert_copy_hgroup,
                        HuffmanTreeGroup::<AllocU32, AllocHC>::default());
+  let mut saved_ringbuffer = core::mem::take(&mut s.ringbuffer);
   {
+    let ringbuffer: &mut [u8] = if s.flat_window {
+      flat_window
+    } else {
+      saved_ringbuffer.slice_mut()
+    };
 
     let literal_hgroup = saved_literal_hgroup.build_hgroup_cache();
     let distance_hgroup = saved_distance_hgroup.build_hgroup_cache();
@@ -2209,6 +2476,10 @@ fn ProcessCommandsInternal<AllocU8: alloc::Allocator<u8>,
     loop {
       match s.state {
         BrotliRunningState::BROTLI_STATE_COMMAND_BEGIN => {
+          if WorkBudgetSpent(s) {
+            result = BrotliDecoderErrorCode::BROTLI_DECODER_YIELD;
+            break; // return
+          }
           if (!CheckInputAmount(safe, &s.br, 28)) {
             // 156 bits + 7 bytes
             mark_unlikely();
@@ -2229,12 +2500,19 @@ fn ProcessCommandsInternal<AllocU8: alloc::Allocator<u8>,
             result = BrotliDecoderErrorCode::BROTLI_DECODER_NEEDS_MORE_INPUT;
             break; // return
           }
+          if S::PROVENANCE {
+            s.provenance_literals = i;
+          }
+          ChargeWork(s, 1, i as usize + s.copy_length as usize);
           BROTLI_LOG!("[ProcessCommandsInternal] pos = %d insert = %d copy = %d distance = %d\n",
               pos, i, s.copy_length, s.distance_code);
           if (i == 0) {
             s.state = BrotliRunningState::BROTLI_STATE_COMMAND_POST_DECODE_LITERALS;
             continue; // goto CommandPostDecodeLiterals;
           }
+          if let Some(ref mut stats) = s.stats {
+            stats.literal_bytes += i as u64;
+          }
           s.meta_block_remaining_len -= i;
           s.state = BrotliRunningState::BROTLI_STATE_COMMAND_INNER;
         }
@@ -2270,7 +2548,7 @@ fn ProcessCommandsInternal<AllocU8: alloc::Allocator<u8>,
                 }
               }
               if (!safe) {
-                fast_mut!((s.ringbuffer.slice_mut())[pos as usize]) =
+                fast_mut!((ringbuffer)[pos as usize]) =
                   ReadPreloadedSymbol(literal_htree, &mut s.br, &mut bits, &mut value, input) as u8;
               } else {
                 let mut literal: u32 = 0;
@@ -2279,11 +2557,11 @@ fn ProcessCommandsInternal<AllocU8: alloc::Allocator<u8>,
                   inner_return = true;
                   break;
                 }
-                fast_mut!((s.ringbuffer.slice_mut())[pos as usize]) = literal as u8;
+                fast_mut!((ringbuffer)[pos as usize]) = literal as u8;
               }
               fast_mut!((s.block_type_length_state.block_length)[0]) -= 1;
               BROTLI_LOG_UINT!(s.literal_htree_index);
-              BROTLI_LOG_ARRAY_INDEX!(s.ringbuffer.slice(), pos);
+              BROTLI_LOG_ARRAY_INDEX!(ringbuffer, pos);
               pos += 1;
               if (pos == s.ringbuffer_size) {
                 mark_unlikely();
@@ -2305,8 +2583,13 @@ fn ProcessCommandsInternal<AllocU8: alloc::Allocator<u8>,
               continue;
             }
           } else {
-            let mut p1 = fast_slice!((s.ringbuffer)[((pos - 1) & s.ringbuffer_mask) as usize]);
-            let mut p2 = fast_slice!((s.ringbuffer)[((pos - 2) & s.ringbuffer_mask) as usize]);
+            let (mut p1, mut p2) = if s.flat_window && pos < 2 {
+              // nothing precedes the stream start in the caller's output
+              (if pos == 1 { fast!((ringbuffer)[0]) } else { 0 }, 0)
+            } else {
+              (fast!((ringbuffer)[((pos - 1) & s.ringbuffer_mask) as usize]),
+               fast!((ringbuffer)[((pos - 2) & s.ringbuffer_mask) as usize]))
+            };
             let mut inner_return: bool = false;
             let mut inner_continue: bool = false;
             loop {
@@ -2351,11 +2634,11 @@ fn ProcessCommandsInternal<AllocU8: alloc::Allocator<u8>,
                 }
                 p1 = literal as u8;
               }
-              fast_slice_mut!((s.ringbuffer)[pos as usize]) = p1;
+              fast_mut!((ringbuffer)[pos as usize]) = p1;
               fast_mut!((s.block_type_length_state.block_length)[0]) -= 1;
               BROTLI_LOG_UINT!(s.context_map.slice()[s.context_map_slice_index as usize +
                                                      context as usize]);
-              BROTLI_LOG_ARRAY_INDEX!(s.ringbuffer.slice(), pos & s.ringbuffer_mask);
+              BROTLI_LOG_ARRAY_INDEX!(ringbuffer, pos & s.ringbuffer_mask);
               pos += 1;
               if (pos == s.ringbuffer_size) {
                 mark_unlikely();
@@ -2385,9 +2668,16 @@ fn ProcessCommandsInternal<AllocU8: alloc::Allocator<u8>,
           s.state = BrotliRunningState::BROTLI_STATE_COMMAND_POST_DECODE_LITERALS;
         }
         BrotliRunningState::BROTLI_STATE_COMMAND_POST_DECODE_LITERALS => {
+          if S::PROVENANCE && s.provenance_literals != 0 {
+            let len = core::mem::replace(&mut s.provenance_literals, 0);
+            EmitProvenance(s, sink, len, Provenance::Literal);
+          }
           if s.distance_code >= 0 {
             let not_distance_code = if s.distance_code != 0 { 0 } else { 1 };
             s.distance_context = not_distance_code;
+            if let Some(ref mut stats) = s.stats {
+              stats.distance_cache_hits[0] += 1;
+            }
             s.dist_rb_idx -= 1;
             s.distance_code = fast!((s.dist_rb)[(s.dist_rb_idx & 3) as usize]);
             // goto postReadDistance
@@ -2420,29 +2710,70 @@ fn ProcessCommandsInternal<AllocU8: alloc::Allocator<u8>,
           // the distance is larger than the max LZ77 distance
           if (s.distance_code > s.max_distance) {
             if s.distance_code > kBrotliMaxAllowedDistance as i32 {
-              return BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_DISTANCE;
+              result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_DISTANCE;
+              break; // return
             }
+            // Attached dictionaries come first beyond the window, then the static one.
+            let mut address = s.distance_code - s.max_distance - 1;
+            let compound_size = s.compound_dictionary.total_size() as i32;
+            if address < compound_size {
+              let offset = s.compound_dictionary.offset_of_address(address as usize);
+              if offset + i as usize > compound_size as usize {
+                result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_COMPOUND_DICTIONARY;
+                break; // return
+              }
+              fast_mut!((s.dist_rb)[(s.dist_rb_idx & 3) as usize]) = s.distance_code;
+              s.dist_rb_idx += 1;
+              s.meta_block_remaining_len -= i;
+              if let Some(ref mut stats) = s.stats {
+                stats.copied_bytes += i as u64;
+                stats.custom_dictionary_references += 1;
+              }
+              if S::PROVENANCE {
+                EmitProvenance(s, sink, i, Provenance::CompoundDictionary {
+                  distance: s.distance_code as u32,
+                  offset: offset as u32,
+                });
+              }
+              s.compound_copy = Some(offset);
+              s.state = BrotliRunningState::BROTLI_STATE_COMMAND_POST_WRAP_COPY;
+              continue; //goto CommandPostWrapCopy;
+            }
+            address -= compound_size;
             if (i >= kBrotliMinDictionaryWordLength as i32 &&
                 i <= kBrotliMaxDictionaryWordLength as i32) {
               let mut offset = fast!((kBrotliDictionaryOffsetsByLength)[i as usize]) as i32;
-              let word_id = s.distance_code - s.max_distance - 1;
+              let word_id = address;
               let shift = fast!((kBrotliDictionarySizeBitsByLength)[i as usize]);
               let mask = bit_reader::BitMask(shift as u32) as i32;
               let word_idx = word_id & mask;
               let transform_idx = word_id >> shift;
               s.dist_rb_idx += s.distance_context;
               offset += word_idx * i;
-              if (transform_idx < kNumTransforms) {
+              let transforms = s.transforms();
+              if (transform_idx < transforms.num_transforms() as i32) {
                 let mut len = i;
                 let word = fast!((kBrotliDictionary)[offset as usize ; (offset + len) as usize]);
-                if (transform_idx == 0) {
-                  fast_slice_mut!((s.ringbuffer)[pos as usize ; ((pos + len) as usize)])
+                if (transform_idx == 0 && s.transform_triplets.slice().is_empty()) {
+                  fast_mut!((ringbuffer)[pos as usize ; ((pos + len) as usize)])
                     .clone_from_slice(word);
                 } else {
-                  len = TransformDictionaryWord(fast_slice_mut!((s.ringbuffer)[pos as usize;]),
-                                                word,
-                                                len,
-                                                transform_idx);
+                  len = TransformDictionaryWordWithTable(fast_mut!((ringbuffer)[pos as usize;]),
+                                                         word,
+                                                         len,
+                                                         &transforms,
+                                                         transform_idx);
+                }
+                if let Some(ref mut stats) = s.stats {
+                  stats.dictionary_references[transform_idx as usize] += 1;
+                  stats.dictionary_bytes += len as u64;
+                }
+                if S::PROVENANCE {
+                  EmitProvenance(s, sink, len, Provenance::StaticDictionary {
+                    word_len: i as u32,
+                    word_idx: word_idx as u32,
+                    transform: transform_idx as u32,
+                  });
                 }
                 pos += len;
                 s.meta_block_remaining_len -= len;
@@ -2471,38 +2802,71 @@ fn ProcessCommandsInternal<AllocU8: alloc::Allocator<u8>,
             fast_mut!((s.dist_rb)[(s.dist_rb_idx & 3) as usize]) = s.distance_code;
             s.dist_rb_idx += 1;
             s.meta_block_remaining_len -= i;
-            // There is 128+ bytes of slack in the ringbuffer allocation.
-            // Also, we have 16 short codes, that make these 16 bytes irrelevant
-            // in the ringbuffer. Let's copy over them as a first guess.
-            //
-            let src_start = ((pos - s.distance_code) & s.ringbuffer_mask) as u32;
-            let dst_start = pos as u32;
-            let dst_end = pos as u32 + i as u32;
-            let src_end = src_start + i as u32;
-            memmove16(&mut s.ringbuffer.slice_mut(), dst_start, src_start);
-            // Now check if the copy extends over the ringbuffer end,
-            // or if the copy overlaps with itself, if yes, do wrap-copy.
-            if (src_end > pos as u32 && dst_end > src_start) {
-              s.state = BrotliRunningState::BROTLI_STATE_COMMAND_POST_WRAP_COPY;
-              continue; //goto CommandPostWrapCopy;
-            }
-            if (dst_end >= s.ringbuffer_size as u32 || src_end >= s.ringbuffer_size as u32) {
-              s.state = BrotliRunningState::BROTLI_STATE_COMMAND_POST_WRAP_COPY;
-              continue; //goto CommandPostWrapCopy;
+            if let Some(ref mut stats) = s.stats {
+              stats.copied_bytes += i as u64;
+              if s.custom_dict_size != 0 &&
+                 s.distance_code as usize > s.rb_roundtrips * s.ringbuffer_size as usize + pos as usize {
+                stats.custom_dictionary_references += 1;
+              }
             }
-            pos += i;
-            if (i > 16) {
-              if (i > 32) {
-                memcpy_within_slice(s.ringbuffer.slice_mut(),
-                                    dst_start as usize + 16,
-                                    src_start as usize + 16,
-                                    (i - 16) as usize);
+            if S::PROVENANCE {
+              let distance = s.distance_code as u32;
+              let source = if distance as u64 > s.provenance_pos {
+                Provenance::CustomDictionary {
+                  distance,
+                  offset: (s.custom_dict_size as u64 + s.provenance_pos - distance as u64) as u32,
+                }
               } else {
-                // This branch covers about 45% cases.
-                // Fixed size short copy allows more compiler optimizations.
-                memmove16(&mut s.ringbuffer.slice_mut(),
-                          dst_start + 16,
-                          src_start + 16);
+                Provenance::Copy { distance }
+              };
+              EmitProvenance(s, sink, i, source);
+            }
+            if S::ACTIVE {
+              PipelineFlushLiterals(s, ringbuffer, pos, sink);
+              sink.copy(i as u32, s.distance_code as u32);
+              s.pipeline_emitted += i as usize;
+            }
+            if S::ACTIVE && s.pipeline_defer_copies {
+              // the sink reconstructs the bytes, only the position advances here
+              if (pos + i >= s.ringbuffer_size) {
+                s.state = BrotliRunningState::BROTLI_STATE_COMMAND_POST_WRAP_COPY;
+                continue; //goto CommandPostWrapCopy;
+              }
+              pos += i;
+            } else {
+              // There is 128+ bytes of slack in the ringbuffer allocation.
+              // Also, we have 16 short codes, that make these 16 bytes irrelevant
+              // in the ringbuffer. Let's copy over them as a first guess.
+              //
+              let src_start = ((pos - s.distance_code) & s.ringbuffer_mask) as u32;
+              let dst_start = pos as u32;
+              let dst_end = pos as u32 + i as u32;
+              let src_end = src_start + i as u32;
+              memmove16(ringbuffer, dst_start, src_start);
+              // Now check if the copy extends over the ringbuffer end,
+              // or if the copy overlaps with itself, if yes, do wrap-copy.
+              if (src_end > pos as u32 && dst_end > src_start) {
+                s.state = BrotliRunningState::BROTLI_STATE_COMMAND_POST_WRAP_COPY;
+                continue; //goto CommandPostWrapCopy;
+              }
+              if (dst_end >= s.ringbuffer_size as u32 || src_end >= s.ringbuffer_size as u32) {
+                s.state = BrotliRunningState::BROTLI_STATE_COMMAND_POST_WRAP_COPY;
+                continue; //goto CommandPostWrapCopy;
+              }
+              pos += i;
+              if (i > 16) {
+                if (i > 32) {
+                  memcpy_within_slice(ringbuffer,
+                                      dst_start as usize + 16,
+                                      src_start as usize + 16,
+                                      (i - 16) as usize);
+                } else {
+                  // This branch covers about 45% cases.
+                  // Fixed size short copy allows more compiler optimizations.
+                  memmove16(ringbuffer,
+                            dst_start + 16,
+                            src_start + 16);
+                }
               }
             }
           }
@@ -2518,10 +2882,34 @@ fn ProcessCommandsInternal<AllocU8: alloc::Allocator<u8>,
         BrotliRunningState::BROTLI_STATE_COMMAND_POST_WRAP_COPY => {
           let mut wrap_guard = s.ringbuffer_size - pos;
           let mut inner_return: bool = false;
+          if let Some(offset) = s.compound_copy {
+            // the bytes come from the attached dictionaries, so even a pipeline sink
+            // receives them as literals
+            let n = core::cmp::min(i, wrap_guard);
+            s.compound_dictionary.copy_to(offset,
+                                          fast_mut!((ringbuffer)[pos as usize ; (pos + n) as usize]));
+            i -= n;
+            pos += n;
+            if (pos == s.ringbuffer_size) {
+              s.compound_copy = Some(offset + n as usize);
+              s.state = BrotliRunningState::BROTLI_STATE_COMMAND_POST_WRITE_2;
+              break; // return
+            }
+            s.compound_copy = None;
+          } else if S::ACTIVE && s.pipeline_defer_copies {
+            if wrap_guard > 0 && i >= wrap_guard {
+              i -= wrap_guard;
+              pos += wrap_guard;
+              s.state = BrotliRunningState::BROTLI_STATE_COMMAND_POST_WRITE_2;
+              break; // return
+            }
+            pos += i;
+            i = 0;
+          }
           while i > 0 {
             i -= 1;
-            fast_slice_mut!((s.ringbuffer)[pos as usize]) =
-              fast_slice!((s.ringbuffer)[((pos - s.distance_code) & s.ringbuffer_mask) as usize]);
+            fast_mut!((ringbuffer)[pos as usize]) =
+              fast!((ringbuffer)[((pos - s.distance_code) & s.ringbuffer_mask) as usize]);
             pos += 1;
             wrap_guard -= 1;
             if (wrap_guard == 0) {
@@ -2555,6 +2943,10 @@ fn ProcessCommandsInternal<AllocU8: alloc::Allocator<u8>,
   }
   s.pos = pos;
   s.loop_counter = i;
+  if S::ACTIVE {
+    PipelineFlushLiterals(s, saved_ringbuffer.slice(), pos, sink);
+  }
+  s.ringbuffer = saved_ringbuffer;
 
   core::mem::replace(&mut s.literal_hgroup,
                      core::mem::replace(&mut saved_literal_hgroup,
@@ -2573,20 +2965,26 @@ fn ProcessCommandsInternal<AllocU8: alloc::Allocator<u8>,
 
 fn ProcessCommands<AllocU8: alloc::Allocator<u8>,
                    AllocU32: alloc::Allocator<u32>,
-                   AllocHC: alloc::Allocator<HuffmanCode>>
+                   AllocHC: alloc::Allocator<HuffmanCode>,
+                   S: CommandSink>
   (s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
-   input: &[u8])
+   flat_window: &mut [u8],
+   input: &[u8],
+   sink: &mut S)
    -> BrotliDecoderErrorCode {
-  ProcessCommandsInternal(false, s, input)
+  ProcessCommandsInternal(false, s, flat_window, input, sink)
 }
 
 fn SafeProcessCommands<AllocU8: alloc::Allocator<u8>,
                        AllocU32: alloc::Allocator<u32>,
-                       AllocHC: alloc::Allocator<HuffmanCode>>
+                       AllocHC: alloc::Allocator<HuffmanCode>,
+                       S: CommandSink>
   (s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
-   input: &[u8])
+   flat_window: &mut [u8],
+   input: &[u8],
+   sink: &mut S)
    -> BrotliDecoderErrorCode {
-  ProcessCommandsInternal(true, s, input)
+  ProcessCommandsInternal(true, s, flat_window, input, sink)
 }
 
 /* Returns the maximum number of distance symbols which can only represent
@@ -2607,6 +3005,208 @@ pub fn BrotliMaxDistanceSymbol(ndirect: u32, npostfix: u32) -> u32{
 pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
                               AllocU32: alloc::Allocator<u32>,
                               AllocHC: alloc::Allocator<HuffmanCode>>
+  (available_in: &mut usize,
+   input_offset: &mut usize,
+   xinput: &[u8],
+   available_out: &mut usize,
+   output_offset: &mut usize,
+   output: &mut [u8],
+   total_out: &mut usize,
+   s: &mut BrotliState<AllocU8, AllocU32, AllocHC>)
+   -> BrotliResult {
+  BrotliDecompressStreamInternal(available_in,
+                                 input_offset,
+                                 xinput,
+                                 available_out,
+                                 output_offset,
+                                 output,
+                                 total_out,
+                                 s,
+                                 &[],
+                                 &mut NopCommandSink)
+}
+
+/// Decodes like `BrotliDecompressStream`, but once the call has done `budget` worth of
+/// work it returns `BudgetedResult::Yield` at the next command, metablock, context map or
+/// tree of a tree group, with whatever it decoded written out. Calling again carries on
+/// where it stopped, with a fresh budget. Every call makes progress however small the
+/// budget.
+#[allow(clippy::too_many_arguments)]
+pub fn BrotliDecompressStreamWithBudget<AllocU8: alloc::Allocator<u8>,
+                                        AllocU32: alloc::Allocator<u32>,
+                                        AllocHC: alloc::Allocator<HuffmanCode>>
+  (available_in: &mut usize,
+   input_offset: &mut usize,
+   xinput: &[u8],
+   available_out: &mut usize,
+   output_offset: &mut usize,
+   output: &mut [u8],
+   total_out: &mut usize,
+   s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
+   budget: WorkBudget)
+   -> BudgetedResult {
+  s.work_budget = Some(budget);
+  let result = BrotliDecompressStreamInternal(available_in,
+                                              input_offset,
+                                              xinput,
+                                              available_out,
+                                              output_offset,
+                                              output,
+                                              total_out,
+                                              s,
+                                              &[],
+                                              &mut NopCommandSink);
+  s.work_budget = None;
+  match s.error_code {
+    BrotliDecoderErrorCode::BROTLI_DECODER_YIELD => BudgetedResult::Yield,
+    _ => BudgetedResult::Done(result),
+  }
+}
+
+/// Decodes like `BrotliDecompressStream` against a custom dictionary borrowed for the
+/// call instead of one owned by the state, so the state needs no copy of it. Pass the
+/// same `dict` to every call for a stream; it takes the place of any dictionary the
+/// state was created with.
+#[allow(clippy::too_many_arguments)]
+pub fn BrotliDecompressStreamWithDictionary<AllocU8: alloc::Allocator<u8>,
+                                            AllocU32: alloc::Allocator<u32>,
+                                            AllocHC: alloc::Allocator<HuffmanCode>>
+  (available_in: &mut usize,
+   input_offset: &mut usize,
+   xinput: &[u8],
+   available_out: &mut usize,
+   output_offset: &mut usize,
+   output: &mut [u8],
+   total_out: &mut usize,
+   s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
+   dict: &[u8])
+   -> BrotliResult {
+  BrotliDecompressStreamInternal(available_in,
+                                 input_offset,
+                                 xinput,
+                                 available_out,
+                                 output_offset,
+                                 output,
+                                 total_out,
+                                 s,
+                                 dict,
+                                 &mut NopCommandSink)
+}
+
+/// Decodes like `BrotliDecompressStream`, but instead of producing output bytes it hands
+/// literal runs and back-reference copies to `sink`, which is responsible for
+/// reconstructing the output (see the `pipeline` module). No output buffer is needed, so
+/// the only possible results are success, failure and `NeedsMoreInput`.
+pub fn BrotliDecompressStreamToSink<AllocU8: alloc::Allocator<u8>,
+                                    AllocU32: alloc::Allocator<u32>,
+                                    AllocHC: alloc::Allocator<HuffmanCode>,
+                                    S: CommandSink>
+  (available_in: &mut usize,
+   input_offset: &mut usize,
+   xinput: &[u8],
+   total_out: &mut usize,
+   s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
+   sink: &mut S)
+   -> BrotliResult {
+  let mut available_out = usize::MAX >> 1;
+  let mut output_offset = 0usize;
+  BrotliDecompressStreamInternal(available_in,
+                                 input_offset,
+                                 xinput,
+                                 &mut available_out,
+                                 &mut output_offset,
+                                 &mut [],
+                                 total_out,
+                                 s,
+                                 &[],
+                                 sink)
+}
+
+/// Decodes like `BrotliDecompressStream` and tells `recorder` where each span of the
+/// output comes from, with the bits of the input that encode it. Wrap the recorder in a
+/// `ProvenanceRecorder` to merge runs of literals and repeated copies.
+#[allow(clippy::too_many_arguments)]
+pub fn BrotliDecompressStreamWithProvenance<AllocU8: alloc::Allocator<u8>,
+                                            AllocU32: alloc::Allocator<u32>,
+                                            AllocHC: alloc::Allocator<HuffmanCode>,
+                                            P: ProvenanceSink>
+  (available_in: &mut usize,
+   input_offset: &mut usize,
+   xinput: &[u8],
+   available_out: &mut usize,
+   output_offset: &mut usize,
+   output: &mut [u8],
+   total_out: &mut usize,
+   s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
+   recorder: &mut P)
+   -> BrotliResult {
+  BrotliDecompressStreamInternal(available_in,
+                                 input_offset,
+                                 xinput,
+                                 available_out,
+                                 output_offset,
+                                 output,
+                                 total_out,
+                                 s,
+                                 &[],
+                                 &mut ProvenanceCommandSink(recorder))
+}
+
+// Passes provenance on to a ProvenanceSink while the decoder produces the output itself.
+struct ProvenanceCommandSink<'a, P: ProvenanceSink + 'a>(&'a mut P);
+
+impl<'a, P: ProvenanceSink + 'a> CommandSink for ProvenanceCommandSink<'a, P> {
+  const ACTIVE: bool = false;
+  const PROVENANCE: bool = true;
+  fn defer_copies(&self) -> bool {
+    false
+  }
+  fn ring_buffer_allocated(&mut self, _ring: &[u8]) {}
+  fn literals(&mut self, _data: &[u8]) {}
+  fn copy(&mut self, _len: u32, _distance: u32) {}
+  fn restore_history(&mut self, _ring: &mut [u8]) {}
+  fn provenance(&mut self, span: &ProvenanceSpan) {
+    self.0.span(span)
+  }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn BrotliDecompressStreamInternal<AllocU8: alloc::Allocator<u8>,
+                                  AllocU32: alloc::Allocator<u32>,
+                                  AllocHC: alloc::Allocator<HuffmanCode>,
+                                  S: CommandSink>
+  (available_in: &mut usize,
+   input_offset: &mut usize,
+   xinput: &[u8],
+   available_out: &mut usize,
+   output_offset: &mut usize,
+   output: &mut [u8],
+   total_out: &mut usize,
+   s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
+   dict: &[u8],
+   sink: &mut S)
+   -> BrotliResult {
+  let entry_offset = *input_offset;
+  let result = DecompressStreamChunk(available_in,
+                                     input_offset,
+                                     xinput,
+                                     available_out,
+                                     output_offset,
+                                     output,
+                                     total_out,
+                                     s,
+                                     dict,
+                                     sink);
+  // bytes moved into s.buffer count as consumed, like the caller sees them
+  s.total_in += (*input_offset - entry_offset) as u64;
+  result
+}
+
+#[allow(clippy::too_many_arguments)]
+fn DecompressStreamChunk<AllocU8: alloc::Allocator<u8>,
+                         AllocU32: alloc::Allocator<u32>,
+                         AllocHC: alloc::Allocator<HuffmanCode>,
+                         S: CommandSink>
   (available_in: &mut usize,
    input_offset: &mut usize,
    xinput: &[u8],
@@ -2614,7 +3214,9 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
    mut output_offset: &mut usize,
    mut output: &mut [u8],
    mut total_out: &mut usize,
-   mut s: &mut BrotliState<AllocU8, AllocU32, AllocHC>)
+   mut s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
+   dict: &[u8],
+   sink: &mut S)
    -> BrotliResult {
 
   let mut result = BrotliDecoderErrorCode::BROTLI_DECODER_SUCCESS;
@@ -2624,6 +3226,7 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
   if is_fatal(s.error_code) {
     return BrotliResult::ResultFailure;
   }
+  s.work_spent = 0;
   if *available_in as u64 >= (1u64 << 32) {
     return SaveErrorCode!(s, BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_INVALID_ARGUMENTS);
   }
@@ -2633,14 +3236,17 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
   if *input_offset + *available_in > xinput.len() {
     return SaveErrorCode!(s, BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_INVALID_ARGUMENTS);
   }
-  if *output_offset + *available_out > output.len() {
+  if !S::ACTIVE && *output_offset + *available_out > output.len() {
     return SaveErrorCode!(s, BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_INVALID_ARGUMENTS);
   }
+  let entry_offset = *input_offset;
   if s.buffer_length == 0 {
     local_input = xinput;
     s.br.avail_in = *available_in as u32;
     s.br.next_in = *input_offset as u32;
+    s.input_base = s.total_in as i64 - entry_offset as i64;
   } else {
+    s.input_base = s.total_in as i64 - s.buffer_length as i64;
     result = BrotliDecoderErrorCode::BROTLI_DECODER_NEEDS_MORE_INPUT;
     let copy_len = core::cmp::min(saved_buffer.len() - s.buffer_length as usize, *available_in);
     if copy_len > 0 {
@@ -2659,9 +3265,13 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
       _ => {
         match result {
           BrotliDecoderErrorCode::BROTLI_DECODER_NEEDS_MORE_INPUT => {
-            if s.ringbuffer.slice().len() != 0 {
+            if s.ringbuffer.slice().len() != 0 || s.flat_window {
               let (intermediate_result, _) = WriteRingBuffer(available_out,
-                                                             Some(&mut output),
+                                                             if S::ACTIVE {
+                                                               None
+                                                             } else {
+                                                               Some(&mut output)
+                                                             },
                                                              &mut output_offset,
                                                              &mut total_out,
                                                              true,
@@ -2683,6 +3293,7 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
                 local_input = xinput;
                 s.br.avail_in = *available_in as u32;
                 s.br.next_in = *input_offset as u32;
+                s.input_base = s.total_in as i64 - entry_offset as i64;
                 continue;
               } else if *available_in != 0 {
                 // Not enough data in buffer, but can take one more byte from
@@ -2724,7 +3335,25 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
             // unreachable!(); <- dead code
           }
           _ => {
-            // Fail or needs more output.
+            if let BrotliDecoderErrorCode::BROTLI_DECODER_YIELD = result {
+              // what was decoded goes out before yielding; a flat window is written below
+              if !s.ringbuffer.slice().is_empty() && !s.flat_window {
+                let (intermediate_result, _) = WriteRingBuffer(available_out,
+                                                               if S::ACTIVE {
+                                                                 None
+                                                               } else {
+                                                                 Some(&mut output)
+                                                               },
+                                                               output_offset,
+                                                               total_out,
+                                                               true,
+                                                               s);
+                if is_fatal(intermediate_result) {
+                  result = intermediate_result;
+                }
+              }
+            }
+            // Fail, needs more output or yields.
             if s.buffer_length != 0 {
               // Just consumed the buffered input and produced some output. Otherwise
               // it would result in "needs more input". Reset internal buffer.
@@ -2777,24 +3406,58 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
           s.state = BrotliRunningState::BROTLI_STATE_INITIALIZE;
         }
         BrotliRunningState::BROTLI_STATE_INITIALIZE => {
+          if s.window_bits > s.max_window_bits {
+            result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_WINDOW_BITS;
+            break;
+          }
           s.max_backward_distance = (1 << s.window_bits) - kBrotliWindowGap as i32;
+          if !dict.is_empty() {
+            s.custom_dict_size = dict.len() as i32;
+          }
           s.max_backward_distance_minus_custom_dict_size = s.max_backward_distance -
                                                            s.custom_dict_size;
 
+          // the table is allocated with the state, which cannot report failing to
+          if s.context_map_table.slice().len() < huffman::BROTLI_HUFFMAN_MAX_TABLE_SIZE as usize {
+            s.alloc_hc.free_cell(core::mem::take(&mut s.context_map_table));
+            s.context_map_table = s.alloc_hc
+              .alloc_cell(huffman::BROTLI_HUFFMAN_MAX_TABLE_SIZE as usize);
+            if s.context_map_table.slice().len() < huffman::BROTLI_HUFFMAN_MAX_TABLE_SIZE as usize {
+              result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_CONTEXT_MAP;
+              break;
+            }
+          }
           // (formerly) Allocate memory for both block_type_trees and block_len_trees.
           s.block_type_length_state.block_type_trees = s.alloc_hc
             .alloc_cell(3 * huffman::BROTLI_HUFFMAN_MAX_TABLE_SIZE as usize);
-          if (s.block_type_length_state.block_type_trees.slice().len() == 0) {
+          if (s.block_type_length_state.block_type_trees.slice().len() <
+              3 * huffman::BROTLI_HUFFMAN_MAX_TABLE_SIZE as usize) {
             result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_BLOCK_TYPE_TREES;
             break;
           }
           s.block_type_length_state.block_len_trees = s.alloc_hc
             .alloc_cell(3 * huffman::BROTLI_HUFFMAN_MAX_TABLE_SIZE as usize);
+          if (s.block_type_length_state.block_len_trees.slice().len() <
+              3 * huffman::BROTLI_HUFFMAN_MAX_TABLE_SIZE as usize) {
+            result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_BLOCK_TYPE_TREES;
+            break;
+          }
 
           s.state = BrotliRunningState::BROTLI_STATE_METABLOCK_BEGIN;
           // No break, continue to next state
         }
         BrotliRunningState::BROTLI_STATE_METABLOCK_BEGIN => {
+          if WorkBudgetSpent(s) {
+            result = BrotliDecoderErrorCode::BROTLI_DECODER_YIELD;
+            break;
+          }
+          if s.caps.max_input_per_output != u64::MAX &&
+             StreamBitPosition(s) / 8 >
+               kInputPerOutputAllowance.saturating_add(
+                 s.caps.max_input_per_output.saturating_mul(s.metablock_output)) {
+            result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_INPUT_PER_OUTPUT;
+            break;
+          }
           s.BrotliStateMetablockBegin();
           BROTLI_LOG_UINT!(s.pos);
           s.state = BrotliRunningState::BROTLI_STATE_METABLOCK_HEADER;
@@ -2815,6 +3478,33 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
             result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_PADDING_2;
             break;
           }
+          let uncompressed_len = if s.is_uncompressed != 0 { s.meta_block_remaining_len as usize } else { 0 };
+          ChargeWork(s, 1, uncompressed_len);
+          s.metablocks += 1;
+          if s.metablocks > s.caps.max_metablocks {
+            result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_METABLOCKS;
+            break;
+          }
+          if s.is_metadata != 0 {
+            s.metadata_bytes += s.meta_block_remaining_len as u64;
+            if s.metadata_bytes > s.caps.max_metadata_bytes {
+              result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_METADATA;
+              break;
+            }
+          } else {
+            s.metablock_output += s.meta_block_remaining_len as u64;
+          }
+          if let Some(ref mut stats) = s.stats {
+            if s.is_metadata != 0 {
+              stats.metadata_metablocks += 1;
+            } else if s.meta_block_remaining_len == 0 {
+              stats.empty_metablocks += 1;
+            } else if s.is_uncompressed != 0 {
+              stats.uncompressed_metablocks += 1;
+            } else {
+              stats.compressed_metablocks += 1;
+            }
+          }
           if s.is_metadata != 0 {
             s.state = BrotliRunningState::BROTLI_STATE_METADATA;
             break;
@@ -2823,9 +3513,18 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
             s.state = BrotliRunningState::BROTLI_STATE_METABLOCK_DONE;
             break;
           }
-          if s.ringbuffer.slice().len() == 0 && !BrotliAllocateRingBuffer(&mut s, local_input) {
-            result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_2;
-            break;
+          if s.ringbuffer.slice().len() == 0 && !s.flat_window &&
+             (S::ACTIVE || !StartFlatWindow(s, *output_offset, *available_out)) {
+            if !BrotliAllocateRingBuffer(&mut s, local_input, dict) {
+              result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_2;
+              break;
+            }
+            if S::ACTIVE {
+              sink.ring_buffer_allocated(fast_slice!((s.ringbuffer)[0 ; s.ringbuffer_size as usize]));
+            }
+          }
+          if S::PROVENANCE {
+            s.provenance_bit_start = StreamBitPosition(s);
           }
           if s.is_uncompressed != 0 {
             s.state = BrotliRunningState::BROTLI_STATE_UNCOMPRESSED;
@@ -2836,14 +3535,21 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
           break;
         }
         BrotliRunningState::BROTLI_STATE_UNCOMPRESSED => {
-          let mut _bytes_copied = s.meta_block_remaining_len;
+          let mut bytes_copied = s.meta_block_remaining_len;
           result = CopyUncompressedBlockToOutput(&mut available_out,
                                                  &mut output,
                                                  &mut output_offset,
                                                  &mut total_out,
                                                  &mut s,
-                                                 local_input);
-          _bytes_copied -= s.meta_block_remaining_len;
+                                                 local_input,
+                                                 sink);
+          bytes_copied -= s.meta_block_remaining_len;
+          if S::PROVENANCE && bytes_copied != 0 {
+            EmitProvenance(s, sink, bytes_copied, Provenance::Uncompressed);
+          }
+          if let Some(ref mut stats) = s.stats {
+            stats.uncompressed_bytes += bytes_copied as u64;
+          }
           match result {
             BrotliDecoderErrorCode::BROTLI_DECODER_SUCCESS => {}
             _ => break,
@@ -2886,6 +3592,12 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
           }
           fast_mut!((s.block_type_length_state.num_block_types)[s.loop_counter as usize]) += 1;
           BROTLI_LOG_UINT!(s.block_type_length_state.num_block_types[s.loop_counter as usize]);
+          // each insert&copy block type has a tree, and the others size the context maps
+          if fast!((s.block_type_length_state.num_block_types)[s.loop_counter as usize]) >
+             s.caps.max_trees_per_group {
+            result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_TREES;
+            break;
+          }
           if fast!((s.block_type_length_state.num_block_types)[s.loop_counter as usize]) < 2 {
             s.loop_counter += 1;
             break;
@@ -2976,7 +3688,8 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
           s.distance_postfix_mask = bit_reader::BitMask(s.distance_postfix_bits) as i32;
           s.context_modes = s.alloc_u8
             .alloc_cell(fast!((s.block_type_length_state.num_block_types)[0]) as usize);
-          if (s.context_modes.slice().len() == 0) {
+          if (s.context_modes.slice().len() <
+              fast!((s.block_type_length_state.num_block_types)[0]) as usize) {
             result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_CONTEXT_MODES;
             break;
           }
@@ -3005,10 +3718,16 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
             _ => break,
           }
           DetectTrivialLiteralBlockTypes(s);
+          let context_map_size = s.context_map.slice().len();
+          ChargeWork(s, 1, context_map_size);
           s.state = BrotliRunningState::BROTLI_STATE_CONTEXT_MAP_2;
           // No break, continue to next state
         }
         BrotliRunningState::BROTLI_STATE_CONTEXT_MAP_2 => {
+            if WorkBudgetSpent(s) {
+              result = BrotliDecoderErrorCode::BROTLI_DECODER_YIELD;
+              break;
+            }
             let num_direct_codes =
               s.num_direct_distance_codes - NUM_DISTANCE_SHORT_CODES;
             let num_distance_codes = BROTLI_DISTANCE_ALPHABET_SIZE(
@@ -3031,6 +3750,8 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
               BrotliDecoderErrorCode::BROTLI_DECODER_SUCCESS => {}
               _ => break,
             }
+            let dist_context_map_size = s.dist_context_map.slice().len();
+            ChargeWork(s, 1, dist_context_map_size);
             s.literal_hgroup.init(&mut s.alloc_u32,
                                   &mut s.alloc_hc,
                                   kNumLiteralCodes,
@@ -3046,10 +3767,29 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
                                    num_distance_codes as u16,
                                    max_distance_symbol as u16,
                                    s.num_dist_htrees as u16);
-            if (s.literal_hgroup.codes.slice().len() == 0 ||
-                s.insert_copy_hgroup.codes.slice().len() == 0 ||
-                s.distance_hgroup.codes.slice().len() == 0) {
-              return SaveErrorCode!(s, BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_UNREACHABLE);
+            if (!s.literal_hgroup.is_allocated() ||
+                !s.insert_copy_hgroup.is_allocated() ||
+                !s.distance_hgroup.is_allocated()) {
+              return SaveErrorCode!(s, BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_TREE_GROUPS);
+            }
+            if let Some(ref mut stats) = s.stats {
+              stats.huffman_trees[0] += s.literal_hgroup.num_htrees as u64;
+              stats.huffman_trees[1] += s.insert_copy_hgroup.num_htrees as u64;
+              stats.huffman_trees[2] += s.distance_hgroup.num_htrees as u64;
+              let table_bytes = ((s.literal_hgroup.codes.slice().len() +
+                                  s.insert_copy_hgroup.codes.slice().len() +
+                                  s.distance_hgroup.codes.slice().len()) *
+                                 core::mem::size_of::<HuffmanCode>()) as u64;
+              stats.huffman_table_bytes += table_bytes;
+              stats.peak_huffman_table_bytes = core::cmp::max(stats.peak_huffman_table_bytes, table_bytes);
+              let tree_group_bytes = table_bytes +
+                ((s.literal_hgroup.htrees.slice().len() +
+                  s.insert_copy_hgroup.htrees.slice().len() +
+                  s.distance_hgroup.htrees.slice().len()) * core::mem::size_of::<u32>()) as u64;
+              stats.peak_tree_group_bytes = core::cmp::max(stats.peak_tree_group_bytes, tree_group_bytes);
+              let context_map_bytes = (s.context_modes.slice().len() + s.context_map.slice().len() +
+                                       s.dist_context_map.slice().len()) as u64;
+              stats.peak_context_map_bytes = core::cmp::max(stats.peak_context_map_bytes, context_map_bytes);
             }
 
           /*{
@@ -3096,6 +3836,9 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
           s.loop_counter += 1;
           if (s.loop_counter >= 3) {
             PrepareLiteralDecoding(s);
+            if S::ACTIVE {
+              PipelinePrepareMetablock(s, sink);
+            }
             s.dist_context_map_slice_index = 0;
               /*
             s.context_map_slice_index = 0;
@@ -3104,6 +3847,9 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
             s.context_lookup = &kContextLookup[context_mode as usize & 3];
                */
             s.htree_command_index = 0;
+            if S::PROVENANCE {
+              s.provenance_bit_start = StreamBitPosition(s);
+            }
             // look it up each time s.literal_htree=s.literal_hgroup.htrees[s.literal_htree_index];
             s.state = BrotliRunningState::BROTLI_STATE_COMMAND_BEGIN;
           }
@@ -3113,9 +3859,14 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
         BrotliRunningState::BROTLI_STATE_COMMAND_INNER |
         BrotliRunningState::BROTLI_STATE_COMMAND_POST_DECODE_LITERALS |
         BrotliRunningState::BROTLI_STATE_COMMAND_POST_WRAP_COPY => {
-          result = ProcessCommands(s, local_input);
+          let flat_window: &mut [u8] = if s.flat_window {
+            fast_mut!((output)[s.flat_window_offset ; *output_offset + *available_out])
+          } else {
+            &mut []
+          };
+          result = ProcessCommands(s, flat_window, local_input, sink);
           if let BrotliDecoderErrorCode::BROTLI_DECODER_NEEDS_MORE_INPUT = result {
-            result = SafeProcessCommands(s, local_input)
+            result = SafeProcessCommands(s, flat_window, local_input, sink)
           }
           break;
         }
@@ -3123,7 +3874,7 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
         BrotliRunningState::BROTLI_STATE_COMMAND_POST_WRITE_1 |
         BrotliRunningState::BROTLI_STATE_COMMAND_POST_WRITE_2 => {
           let (xresult, _) = WriteRingBuffer(&mut available_out,
-                                             Some(&mut output),
+                                             if S::ACTIVE { None } else { Some(&mut output) },
                                              &mut output_offset,
                                              &mut total_out,
                                              false,
@@ -3133,9 +3884,16 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
             BrotliDecoderErrorCode::BROTLI_DECODER_SUCCESS => {}
             _ => break,
           }
-          WrapRingBuffer(s);
-          if s.ringbuffer_size == 1 << s.window_bits {
-            s.max_distance = s.max_backward_distance;
+          if s.flat_window {
+            if !FlatWindowToRingBuffer(s, output) {
+              result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_1;
+              break;
+            }
+          } else {
+            WrapRingBuffer(s);
+            if s.ringbuffer_size == 1 << s.window_bits {
+              s.max_distance = s.max_backward_distance;
+            }
           }
           match s.state {
             BrotliRunningState::BROTLI_STATE_COMMAND_POST_WRITE_1 => {
@@ -3166,6 +3924,14 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
           break;
         }
         BrotliRunningState::BROTLI_STATE_METABLOCK_DONE => {
+          if s.meta_block_remaining_len < 0 {
+            result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_BLOCK_LENGTH_2;
+            break;
+          }
+          if S::PROVENANCE && s.provenance_literals != 0 {
+            let len = core::mem::replace(&mut s.provenance_literals, 0);
+            EmitProvenance(s, sink, len, Provenance::Literal);
+          }
           s.BrotliStateCleanupAfterMetablock();
           if (s.is_last_metablock == 0) {
             s.state = BrotliRunningState::BROTLI_STATE_METABLOCK_BEGIN;
@@ -3183,9 +3949,9 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
           // No break, continue to next state
         }
         BrotliRunningState::BROTLI_STATE_DONE => {
-          if (s.ringbuffer.slice().len() != 0) {
+          if (s.ringbuffer.slice().len() != 0 || s.flat_window) {
             let (xresult, _) = WriteRingBuffer(&mut available_out,
-                                               Some(&mut output),
+                                               if S::ACTIVE { None } else { Some(&mut output) },
                                                &mut output_offset,
                                                &mut total_out,
                                                true,
@@ -3196,11 +3962,23 @@ pub fn BrotliDecompressStream<AllocU8: alloc::Allocator<u8>,
               _ => break,
             }
           }
+          s.flat_window = false;
           return SaveErrorCode!(s, result);
         }
       }
     }
   }
-
+  if s.flat_window {
+    // the next call may pass a different output, so the window cannot stay there
+    if !is_fatal(result) {
+      let (xresult, _) = WriteRingBuffer(available_out, None, output_offset, total_out, true, s);
+      if is_fatal(xresult) {
+        result = xresult;
+      } else if !FlatWindowToRingBuffer(s, output) {
+        result = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_1;
+      }
+    }
+    s.flat_window = false;
+  }
   SaveErrorCode!(s, result)
 }
@@ -0,0 +1,99 @@
+use alloc::{Allocator, SliceWrapper};
+use core;
+
+/// Most raw dictionaries one decoder can have attached, as in the reference decoder.
+#[allow(non_upper_case_globals)]
+pub const kMaxCompoundDictionaries: usize = 15;
+
+/// Raw dictionaries attached to a decoder, in order.
+///
+/// Together they form one address space just beyond the window: the distance one past the
+/// furthest the window reaches names the last byte of the last dictionary, and larger
+/// distances walk back through the dictionaries towards the first byte of the first. The
+/// static dictionary starts where they end. Each dictionary stays in its own memory, and a
+/// copy may run from one into the next.
+pub struct CompoundDictionary<Mem: SliceWrapper<u8> + Default> {
+  chunks: [Mem; kMaxCompoundDictionaries],
+  // chunk_offsets[k] is where chunk k starts in the address space, and
+  // chunk_offsets[num_chunks] is the total size.
+  chunk_offsets: [usize; kMaxCompoundDictionaries + 1],
+  num_chunks: usize,
+}
+
+impl<Mem: SliceWrapper<u8> + Default> Default for CompoundDictionary<Mem> {
+  fn default() -> Self {
+    CompoundDictionary {
+      chunks: Default::default(),
+      chunk_offsets: [0; kMaxCompoundDictionaries + 1],
+      num_chunks: 0,
+    }
+  }
+}
+
+impl<Mem: SliceWrapper<u8> + Default> CompoundDictionary<Mem> {
+  /// Appends `chunk`, or hands it back if `kMaxCompoundDictionaries` are attached already
+  /// or the total would no longer fit a brotli distance.
+  pub fn attach(&mut self, chunk: Mem) -> Result<(), Mem> {
+    let total = self.total_size() + chunk.slice().len();
+    if self.num_chunks == kMaxCompoundDictionaries || total > i32::MAX as usize {
+      return Err(chunk);
+    }
+    self.chunks[self.num_chunks] = chunk;
+    self.num_chunks += 1;
+    self.chunk_offsets[self.num_chunks] = total;
+    Ok(())
+  }
+
+  pub fn num_chunks(&self) -> usize {
+    self.num_chunks
+  }
+
+  pub fn chunk(&self, index: usize) -> &[u8] {
+    self.chunks[..self.num_chunks][index].slice()
+  }
+
+  /// Size of all the dictionaries together.
+  pub fn total_size(&self) -> usize {
+    self.chunk_offsets[self.num_chunks]
+  }
+
+  /// Offset in the concatenated dictionaries of the byte that `address` names, counting
+  /// addresses back from the end as distances do. `address` must be below `total_size`.
+  pub fn offset_of_address(&self, address: usize) -> usize {
+    self.total_size() - 1 - address
+  }
+
+  /// Fills `dst` with the bytes from `offset` on, across chunk boundaries. Returns false,
+  /// copying nothing, if they run past the end of the last dictionary.
+  pub fn copy_to(&self, offset: usize, dst: &mut [u8]) -> bool {
+    if offset + dst.len() > self.total_size() {
+      return false;
+    }
+    if dst.is_empty() {
+      return true;
+    }
+    let mut index = 0;
+    while offset >= self.chunk_offsets[index + 1] {
+      index += 1;
+    }
+    let mut chunk_offset = offset - self.chunk_offsets[index];
+    let mut copied = 0;
+    while copied < dst.len() {
+      let chunk = self.chunks[index].slice();
+      let n = core::cmp::min(chunk.len() - chunk_offset, dst.len() - copied);
+      dst[copied..copied + n].clone_from_slice(&chunk[chunk_offset..chunk_offset + n]);
+      copied += n;
+      chunk_offset = 0;
+      index += 1;
+    }
+    true
+  }
+
+  /// Detaches every dictionary and gives its memory back to `alloc`.
+  pub fn free<AllocU8: Allocator<u8, AllocatedMemory = Mem>>(&mut self, alloc: &mut AllocU8) {
+    for chunk in self.chunks[..self.num_chunks].iter_mut() {
+      alloc.free_cell(core::mem::take(chunk));
+    }
+    self.num_chunks = 0;
+  }
+}
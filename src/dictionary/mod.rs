@@ -1,4 +1,11 @@
 
+mod compound;
+mod sha256;
+mod shared;
+pub use self::compound::{CompoundDictionary, kMaxCompoundDictionaries};
+pub use self::sha256::{Sha256, sha256};
+pub use self::shared::SharedDictionary;
+
 #[allow(non_upper_case_globals)]
 pub const kBrotliDictionaryOffsetsByLength: [u32; 25] =
   [0, 0, 0, 0, 0, 4096, 9216, 21504, 35840, 44032, 53248, 63488, 74752, 87040, 93696, 100864,
@@ -14,6 +21,57 @@ pub const kBrotliMinDictionaryWordLength: u8 = 4;
 #[allow(non_upper_case_globals)]
 pub const kBrotliMaxDictionaryWordLength: u8 = 24;
 
+/// Number of words of length `len` in the static dictionary, 0 for lengths it has none of.
+pub fn num_words(len: usize) -> usize {
+  if len < kBrotliMinDictionaryWordLength as usize || len > kBrotliMaxDictionaryWordLength as usize {
+    return 0;
+  }
+  1 << kBrotliDictionarySizeBitsByLength[len]
+}
+
+/// Word `idx` among the static dictionary words of length `len`.
+///
+/// # Panics
+///
+/// Panics if `idx >= num_words(len)`.
+pub fn word(len: usize, idx: usize) -> &'static [u8] {
+  assert!(idx < num_words(len), "no dictionary word {} of length {}", idx, len);
+  let offset = kBrotliDictionaryOffsetsByLength[len] as usize + idx * len;
+  &kBrotliDictionary[offset..offset + len]
+}
+
+/// Iterator over every static dictionary word as `(len, idx, word)`, by length and then
+/// index, as returned by `words`.
+#[derive(Clone, Debug)]
+pub struct Words {
+  len: usize,
+  idx: usize,
+}
+
+/// Iterates over every word of the static dictionary.
+pub fn words() -> Words {
+  Words {
+    len: kBrotliMinDictionaryWordLength as usize,
+    idx: 0,
+  }
+}
+
+impl Iterator for Words {
+  type Item = (usize, usize, &'static [u8]);
+  fn next(&mut self) -> Option<Self::Item> {
+    while self.idx >= num_words(self.len) {
+      if self.len >= kBrotliMaxDictionaryWordLength as usize {
+        return None;
+      }
+      self.len += 1;
+      self.idx = 0;
+    }
+    let idx = self.idx;
+    self.idx += 1;
+    Some((self.len, idx, word(self.len, idx)))
+  }
+}
+
 #[allow(non_upper_case_globals)]
 pub static kBrotliDictionary: [u8; 122784] =
   [0x74, 0x69, 0x6d, 0x65, 0x64, 0x6f, 0x77, 0x6e, 0x6c, 0x69, 0x66, 0x65, 0x6c, 0x65, 0x66, 0x74,
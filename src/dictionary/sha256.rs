@@ -0,0 +1,125 @@
+// SHA-256 (FIPS 180-4), which names dictionaries in dictionary frames and checks the
+// content of streams framed with a dictionary id.
+
+#[allow(non_upper_case_globals)]
+const kRoundConstants: [u32; 64] = [
+  0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+  0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+  0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+  0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+  0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+  0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+  0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+  0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+#[allow(non_upper_case_globals)]
+const kInitialHash: [u32; 8] = [
+  0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+fn compress_block(hash: &mut [u32; 8], block: &[u8]) {
+  let mut w = [0u32; 64];
+  for (i, word) in block.chunks(4).enumerate() {
+    w[i] = (u32::from(word[0]) << 24) | (u32::from(word[1]) << 16) |
+           (u32::from(word[2]) << 8) | u32::from(word[3]);
+  }
+  for i in 16..64 {
+    let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+    let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+    w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+  }
+  let mut v = *hash;
+  for i in 0..64 {
+    let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+    let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+    let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(kRoundConstants[i]).wrapping_add(w[i]);
+    let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+    let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+    let t2 = s0.wrapping_add(maj);
+    v = [t1.wrapping_add(t2), v[0], v[1], v[2], v[3].wrapping_add(t1), v[4], v[5], v[6]];
+  }
+  for (h, x) in hash.iter_mut().zip(v.iter()) {
+    *h = h.wrapping_add(*x);
+  }
+}
+
+/// SHA-256 of data fed in pieces, for content too large to hold at once.
+#[derive(Clone)]
+pub struct Sha256 {
+  hash: [u32; 8],
+  block: [u8; 64],
+  block_len: usize,
+  len: u64,
+}
+
+impl Default for Sha256 {
+  fn default() -> Self {
+    Sha256 {
+      hash: kInitialHash,
+      block: [0; 64],
+      block_len: 0,
+      len: 0,
+    }
+  }
+}
+
+impl Sha256 {
+  pub fn new() -> Self {
+    Sha256::default()
+  }
+
+  pub fn update(&mut self, mut data: &[u8]) {
+    self.len = self.len.wrapping_add(data.len() as u64);
+    if self.block_len != 0 {
+      let n = core::cmp::min(64 - self.block_len, data.len());
+      self.block[self.block_len..self.block_len + n].clone_from_slice(&data[..n]);
+      self.block_len += n;
+      data = &data[n..];
+      if self.block_len < 64 {
+        return;
+      }
+      compress_block(&mut self.hash, &self.block);
+      self.block_len = 0;
+    }
+    let mut blocks = data.chunks_exact(64);
+    for block in &mut blocks {
+      compress_block(&mut self.hash, block);
+    }
+    let rest = blocks.remainder();
+    self.block[..rest.len()].clone_from_slice(rest);
+    self.block_len = rest.len();
+  }
+
+  pub fn finish(&self) -> [u8; 32] {
+    let mut hash = self.hash;
+    // the rest, a 1 bit, zeros and the length in bits fill one or two final blocks
+    let rest = &self.block[..self.block_len];
+    let mut tail = [0u8; 128];
+    tail[..rest.len()].clone_from_slice(rest);
+    tail[rest.len()] = 0x80;
+    let tail_len = if rest.len() < 56 { 64 } else { 128 };
+    let bits = self.len.wrapping_mul(8);
+    for i in 0..8 {
+      tail[tail_len - 1 - i] = (bits >> (8 * i)) as u8;
+    }
+    for block in tail[..tail_len].chunks(64) {
+      compress_block(&mut hash, block);
+    }
+    let mut digest = [0u8; 32];
+    for (bytes, h) in digest.chunks_mut(4).zip(hash.iter()) {
+      bytes[0] = (h >> 24) as u8;
+      bytes[1] = (h >> 16) as u8;
+      bytes[2] = (h >> 8) as u8;
+      bytes[3] = *h as u8;
+    }
+    digest
+  }
+}
+
+/// SHA-256 digest of `data`.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+  let mut hasher = Sha256::new();
+  hasher.update(data);
+  hasher.finish()
+}
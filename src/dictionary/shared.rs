@@ -0,0 +1,63 @@
+#[cfg(feature="std")]
+use std::sync::Arc;
+use alloc::SliceWrapper;
+use decode::kBrotliLargeMaxWbits;
+
+// The window keeps 16 bytes free, so no stream reaches further back into a custom
+// dictionary than this.
+#[allow(non_upper_case_globals)]
+const kMaxReachableDictionarySize: usize = (1 << kBrotliLargeMaxWbits) - 16;
+
+/// A custom dictionary that any number of decoders can use at once without holding a
+/// copy of their own. Cloning one only copies a reference.
+///
+/// Each decoder still places the part of the dictionary its window can reach into its
+/// ring buffer, so memory use follows the window size rather than the number of decoders
+/// sharing the dictionary.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SharedDictionary {
+  /// A dictionary that lives as long as the program, such as one from `include_bytes!`.
+  Static(&'static [u8]),
+  /// A dictionary kept alive by reference counting.
+  #[cfg(feature="std")]
+  Shared(Arc<[u8]>),
+}
+
+impl SharedDictionary {
+  /// Copies the part of `dict` that a stream can refer to, once, into a dictionary to
+  /// hand to every decoder that needs it.
+  #[cfg(feature="std")]
+  pub fn prepare(dict: &[u8]) -> Self {
+    let start = dict.len().saturating_sub(kMaxReachableDictionarySize);
+    SharedDictionary::Shared(Arc::from(&dict[start..]))
+  }
+  pub fn len(&self) -> usize {
+    self.slice().len()
+  }
+  pub fn is_empty(&self) -> bool {
+    self.slice().is_empty()
+  }
+}
+
+impl SliceWrapper<u8> for SharedDictionary {
+  fn slice(&self) -> &[u8] {
+    match *self {
+      SharedDictionary::Static(dict) => dict,
+      #[cfg(feature="std")]
+      SharedDictionary::Shared(ref dict) => dict,
+    }
+  }
+}
+
+impl From<&'static [u8]> for SharedDictionary {
+  fn from(dict: &'static [u8]) -> Self {
+    SharedDictionary::Static(dict)
+  }
+}
+
+#[cfg(feature="std")]
+impl From<Arc<[u8]>> for SharedDictionary {
+  fn from(dict: Arc<[u8]>) -> Self {
+    SharedDictionary::Shared(dict)
+  }
+}
@@ -0,0 +1,281 @@
+#![cfg(feature="std")]
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Error, ErrorKind, Read};
+use std::path::Path;
+use std::string::String;
+use std::vec::Vec;
+use dictionary::{Sha256, SharedDictionary, sha256};
+use options::DecompressorOptions;
+use reader::Decompressor;
+
+// A dictionary frame, as in the compression dictionary transport drafts, is
+//   DICTIONARY_FRAME_MAGIC
+//   SHA-256 of the whole custom dictionary (32 bytes)
+//   a brotli stream compressed against that dictionary
+// An id frame names the dictionary by an id of the store's instead:
+//   DICTIONARY_ID_FRAME_MAGIC
+//   length of the id (1 byte, at least 1)
+//   the id, in UTF-8
+//   SHA-256 of the decompressed content (32 bytes)
+//   a brotli stream compressed against the dictionary
+// An id does not pin down the dictionary's bytes the way a hash does, so the decoder
+// checks the content instead: a stream decoded against the wrong dictionary fails the
+// check at its end, if it does not fail to decode before that.
+// No valid brotli stream starts with 0xff, so the magic tells framed streams from plain
+// ones.
+pub const DICTIONARY_FRAME_MAGIC: [u8; 4] = [0xff, 0x44, 0x43, 0x42];
+pub const DICTIONARY_FRAME_HEADER_SIZE: usize = 36;
+pub const DICTIONARY_ID_FRAME_MAGIC: [u8; 4] = [0xff, 0x44, 0x49, 0x44];
+
+/// A dictionary ready to be shared by decoders, with the SHA-256 that names it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DictionaryEntry {
+  pub hash: [u8; 32],
+  pub dictionary: SharedDictionary,
+}
+
+impl DictionaryEntry {
+  pub fn new(dict: &[u8]) -> Self {
+    DictionaryEntry {
+      hash: sha256(dict),
+      dictionary: SharedDictionary::prepare(dict),
+    }
+  }
+}
+
+/// Custom dictionaries keyed by their SHA-256, some also by an id of the caller's choice.
+#[derive(Clone, Debug, Default)]
+pub struct DictionaryStore {
+  by_hash: HashMap<[u8; 32], DictionaryEntry>,
+  by_id: HashMap<String, [u8; 32]>,
+}
+
+impl DictionaryStore {
+  pub fn new() -> Self {
+    DictionaryStore::default()
+  }
+
+  /// Loads every file in `dir` as a dictionary whose id is the file name.
+  pub fn load_dir<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+    let mut store = DictionaryStore::new();
+    for dir_entry in fs::read_dir(dir)? {
+      let dir_entry = dir_entry?;
+      if !dir_entry.file_type()?.is_file() {
+        continue;
+      }
+      let dict = fs::read(dir_entry.path())?;
+      store.insert_with_id(&dir_entry.file_name().to_string_lossy(), &dict[..]);
+    }
+    Ok(store)
+  }
+
+  /// Adds `dict` and returns its SHA-256.
+  pub fn insert(&mut self, dict: &[u8]) -> [u8; 32] {
+    let entry = DictionaryEntry::new(dict);
+    let hash = entry.hash;
+    self.by_hash.insert(hash, entry);
+    hash
+  }
+
+  /// Adds `dict` under `id` as well as its SHA-256, which it returns. An id given before
+  /// moves to the new dictionary.
+  pub fn insert_with_id(&mut self, id: &str, dict: &[u8]) -> [u8; 32] {
+    let hash = self.insert(dict);
+    self.by_id.insert(String::from(id), hash);
+    hash
+  }
+
+  pub fn get(&self, hash: &[u8; 32]) -> Option<&DictionaryEntry> {
+    self.by_hash.get(hash)
+  }
+
+  pub fn get_by_id(&self, id: &str) -> Option<&DictionaryEntry> {
+    self.by_id.get(id).and_then(|hash| self.by_hash.get(hash))
+  }
+
+  /// Number of distinct dictionaries.
+  pub fn len(&self) -> usize {
+    self.by_hash.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.by_hash.is_empty()
+  }
+}
+
+/// The frame header that goes before a brotli stream compressed against `dict`.
+pub fn dictionary_frame_header(dict: &[u8]) -> [u8; DICTIONARY_FRAME_HEADER_SIZE] {
+  let mut header = [0u8; DICTIONARY_FRAME_HEADER_SIZE];
+  header[..4].clone_from_slice(&DICTIONARY_FRAME_MAGIC[..]);
+  header[4..].clone_from_slice(&sha256(dict)[..]);
+  header
+}
+
+/// The frame header that goes before a brotli stream of `content` compressed against the
+/// dictionary a store holds under `id`. Ids must be 1 to 255 bytes long.
+pub fn dictionary_id_frame_header(id: &str, content: &[u8]) -> io::Result<Vec<u8>> {
+  if id.is_empty() || id.len() > 255 {
+    return Err(Error::new(ErrorKind::InvalidInput, "dictionary ids are 1 to 255 bytes long"));
+  }
+  let mut header = Vec::with_capacity(DICTIONARY_ID_FRAME_MAGIC.len() + 1 + id.len() + 32);
+  header.extend_from_slice(&DICTIONARY_ID_FRAME_MAGIC[..]);
+  header.push(id.len() as u8);
+  header.extend_from_slice(id.as_bytes());
+  header.extend_from_slice(&sha256(content)[..]);
+  Ok(header)
+}
+
+// Reads up to len bytes from r, fewer only at the end of the input.
+fn read_prefix<R: Read>(r: &mut R, len: usize) -> io::Result<Vec<u8>> {
+  let mut prefix = Vec::with_capacity(len);
+  r.by_ref().take(len as u64).read_to_end(&mut prefix)?;
+  Ok(prefix)
+}
+
+// What a stream is framed with.
+enum Frame {
+  // not framed: the bytes read that belong to the brotli stream itself
+  Plain(Vec<u8>),
+  Hash([u8; 32]),
+  // the dictionary id and the SHA-256 of the content
+  Id(String, [u8; 32]),
+}
+
+fn read_exact_prefix<R: Read>(r: &mut R, len: usize) -> io::Result<Vec<u8>> {
+  let prefix = read_prefix(r, len)?;
+  if prefix.len() != len {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "truncated dictionary frame header"));
+  }
+  Ok(prefix)
+}
+
+fn read_frame_header<R: Read>(r: &mut R) -> io::Result<Frame> {
+  let prefix = read_prefix(r, DICTIONARY_FRAME_MAGIC.len())?;
+  let mut hash = [0u8; 32];
+  if prefix[..] == DICTIONARY_FRAME_MAGIC[..] {
+    hash.clone_from_slice(&read_exact_prefix(r, 32)?[..]);
+    return Ok(Frame::Hash(hash));
+  }
+  if prefix[..] != DICTIONARY_ID_FRAME_MAGIC[..] {
+    return Ok(Frame::Plain(prefix));
+  }
+  let id_len = read_exact_prefix(r, 1)?[0] as usize;
+  if id_len == 0 {
+    return Err(Error::new(ErrorKind::InvalidData, "empty dictionary id"));
+  }
+  let id = match String::from_utf8(read_exact_prefix(r, id_len)?) {
+    Ok(id) => id,
+    Err(_) => return Err(Error::new(ErrorKind::InvalidData, "dictionary id is not UTF-8")),
+  };
+  hash.clone_from_slice(&read_exact_prefix(r, 32)?[..]);
+  Ok(Frame::Id(id, hash))
+}
+
+/// Decompresses a stream that may be framed with the hash or the id of its dictionary,
+/// using the dictionary the frame names. Streams without a frame decode without a
+/// dictionary.
+///
+/// A hash names exactly one dictionary, so a stream framed with one cannot be decoded
+/// against another. An id frame carries the SHA-256 of the content instead, and reading
+/// to the end of the stream fails with `InvalidData` when the output does not match it.
+pub struct DictionaryDecompressor<R: Read> {
+  inner: Decompressor<io::Chain<io::Cursor<Vec<u8>>, R>>,
+  hash: Option<[u8; 32]>,
+  id: Option<String>,
+  // the content hash of an id frame, and the hash of the output so far
+  checksum: Option<([u8; 32], Sha256)>,
+}
+
+impl<R: Read> DictionaryDecompressor<R> {
+  /// Reads the frame header from `r` and picks its dictionary from `store`, failing with
+  /// `NotFound` if the store does not have it.
+  pub fn new(mut r: R, buffer_size: usize, store: &DictionaryStore) -> io::Result<Self> {
+    let frame = read_frame_header(&mut r)?;
+    let entry = match frame {
+      Frame::Plain(_) => None,
+      Frame::Hash(ref hash) => match store.get(hash) {
+        Some(entry) => Some(entry),
+        None => return Err(Error::new(ErrorKind::NotFound, "no dictionary with the frame's hash")),
+      },
+      Frame::Id(ref id, _) => match store.get_by_id(id) {
+        Some(entry) => Some(entry),
+        None => return Err(Error::new(ErrorKind::NotFound, "no dictionary with the frame's id")),
+      },
+    };
+    Ok(Self::with_entry(r, buffer_size, frame, entry))
+  }
+
+  /// Decodes with `entry`, chosen by the caller, and fails with `InvalidData` before
+  /// decoding if the stream is framed with the hash of another dictionary. Streams framed
+  /// with an id are checked against their content hash as they end.
+  pub fn with_dictionary(mut r: R, buffer_size: usize, entry: &DictionaryEntry) -> io::Result<Self> {
+    let frame = read_frame_header(&mut r)?;
+    if let Frame::Hash(ref hash) = frame {
+      if *hash != entry.hash {
+        return Err(Error::new(ErrorKind::InvalidData, "dictionary does not match the frame's hash"));
+      }
+    }
+    Ok(Self::with_entry(r, buffer_size, frame, Some(entry)))
+  }
+
+  fn with_entry(r: R,
+                buffer_size: usize,
+                frame: Frame,
+                entry: Option<&DictionaryEntry>) -> Self {
+    // trailing input is left unread, as by Decompressor::new
+    let mut options = DecompressorOptions::new().buffer_size(buffer_size).strict(false);
+    if let Some(entry) = entry {
+      options = options.shared_dictionary(&entry.dictionary);
+    }
+    let (prefix, hash, id, checksum) = match frame {
+      Frame::Plain(prefix) => (prefix, None, None, None),
+      Frame::Hash(hash) => (Vec::new(), Some(hash), None, None),
+      Frame::Id(id, content_hash) => (Vec::new(), None, Some(id), Some((content_hash, Sha256::new()))),
+    };
+    DictionaryDecompressor {
+      inner: Decompressor::with_options(io::Cursor::new(prefix).chain(r), &options),
+      hash,
+      id,
+      checksum,
+    }
+  }
+
+  /// Hash the stream is framed with, if it is framed with one.
+  pub fn dictionary_hash(&self) -> Option<&[u8; 32]> {
+    self.hash.as_ref()
+  }
+
+  /// Id the stream is framed with, if it is framed with one.
+  pub fn dictionary_id(&self) -> Option<&str> {
+    self.id.as_ref().map(|id| &id[..])
+  }
+
+  /// See `Decompressor::skip_output`. The content hash of an id frame covers the whole
+  /// output, so skipping any of it gives up the check.
+  pub fn skip_output(&mut self, n: usize) {
+    if n != 0 {
+      self.checksum = None;
+    }
+    self.inner.skip_output(n)
+  }
+}
+
+impl<R: Read> Read for DictionaryDecompressor<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let size = self.inner.read(buf)?;
+    if let Some((_, ref mut hasher)) = self.checksum {
+      hasher.update(&buf[..size]);
+    }
+    if size == 0 && !buf.is_empty() {
+      // checked once, as the stream ends
+      if let Some((expected, hasher)) = self.checksum.take() {
+        if hasher.finish() != expected {
+          return Err(Error::new(ErrorKind::InvalidData,
+                                "decompressed content does not match the frame's checksum"));
+        }
+      }
+    }
+    Ok(size)
+  }
+}
@@ -0,0 +1,90 @@
+// Making allocations fail on purpose, to test what happens when memory runs out.
+//
+// FailingAllocator wraps any allocator and asks a FaultInjector the caller keeps whether to
+// fail each allocation. One injector usually serves all three of a state's allocators, so
+// they count their allocations together and the Nth allocation of the whole state fails.
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+use alloc::Allocator;
+
+/// Numbers the allocations of the `FailingAllocator`s asking it and fails one of them.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+  fail_at: Cell<u64>,
+  allocations: Cell<u64>,
+  failed: Cell<bool>,
+}
+
+impl FaultInjector {
+  /// An injector that never fails, for counting how many allocations there are.
+  pub fn new() -> Self {
+    FaultInjector::default()
+  }
+
+  /// An injector that fails the `n`th allocation, counting from 1, and no other.
+  pub fn fail_nth(n: u64) -> Self {
+    let injector = FaultInjector::default();
+    injector.fail_at.set(n);
+    injector
+  }
+
+  /// Allocations asked for so far, the failed one included. Empty ones are not counted.
+  pub fn allocations(&self) -> u64 {
+    self.allocations.get()
+  }
+
+  /// Whether the allocation meant to fail was asked for.
+  pub fn failed(&self) -> bool {
+    self.failed.get()
+  }
+
+  fn should_fail(&self) -> bool {
+    let n = self.allocations.get() + 1;
+    self.allocations.set(n);
+    if n == self.fail_at.get() {
+      self.failed.set(true);
+      return true;
+    }
+    false
+  }
+}
+
+/// Allocates from `inner` except when its injector says to fail, when it returns the
+/// empty memory allocators return when they run out.
+pub struct FailingAllocator<'a, T, A: Allocator<T>> {
+  inner: A,
+  injector: &'a FaultInjector,
+  element: PhantomData<T>,
+}
+
+impl<'a, T, A: Allocator<T>> FailingAllocator<'a, T, A> {
+  pub fn new(inner: A, injector: &'a FaultInjector) -> Self {
+    FailingAllocator {
+      inner,
+      injector,
+      element: PhantomData,
+    }
+  }
+
+  pub fn injector(&self) -> &'a FaultInjector {
+    self.injector
+  }
+
+  pub fn into_inner(self) -> A {
+    self.inner
+  }
+}
+
+impl<'a, T, A: Allocator<T>> Allocator<T> for FailingAllocator<'a, T, A> {
+  type AllocatedMemory = A::AllocatedMemory;
+  fn alloc_cell(&mut self, len: usize) -> A::AllocatedMemory {
+    if len != 0 && self.injector.should_fail() {
+      return A::AllocatedMemory::default();
+    }
+    self.inner.alloc_cell(len)
+  }
+  fn free_cell(&mut self, data: A::AllocatedMemory) {
+    self.inner.free_cell(data)
+  }
+}
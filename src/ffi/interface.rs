@@ -12,6 +12,13 @@ pub enum BrotliDecoderParameter {
     BROTLI_DECODER_PARAM_LARGE_WINDOW = 1,
 }
 
+#[repr(C)]
+#[allow(dead_code)]
+pub enum BrotliSharedDictionaryType {
+    BROTLI_SHARED_DICTIONARY_RAW = 0,
+    BROTLI_SHARED_DICTIONARY_SERIALIZED = 1,
+}
+
 
 #[repr(C)]
 #[no_mangle]
@@ -11,8 +11,9 @@ pub mod interface;
 pub mod alloc_util;
 use self::alloc_util::SubclassableAllocator;
 use alloc::Allocator;
-use self::interface::{CAllocator, c_void, BrotliDecoderParameter, BrotliDecoderResult, brotli_alloc_func, brotli_free_func};
+use self::interface::{CAllocator, c_void, BrotliDecoderParameter, BrotliDecoderResult, BrotliSharedDictionaryType, brotli_alloc_func, brotli_free_func};
 use ::BrotliResult;
+use options::DecompressorOptions;
 pub use super::state::BrotliDecoderErrorCode;
 
 #[cfg(feature="std")]
@@ -46,6 +47,22 @@ pub unsafe extern fn BrotliDecoderCreateInstance(
     alloc_func: brotli_alloc_func,
     free_func: brotli_free_func,
     opaque: *mut c_void,
+) -> *mut BrotliDecoderState {
+    BrotliDecoderCreateInstanceWithOptions(alloc_func, free_func, opaque, &DecompressorOptions::new())
+}
+
+/// Creates a decoder for C callers set up from Rust. Of `options`, only `large_window` and
+/// the dictionaries apply: C callers pass their own buffers to
+/// `BrotliDecoderDecompressStream` and see any input left after the stream ends.
+///
+/// # Safety
+///
+/// The allocator functions must be as `BrotliDecoderCreateInstance` requires.
+pub unsafe fn BrotliDecoderCreateInstanceWithOptions(
+    alloc_func: brotli_alloc_func,
+    free_func: brotli_free_func,
+    opaque: *mut c_void,
+    options: &DecompressorOptions,
 ) -> *mut BrotliDecoderState {
     match catch_panic_state(|| {
       let allocators = CAllocator {
@@ -53,14 +70,13 @@ pub unsafe extern fn BrotliDecoderCreateInstance(
         free_func:free_func,
         opaque:opaque,
       };
-      let custom_dictionary = <SubclassableAllocator as Allocator<u8>>::AllocatedMemory::default();
       let to_box = BrotliDecoderState {
         custom_allocator: allocators.clone(),
-        decompressor: ::BrotliState::new_with_custom_dictionary(
+        decompressor: ::BrotliState::new_with_options(
           SubclassableAllocator::new(allocators.clone()),
           SubclassableAllocator::new(allocators.clone()),
           SubclassableAllocator::new(allocators.clone()),
-          custom_dictionary,
+          options,
         ),
       };
       if let Some(alloc) = alloc_func {
@@ -84,10 +100,40 @@ pub unsafe extern fn BrotliDecoderCreateInstance(
 }
 
 #[no_mangle]
-pub unsafe extern fn BrotliDecoderSetParameter(_state_ptr: *mut BrotliDecoderState,
-                                       _selector: BrotliDecoderParameter,
-                                       _value: u32) {
-  // not implemented
+pub unsafe extern fn BrotliDecoderSetParameter(state_ptr: *mut BrotliDecoderState,
+                                       selector: BrotliDecoderParameter,
+                                       value: u32) {
+  match selector {
+    BrotliDecoderParameter::BROTLI_DECODER_PARAM_LARGE_WINDOW => {
+      (*state_ptr).decompressor.set_large_window(value != 0);
+    }
+    // the ring buffer is sized once per stream already
+    BrotliDecoderParameter::BROTLI_DECODER_PARAM_DISABLE_RING_BUFFER_REALLOCATION => {}
+  }
+}
+
+/// Attaches a raw dictionary, copying it; see `BrotliState::attach_dictionary`. Returns 0
+/// for serialized dictionaries, which are not supported.
+///
+/// # Safety
+///
+/// `state_ptr` must come from `BrotliDecoderCreateInstance`, and `data` must point to
+/// `data_size` readable bytes unless `data_size` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn BrotliDecoderAttachDictionary(state_ptr: *mut BrotliDecoderState,
+                                                       dict_type: BrotliSharedDictionaryType,
+                                                       data_size: usize,
+                                                       data: *const u8) -> i32 {
+  match dict_type {
+    BrotliSharedDictionaryType::BROTLI_SHARED_DICTIONARY_RAW => {}
+    BrotliSharedDictionaryType::BROTLI_SHARED_DICTIONARY_SERIALIZED => return 0,
+  }
+  let dict = if data_size != 0 {
+    slice::from_raw_parts(data, data_size)
+  } else {
+    &[]
+  };
+  (*state_ptr).decompressor.attach_dictionary_copy(dict) as i32
 }
 
 #[cfg(not(feature="std"))] // error always since no default allocator
@@ -222,6 +268,7 @@ pub unsafe extern fn BrotliDecoderDecompressStream(
                 BrotliResult::ResultFailure => BrotliDecoderResult::BROTLI_DECODER_RESULT_ERROR,
                 BrotliResult::NeedsMoreInput => BrotliDecoderResult::BROTLI_DECODER_RESULT_NEEDS_MORE_INPUT ,
                 BrotliResult::NeedsMoreOutput => BrotliDecoderResult::BROTLI_DECODER_RESULT_NEEDS_MORE_OUTPUT ,
+                // C has no work budget to set, and calling again is what it asks for anyway
             };
     }
     *input_buf_ptr = (*input_buf_ptr).offset(input_offset as isize);
@@ -342,6 +389,7 @@ pub extern fn BrotliDecoderErrorString(c: BrotliDecoderErrorCode) -> *const u8 {
   BrotliDecoderErrorCode::BROTLI_DECODER_SUCCESS => "SUCCESS\0",
   BrotliDecoderErrorCode::BROTLI_DECODER_NEEDS_MORE_INPUT => "NEEDS_MORE_INPUT\0",
   BrotliDecoderErrorCode::BROTLI_DECODER_NEEDS_MORE_OUTPUT => "NEEDS_MORE_OUTPUT\0",
+  BrotliDecoderErrorCode::BROTLI_DECODER_YIELD => "YIELD\0",
 
   /* Errors caused by invalid input */
   BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_EXUBERANT_NIBBLE => "ERROR_FORMAT_EXUBERANT_NIBBLE\0",
@@ -361,7 +409,9 @@ pub extern fn BrotliDecoderErrorString(c: BrotliDecoderErrorCode) -> *const u8 {
   BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_PADDING_2 =>"ERROR_FORMAT_PADDING_2\0",
   BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_FORMAT_DISTANCE =>"ERROR_FORMAT_DISTANCE\0",
 
-  /* -17..-18 codes are reserved */
+  /* -17 code is reserved */
+
+  BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_COMPOUND_DICTIONARY =>"ERROR_COMPOUND_DICTIONARY\0",
 
   BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_DICTIONARY_NOT_SET => "ERROR_DICTIONARY_NOT_SET\0",
   BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_INVALID_ARGUMENTS => "ERROR_INVALID_ARGUMENTS\0",
@@ -379,6 +429,12 @@ pub extern fn BrotliDecoderErrorString(c: BrotliDecoderErrorCode) -> *const u8 {
 
   /* "Impossible" states */
   BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_UNREACHABLE => "ERROR_UNREACHABLE\0",
+
+  /* Caps set with BrotliState::set_caps */
+  BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_METABLOCKS => "ERROR_CAP_METABLOCKS\0",
+  BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_METADATA => "ERROR_CAP_METADATA\0",
+  BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_TREES => "ERROR_CAP_TREES\0",
+  BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_CAP_INPUT_PER_OUTPUT => "ERROR_CAP_INPUT_PER_OUTPUT\0",
   }.as_ptr()
 }
 
@@ -0,0 +1,67 @@
+#![cfg(feature="std")]
+use std::io::{self, Error, ErrorKind};
+use std::vec::Vec;
+use alloc_stdlib::StandardAlloc;
+use decode::{BrotliDecompressStream, BrotliResult};
+use state::BrotliState;
+
+// Whole-frame decoding shared by the seekable and parallel decoders, which both work on
+// independent brotli streams whose compressed extent is known up front.
+
+pub fn new_state() -> BrotliState<StandardAlloc, StandardAlloc, StandardAlloc> {
+  BrotliState::new(StandardAlloc::default(),
+                   StandardAlloc::default(),
+                   StandardAlloc::default())
+}
+
+// Decodes the single brotli stream in input, which must span all of it. The state is
+// reset first, so one state can decode any number of frames. A frame whose seek table
+// entry gives its size fails as soon as it decodes to more than `max_output` bytes.
+pub fn decode_frame(state: &mut BrotliState<StandardAlloc, StandardAlloc, StandardAlloc>,
+                    input: &[u8],
+                    output: &mut Vec<u8>,
+                    max_output: Option<usize>) -> io::Result<()> {
+  let max_output = max_output.unwrap_or(usize::MAX);
+  state.reset();
+  let mut available_in = input.len();
+  let mut input_offset: usize = 0;
+  let mut total_out: usize = 0;
+  output.clear();
+  loop {
+    if output.len() == output.capacity() {
+      output.reserve(4096);
+    }
+    let old_len = output.len();
+    let capacity = ::core::cmp::min(output.capacity(), max_output);
+    output.resize(capacity, 0);
+    let mut available_out = capacity - old_len;
+    let mut output_offset = old_len;
+    let result = BrotliDecompressStream(&mut available_in,
+                                        &mut input_offset,
+                                        input,
+                                        &mut available_out,
+                                        &mut output_offset,
+                                        &mut output[..],
+                                        &mut total_out,
+                                        state);
+    output.truncate(output_offset);
+    match result {
+      BrotliResult::NeedsMoreOutput if output.len() == max_output => {
+        return Err(Error::new(ErrorKind::InvalidData,
+                              "brotli frame larger than its seek table entry"));
+      }
+      BrotliResult::NeedsMoreOutput => {}
+      BrotliResult::ResultSuccess => {
+        if available_in != 0 {
+          return Err(Error::new(ErrorKind::InvalidData,
+                                "brotli frame shorter than its recorded size"));
+        }
+        return Ok(());
+      }
+      BrotliResult::NeedsMoreInput => {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated brotli frame"));
+      }
+      BrotliResult::ResultFailure => return Err(Error::new(ErrorKind::InvalidData, "Invalid Data")),
+    }
+  }
+}
@@ -69,6 +69,13 @@ impl<AllocU32 : alloc::Allocator<u32>,
                            alloc_hc.alloc_cell(nt * BROTLI_HUFFMAN_MAX_TABLE_SIZE as usize));
     }
 
+    /// Whether `init` got all the memory the group's trees need.
+    pub fn is_allocated(&self) -> bool {
+        let nt = self.num_htrees as usize;
+        self.htrees.slice().len() >= nt &&
+          self.codes.slice().len() >= nt * BROTLI_HUFFMAN_MAX_TABLE_SIZE as usize
+    }
+
 //  pub fn get_tree_mut<'a>(self :&'a mut Self, index : u32, mut tree_out : &'a mut [HuffmanCode]) {
 //        let start : usize = fast!((self.htrees)[index as usize]) as usize;
 //        core::mem::replace(&mut tree_out, fast_mut!((self.codes.slice_mut())[start;]));
@@ -268,27 +275,86 @@ pub fn BrotliBuildCodeLengthsHuffmanTable(mut table: &mut [HuffmanCode],
   }
 }
 
-pub fn BrotliBuildHuffmanTable(mut root_table: &mut [HuffmanCode],
+// Yields the symbols of each code length in increasing order, as the table builder
+// consumes them.
+trait SymbolsByLength {
+  fn next_symbol(&mut self, len: i32) -> u16;
+}
+
+// The linked lists the decoder fills while reading code lengths: the head of the list for
+// length len sits at offset + len - 16 and each symbol's entry holds the next symbol.
+struct SymbolLists<'a> {
+  symbol_lists: &'a [u16],
+  offset: usize,
+  current: [i32; BROTLI_HUFFMAN_MAX_CODE_LENGTH + 1],
+}
+
+impl<'a> SymbolsByLength for SymbolLists<'a> {
+  fn next_symbol(&mut self, len: i32) -> u16 {
+    let current = &mut fast_mut!((self.current)[len as usize]);
+    *current =
+      fast!((self.symbol_lists)[(self.offset as isize + *current as isize) as usize]) as i32;
+    *current as u16
+  }
+}
+
+// Scans the code lengths for the next symbol of each length.
+struct CodeLengthScan<'a> {
+  code_lengths: &'a [u8],
+  next: [usize; BROTLI_HUFFMAN_MAX_CODE_LENGTH + 1],
+}
+
+impl<'a> SymbolsByLength for CodeLengthScan<'a> {
+  fn next_symbol(&mut self, len: i32) -> u16 {
+    let next = &mut self.next[len as usize];
+    while self.code_lengths[*next] as i32 != len {
+      *next += 1;
+    }
+    *next += 1;
+    (*next - 1) as u16
+  }
+}
+
+pub fn BrotliBuildHuffmanTable(root_table: &mut [HuffmanCode],
                                root_bits: i32,
                                symbol_lists: &[u16],
                                symbol_lists_offset: usize, /* need negative-index to symbol_lists */
                                count: &mut [u16])
                                -> u32 {
-  let mut code: HuffmanCode = HuffmanCode {
-    bits: 0,
-    value: 0,
-  };       /* current table entry */
   let mut max_length: i32 = -1;
-
-  assert!(root_bits as isize <= BROTLI_REVERSE_BITS_MAX as isize);
-  assert!(BROTLI_HUFFMAN_MAX_CODE_LENGTH as isize - root_bits as isize <=
-          BROTLI_REVERSE_BITS_MAX as isize);
-
   while fast!((symbol_lists)[((symbol_lists_offset as isize) + max_length as isize) as usize]) ==
         0xFFFF {
     max_length -= 1;
   }
   max_length += BROTLI_HUFFMAN_MAX_CODE_LENGTH as i32 + 1;
+  let mut current = [0i32; BROTLI_HUFFMAN_MAX_CODE_LENGTH + 1];
+  for (len, head) in current.iter_mut().enumerate() {
+    *head = len as i32 - (BROTLI_HUFFMAN_MAX_CODE_LENGTH as i32 + 1);
+  }
+  BuildHuffmanTable(root_table,
+                    root_bits,
+                    max_length,
+                    &mut SymbolLists {
+                      symbol_lists,
+                      offset: symbol_lists_offset,
+                      current,
+                    },
+                    count)
+}
+
+fn BuildHuffmanTable<Symbols: SymbolsByLength>(mut root_table: &mut [HuffmanCode],
+                                               root_bits: i32,
+                                               max_length: i32,
+                                               symbols: &mut Symbols,
+                                               count: &mut [u16])
+                                               -> u32 {
+  let mut code: HuffmanCode = HuffmanCode {
+    bits: 0,
+    value: 0,
+  };       /* current table entry */
+
+  assert!(root_bits as isize <= BROTLI_REVERSE_BITS_MAX as isize);
+  assert!(max_length as isize - root_bits as isize <= BROTLI_REVERSE_BITS_MAX as isize);
 
   let mut table_free_offset: u32 = 0;
   let mut table_bits: i32 = root_bits;      /* key length of current table */
@@ -308,12 +374,9 @@ pub fn BrotliBuildHuffmanTable(mut root_table: &mut [HuffmanCode],
   let mut step: i32 = 2; /* step size to replicate values in current table */
   loop {
     code.bits = bits as u8;
-    let mut symbol: i32 = bits - (BROTLI_HUFFMAN_MAX_CODE_LENGTH as i32 + 1);
     let mut bits_count: i32 = fast!((count)[bits as usize]) as i32;
     while bits_count != 0 {
-      symbol =
-        fast!((symbol_lists)[(symbol_lists_offset as isize + symbol as isize) as usize]) as i32;
-      code.value = symbol as u16;
+      code.value = symbols.next_symbol(bits);
       ReplicateValue(&mut root_table,
                      table_free_offset + BrotliReverseBits(key),
                      step,
@@ -350,7 +413,6 @@ pub fn BrotliBuildHuffmanTable(mut root_table: &mut [HuffmanCode],
 
   let mut len: i32 = root_bits + 1; /* current code length */
   while len <= max_length {
-    let mut symbol: i32 = len - (BROTLI_HUFFMAN_MAX_CODE_LENGTH as i32 + 1);
     while fast!((count)[len as usize]) != 0 {
       if sub_key == (BROTLI_REVERSE_BITS_LOWEST << 1u32) {
         table_free_offset += table_size as u32;
@@ -365,9 +427,7 @@ pub fn BrotliBuildHuffmanTable(mut root_table: &mut [HuffmanCode],
         sub_key = 0;
       }
       code.bits = (len - root_bits) as u8;
-      symbol =
-        fast!((symbol_lists)[(symbol_lists_offset as isize + symbol as isize) as usize]) as i32;
-      code.value = symbol as u16;
+      code.value = symbols.next_symbol(len);
       ReplicateValue(&mut root_table,
                      table_free_offset + BrotliReverseBits(sub_key),
                      step,
@@ -467,3 +527,221 @@ pub fn BrotliBuildSimpleHuffmanTable(table: &mut [HuffmanCode],
   }
   goal_size
 }
+
+/// Why `build_table` rejected a set of code lengths.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HuffmanTableError {
+  /// A code length exceeds `BROTLI_HUFFMAN_MAX_CODE_LENGTH`.
+  CodeLengthTooLong,
+  /// The code lengths leave some bit patterns without a symbol.
+  Incomplete,
+  /// The code lengths claim more bit patterns than exist.
+  Oversubscribed,
+  /// There are more than `u16::MAX` symbols, or more table entries than 16-bit values
+  /// can address.
+  AlphabetTooLarge,
+  /// `root_bits` is 0, above 8, or more than 8 below the longest code length.
+  InvalidRootBits,
+  /// The table passed to `build_table` is shorter than `table_size` requires.
+  TableTooSmall,
+  /// A root entry of the table passed to `HuffmanTable::new` points to a second level
+  /// table past its end, or claims a code longer than `BROTLI_HUFFMAN_MAX_CODE_LENGTH`.
+  InvalidTable,
+}
+
+// The histogram of code lengths and the longest length, once the lengths have been
+// checked to describe a complete prefix code. None stands for a code with a single
+// symbol, which takes no bits.
+fn CheckCodeLengths(code_lengths: &[u8], root_bits: u32)
+                    -> Result<Option<([u16; BROTLI_HUFFMAN_MAX_CODE_LENGTH + 1], i32)>, HuffmanTableError> {
+  // symbols are u16 values, and so are the counts of each length
+  if code_lengths.len() > u16::MAX as usize {
+    return Err(HuffmanTableError::AlphabetTooLarge);
+  }
+  if root_bits == 0 || root_bits > BROTLI_REVERSE_BITS_MAX as u32 {
+    return Err(HuffmanTableError::InvalidRootBits);
+  }
+  let mut count = [0u16; BROTLI_HUFFMAN_MAX_CODE_LENGTH + 1];
+  let mut space: i64 = 1 << BROTLI_HUFFMAN_MAX_CODE_LENGTH;
+  let mut max_length: i32 = 0;
+  for code_length in code_lengths.iter() {
+    let len = *code_length as usize;
+    if len > BROTLI_HUFFMAN_MAX_CODE_LENGTH {
+      return Err(HuffmanTableError::CodeLengthTooLong);
+    }
+    if len != 0 {
+      count[len] += 1;
+      space -= 1 << (BROTLI_HUFFMAN_MAX_CODE_LENGTH - len);
+      max_length = core::cmp::max(max_length, len as i32);
+    }
+  }
+  if code_lengths.iter().filter(|len| **len != 0).count() == 1 {
+    return Ok(None);
+  }
+  if space > 0 {
+    return Err(HuffmanTableError::Incomplete);
+  }
+  if space < 0 {
+    return Err(HuffmanTableError::Oversubscribed);
+  }
+  if max_length - root_bits as i32 > BROTLI_REVERSE_BITS_MAX as i32 {
+    return Err(HuffmanTableError::InvalidRootBits);
+  }
+  Ok(Some((count, max_length)))
+}
+
+/// Number of entries `build_table` needs for a prefix code with the given code lengths
+/// and a root table indexed by `root_bits` bits.
+pub fn table_size(code_lengths: &[u8], root_bits: u32) -> Result<usize, HuffmanTableError> {
+  let (mut count, max_length) = match CheckCodeLengths(code_lengths, root_bits)? {
+    Some(histogram) => histogram,
+    None => return Ok(1 << root_bits),
+  };
+  // walk the second level tables the way BuildHuffmanTable allocates them
+  let root_bits = root_bits as i32;
+  let mut total_size: usize = 1 << root_bits;
+  let mut table_bits: i32 = 0;
+  let mut left: usize = 0;
+  for len in root_bits + 1..max_length + 1 {
+    while count[len as usize] != 0 {
+      if left == 0 {
+        table_bits = NextTableBitSize(&count, len, root_bits);
+        total_size += 1 << table_bits;
+        left = 1 << table_bits;
+      }
+      left -= 1 << (table_bits - (len - root_bits));
+      count[len as usize] -= 1;
+    }
+  }
+  if total_size > 1 << 16 {
+    return Err(HuffmanTableError::AlphabetTooLarge);
+  }
+  Ok(total_size)
+}
+
+/// Builds the two-level lookup table of the canonical prefix code (RFC 7932 section 3.2)
+/// with the given code length per symbol, 0 for symbols without a code. Codes are read
+/// least significant bit first; the root table is indexed by the next `root_bits` bits
+/// and longer codes continue in second level tables placed after it. A single symbol
+/// with a code gets a code of no bits, as in a simple prefix code with one symbol.
+/// Returns the number of entries of `table` used, which `table_size` gives in advance.
+pub fn build_table(code_lengths: &[u8],
+                   root_bits: u32,
+                   table: &mut [HuffmanCode])
+                   -> Result<usize, HuffmanTableError> {
+  let size = table_size(code_lengths, root_bits)?;
+  if table.len() < size {
+    return Err(HuffmanTableError::TableTooSmall);
+  }
+  let mut count = match CheckCodeLengths(code_lengths, root_bits)? {
+    Some((count, max_length)) => (count, max_length),
+    None => {
+      let symbol = code_lengths.iter().position(|len| *len != 0).unwrap() as u16;
+      BrotliBuildSimpleHuffmanTable(table, root_bits as i32, &[symbol], 0);
+      return Ok(size);
+    }
+  };
+  let mut symbols = CodeLengthScan {
+    code_lengths,
+    next: [0; BROTLI_HUFFMAN_MAX_CODE_LENGTH + 1],
+  };
+  Ok(BuildHuffmanTable(table, root_bits as i32, count.1, &mut symbols, &mut count.0[..]) as usize)
+}
+
+/// Supplies bits to `HuffmanTable::read_symbol`, least significant bit first.
+pub trait BitSource {
+  /// Returns the next `n_bits` bits, at most 16, without consuming them. Bits past the
+  /// end of the input read as zero.
+  fn peek_bits(&mut self, n_bits: u32) -> u32;
+  /// Consumes `n_bits` bits, returning false if fewer are left.
+  fn skip_bits(&mut self, n_bits: u32) -> bool;
+}
+
+/// One entry of a `HuffmanTable`, as returned by `HuffmanTable::entry`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableEntry {
+  /// The bits select `symbol`, whose code is `length` bits long.
+  Symbol { symbol: u16, length: u32 },
+  /// The code continues in the second level table at `offset`, indexed by `bits` more bits.
+  SubTable { offset: usize, bits: u32 },
+}
+
+/// A lookup table made by `build_table`.
+#[derive(Clone, Copy, Debug)]
+pub struct HuffmanTable<'a> {
+  codes: &'a [HuffmanCode],
+  root_bits: u32,
+}
+
+impl<'a> HuffmanTable<'a> {
+  /// Wraps the entries `build_table` filled in, with the same `root_bits`. Fails if
+  /// `codes` is shorter than the root table or a root entry leads outside of `codes`, so
+  /// that `lookup` stays within the table.
+  pub fn new(codes: &'a [HuffmanCode], root_bits: u32) -> Result<Self, HuffmanTableError> {
+    if root_bits == 0 || root_bits > BROTLI_REVERSE_BITS_MAX as u32 {
+      return Err(HuffmanTableError::InvalidRootBits);
+    }
+    if codes.len() < 1 << root_bits {
+      return Err(HuffmanTableError::TableTooSmall);
+    }
+    for (index, code) in codes[..1 << root_bits].iter().enumerate() {
+      let bits = code.bits as u32;
+      if bits > BROTLI_HUFFMAN_MAX_CODE_LENGTH as u32 {
+        return Err(HuffmanTableError::InvalidTable);
+      }
+      if bits > root_bits && index + code.value as usize + (1 << (bits - root_bits)) > codes.len() {
+        return Err(HuffmanTableError::InvalidTable);
+      }
+    }
+    Ok(HuffmanTable { codes, root_bits })
+  }
+
+  pub fn root_bits(&self) -> u32 {
+    self.root_bits
+  }
+
+  pub fn codes(&self) -> &'a [HuffmanCode] {
+    self.codes
+  }
+
+  /// Describes entry `index`; entries past the root table belong to second level tables.
+  /// Panics if `index` is past the end of the table.
+  pub fn entry(&self, index: usize) -> TableEntry {
+    let code = self.codes[index];
+    if index < 1 << self.root_bits {
+      if code.bits as u32 > self.root_bits {
+        return TableEntry::SubTable {
+          offset: index + code.value as usize,
+          bits: code.bits as u32 - self.root_bits,
+        };
+      }
+      return TableEntry::Symbol { symbol: code.value, length: code.bits as u32 };
+    }
+    TableEntry::Symbol { symbol: code.value, length: code.bits as u32 + self.root_bits }
+  }
+
+  /// Decodes the symbol whose code starts `bits`, the next 15 or more bits of input, least
+  /// significant first. Returns the symbol and the length of its code.
+  pub fn lookup(&self, bits: u32) -> (u16, u32) {
+    let mut index = (bits & ((1 << self.root_bits) - 1)) as usize;
+    let mut code = self.codes[index];
+    let mut length = 0;
+    if code.bits as u32 > self.root_bits {
+      let sub_bits = code.bits as u32 - self.root_bits;
+      index += code.value as usize + ((bits >> self.root_bits) & ((1 << sub_bits) - 1)) as usize;
+      code = self.codes[index];
+      length = self.root_bits;
+    }
+    (code.value, length + code.bits as u32)
+  }
+
+  /// Reads one symbol from `source`, or returns None if the input ends inside its code.
+  pub fn read_symbol<Source: BitSource>(&self, source: &mut Source) -> Option<u16> {
+    let (symbol, length) = self.lookup(source.peek_bits(BROTLI_HUFFMAN_MAX_CODE_LENGTH as u32));
+    if source.skip_bits(length) {
+      Some(symbol)
+    } else {
+      None
+    }
+  }
+}
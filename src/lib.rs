@@ -31,7 +31,7 @@ pub mod dictionary;
 mod brotli_alloc;
 #[macro_use]
 mod bit_reader;
-mod huffman;
+pub mod huffman;
 mod state;
 mod prefix;
 mod context;
@@ -41,8 +41,37 @@ mod decode;
 pub mod io_wrappers;
 pub mod reader;
 pub mod writer;
+pub mod threaded;
+pub mod pipeline;
+pub mod seekable;
+mod frame;
+#[cfg(feature="parallel")]
+#[clippy::msrv = "1.63"] // the parallel feature needs scoped threads
+pub mod parallel;
+pub mod oneshot;
+pub mod options;
+pub mod stats;
+pub mod provenance;
+pub mod sniff;
+pub mod dictionary_store;
+pub mod arena;
+pub mod accounting;
+pub mod fault_injection;
+pub mod budget;
+#[cfg(feature="unsafe")]
+pub mod static_decompressor;
 pub use huffman::{HuffmanCode, HuffmanTreeGroup};
-pub use state::BrotliState;
+pub use bit_reader::{BitReader, BitReaderCheckpoint};
+pub use state::{BrotliState, DecodeCaps, WorkBudget, kInputPerOutputAllowance};
+pub use stats::DecodeStats;
+pub use options::DecompressorOptions;
+pub use arena::{ArenaAllocator, ArenaMemory, DecoderArena, MemoryRequirement, memory_requirement};
+pub use accounting::{AllocCounter, AllocStats, CountingAllocator};
+pub use fault_injection::{FailingAllocator, FaultInjector};
+pub use budget::{BudgetedAllocator, MemoryBudget};
+#[cfg(feature="unsafe")]
+pub use static_decompressor::StaticDecompressor;
+pub use sniff::{HeaderConfidence, HeaderInfo, sniff_header};
 pub mod ffi;
 pub use reader::{DecompressorCustomIo};
 
@@ -52,6 +81,20 @@ pub use reader::{Decompressor};
 pub use writer::{DecompressorWriterCustomIo};
 #[cfg(feature="std")]
 pub use writer::{DecompressorWriter};
+#[cfg(feature="std")]
+pub use threaded::ThreadedDecompressor;
+#[cfg(feature="std")]
+pub use pipeline::PipelinedDecompressor;
+#[cfg(feature="std")]
+pub use seekable::{SeekableDecompressor, SeekTable, SeekTableEntry};
+#[cfg(feature="parallel")]
+pub use parallel::ParallelDecompressor;
+#[cfg(feature="std")]
+pub use dictionary_store::{DictionaryDecompressor, DictionaryEntry, DictionaryStore};
+pub use oneshot::{DecompressError, DecompressLimits, decompress_into_custom_alloc, decompress_to_vec_custom_alloc};
+#[cfg(feature="std")]
+pub use oneshot::{decompress_into, decompress_into_with_options, decompress_to_vec,
+                  decompress_to_vec_with_options};
 
 // use io_wrappers::write_all;
 pub use io_wrappers::{CustomRead, CustomWrite};
@@ -69,6 +112,12 @@ pub use io_wrappers::{IntoIoReader, IoReaderWrapper, IntoIoWriter, IoWriterWrapp
 //                               mut s: &mut BrotliState<AllocU8, AllocU32, AllocHC>);
 
 pub use decode::{BrotliDecompressStream, BrotliResult, BrotliDecoderHasMoreOutput};
+pub use decode::BrotliDecompressStreamToSink;
+pub use pipeline::{CommandSink, NopCommandSink};
+pub use decode::BrotliDecompressStreamWithProvenance;
+pub use decode::BrotliDecompressStreamWithDictionary;
+pub use decode::{BrotliDecompressStreamWithBudget, BudgetedResult};
+pub use provenance::{Provenance, ProvenanceRecorder, ProvenanceSink, ProvenanceSpan};
 
 
 
@@ -0,0 +1,320 @@
+#[cfg(feature="std")]
+use std::{error, fmt, io};
+#[cfg(feature="std")]
+use std::vec::Vec;
+#[cfg(feature="std")]
+use alloc_stdlib::StandardAlloc;
+use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
+use decode::{BrotliDecompressStreamWithDictionary, BrotliResult};
+use huffman::HuffmanCode;
+use options::DecompressorOptions;
+use state::{BrotliDecoderErrorCode, BrotliState};
+
+// first guess at the output size of decompress_to_vec, as a multiple of the input size
+const INITIAL_EXPANSION: usize = 4;
+const MIN_INITIAL_CAPACITY: usize = 4096;
+
+/// Why a one-shot decompression did not produce a complete brotli stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecompressError {
+  /// The input ended before the brotli stream did.
+  Truncated,
+  /// The output buffer passed to `decompress_into` is too small for the stream.
+  OutputTooSmall,
+  /// The output would grow past `DecompressLimits::max_output_size`.
+  LimitExceeded,
+  /// The brotli stream ends before the input does, at the given offset.
+  TrailingData(usize),
+  /// The output buffer could not be allocated.
+  OutOfMemory,
+  /// The decoder rejected the stream or could not allocate its own tables.
+  Decoder(BrotliDecoderErrorCode),
+}
+
+/// Bounds on the output of `decompress_to_vec`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecompressLimits {
+  /// Largest decompressed size accepted, in bytes.
+  pub max_output_size: usize,
+  /// Size of the first output allocation, or 0 to guess from the input size.
+  pub initial_capacity: usize,
+}
+
+impl Default for DecompressLimits {
+  fn default() -> Self {
+    DecompressLimits {
+      max_output_size: usize::MAX,
+      initial_capacity: 0,
+    }
+  }
+}
+
+impl DecompressLimits {
+  pub fn new() -> Self {
+    DecompressLimits::default()
+  }
+}
+
+fn finish<AllocU8: Allocator<u8>, AllocU32: Allocator<u32>, AllocHC: Allocator<HuffmanCode>>
+  (result: BrotliResult,
+   input: &[u8],
+   input_offset: usize,
+   strict: bool,
+   s: &BrotliState<AllocU8, AllocU32, AllocHC>)
+   -> Result<(), DecompressError> {
+  match result {
+    BrotliResult::ResultSuccess => {
+      if strict && input_offset != input.len() {
+        return Err(DecompressError::TrailingData(input_offset));
+      }
+      Ok(())
+    }
+    BrotliResult::NeedsMoreInput => Err(DecompressError::Truncated),
+    BrotliResult::NeedsMoreOutput => Err(DecompressError::OutputTooSmall),
+    BrotliResult::ResultFailure => Err(DecompressError::Decoder(s.error_code)),
+  }
+}
+
+/// Decompresses the brotli stream that makes up all of `input` into `output` and returns
+/// the decompressed size. When `output` can hold the whole window, it doubles as the
+/// decoder's history and no ringbuffer is allocated.
+///
+/// Input after the end of the stream is rejected with `TrailingData`, as it is by
+/// `decompress_into_with_options` unless `strict` is cleared.
+pub fn decompress_into_custom_alloc<AllocU8: Allocator<u8>,
+                                    AllocU32: Allocator<u32>,
+                                    AllocHC: Allocator<HuffmanCode>>
+  (input: &[u8],
+   output: &mut [u8],
+   alloc_u8: AllocU8,
+   alloc_u32: AllocU32,
+   alloc_hc: AllocHC)
+   -> Result<usize, DecompressError> {
+  let mut s = BrotliState::new(alloc_u8, alloc_u32, alloc_hc);
+  decompress_into_state(&mut s, input, output, &[], &DecompressLimits::default(), true)
+}
+
+fn decompress_into_state<AllocU8: Allocator<u8>,
+                         AllocU32: Allocator<u32>,
+                         AllocHC: Allocator<HuffmanCode>>
+  (s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
+   input: &[u8],
+   output: &mut [u8],
+   dict: &[u8],
+   limits: &DecompressLimits,
+   strict: bool)
+   -> Result<usize, DecompressError> {
+  let limited = limits.max_output_size < output.len();
+  let output = if limited {
+    &mut output[..limits.max_output_size]
+  } else {
+    output
+  };
+  let mut available_in = input.len();
+  let mut input_offset: usize = 0;
+  let mut available_out = output.len();
+  let mut output_offset: usize = 0;
+  let mut total_out: usize = 0;
+  let result = BrotliDecompressStreamWithDictionary(&mut available_in,
+                                                    &mut input_offset,
+                                                    input,
+                                                    &mut available_out,
+                                                    &mut output_offset,
+                                                    output,
+                                                    &mut total_out,
+                                                    s,
+                                                    dict);
+  if let BrotliResult::NeedsMoreOutput = result {
+    if limited {
+      return Err(DecompressError::LimitExceeded);
+    }
+  }
+  finish(result, input, input_offset, strict, s)?;
+  Ok(output_offset)
+}
+
+/// Decompresses the brotli stream that makes up all of `input` into memory from
+/// `alloc_u8`, growing it as needed within `limits`. Returns the memory and the number
+/// of decompressed bytes at its start. Input after the end of the stream is rejected, as
+/// by `decompress_into_custom_alloc`.
+pub fn decompress_to_vec_custom_alloc<AllocU8: Allocator<u8>,
+                                      AllocU32: Allocator<u32>,
+                                      AllocHC: Allocator<HuffmanCode>>
+  (input: &[u8],
+   limits: &DecompressLimits,
+   alloc_u8: AllocU8,
+   alloc_u32: AllocU32,
+   alloc_hc: AllocHC)
+   -> Result<(AllocU8::AllocatedMemory, usize), DecompressError> {
+  let mut s = BrotliState::new(alloc_u8, alloc_u32, alloc_hc);
+  decompress_to_vec_state(&mut s, input, &[], limits, true)
+}
+
+fn decompress_to_vec_state<AllocU8: Allocator<u8>,
+                           AllocU32: Allocator<u32>,
+                           AllocHC: Allocator<HuffmanCode>>
+  (s: &mut BrotliState<AllocU8, AllocU32, AllocHC>,
+   input: &[u8],
+   dict: &[u8],
+   limits: &DecompressLimits,
+   strict: bool)
+   -> Result<(AllocU8::AllocatedMemory, usize), DecompressError> {
+  let mut capacity = if limits.initial_capacity != 0 {
+    limits.initial_capacity
+  } else {
+    core::cmp::max(input.len().saturating_mul(INITIAL_EXPANSION), MIN_INITIAL_CAPACITY)
+  };
+  capacity = core::cmp::min(capacity, limits.max_output_size);
+  let mut output = s.alloc_u8.alloc_cell(capacity);
+  if output.slice().len() != capacity {
+    return Err(DecompressError::OutOfMemory);
+  }
+  let mut available_in = input.len();
+  let mut input_offset: usize = 0;
+  let mut output_offset: usize = 0;
+  let mut total_out: usize = 0;
+  loop {
+    let mut available_out = capacity - output_offset;
+    let result = BrotliDecompressStreamWithDictionary(&mut available_in,
+                                                      &mut input_offset,
+                                                      input,
+                                                      &mut available_out,
+                                                      &mut output_offset,
+                                                      output.slice_mut(),
+                                                      &mut total_out,
+                                                      s,
+                                                      dict);
+    if let BrotliResult::NeedsMoreOutput = result {
+      if capacity == limits.max_output_size {
+        s.alloc_u8.free_cell(output);
+        return Err(DecompressError::LimitExceeded);
+      }
+      // doubling keeps the copies linear in the output size
+      capacity = core::cmp::min(capacity.saturating_mul(2), limits.max_output_size);
+      let mut grown = s.alloc_u8.alloc_cell(capacity);
+      if grown.slice().len() != capacity {
+        s.alloc_u8.free_cell(output);
+        return Err(DecompressError::OutOfMemory);
+      }
+      grown.slice_mut()[..output_offset].clone_from_slice(&output.slice()[..output_offset]);
+      s.alloc_u8.free_cell(core::mem::replace(&mut output, grown));
+      continue;
+    }
+    if let Err(e) = finish(result, input, input_offset, strict, s) {
+      s.alloc_u8.free_cell(output);
+      return Err(e);
+    }
+    return Ok((output, output_offset));
+  }
+}
+
+/// Decompresses the brotli stream that makes up all of `input` into `output` and returns
+/// the decompressed size. Input after the end of the stream is rejected with
+/// `TrailingData`.
+#[cfg(feature="std")]
+pub fn decompress_into(input: &[u8], output: &mut [u8]) -> Result<usize, DecompressError> {
+  decompress_into_custom_alloc(input,
+                               output,
+                               StandardAlloc::default(),
+                               StandardAlloc::default(),
+                               StandardAlloc::default())
+}
+
+/// Decompresses the brotli stream that makes up all of `input` into a new `Vec`. Input
+/// after the end of the stream is rejected with `TrailingData`.
+///
+/// The output starts at `limits.initial_capacity`, or a few times the input size, and
+/// doubles when full, so a stream costs one allocation per doubling at most.
+#[cfg(feature="std")]
+pub fn decompress_to_vec(input: &[u8], limits: &DecompressLimits) -> Result<Vec<u8>, DecompressError> {
+  let (output, len) = decompress_to_vec_custom_alloc(input,
+                                                     limits,
+                                                     StandardAlloc::default(),
+                                                     StandardAlloc::default(),
+                                                     StandardAlloc::default())?;
+  into_vec(output, len)
+}
+
+#[cfg(feature="std")]
+fn into_vec(output: <StandardAlloc as Allocator<u8>>::AllocatedMemory, len: usize) -> Result<Vec<u8>, DecompressError> {
+  let boxed: std::boxed::Box<[u8]> = output.into();
+  let mut output = boxed.into_vec();
+  output.truncate(len);
+  Ok(output)
+}
+
+// The state lives only as long as the call, so the custom dictionary is read where it is
+// rather than copied into the state.
+#[cfg(feature="std")]
+fn borrow_dictionary<'a>(options: &DecompressorOptions<'a>) -> (DecompressorOptions<'a>, &'a [u8]) {
+  let mut state_options = *options;
+  state_options.custom_dictionary = None;
+  let dict = match options.shared_dictionary {
+    Some(_) => &[],
+    None => options.custom_dictionary.unwrap_or(&[]),
+  };
+  (state_options, dict)
+}
+
+/// Like `decompress_into`, set up by `options`. Input after the end of the stream is an
+/// error unless `options.strict` is cleared.
+#[cfg(feature="std")]
+pub fn decompress_into_with_options(input: &[u8],
+                                    output: &mut [u8],
+                                    options: &DecompressorOptions)
+                                    -> Result<usize, DecompressError> {
+  let (state_options, dict) = borrow_dictionary(options);
+  let mut s = BrotliState::new_with_options(StandardAlloc::default(),
+                                            StandardAlloc::default(),
+                                            StandardAlloc::default(),
+                                            &state_options);
+  decompress_into_state(&mut s, input, output, dict, &options.limits, options.strict)
+}
+
+/// Like `decompress_to_vec`, set up by `options`, which also supply the limits. Input after
+/// the end of the stream is an error unless `options.strict` is cleared, as for
+/// `decompress_into_with_options`.
+#[cfg(feature="std")]
+pub fn decompress_to_vec_with_options(input: &[u8],
+                                      options: &DecompressorOptions)
+                                      -> Result<Vec<u8>, DecompressError> {
+  let (state_options, dict) = borrow_dictionary(options);
+  let mut s = BrotliState::new_with_options(StandardAlloc::default(),
+                                            StandardAlloc::default(),
+                                            StandardAlloc::default(),
+                                            &state_options);
+  let (output, len) = decompress_to_vec_state(&mut s, input, dict, &options.limits, options.strict)?;
+  into_vec(output, len)
+}
+
+#[cfg(feature="std")]
+impl fmt::Display for DecompressError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      DecompressError::Truncated => write!(f, "brotli stream is truncated"),
+      DecompressError::OutputTooSmall => write!(f, "output buffer too small for the brotli stream"),
+      DecompressError::LimitExceeded => write!(f, "decompressed size exceeds the limit"),
+      DecompressError::TrailingData(offset) => {
+        write!(f, "unexpected data after the brotli stream at offset {}", offset)
+      }
+      DecompressError::OutOfMemory => write!(f, "could not allocate the output buffer"),
+      DecompressError::Decoder(code) => write!(f, "invalid brotli stream ({:?})", code),
+    }
+  }
+}
+
+#[cfg(feature="std")]
+impl error::Error for DecompressError {}
+
+#[cfg(feature="std")]
+impl From<DecompressError> for io::Error {
+  fn from(e: DecompressError) -> io::Error {
+    let kind = match e {
+      DecompressError::Truncated => io::ErrorKind::UnexpectedEof,
+      DecompressError::OutputTooSmall => io::ErrorKind::WriteZero,
+      DecompressError::OutOfMemory => io::ErrorKind::OutOfMemory,
+      _ => io::ErrorKind::InvalidData,
+    };
+    io::Error::new(kind, e)
+  }
+}
@@ -0,0 +1,100 @@
+use dictionary::SharedDictionary;
+use oneshot::DecompressLimits;
+use state::DecodeCaps;
+
+/// How a decoder is set up, in one place for `BrotliState::new_with_options`, the reader
+/// and writer wrappers, the one-shot functions and the FFI.
+///
+/// Every setting starts from the default of the plain constructors, so
+/// `DecompressorOptions::new().custom_dictionary(dict)` decodes exactly like
+/// `new_with_custom_dictionary`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecompressorOptions<'a> {
+  /// Accept streams that signal a large window. Off is what `BrotliState::new_strict`
+  /// does.
+  pub large_window: bool,
+  /// Dictionary the stream was compressed against. The state based wrappers copy it into
+  /// memory from the decoder's allocator; the one-shot functions read it in place, as
+  /// do the wrappers built with `with_dictionary`.
+  pub custom_dictionary: Option<&'a [u8]>,
+  /// Dictionary the stream was compressed against, shared with other decoders rather
+  /// than copied. Takes the place of `custom_dictionary`.
+  pub shared_dictionary: Option<&'a SharedDictionary>,
+  /// Raw dictionaries to attach, in the order the encoder attached them; see
+  /// `BrotliState::attach_dictionary`. Each is copied like `custom_dictionary`. More
+  /// than `kMaxCompoundDictionaries`, or one that cannot be copied, is an error: every
+  /// stream fails with `BROTLI_DECODER_ERROR_COMPOUND_DICTIONARY`.
+  pub compound_dictionaries: &'a [&'a [u8]],
+  /// Size of the buffers that `Decompressor` and `DecompressorWriter` allocate for
+  /// themselves. The other wrappers are handed their buffer.
+  pub buffer_size: usize,
+  /// Bounds on the output. `max_output_size` applies to every wrapper;
+  /// `initial_capacity` to the functions that return a `Vec`.
+  pub limits: DecompressLimits,
+  /// Bounds on the work a stream can cause; see `BrotliState::set_caps`.
+  pub caps: DecodeCaps,
+  /// Treat input that follows the end of the stream as an error rather than leaving it
+  /// unread. On by default, as for the one-shot functions without options; the streaming
+  /// wrappers set up without options leave trailing input unread.
+  pub strict: bool,
+}
+
+impl<'a> Default for DecompressorOptions<'a> {
+  fn default() -> Self {
+    DecompressorOptions {
+      large_window: true,
+      custom_dictionary: None,
+      shared_dictionary: None,
+      compound_dictionaries: &[],
+      buffer_size: 4096,
+      limits: DecompressLimits::default(),
+      caps: DecodeCaps::default(),
+      strict: true,
+    }
+  }
+}
+
+impl<'a> DecompressorOptions<'a> {
+  pub fn new() -> Self {
+    DecompressorOptions::default()
+  }
+  pub fn large_window(mut self, large_window: bool) -> Self {
+    self.large_window = large_window;
+    self
+  }
+  pub fn custom_dictionary(mut self, dict: &'a [u8]) -> Self {
+    self.custom_dictionary = Some(dict);
+    self
+  }
+  pub fn shared_dictionary(mut self, dict: &'a SharedDictionary) -> Self {
+    self.shared_dictionary = Some(dict);
+    self
+  }
+  pub fn compound_dictionaries(mut self, dicts: &'a [&'a [u8]]) -> Self {
+    self.compound_dictionaries = dicts;
+    self
+  }
+  /// A size of 0 keeps the default.
+  pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+    if buffer_size != 0 {
+      self.buffer_size = buffer_size;
+    }
+    self
+  }
+  pub fn limits(mut self, limits: DecompressLimits) -> Self {
+    self.limits = limits;
+    self
+  }
+  pub fn max_output_size(mut self, max_output_size: usize) -> Self {
+    self.limits.max_output_size = max_output_size;
+    self
+  }
+  pub fn caps(mut self, caps: DecodeCaps) -> Self {
+    self.caps = caps;
+    self
+  }
+  pub fn strict(mut self, strict: bool) -> Self {
+    self.strict = strict;
+    self
+  }
+}
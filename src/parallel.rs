@@ -0,0 +1,195 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, sync_channel};
+use std::thread;
+use std::vec::Vec;
+use frame::{decode_frame, new_state};
+use seekable::{SeekTable, SeekTableEntry};
+
+// A frame handed to a worker: its position in the output, its compressed bytes and,
+// when a seek table gives it, the decompressed size it must have.
+struct Job<'a> {
+  index: usize,
+  input: Cow<'a, [u8]>,
+  decompressed_size: Option<u32>,
+}
+
+type JobResult = thread::Result<io::Result<Vec<u8>>>;
+
+/// Decompresses files made of independent brotli streams (frames) on several threads.
+///
+/// Frames are decoded concurrently, each worker reusing one `BrotliState` for all the
+/// frames it handles, and the output is written in frame order. At most two frames per
+/// thread are in flight, so memory use is bounded by the largest frames rather than
+/// the whole file. The first error in frame order is returned after the output of all
+/// earlier frames has been written, and a panic on a worker is resumed on the caller.
+pub struct ParallelDecompressor {
+  num_threads: usize,
+}
+
+impl ParallelDecompressor {
+  /// Uses `num_threads` worker threads, or one per available core when 0.
+  pub fn new(num_threads: usize) -> Self {
+    let num_threads = if num_threads == 0 {
+      thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    } else {
+      num_threads
+    };
+    ParallelDecompressor { num_threads }
+  }
+
+  pub fn num_threads(&self) -> usize {
+    self.num_threads
+  }
+
+  /// Decompresses `input`, a concatenation of brotli streams whose compressed sizes
+  /// are `frame_sizes`, and returns the number of bytes written.
+  pub fn decompress_frames<W: Write>(&self,
+                                     input: &[u8],
+                                     frame_sizes: &[usize],
+                                     w: &mut W) -> io::Result<u64> {
+    if frame_sizes.iter().try_fold(0usize, |sum, size| sum.checked_add(*size)) != Some(input.len()) {
+      return Err(Error::new(ErrorKind::InvalidInput, "frame sizes do not add up to the input length"));
+    }
+    let mut offset: usize = 0;
+    let jobs = frame_sizes.iter().enumerate().map(|(index, size)| {
+      let frame = &input[offset..offset + *size];
+      offset += *size;
+      Ok(Job {
+        index,
+        input: Cow::Borrowed(frame),
+        decompressed_size: None,
+      })
+    });
+    self.run(jobs, w)
+  }
+
+  /// Decompresses an in-memory seekable brotli file, using its seek table to find the
+  /// frames.
+  pub fn decompress_seekable<W: Write>(&self, input: &[u8], w: &mut W) -> io::Result<u64> {
+    let table = SeekTable::read_from(&mut Cursor::new(input))?;
+    let jobs = table.entries().iter().enumerate().map(|(index, entry)| {
+      let start = entry.compressed_offset as usize;
+      Ok(Job {
+        index,
+        input: Cow::Borrowed(&input[start..start + entry.compressed_size as usize]),
+        decompressed_size: Some(entry.decompressed_size),
+      })
+    });
+    self.run(jobs, w)
+  }
+
+  /// Decompresses a seekable brotli file, reading each frame on the calling thread as
+  /// the workers become ready for it.
+  pub fn decompress_seekable_file<R: Read + Seek, W: Write>(&self,
+                                                            r: &mut R,
+                                                            w: &mut W) -> io::Result<u64> {
+    let table = SeekTable::read_from(r)?;
+    let jobs = table.entries().iter().enumerate().map(|(index, entry)| {
+      read_frame(r, entry).map(|frame| Job {
+        index,
+        input: Cow::Owned(frame),
+        decompressed_size: Some(entry.decompressed_size),
+      })
+    });
+    self.run(jobs, w)
+  }
+
+  fn run<'a, I: Iterator<Item = io::Result<Job<'a>>>, W: Write>(&self,
+                                                                mut jobs: I,
+                                                                w: &mut W) -> io::Result<u64> {
+    let max_in_flight = 2 * self.num_threads;
+    let (job_send, job_recv) = sync_channel::<Job<'a>>(self.num_threads);
+    let job_recv = &Mutex::new(job_recv);
+    let (done_send, done_recv) = channel::<(usize, JobResult)>();
+    // moved into the scope so an early return drops job_send, letting the workers exit
+    thread::scope(move |scope| {
+      for _ in 0..self.num_threads {
+        let done_send = done_send.clone();
+        scope.spawn(move || {
+          let mut state = new_state();
+          loop {
+            let job = match job_recv.lock().unwrap().recv() {
+              Ok(job) => job,
+              Err(_) => return,
+            };
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+              let mut output = Vec::new();
+              decode_frame(&mut state,
+                           &job.input[..],
+                           &mut output,
+                           job.decompressed_size.map(|size| size as usize))?;
+              match job.decompressed_size {
+                Some(size) if size as usize != output.len() => {
+                  Err(Error::new(ErrorKind::InvalidData,
+                                 "brotli frame size does not match its seek table entry"))
+                }
+                _ => Ok(output),
+              }
+            }));
+            if result.is_err() {
+              state = new_state();
+            }
+            if done_send.send((job.index, result)).is_err() {
+              return;
+            }
+          }
+        });
+      }
+      drop(done_send);
+
+      let mut done = BTreeMap::<usize, JobResult>::new();
+      let mut next_job: usize = 0;
+      let mut next_write: usize = 0;
+      let mut input_err: Option<Error> = None;
+      let mut jobs_done = false;
+      let mut total_out: u64 = 0;
+      loop {
+        while !jobs_done && next_job - next_write < max_in_flight {
+          match jobs.next() {
+            Some(Ok(job)) => {
+              job_send.send(job).unwrap();
+              next_job += 1;
+            }
+            Some(Err(e)) => {
+              input_err = Some(e);
+              jobs_done = true;
+            }
+            None => jobs_done = true,
+          }
+        }
+        if next_write == next_job {
+          break;
+        }
+        // workers only exit once job_send is dropped, so a result is always coming
+        let (index, result) = done_recv.recv().unwrap();
+        done.insert(index, result);
+        while let Some(result) = done.remove(&next_write) {
+          match result {
+            Ok(Ok(output)) => {
+              w.write_all(&output[..])?;
+              total_out += output.len() as u64;
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(payload) => panic::resume_unwind(payload),
+          }
+          next_write += 1;
+        }
+      }
+      match input_err {
+        Some(e) => Err(e),
+        None => Ok(total_out),
+      }
+    })
+  }
+}
+
+fn read_frame<R: Read + Seek>(r: &mut R, entry: &SeekTableEntry) -> io::Result<Vec<u8>> {
+  let mut frame = vec![0u8; entry.compressed_size as usize];
+  r.seek(SeekFrom::Start(entry.compressed_offset))?;
+  r.read_exact(&mut frame[..])?;
+  Ok(frame)
+}
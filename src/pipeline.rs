@@ -0,0 +1,438 @@
+// Receives the decoded command stream from BrotliDecompressStreamToSink.
+//
+// The decoder describes its output as a sequence of literal runs and LZ77 copies in
+// output order. Literal runs already include static dictionary words and uncompressed
+// metablocks, so a sink only has to append literals and replay copies against its own
+// window to reconstruct the output byte for byte.
+
+use provenance::ProvenanceSpan;
+#[cfg(feature="std")]
+use std::io::{self, Error, ErrorKind, Read};
+#[cfg(feature="std")]
+use std::panic;
+#[cfg(feature="std")]
+use std::sync::Arc;
+#[cfg(feature="std")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature="std")]
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TryRecvError};
+#[cfg(feature="std")]
+use std::thread::{self, JoinHandle};
+#[cfg(feature="std")]
+use std::vec::Vec;
+#[cfg(feature="std")]
+use alloc::Allocator;
+#[cfg(feature="std")]
+use alloc_stdlib::StandardAlloc;
+#[cfg(feature="std")]
+use decode::{BrotliDecompressStreamToSink, BrotliResult};
+#[cfg(feature="std")]
+use state::BrotliState;
+
+/// Consumer of the command stream produced by `BrotliDecompressStreamToSink`.
+pub trait CommandSink {
+  /// Lets the decoder compile out every sink call when false.
+  const ACTIVE: bool = true;
+
+  /// Asked once per compressed metablock whose literals do not depend on earlier
+  /// output. When true, the decoder does not apply that metablock's copies to its own
+  /// ringbuffer and leaves the work to the sink.
+  fn defer_copies(&self) -> bool;
+
+  /// Called once the window has been allocated. `ring` holds its initial contents,
+  /// including any custom dictionary placed at its end; output position `p` maps to
+  /// `ring[p & (ring.len() - 1)]`.
+  fn ring_buffer_allocated(&mut self, ring: &[u8]);
+
+  /// Appends literal bytes to the output.
+  fn literals(&mut self, data: &[u8]);
+
+  /// Appends `len` bytes copied from `distance` bytes back in the output.
+  fn copy(&mut self, len: u32, distance: u32);
+
+  /// Called before a metablock that needs the real history, after copies were
+  /// deferred. The sink must overwrite `ring` with its reconstructed window.
+  fn restore_history(&mut self, ring: &mut [u8]);
+
+  /// Lets the decoder compile out every `provenance` call when false. Independent of
+  /// `ACTIVE`: a sink may only watch provenance while the decoder produces the output.
+  const PROVENANCE: bool = false;
+
+  /// Describes where the next span of output came from.
+  fn provenance(&mut self, _span: &ProvenanceSpan) {}
+}
+
+/// The sink used by `BrotliDecompressStream`, where the decoder produces output itself.
+#[derive(Default)]
+pub struct NopCommandSink;
+
+impl CommandSink for NopCommandSink {
+  const ACTIVE: bool = false;
+  fn defer_copies(&self) -> bool {
+    false
+  }
+  fn ring_buffer_allocated(&mut self, _ring: &[u8]) {}
+  fn literals(&mut self, _data: &[u8]) {}
+  fn copy(&mut self, _len: u32, _distance: u32) {}
+  fn restore_history(&mut self, _ring: &mut [u8]) {}
+}
+
+// PipelinedDecompressor hands the command stream from the entropy decoding thread to
+// the reconstructing reader in batches of about this many literal bytes or commands
+#[cfg(feature="std")]
+const PIPELINE_BATCH_LITERALS: usize = 65536;
+#[cfg(feature="std")]
+const PIPELINE_BATCH_OPS: usize = 8192;
+#[cfg(feature="std")]
+const PIPELINE_QUEUE_DEPTH: usize = 4;
+
+#[cfg(feature="std")]
+enum Op {
+  Literals(u32),
+  Copy(u32, u32), // length, distance
+}
+
+#[cfg(feature="std")]
+enum Command {
+  Window(Vec<u8>),
+  Batch(Vec<u8>, Vec<Op>),
+  History(Sender<Vec<u8>>),
+  Err(Error),
+  Eof,
+}
+
+#[cfg(feature="std")]
+struct ChannelSink {
+  commands: SyncSender<Command>,
+  literals: Vec<u8>,
+  ops: Vec<Op>,
+  hung_up: bool,
+}
+
+#[cfg(feature="std")]
+impl ChannelSink {
+  fn send(&mut self, command: Command) {
+    if !self.hung_up && self.commands.send(command).is_err() {
+      self.hung_up = true;
+    }
+  }
+  fn flush(&mut self) {
+    if !self.ops.is_empty() {
+      let literals = ::core::mem::replace(&mut self.literals,
+                                          Vec::with_capacity(PIPELINE_BATCH_LITERALS));
+      let ops = ::core::mem::replace(&mut self.ops, Vec::with_capacity(PIPELINE_BATCH_OPS));
+      self.send(Command::Batch(literals, ops));
+    }
+  }
+}
+
+#[cfg(feature="std")]
+impl CommandSink for ChannelSink {
+  fn defer_copies(&self) -> bool {
+    true
+  }
+  fn ring_buffer_allocated(&mut self, ring: &[u8]) {
+    self.send(Command::Window(ring.to_vec()));
+  }
+  fn literals(&mut self, data: &[u8]) {
+    if let Some(&mut Op::Literals(ref mut len)) = self.ops.last_mut() {
+      *len += data.len() as u32;
+    } else {
+      self.ops.push(Op::Literals(data.len() as u32));
+    }
+    self.literals.extend_from_slice(data);
+    if self.literals.len() >= PIPELINE_BATCH_LITERALS {
+      self.flush();
+    }
+  }
+  fn copy(&mut self, len: u32, distance: u32) {
+    self.ops.push(Op::Copy(len, distance));
+    if self.ops.len() >= PIPELINE_BATCH_OPS {
+      self.flush();
+    }
+  }
+  fn restore_history(&mut self, ring: &mut [u8]) {
+    self.flush();
+    let (reply_send, reply_recv) = channel::<Vec<u8>>();
+    self.send(Command::History(reply_send));
+    if self.hung_up {
+      return;
+    }
+    match reply_recv.recv() {
+      Ok(history) => ring.clone_from_slice(&history[..ring.len()]),
+      Err(_) => self.hung_up = true,
+    }
+  }
+}
+
+#[cfg(feature="std")]
+fn pipeline_worker<R: Read>(mut r: R,
+                            buffer_size: usize,
+                            dict: <StandardAlloc as Allocator<u8>>::AllocatedMemory,
+                            mut sink: ChannelSink,
+                            shutdown: Arc<AtomicBool>) {
+  let mut input = vec![0u8; buffer_size];
+  let mut available_in: usize = 0;
+  let mut input_offset: usize = 0;
+  let mut total_out: usize = 0;
+  let mut state = BrotliState::new_with_custom_dictionary(StandardAlloc::default(),
+                                                          StandardAlloc::default(),
+                                                          StandardAlloc::default(),
+                                                          dict);
+  while !shutdown.load(Ordering::Relaxed) {
+    let result = BrotliDecompressStreamToSink(&mut available_in,
+                                              &mut input_offset,
+                                              &input[..],
+                                              &mut total_out,
+                                              &mut state,
+                                              &mut sink);
+    if sink.hung_up {
+      return;
+    }
+    match result {
+      BrotliResult::NeedsMoreInput => {
+        // all input was consumed, since the decoder buffers partial reads internally
+        loop {
+          match r.read(&mut input[..]) {
+            Ok(0) => {
+              // like Decompressor, a truncated stream simply ends early
+              sink.flush();
+              sink.send(Command::Eof);
+              return;
+            }
+            Ok(size) => {
+              available_in = size;
+              input_offset = 0;
+              break;
+            }
+            Err(e) => {
+              if let ErrorKind::Interrupted = e.kind() {
+                continue;
+              }
+              sink.flush();
+              sink.send(Command::Err(e));
+              return;
+            }
+          }
+        }
+      }
+      BrotliResult::NeedsMoreOutput => {}
+      BrotliResult::ResultSuccess => {
+        sink.flush();
+        sink.send(Command::Eof);
+        return;
+      }
+      BrotliResult::ResultFailure => {
+        sink.flush();
+        sink.send(Command::Err(Error::new(ErrorKind::InvalidData, "Invalid Data")));
+        return;
+      }
+    }
+  }
+}
+
+#[cfg(feature="std")]
+/// Decompresses a brotli stream in two stages running on separate threads.
+///
+/// A worker thread does the entropy decoding and sends literals and back-reference
+/// commands over a bounded queue; the thread calling `read` replays them into its own
+/// window and the caller's buffer. In metablocks whose literals are coded without
+/// context modeling (typical of lower compression qualities and of incompressible
+/// data) the worker leaves the copies to the reader altogether. Context modeling picks
+/// each literal's code from the two bytes before it, so in metablocks that use it the
+/// worker applies the copies to its own window as well, and the reader's replay runs
+/// alongside it instead of taking it over. When a stream switches from the first kind
+/// of metablock to the second, the worker fetches the window back from the reader
+/// once. The output is identical to `Decompressor`, and errors are returned after
+/// every byte decoded before them.
+///
+/// Dropping the decompressor stops the worker and joins it, as with
+/// `ThreadedDecompressor`.
+pub struct PipelinedDecompressor<R: Read + Send + 'static> {
+  commands: Option<Receiver<Command>>,
+  shutdown: Arc<AtomicBool>,
+  worker: Option<JoinHandle<()>>,
+  window: Vec<u8>,
+  window_pos: usize,
+  literals: Vec<u8>,
+  literal_offset: usize,
+  ops: Vec<Op>,
+  op_index: usize,
+  op_done: u32,
+  finished: bool,
+  failure: Option<ErrorKind>,
+  pending_error: Option<Error>,
+  _reader: ::core::marker::PhantomData<R>,
+}
+
+#[cfg(feature="std")]
+impl<R: Read + Send + 'static> PipelinedDecompressor<R> {
+  pub fn new(r: R, buffer_size: usize) -> Self {
+    let dict = <StandardAlloc as Allocator<u8>>::AllocatedMemory::default();
+    Self::new_with_custom_dict(r, buffer_size, dict)
+  }
+  pub fn new_with_custom_dict(r: R,
+                              buffer_size: usize,
+                              dict: <StandardAlloc as Allocator<u8>>::AllocatedMemory)
+                              -> Self {
+    let buffer_size = if buffer_size == 0 { 4096 } else { buffer_size };
+    let (commands_send, commands_recv) = sync_channel::<Command>(PIPELINE_QUEUE_DEPTH);
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let worker_shutdown = shutdown.clone();
+    let worker = thread::spawn(move || {
+      let sink = ChannelSink {
+        commands: commands_send,
+        literals: Vec::with_capacity(PIPELINE_BATCH_LITERALS),
+        ops: Vec::with_capacity(PIPELINE_BATCH_OPS),
+        hung_up: false,
+      };
+      pipeline_worker(r, buffer_size, dict, sink, worker_shutdown)
+    });
+    PipelinedDecompressor::<R> {
+      commands: Some(commands_recv),
+      shutdown,
+      worker: Some(worker),
+      window: Vec::new(),
+      window_pos: 0,
+      literals: Vec::new(),
+      literal_offset: 0,
+      ops: Vec::new(),
+      op_index: 0,
+      op_done: 0,
+      finished: false,
+      failure: None,
+      pending_error: None,
+      _reader: ::core::marker::PhantomData,
+    }
+  }
+
+  // Runs the buffered commands into the window and out, returning the bytes produced.
+  fn replay(&mut self, out: &mut [u8]) -> usize {
+    let size = self.window.len();
+    let mask = size.wrapping_sub(1);
+    let mut written: usize = 0;
+    while written < out.len() && self.op_index < self.ops.len() {
+      let (len, distance) = match self.ops[self.op_index] {
+        Op::Literals(len) => (len, 0u32),
+        Op::Copy(len, distance) => (len, distance),
+      };
+      let n = ::core::cmp::min((len - self.op_done) as usize, out.len() - written);
+      let mut done: usize = 0;
+      while done < n {
+        let dst = (self.window_pos + done) & mask;
+        let mut chunk = ::core::cmp::min(n - done, size - dst);
+        if distance == 0 {
+          let src = &self.literals[self.literal_offset + done..self.literal_offset + done + chunk];
+          self.window[dst..dst + chunk].clone_from_slice(src);
+        } else {
+          // copy at most `distance` bytes at a time so overlapping copies repeat correctly
+          let src = (self.window_pos + done).wrapping_sub(distance as usize) & mask;
+          chunk = ::core::cmp::min(chunk, ::core::cmp::min(distance as usize, size - src));
+          self.window.copy_within(src..src + chunk, dst);
+        }
+        out[written + done..written + done + chunk].clone_from_slice(&self.window[dst..dst + chunk]);
+        done += chunk;
+      }
+      if distance == 0 {
+        self.literal_offset += n;
+      }
+      self.window_pos += n;
+      written += n;
+      self.op_done += n as u32;
+      if self.op_done == len {
+        self.op_index += 1;
+        self.op_done = 0;
+      }
+    }
+    written
+  }
+
+  fn finish(&mut self) {
+    self.finished = true;
+    self.shutdown.store(true, Ordering::Relaxed);
+    self.commands = None;
+    if let Some(worker) = self.worker.take() {
+      let _ = worker.join();
+    }
+  }
+}
+
+#[cfg(feature="std")]
+impl<R: Read + Send + 'static> Read for PipelinedDecompressor<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    let mut written: usize = 0;
+    loop {
+      written += self.replay(&mut buf[written..]);
+      if written == buf.len() {
+        return Ok(written);
+      }
+      if let Some(kind) = self.failure {
+        if written != 0 {
+          return Ok(written);
+        }
+        return Err(self.pending_error
+          .take()
+          .unwrap_or_else(|| Error::new(kind, "brotli stream already failed")));
+      }
+      if self.finished {
+        return Ok(written);
+      }
+      // only block for more commands while nothing can be returned yet
+      let command = match self.commands {
+        Some(ref commands) => {
+          if written == 0 {
+            commands.recv().ok()
+          } else {
+            match commands.try_recv() {
+              Ok(command) => Some(command),
+              Err(TryRecvError::Empty) => return Ok(written),
+              Err(TryRecvError::Disconnected) => None,
+            }
+          }
+        }
+        None => return Ok(written),
+      };
+      match command {
+        Some(Command::Window(window)) => self.window = window,
+        Some(Command::Batch(literals, ops)) => {
+          self.literals = literals;
+          self.literal_offset = 0;
+          self.ops = ops;
+          self.op_index = 0;
+          self.op_done = 0;
+        }
+        Some(Command::History(reply)) => {
+          let _ = reply.send(self.window.clone());
+        }
+        Some(Command::Err(e)) => {
+          self.finish();
+          self.failure = Some(e.kind());
+          self.pending_error = Some(e);
+        }
+        Some(Command::Eof) => self.finish(),
+        None => {
+          // the worker went away without a final message: it must have panicked
+          self.commands = None;
+          if let Some(worker) = self.worker.take() {
+            if let Err(payload) = worker.join() {
+              panic::resume_unwind(payload);
+            }
+          }
+          self.finished = true;
+          self.failure = Some(ErrorKind::Other);
+          self.pending_error = Some(Error::new(ErrorKind::Other, "brotli worker thread exited unexpectedly"));
+        }
+      }
+    }
+  }
+}
+
+#[cfg(feature="std")]
+impl<R: Read + Send + 'static> Drop for PipelinedDecompressor<R> {
+  fn drop(&mut self) {
+    self.finish();
+  }
+}
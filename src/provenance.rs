@@ -0,0 +1,111 @@
+// Where each byte of the output comes from, for BrotliDecompressStreamWithProvenance.
+//
+// The decoder reports one span per literal run, back-reference, dictionary reference or
+// piece of an uncompressed metablock, in output order, together with the bits of the
+// compressed stream that encode it. Bit positions count from the start of the stream,
+// least significant bit of each byte first, as brotli reads them.
+
+/// What produced a span of output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Provenance {
+  /// Literals inserted by a command.
+  Literal,
+  /// Bytes stored in an uncompressed metablock.
+  Uncompressed,
+  /// A back-reference to earlier output.
+  Copy { distance: u32 },
+  /// A back-reference that starts in the custom dictionary, at `offset` within it. The
+  /// copy may run on past the end of the dictionary into the output.
+  CustomDictionary { distance: u32, offset: u32 },
+  /// A reference to the dictionaries attached with `attach_dictionary`, starting at
+  /// `offset` within them taken in order.
+  CompoundDictionary { distance: u32, offset: u32 },
+  /// Word `word_idx` of length `word_len` from the static dictionary, through `transform`.
+  StaticDictionary { word_len: u32, word_idx: u32, transform: u32 },
+}
+
+/// A run of output bytes with a single source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProvenanceSpan {
+  /// Position of the first byte in the decompressed stream.
+  pub output_start: u64,
+  pub len: u64,
+  pub source: Provenance,
+  /// First bit of the compressed stream that went into the span. Literal and copy spans
+  /// include the command and block switches that precede them.
+  pub bit_start: u64,
+  /// Bit after the last one that went into the span.
+  pub bit_end: u64,
+}
+
+/// Receives spans from `BrotliDecompressStreamWithProvenance`.
+pub trait ProvenanceSink {
+  fn span(&mut self, span: &ProvenanceSpan);
+}
+
+/// Merges consecutive spans of literals, uncompressed bytes or copies from the same
+/// distance before passing them on to `inner`. Call `finish` once the stream ends.
+pub struct ProvenanceRecorder<Inner: ProvenanceSink> {
+  inner: Inner,
+  pending: Option<ProvenanceSpan>,
+}
+
+impl<Inner: ProvenanceSink> ProvenanceRecorder<Inner> {
+  pub fn new(inner: Inner) -> Self {
+    ProvenanceRecorder {
+      inner,
+      pending: None,
+    }
+  }
+
+  /// Passes on the span still held back for merging.
+  pub fn finish(&mut self) {
+    if let Some(span) = self.pending.take() {
+      self.inner.span(&span);
+    }
+  }
+
+  pub fn get_ref(&self) -> &Inner {
+    &self.inner
+  }
+
+  pub fn get_mut(&mut self) -> &mut Inner {
+    &mut self.inner
+  }
+
+  /// Returns `inner` after calling `finish`.
+  pub fn into_inner(mut self) -> Inner {
+    self.finish();
+    self.inner
+  }
+}
+
+impl<Inner: ProvenanceSink> ProvenanceSink for ProvenanceRecorder<Inner> {
+  fn span(&mut self, span: &ProvenanceSpan) {
+    let mergeable = matches!(span.source,
+                             Provenance::Literal | Provenance::Uncompressed | Provenance::Copy { .. });
+    if let Some(ref mut pending) = self.pending {
+      if mergeable && pending.source == span.source &&
+         pending.output_start + pending.len == span.output_start {
+        pending.len += span.len;
+        pending.bit_end = span.bit_end;
+        return;
+      }
+    }
+    if let Some(pending) = self.pending.take() {
+      self.inner.span(&pending);
+    }
+    if mergeable {
+      self.pending = Some(*span);
+    } else {
+      self.inner.span(span);
+    }
+  }
+}
+
+#[cfg(feature="std")]
+impl ProvenanceSink for std::vec::Vec<ProvenanceSpan> {
+  fn span(&mut self, span: &ProvenanceSpan) {
+    self.push(*span);
+  }
+}
@@ -6,11 +6,13 @@ pub use alloc_stdlib::StandardAlloc;
 pub use alloc_stdlib::HeapAllocUninitialized;
 pub use huffman::{HuffmanCode, HuffmanTreeGroup};
 pub use state::BrotliState;
+use stats::DecodeStats;
+use options::DecompressorOptions;
 // use io_wrappers::write_all;
 pub use io_wrappers::{CustomRead, CustomWrite};
 #[cfg(feature="std")]
 pub use io_wrappers::{IntoIoReader, IoReaderWrapper, IoWriterWrapper};
-pub use super::decode::{BrotliDecompressStream, BrotliResult};
+pub use super::decode::{BrotliDecompressStream, BrotliDecompressStreamWithDictionary, BrotliResult};
 pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 
 #[cfg(feature="std")]
@@ -18,10 +20,12 @@ pub struct DecompressorCustomAlloc<R: Read,
      BufferType : SliceWrapperMut<u8>,
      AllocU8 : Allocator<u8>,
      AllocU32 : Allocator<u32>,
-     AllocHC : Allocator<HuffmanCode> >(DecompressorCustomIo<io::Error,
+     AllocHC : Allocator<HuffmanCode>,
+     Dict : AsRef<[u8]> = &'static [u8]>(DecompressorCustomIo<io::Error,
                                                              IntoIoReader<R>,
                                                              BufferType,
-                                                             AllocU8, AllocU32, AllocHC>);
+                                                             AllocU8, AllocU32, AllocHC,
+                                                             Dict>);
 
 
 #[cfg(feature="std")]
@@ -61,23 +65,77 @@ impl<R: Read,
                                                                                               "Invalid Data")))
     }
 
+    pub fn with_options(r: R, buffer : BufferType,
+               alloc_u8 : AllocU8, alloc_u32 : AllocU32, alloc_hc : AllocHC,
+               options: &DecompressorOptions) -> Self {
+        DecompressorCustomAlloc::<R, BufferType, AllocU8, AllocU32, AllocHC>(
+          DecompressorCustomIo::<Error,
+                                 IntoIoReader<R>,
+                                 BufferType,
+                                 AllocU8, AllocU32, AllocHC>::with_options(IntoIoReader::<R>(r),
+                                                                           buffer,
+                                                                           alloc_u8, alloc_u32, alloc_hc,
+                                                                           options,
+                                                                           Error::new(ErrorKind::InvalidData,
+                                                                                      "Invalid Data")))
+    }
+}
+
+#[cfg(feature="std")]
+impl<R: Read,
+     BufferType : SliceWrapperMut<u8>,
+     AllocU8,
+     AllocU32,
+     AllocHC,
+     Dict : AsRef<[u8]>> DecompressorCustomAlloc<R, BufferType, AllocU8, AllocU32, AllocHC, Dict>
+ where AllocU8 : Allocator<u8>, AllocU32 : Allocator<u32>, AllocHC : Allocator<HuffmanCode>
+    {
+    /// Decodes as `options` asks, against `dict` read in place; see
+    /// `DecompressorCustomIo::with_dictionary`.
+    pub fn with_dictionary(r: R, buffer : BufferType,
+               alloc_u8 : AllocU8, alloc_u32 : AllocU32, alloc_hc : AllocHC,
+               dict: Dict,
+               options: &DecompressorOptions) -> Self {
+        DecompressorCustomAlloc(
+          DecompressorCustomIo::with_dictionary(IntoIoReader::<R>(r),
+                                                buffer,
+                                                alloc_u8, alloc_u32, alloc_hc,
+                                                dict,
+                                                options,
+                                                Error::new(ErrorKind::InvalidData,
+                                                           "Invalid Data")))
+    }
+
     pub fn get_ref(&self) -> &R {
       &self.0.get_ref().0
     }
     pub fn get_mut(&mut self) -> &mut R {
       &mut self.0.get_mut().0
     }
+    /// Drops the next `n` bytes of decompressed output without copying them out.
+    pub fn skip_output(&mut self, n: usize) {
+      self.0.skip_output(n)
+    }
+    /// Starts counting what the stream is made of; see `BrotliState::enable_stats`.
+    pub fn enable_stats(&mut self) {
+      self.0.enable_stats()
+    }
+    pub fn stats(&self) -> Option<&DecodeStats> {
+      self.0.stats()
+    }
 }
 #[cfg(feature="std")]
 impl<R: Read,
      BufferType : SliceWrapperMut<u8>,
      AllocU8 : Allocator<u8>,
      AllocU32 : Allocator<u32>,
-     AllocHC : Allocator<HuffmanCode> > Read for DecompressorCustomAlloc<R,
-                                                                         BufferType,
-                                                                         AllocU8,
-                                                                         AllocU32,
-                                                                         AllocHC> {
+     AllocHC : Allocator<HuffmanCode>,
+     Dict : AsRef<[u8]> > Read for DecompressorCustomAlloc<R,
+                                                           BufferType,
+                                                           AllocU8,
+                                                           AllocU32,
+                                                           AllocHC,
+                                                           Dict> {
   	fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
        self.0.read(buf)
     }
@@ -85,12 +143,13 @@ impl<R: Read,
 
 
 #[cfg(not(any(feature="unsafe", not(feature="std"))))]
-pub struct Decompressor<R: Read>(DecompressorCustomAlloc<R,
+pub struct Decompressor<R: Read, Dict: AsRef<[u8]> = &'static [u8]>(DecompressorCustomAlloc<R,
                                                          <StandardAlloc
                                                           as Allocator<u8>>::AllocatedMemory,
                                                          StandardAlloc,
                                                          StandardAlloc,
-                                                         StandardAlloc>);
+                                                         StandardAlloc,
+                                                         Dict>);
 
 
 #[cfg(not(any(feature="unsafe", not(feature="std"))))]
@@ -114,6 +173,40 @@ impl<R: Read> Decompressor<R> {
                                                                               StandardAlloc::default(),
                                                                               dict))
   }
+  /// Decodes as `options` asks, reading through a buffer of `options.buffer_size`.
+  pub fn with_options(r: R, options: &DecompressorOptions) -> Self {
+    let mut alloc = StandardAlloc::default();
+    let buffer = <StandardAlloc as Allocator<u8>>::alloc_cell(&mut alloc, options.buffer_size);
+    Decompressor::<R>(DecompressorCustomAlloc::<R,
+                                                <StandardAlloc
+                                                 as Allocator<u8>>::AllocatedMemory,
+                                                StandardAlloc,
+                                                StandardAlloc,
+                                                StandardAlloc>::with_options(r,
+                                                                             buffer,
+                                                                             alloc,
+                                                                             StandardAlloc::default(),
+                                                                             StandardAlloc::default(),
+                                                                             options))
+  }
+}
+
+#[cfg(not(any(feature="unsafe", not(feature="std"))))]
+impl<R: Read, Dict: AsRef<[u8]>> Decompressor<R, Dict> {
+  /// Decodes as `options` asks, against `dict` rather than any dictionary in `options`.
+  /// `dict` is read in place for the whole stream, so a `Decompressor<R, &'a [u8]>`
+  /// borrows its dictionary and a `Decompressor<R, Arc<[u8]>>` shares one.
+  pub fn with_dictionary(r: R, dict: Dict, options: &DecompressorOptions) -> Self {
+    let mut alloc = StandardAlloc::default();
+    let buffer = <StandardAlloc as Allocator<u8>>::alloc_cell(&mut alloc, options.buffer_size);
+    Decompressor(DecompressorCustomAlloc::with_dictionary(r,
+                                                          buffer,
+                                                          alloc,
+                                                          StandardAlloc::default(),
+                                                          StandardAlloc::default(),
+                                                          dict,
+                                                          options))
+  }
 
   pub fn get_ref(&self) -> &R {
     &self.0.get_ref()
@@ -121,16 +214,31 @@ impl<R: Read> Decompressor<R> {
   pub fn get_mut(&mut self) -> &mut R {
     &mut ((self.0).0).get_mut().0
   }
+  /// Drops the next `n` bytes of decompressed output without copying them out, so
+  /// `skip_output(offset)` followed by `take(length)` reads a range of the stream and
+  /// stops consuming the input once the range is complete.
+  pub fn skip_output(&mut self, n: usize) {
+    self.0.skip_output(n)
+  }
+  /// Starts counting what the stream is made of, to read back with `stats` once the
+  /// stream ends.
+  pub fn enable_stats(&mut self) {
+    self.0.enable_stats()
+  }
+  pub fn stats(&self) -> Option<&DecodeStats> {
+    self.0.stats()
+  }
 }
 
 
 #[cfg(all(feature="unsafe", feature="std"))]
-pub struct Decompressor<R: Read>(DecompressorCustomAlloc<R,
+pub struct Decompressor<R: Read, Dict: AsRef<[u8]> = &'static [u8]>(DecompressorCustomAlloc<R,
                                                          <HeapAllocUninitialized<u8>
                                                           as Allocator<u8>>::AllocatedMemory,
                                                          HeapAllocUninitialized<u8>,
                                                          HeapAllocUninitialized<u32>,
-                                                         HeapAllocUninitialized<HuffmanCode> >);
+                                                         HeapAllocUninitialized<HuffmanCode>,
+                                                         Dict>);
 
 
 #[cfg(all(feature="unsafe", feature="std"))]
@@ -153,6 +261,35 @@ impl<R: Read> Decompressor<R> {
                                                 HeapAllocUninitialized<HuffmanCode> >
       ::new_with_custom_dictionary(r, buffer, alloc_u8, alloc_u32, alloc_hc, dict))
   }
+  /// Decodes as `options` asks, reading through a buffer of `options.buffer_size`.
+  pub fn with_options(r: R, options: &DecompressorOptions) -> Self {
+    let mut alloc_u8 = unsafe { HeapAllocUninitialized::<u8>::new() };
+    let buffer = alloc_u8.alloc_cell(options.buffer_size);
+    let alloc_u32 = unsafe { HeapAllocUninitialized::<u32>::new() };
+    let alloc_hc = unsafe { HeapAllocUninitialized::<HuffmanCode>::new() };
+    Decompressor::<R>(DecompressorCustomAlloc::<R,
+                                                <HeapAllocUninitialized<u8>
+                                                 as Allocator<u8>>::AllocatedMemory,
+                                                HeapAllocUninitialized<u8>,
+                                                HeapAllocUninitialized<u32>,
+                                                HeapAllocUninitialized<HuffmanCode> >
+      ::with_options(r, buffer, alloc_u8, alloc_u32, alloc_hc, options))
+  }
+}
+
+#[cfg(all(feature="unsafe", feature="std"))]
+impl<R: Read, Dict: AsRef<[u8]>> Decompressor<R, Dict> {
+  /// Decodes as `options` asks, against `dict` rather than any dictionary in `options`.
+  /// `dict` is read in place for the whole stream, so a `Decompressor<R, &'a [u8]>`
+  /// borrows its dictionary and a `Decompressor<R, Arc<[u8]>>` shares one.
+  pub fn with_dictionary(r: R, dict: Dict, options: &DecompressorOptions) -> Self {
+    let mut alloc_u8 = unsafe { HeapAllocUninitialized::<u8>::new() };
+    let buffer = alloc_u8.alloc_cell(options.buffer_size);
+    let alloc_u32 = unsafe { HeapAllocUninitialized::<u32>::new() };
+    let alloc_hc = unsafe { HeapAllocUninitialized::<HuffmanCode>::new() };
+    Decompressor(DecompressorCustomAlloc::with_dictionary(r, buffer, alloc_u8, alloc_u32, alloc_hc,
+                                                          dict, options))
+  }
 
   pub fn get_ref(&self) -> &R {
     self.0.get_ref()
@@ -160,11 +297,25 @@ impl<R: Read> Decompressor<R> {
   pub fn get_mut(&mut self) -> &mut R {
     &mut (self.0).0.get_mut().0
   }
+  /// Drops the next `n` bytes of decompressed output without copying them out, so
+  /// `skip_output(offset)` followed by `take(length)` reads a range of the stream and
+  /// stops consuming the input once the range is complete.
+  pub fn skip_output(&mut self, n: usize) {
+    self.0.skip_output(n)
+  }
+  /// Starts counting what the stream is made of, to read back with `stats` once the
+  /// stream ends.
+  pub fn enable_stats(&mut self) {
+    self.0.enable_stats()
+  }
+  pub fn stats(&self) -> Option<&DecodeStats> {
+    self.0.stats()
+  }
 }
 
 
 #[cfg(feature="std")]
-impl<R: Read> Read for Decompressor<R> {
+impl<R: Read, Dict: AsRef<[u8]>> Read for Decompressor<R, Dict> {
   fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
     self.0.read(buf)
   }
@@ -175,7 +326,8 @@ pub struct DecompressorCustomIo<ErrType,
                                 BufferType: SliceWrapperMut<u8>,
                                 AllocU8: Allocator<u8>,
                                 AllocU32: Allocator<u32>,
-                                AllocHC: Allocator<HuffmanCode>>
+                                AllocHC: Allocator<HuffmanCode>,
+                                Dict: AsRef<[u8]> = &'static [u8]>
 {
   input_buffer: BufferType,
   total_out: usize,
@@ -185,6 +337,11 @@ pub struct DecompressorCustomIo<ErrType,
   input: R,
   error_if_invalid_data: Option<ErrType>,
   read_error: Option<ErrType>,
+  max_output_size: usize,
+  delivered: usize, // bytes returned by read, unlike total_out which counts skipped ones
+  strict: bool,
+  // decoded against in place of any dictionary in the state; empty for none
+  dict: Dict,
   state: BrotliState<AllocU8, AllocU32, AllocHC>,
 }
 
@@ -220,6 +377,79 @@ impl<ErrType,
                                      dict),
             error_if_invalid_data : Some(invalid_data_error_type),
             read_error : None,
+            max_output_size : usize::MAX,
+            delivered : 0,
+            strict : false,
+            dict : &[],
+        }
+    }
+    /// Decodes as `options` asks. The buffer is the one given here, whatever
+    /// `options.buffer_size` says.
+    pub fn with_options(r: R, buffer : BufferType,
+               alloc_u8 : AllocU8, alloc_u32 : AllocU32, alloc_hc : AllocHC,
+               options: &DecompressorOptions,
+               invalid_data_error_type : ErrType) -> Self {
+        DecompressorCustomIo::<ErrType, R, BufferType, AllocU8, AllocU32, AllocHC>{
+            input_buffer : buffer,
+            total_out : 0,
+            input_offset : 0,
+            input_len : 0,
+            input_eof : false,
+            input: r,
+            state : BrotliState::new_with_options(alloc_u8,
+                                     alloc_u32,
+                                     alloc_hc,
+                                     options),
+            error_if_invalid_data : Some(invalid_data_error_type),
+            read_error : None,
+            max_output_size : options.limits.max_output_size,
+            delivered : 0,
+            strict : options.strict,
+            dict : &[],
+        }
+    }
+}
+
+impl<ErrType,
+     R: CustomRead<ErrType>,
+     BufferType : SliceWrapperMut<u8>,
+     AllocU8,
+     AllocU32,
+     AllocHC,
+     Dict : AsRef<[u8]>> DecompressorCustomIo<ErrType, R, BufferType, AllocU8, AllocU32, AllocHC, Dict>
+ where AllocU8 : Allocator<u8>, AllocU32 : Allocator<u32>, AllocHC : Allocator<HuffmanCode>
+{
+    /// Decodes as `options` asks, against `dict` rather than any dictionary in
+    /// `options`. The decoder reads `dict` in place for the whole stream instead of
+    /// copying it, so it can be borrowed as `&'a [u8]` or shared as an `Arc<[u8]>`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_dictionary(r: R, buffer : BufferType,
+               alloc_u8 : AllocU8, alloc_u32 : AllocU32, alloc_hc : AllocHC,
+               dict: Dict,
+               options: &DecompressorOptions,
+               invalid_data_error_type : ErrType) -> Self {
+        let options = DecompressorOptions {
+          custom_dictionary: None,
+          shared_dictionary: None,
+          ..*options
+        };
+        DecompressorCustomIo {
+            input_buffer : buffer,
+            total_out : 0,
+            input_offset : 0,
+            input_len : 0,
+            input_eof : false,
+            input: r,
+            state : BrotliState::new_with_options(alloc_u8,
+                                     alloc_u32,
+                                     alloc_hc,
+                                     &options),
+            error_if_invalid_data : Some(invalid_data_error_type),
+            read_error : None,
+            max_output_size : options.limits.max_output_size,
+            delivered : 0,
+            strict : options.strict,
+            dict,
         }
     }
 
@@ -230,6 +460,21 @@ impl<ErrType,
       &mut self.input
     }
 
+    /// Drops the next `n` bytes of decompressed output: they are decoded but never
+    /// copied into the buffers passed to `read`, and do not count towards
+    /// `max_output_size`.
+    pub fn skip_output(&mut self, n: usize) {
+      self.state.skip_output(n)
+    }
+
+    /// Starts counting what the stream is made of; see `BrotliState::enable_stats`.
+    pub fn enable_stats(&mut self) {
+      self.state.enable_stats()
+    }
+    pub fn stats(&self) -> Option<&DecodeStats> {
+      self.state.stats()
+    }
+
     pub fn copy_to_front(&mut self) {
         let avail_in = self.input_len - self.input_offset;
         if self.input_offset == self.input_buffer.slice_mut().len() {
@@ -242,19 +487,53 @@ impl<ErrType,
             self.input_offset = 0;
         }
     }
+
+    // Reports the invalid data error; reads after that return no data.
+    fn invalid_data(&mut self) -> Result<usize, ErrType> {
+        match self.error_if_invalid_data.take() {
+            Some(e) => Err(e),
+            None => Ok(0),
+        }
+    }
+
+    // Whether any input follows the end of the stream, reading ahead if the buffer holds
+    // none.
+    fn has_trailing_input(&mut self) -> Result<bool, ErrType> {
+        if self.input_offset != self.input_len {
+            return Ok(true);
+        }
+        while !self.input_eof {
+            self.input_offset = 0;
+            self.input_len = 0;
+            match self.input.read(self.input_buffer.slice_mut()) {
+                Err(e) => return Err(e),
+                Ok(0) => self.input_eof = true,
+                Ok(size) => {
+                    self.input_len = size;
+                    return Ok(true);
+                },
+            }
+        }
+        Ok(false)
+    }
 }
 impl<ErrType,
      R: CustomRead<ErrType>,
      BufferType : SliceWrapperMut<u8>,
      AllocU8 : Allocator<u8>,
      AllocU32 : Allocator<u32>,
-     AllocHC : Allocator<HuffmanCode> > CustomRead<ErrType> for DecompressorCustomIo<ErrType,
-                                                                                     R,
-                                                                                     BufferType,
-                                                                                     AllocU8,
-                                                                                     AllocU32,
-                                                                                     AllocHC> {
+     AllocHC : Allocator<HuffmanCode>,
+     Dict : AsRef<[u8]> > CustomRead<ErrType> for DecompressorCustomIo<ErrType,
+                                                                       R,
+                                                                       BufferType,
+                                                                       AllocU8,
+                                                                       AllocU32,
+                                                                       AllocHC,
+                                                                       Dict> {
 	fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrType > {
+      // the decoder may only fill what is left of max_output_size
+      let out_len = core::cmp::min(buf.len(), self.max_output_size - self.delivered);
+      let buf = &mut buf[..out_len];
       let mut output_offset : usize = 0;
       let mut avail_out = buf.len() - output_offset;
       let mut avail_in = self.input_len - self.input_offset;
@@ -275,14 +554,16 @@ impl<ErrType,
             },
           }
         }
-        match BrotliDecompressStream(&mut avail_in,
-                                     &mut self.input_offset,
-                                     &self.input_buffer.slice_mut()[..],
-                                     &mut avail_out,
-                                     &mut output_offset,
-                                     buf,
-                                     &mut self.total_out,
-                                     &mut self.state) {
+        let result = BrotliDecompressStreamWithDictionary(&mut avail_in,
+                                                          &mut self.input_offset,
+                                                          &self.input_buffer.slice_mut()[..],
+                                                          &mut avail_out,
+                                                          &mut output_offset,
+                                                          buf,
+                                                          &mut self.total_out,
+                                                          &mut self.state,
+                                                          self.dict.as_ref());
+        match result {
           BrotliResult::NeedsMoreInput => {
             match self.read_error.take() {
               Some(err) => return Err(err),
@@ -292,11 +573,26 @@ impl<ErrType,
               },
             }
           },
+          BrotliResult::NeedsMoreOutput if self.delivered == self.max_output_size && output_offset == 0 => {
+            return self.invalid_data();
+          },
           BrotliResult::NeedsMoreOutput => {},
-          BrotliResult::ResultSuccess => break,
+          BrotliResult::ResultSuccess => {
+            if self.strict {
+              // checked once, as the stream ends
+              self.strict = false;
+              match self.has_trailing_input() {
+                Err(e) => return Err(e),
+                Ok(true) => return self.invalid_data(),
+                Ok(false) => {},
+              }
+            }
+            break
+          },
           BrotliResult::ResultFailure => return Err(self.error_if_invalid_data.take().unwrap()),
         }
       }
+      self.delivered += output_offset;
       Ok(output_offset)
     }
 }
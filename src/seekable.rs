@@ -0,0 +1,365 @@
+#![cfg(feature="std")]
+use std::io::{self, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::vec::Vec;
+use alloc_stdlib::StandardAlloc;
+use decode::{BrotliDecompressStream, BrotliResult};
+use frame::{decode_frame, new_state};
+use state::BrotliState;
+
+// A seekable file is a sequence of independent brotli streams (frames) followed by one
+// more brotli stream that holds the seek table in a metadata metablock, so any brotli
+// decoder can skip over it. The metadata payload is, little endian:
+//   for each frame: compressed size (u32), decompressed size (u32)
+//   number of frames (u32)
+//   SEEK_TABLE_MAGIC
+// The table stream ends with an empty last metablock, a single 0x03 byte, which makes
+// the payload footer readable from the fixed-size tail of the file.
+const SEEK_TABLE_MAGIC: [u8; 4] = *b"BRST";
+const SEEK_TABLE_ENTRY_SIZE: usize = 8;
+const SEEK_TABLE_FOOTER_SIZE: usize = 8;
+const SEEK_TABLE_TAIL_SIZE: usize = SEEK_TABLE_FOOTER_SIZE + 1;
+const MAX_METADATA_LEN: usize = 1 << 24;
+
+/// Location of one frame in the compressed file and in the decompressed output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SeekTableEntry {
+  pub compressed_offset: u64,
+  pub compressed_size: u32,
+  pub decompressed_offset: u64,
+  pub decompressed_size: u32,
+}
+
+/// Index of the frames of a seekable brotli file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SeekTable {
+  entries: Vec<SeekTableEntry>,
+}
+
+// Stream header for a metadata metablock of len bytes: WBITS = 16, ISLAST = 0,
+// MNIBBLES = 0, the reserved bit, MSKIPBYTES and MSKIPLEN - 1, padded to a byte.
+fn metadata_header(len: usize) -> Vec<u8> {
+  let skip_bytes: usize = if len - 1 < 1 << 8 {
+    1
+  } else if len - 1 < 1 << 16 {
+    2
+  } else {
+    3
+  };
+  let bits = (3u64 << 2) | ((skip_bytes as u64) << 5) | (((len - 1) as u64) << 7);
+  let mut header = Vec::with_capacity(skip_bytes + 1);
+  for i in 0..skip_bytes + 1 {
+    header.push((bits >> (8 * i)) as u8);
+  }
+  header
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+  bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+fn push_u32_le(out: &mut Vec<u8>, value: u32) {
+  out.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+}
+
+fn invalid_table(message: &'static str) -> Error {
+  Error::new(ErrorKind::InvalidData, message)
+}
+
+impl SeekTable {
+  pub fn new() -> Self {
+    SeekTable::default()
+  }
+
+  /// Appends a frame after the last one.
+  pub fn push(&mut self, compressed_size: u32, decompressed_size: u32) {
+    let (compressed_offset, decompressed_offset) = (self.compressed_size(),
+                                                    self.decompressed_size());
+    self.entries.push(SeekTableEntry {
+      compressed_offset,
+      compressed_size,
+      decompressed_offset,
+      decompressed_size,
+    });
+  }
+
+  pub fn entries(&self) -> &[SeekTableEntry] {
+    &self.entries
+  }
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  /// Total size of the frames, not counting the seek table itself.
+  pub fn compressed_size(&self) -> u64 {
+    match self.entries.last() {
+      Some(last) => last.compressed_offset + last.compressed_size as u64,
+      None => 0,
+    }
+  }
+  pub fn decompressed_size(&self) -> u64 {
+    match self.entries.last() {
+      Some(last) => last.decompressed_offset + last.decompressed_size as u64,
+      None => 0,
+    }
+  }
+
+  /// Index of the frame holding decompressed byte `offset`, skipping empty frames.
+  pub fn frame_for_offset(&self, offset: u64) -> Option<usize> {
+    if offset >= self.decompressed_size() {
+      return None;
+    }
+    let index = match self.entries.binary_search_by(|e| e.decompressed_offset.cmp(&offset)) {
+      Ok(mut index) => {
+        // several frames start here when some are empty; the data is in the last one
+        while self.entries[index].decompressed_size == 0 {
+          index += 1;
+        }
+        index
+      }
+      Err(index) => index - 1,
+    };
+    Some(index)
+  }
+
+  /// Builds the table by decoding a sequence of concatenated brotli streams.
+  pub fn from_concatenated_frames<R: Read>(mut r: R) -> io::Result<SeekTable> {
+    let mut table = SeekTable::new();
+    let mut input = vec![0u8; 65536];
+    let mut output = vec![0u8; 65536];
+    let mut input_offset: usize = 0;
+    let mut input_len: usize = 0;
+    let mut input_eof = false;
+    let mut state = new_state();
+    let mut frame_in: u64 = 0;
+    let mut frame_out: u64 = 0;
+    let mut needs_input = true;
+    loop {
+      if needs_input && input_offset == input_len && !input_eof {
+        input_offset = 0;
+        input_len = 0;
+        match r.read(&mut input[..]) {
+          Ok(0) => input_eof = true,
+          Ok(size) => input_len = size,
+          Err(e) => {
+            if let ErrorKind::Interrupted = e.kind() {
+              continue;
+            }
+            return Err(e);
+          }
+        }
+      }
+      if needs_input && input_offset == input_len && input_eof {
+        if frame_in != 0 {
+          return Err(Error::new(ErrorKind::UnexpectedEof, "truncated brotli frame"));
+        }
+        return Ok(table);
+      }
+      let mut available_in = input_len - input_offset;
+      let consumed_before = input_offset;
+      let mut available_out = output.len();
+      let mut output_offset: usize = 0;
+      let mut total_out: usize = 0;
+      let result = BrotliDecompressStream(&mut available_in,
+                                          &mut input_offset,
+                                          &input[..input_len],
+                                          &mut available_out,
+                                          &mut output_offset,
+                                          &mut output[..],
+                                          &mut total_out,
+                                          &mut state);
+      frame_in += (input_offset - consumed_before) as u64;
+      frame_out += output_offset as u64;
+      needs_input = true;
+      match result {
+        BrotliResult::NeedsMoreInput => {}
+        BrotliResult::NeedsMoreOutput => needs_input = false,
+        BrotliResult::ResultSuccess => {
+          if frame_in > u32::MAX as u64 || frame_out > u32::MAX as u64 {
+            return Err(Error::new(ErrorKind::InvalidInput, "frame too large for a seek table"));
+          }
+          table.push(frame_in as u32, frame_out as u32);
+          state.reset();
+          frame_in = 0;
+          frame_out = 0;
+        }
+        BrotliResult::ResultFailure => return Err(Error::new(ErrorKind::InvalidData, "Invalid Data")),
+      }
+    }
+  }
+
+  /// Encodes the table as the brotli stream that ends a seekable file.
+  pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+    let payload_len = self.entries.len() * SEEK_TABLE_ENTRY_SIZE + SEEK_TABLE_FOOTER_SIZE;
+    if payload_len > MAX_METADATA_LEN || self.entries.len() > u32::MAX as usize {
+      return Err(Error::new(ErrorKind::InvalidInput, "too many frames for a seek table"));
+    }
+    let mut out = metadata_header(payload_len);
+    for entry in self.entries.iter() {
+      push_u32_le(&mut out, entry.compressed_size);
+      push_u32_le(&mut out, entry.decompressed_size);
+    }
+    push_u32_le(&mut out, self.entries.len() as u32);
+    out.extend_from_slice(&SEEK_TABLE_MAGIC[..]);
+    out.push(3); // ISLAST, ISEMPTY
+    Ok(out)
+  }
+
+  pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+    w.write_all(&self.to_bytes()?[..])
+  }
+
+  /// Reads the seek table from the end of a seekable file. The table must describe
+  /// every byte before it.
+  pub fn read_from<R: Read + Seek>(r: &mut R) -> io::Result<SeekTable> {
+    let file_len = r.seek(SeekFrom::End(0))?;
+    if file_len < SEEK_TABLE_TAIL_SIZE as u64 {
+      return Err(invalid_table("file too short for a brotli seek table"));
+    }
+    let mut tail = [0u8; SEEK_TABLE_TAIL_SIZE];
+    r.seek(SeekFrom::End(-(SEEK_TABLE_TAIL_SIZE as i64)))?;
+    r.read_exact(&mut tail[..])?;
+    if tail[4..8] != SEEK_TABLE_MAGIC[..] || tail[8] != 3 {
+      return Err(invalid_table("missing brotli seek table"));
+    }
+    let num_frames = read_u32_le(&tail[..4]) as usize;
+    let payload_len = match num_frames.checked_mul(SEEK_TABLE_ENTRY_SIZE) {
+      Some(len) if len <= MAX_METADATA_LEN - SEEK_TABLE_FOOTER_SIZE => len + SEEK_TABLE_FOOTER_SIZE,
+      _ => return Err(invalid_table("corrupt brotli seek table")),
+    };
+    let header = metadata_header(payload_len);
+    let table_len = (header.len() + payload_len + 1) as u64;
+    if table_len > file_len {
+      return Err(invalid_table("corrupt brotli seek table"));
+    }
+    let mut stream = vec![0u8; table_len as usize];
+    r.seek(SeekFrom::End(-(table_len as i64)))?;
+    r.read_exact(&mut stream[..])?;
+    if stream[..header.len()] != header[..] {
+      return Err(invalid_table("corrupt brotli seek table"));
+    }
+    let mut table = SeekTable::new();
+    for entry in stream[header.len()..header.len() + num_frames * SEEK_TABLE_ENTRY_SIZE]
+          .chunks(SEEK_TABLE_ENTRY_SIZE) {
+      table.push(read_u32_le(&entry[..4]), read_u32_le(&entry[4..]));
+    }
+    if table.compressed_size() != file_len - table_len {
+      return Err(invalid_table("brotli seek table does not match the file size"));
+    }
+    Ok(table)
+  }
+}
+
+/// Random access reader over a seekable brotli file.
+///
+/// Reads decode only the frames overlapping the requested range, one at a time, so
+/// memory use is bounded by the largest frame. Seeking past the end is allowed and
+/// reads there return 0 bytes.
+pub struct SeekableDecompressor<R: Read + Seek> {
+  input: R,
+  table: SeekTable,
+  pos: u64,
+  compressed: Vec<u8>,
+  frame: Vec<u8>,
+  frame_index: Option<usize>,
+  state: BrotliState<StandardAlloc, StandardAlloc, StandardAlloc>,
+}
+
+impl<R: Read + Seek> SeekableDecompressor<R> {
+  /// Reads the seek table from the end of `r`.
+  pub fn new(mut r: R) -> io::Result<Self> {
+    let table = SeekTable::read_from(&mut r)?;
+    Ok(Self::new_with_seek_table(r, table))
+  }
+  /// Uses a seek table obtained elsewhere; its offsets are relative to the start of `r`.
+  pub fn new_with_seek_table(r: R, table: SeekTable) -> Self {
+    SeekableDecompressor::<R> {
+      input: r,
+      table,
+      pos: 0,
+      compressed: Vec::new(),
+      frame: Vec::new(),
+      frame_index: None,
+      state: new_state(),
+    }
+  }
+
+  pub fn seek_table(&self) -> &SeekTable {
+    &self.table
+  }
+  pub fn get_ref(&self) -> &R {
+    &self.input
+  }
+  pub fn get_mut(&mut self) -> &mut R {
+    &mut self.input
+  }
+  pub fn into_inner(self) -> R {
+    self.input
+  }
+
+  fn load_frame(&mut self, index: usize) -> io::Result<()> {
+    if self.frame_index == Some(index) {
+      return Ok(());
+    }
+    self.frame_index = None;
+    let entry = self.table.entries()[index];
+    self.compressed.resize(entry.compressed_size as usize, 0);
+    self.input.seek(SeekFrom::Start(entry.compressed_offset))?;
+    self.input.read_exact(&mut self.compressed[..])?;
+    decode_frame(&mut self.state,
+                 &self.compressed[..],
+                 &mut self.frame,
+                 Some(entry.decompressed_size as usize))?;
+    if self.frame.len() != entry.decompressed_size as usize {
+      return Err(invalid_table("brotli frame size does not match its seek table entry"));
+    }
+    self.frame_index = Some(index);
+    Ok(())
+  }
+}
+
+impl<R: Read + Seek> Read for SeekableDecompressor<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    let index = match self.table.frame_for_offset(self.pos) {
+      Some(index) => index,
+      None => return Ok(0),
+    };
+    self.load_frame(index)?;
+    let start = (self.pos - self.table.entries()[index].decompressed_offset) as usize;
+    let to_copy = ::core::cmp::min(buf.len(), self.frame.len() - start);
+    buf[..to_copy].clone_from_slice(&self.frame[start..start + to_copy]);
+    self.pos += to_copy as u64;
+    Ok(to_copy)
+  }
+}
+
+impl<R: Read + Seek> Seek for SeekableDecompressor<R> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    let (base, offset) = match pos {
+      SeekFrom::Start(offset) => {
+        self.pos = offset;
+        return Ok(offset);
+      }
+      SeekFrom::End(offset) => (self.table.decompressed_size(), offset),
+      SeekFrom::Current(offset) => (self.pos, offset),
+    };
+    let new_pos = if offset >= 0 {
+      base.checked_add(offset as u64)
+    } else {
+      base.checked_sub(offset.unsigned_abs())
+    };
+    match new_pos {
+      Some(new_pos) => {
+        self.pos = new_pos;
+        Ok(new_pos)
+      }
+      None => Err(Error::new(ErrorKind::InvalidInput,
+                             "invalid seek to a negative or overflowing position")),
+    }
+  }
+}
@@ -0,0 +1,150 @@
+// Recognizing brotli data from the first bytes of a stream.
+//
+// Brotli has no magic number, so sniff_header runs the decoder's own header parsing over a
+// prefix: the stream header, then metablock headers for as long as they can be read without
+// prefix codes, skipping the bytes of metadata and uncompressed metablocks in between.
+
+use core;
+use alloc::{AllocatedStackMemory, Allocator};
+use bit_reader;
+use decode::{DecodeMetaBlockLength, DecodeWindowBits, kBrotliLargeMaxWbits, kBrotliLargeMinWbits};
+use state::{BrotliDecoderErrorCode, BrotliState};
+
+/// How strongly a prefix suggests brotli data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HeaderConfidence {
+  /// The prefix is empty or cannot start a brotli stream.
+  None,
+  /// Only the window size could be read. Almost any first byte passes this.
+  Low,
+  /// A metablock header was read as well, but the prefix ends before anything that
+  /// follows it could be checked.
+  Medium,
+  /// The stream ended cleanly within the prefix, or a metablock header was read after a
+  /// metadata or uncompressed metablock whose padding bits were zero.
+  High,
+}
+
+/// What `sniff_header` learned from the start of a stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeaderInfo {
+  /// False if the prefix is empty or cannot start a brotli stream.
+  pub valid: bool,
+  /// Log2 of the window size. When the prefix ends inside a large window header this is
+  /// the largest window such a stream may use.
+  pub window_bits: u32,
+  /// The stream signals a large window, which `BrotliState::new_strict` rejects.
+  pub large_window: bool,
+  /// The stream ends within the prefix without producing any output.
+  pub empty: bool,
+  pub confidence: HeaderConfidence,
+}
+
+impl HeaderInfo {
+  /// Largest ring buffer a decoder allocates for the stream.
+  pub fn window_size(&self) -> usize {
+    1usize << self.window_bits
+  }
+}
+
+// Sniffing reads headers only, so the state it runs on never needs memory.
+struct NoAlloc;
+
+impl<T: 'static> Allocator<T> for NoAlloc {
+  type AllocatedMemory = AllocatedStackMemory<'static, T>;
+  fn alloc_cell(&mut self, _len: usize) -> AllocatedStackMemory<'static, T> {
+    AllocatedStackMemory::default()
+  }
+  fn free_cell(&mut self, _data: AllocatedStackMemory<'static, T>) {}
+}
+
+fn invalid(mut info: HeaderInfo) -> HeaderInfo {
+  info.valid = false;
+  info.confidence = HeaderConfidence::None;
+  info
+}
+
+/// Parses the headers at the start of `prefix`, which may be any leading part of a
+/// stream, without allocating or decoding any data.
+pub fn sniff_header(prefix: &[u8]) -> HeaderInfo {
+  let prefix = &prefix[..core::cmp::min(prefix.len(), u32::MAX as usize)];
+  let mut info = HeaderInfo {
+    valid: false,
+    window_bits: 0,
+    large_window: false,
+    empty: false,
+    confidence: HeaderConfidence::None,
+  };
+  let mut s = BrotliState::new(NoAlloc, NoAlloc, NoAlloc);
+  s.br.avail_in = prefix.len() as u32;
+  s.br.next_in = 0;
+  if !bit_reader::BrotliWarmupBitReader(&mut s.br, prefix) {
+    return info;
+  }
+  match DecodeWindowBits(&mut s.large_window, &mut s.window_bits, &mut s.br) {
+    BrotliDecoderErrorCode::BROTLI_DECODER_SUCCESS => {}
+    _ => return info,
+  }
+  info.valid = true;
+  info.large_window = s.large_window;
+  info.window_bits = s.window_bits;
+  info.confidence = HeaderConfidence::Low;
+  if s.large_window {
+    if !bit_reader::BrotliSafeReadBits(&mut s.br, 6, &mut s.window_bits, prefix) {
+      info.window_bits = kBrotliLargeMaxWbits;
+      return info;
+    }
+    if s.window_bits < kBrotliLargeMinWbits || s.window_bits > kBrotliLargeMaxWbits {
+      return invalid(info);
+    }
+    info.window_bits = s.window_bits;
+  }
+  let mut after_aligned_block = false;
+  let mut only_metadata = true;
+  loop {
+    match DecodeMetaBlockLength(&mut s, prefix) {
+      BrotliDecoderErrorCode::BROTLI_DECODER_SUCCESS => {}
+      BrotliDecoderErrorCode::BROTLI_DECODER_NEEDS_MORE_INPUT => return info,
+      _ => return invalid(info),
+    }
+    info.confidence = if after_aligned_block {
+      HeaderConfidence::High
+    } else {
+      HeaderConfidence::Medium
+    };
+    if s.is_metadata == 0 && s.is_uncompressed == 0 {
+      if s.is_last_metablock != 0 && s.meta_block_remaining_len == 0 {
+        if !bit_reader::BrotliJumpToByteBoundary(&mut s.br) {
+          return invalid(info);
+        }
+        info.empty = only_metadata;
+        info.confidence = HeaderConfidence::High;
+        return info;
+      }
+      // Anything else is a compressed metablock, whose prefix codes come next.
+      return info;
+    }
+    if !bit_reader::BrotliJumpToByteBoundary(&mut s.br) {
+      return invalid(info);
+    }
+    let mut skip = s.meta_block_remaining_len as u32;
+    if bit_reader::BrotliGetRemainingBytes(&s.br) < skip {
+      return info;
+    }
+    while skip != 0 && bit_reader::BrotliGetAvailableBits(&s.br) >= 8 {
+      bit_reader::BrotliDropBits(&mut s.br, 8);
+      skip -= 1;
+    }
+    s.br.next_in += skip;
+    s.br.avail_in -= skip;
+    if s.is_uncompressed != 0 {
+      only_metadata = false;
+    }
+    if s.is_last_metablock != 0 {
+      info.empty = only_metadata;
+      info.confidence = HeaderConfidence::High;
+      return info;
+    }
+    after_aligned_block = true;
+  }
+}
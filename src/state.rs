@@ -9,7 +9,12 @@ use context::kContextLookup;
 use bit_reader::{BrotliBitReader, BrotliGetAvailableBits, BrotliInitBitReader};
 use huffman::{BROTLI_HUFFMAN_MAX_CODE_LENGTH, BROTLI_HUFFMAN_MAX_CODE_LENGTHS_SIZE,
               BROTLI_HUFFMAN_MAX_TABLE_SIZE, HuffmanCode, HuffmanTreeGroup};
-use alloc::SliceWrapper;
+use alloc::{SliceWrapper, SliceWrapperMut};
+use options::DecompressorOptions;
+use transform::{TransformTable, kRfcTransforms};
+use stats::DecodeStats;
+use dictionary::{CompoundDictionary, SharedDictionary};
+use decode::kBrotliLargeMaxWbits;
 
 #[allow(dead_code)]
 pub enum WhichTreeGroup {
@@ -18,7 +23,7 @@ pub enum WhichTreeGroup {
   DISTANCE,
 }
 #[repr(C)]
-#[derive(Clone,Copy)]
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
 #[no_mangle]
 pub enum BrotliDecoderErrorCode{
   BROTLI_DECODER_NO_ERROR = 0,
@@ -26,6 +31,8 @@ pub enum BrotliDecoderErrorCode{
   BROTLI_DECODER_SUCCESS = 1,
   BROTLI_DECODER_NEEDS_MORE_INPUT = 2,
   BROTLI_DECODER_NEEDS_MORE_OUTPUT = 3,
+  /* Only with a work budget, see BrotliDecompressStreamWithBudget */
+  BROTLI_DECODER_YIELD = 4,
 
   /* Errors caused by invalid input */
   BROTLI_DECODER_ERROR_FORMAT_EXUBERANT_NIBBLE = -1,
@@ -45,7 +52,9 @@ pub enum BrotliDecoderErrorCode{
   BROTLI_DECODER_ERROR_FORMAT_PADDING_2 = -15,
   BROTLI_DECODER_ERROR_FORMAT_DISTANCE = -16,
 
-  /* -17..-18 codes are reserved */
+  /* -17 code is reserved */
+
+  BROTLI_DECODER_ERROR_COMPOUND_DICTIONARY = -18,
 
   BROTLI_DECODER_ERROR_DICTIONARY_NOT_SET = -19,
   BROTLI_DECODER_ERROR_INVALID_ARGUMENTS = -20,
@@ -63,6 +72,63 @@ pub enum BrotliDecoderErrorCode{
 
   /* "Impossible" states */
   BROTLI_DECODER_ERROR_UNREACHABLE = -31,
+
+  /* Caps set with BrotliState::set_caps */
+  BROTLI_DECODER_ERROR_CAP_METABLOCKS = -32,
+  BROTLI_DECODER_ERROR_CAP_METADATA = -33,
+  BROTLI_DECODER_ERROR_CAP_TREES = -34,
+  BROTLI_DECODER_ERROR_CAP_INPUT_PER_OUTPUT = -35,
+}
+
+/// How much work one call to `BrotliDecompressStreamWithBudget` may do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkBudget {
+  /// Bytes produced by commands and uncompressed metablocks. Decoding a context map counts
+  /// as many as it has entries, and building a Huffman tree of a tree group as many as its
+  /// table has.
+  OutputBytes(usize),
+  /// Commands decoded, with every metablock, context map and tree of a tree group counted
+  /// as one more.
+  Commands(usize),
+}
+
+/// Input the `max_input_per_output` cap of `DecodeCaps` lets through before it applies,
+/// enough for the headers of a small stream.
+#[allow(non_upper_case_globals)]
+pub const kInputPerOutputAllowance: u64 = 4096;
+
+/// Bounds on the work a stream can make the decoder do, whatever it decodes to; see
+/// `BrotliState::set_caps`. Every cap starts out unbounded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeCaps {
+  /// Metablocks in the stream, empty and metadata ones included.
+  pub max_metablocks: u64,
+  /// Metadata bytes in the stream, all metablocks together.
+  pub max_metadata_bytes: u64,
+  /// Huffman trees in each of a metablock's three tree groups, and block types in each
+  /// of its three categories. Checked as each count is read, before anything is
+  /// allocated for it.
+  pub max_trees_per_group: u32,
+  /// Input consumed per byte of output, beyond the first `kInputPerOutputAllowance`
+  /// bytes. Checked between metablocks.
+  pub max_input_per_output: u64,
+}
+
+impl Default for DecodeCaps {
+  fn default() -> Self {
+    DecodeCaps {
+      max_metablocks: u64::MAX,
+      max_metadata_bytes: u64::MAX,
+      max_trees_per_group: u32::MAX,
+      max_input_per_output: u64::MAX,
+    }
+  }
+}
+
+impl DecodeCaps {
+  pub fn new() -> Self {
+    DecodeCaps::default()
+  }
 }
 
 pub enum BrotliRunningState {
@@ -206,7 +272,9 @@ pub struct BrotliState<AllocU8: alloc::Allocator<u8>,
   // NOT NEEDED? the index below seems to supersede it pub literal_htree : AllocHC::AllocatedMemory,
   pub literal_htree_index: u8,
   pub dist_htree_index: u8,
-  pub large_window: bool,
+  pub large_window: bool, // cleared by DecodeWindowBits unless the stream uses a large window
+  pub large_window_allowed: bool, // whether large windows are accepted, see set_large_window
+  pub max_window_bits: u32, // larger windows are rejected, see set_max_window_bits
   pub should_wrap_ringbuffer: bool,
   pub error_code: BrotliDecoderErrorCode,
   pub repeat_code_len: u32,
@@ -252,8 +320,19 @@ pub struct BrotliState<AllocU8: alloc::Allocator<u8>,
   pub mtf_or_error_string: Result<[u8; 256], [u8; 256]>,
 
   // For custom dictionaries
-  pub custom_dict: AllocU8::AllocatedMemory,
+  pub custom_dict: AllocU8::AllocatedMemory, // freed once in the window, see keep_custom_dictionary
+  pub keep_custom_dict: bool,
   pub custom_dict_size: i32,
+  pub custom_dict_missing: bool, // the dictionary asked for could not be copied, see reset
+  pub shared_dict: Option<SharedDictionary>, // used in place of custom_dict, and kept across streams
+  // Raw dictionaries attached with attach_dictionary, addressed beyond the window
+  pub compound_dictionary: CompoundDictionary<AllocU8::AllocatedMemory>,
+  pub compound_dict_missing: bool, // a dictionary in the options could not be attached, see reset
+  pub compound_copy: Option<usize>, // dictionary offset an unfinished compound copy resumes from
+  // Custom transforms for dictionary references, all empty for the RFC 7932 set
+  pub transform_prefix_suffix: AllocU8::AllocatedMemory,
+  pub transform_triplets: AllocU8::AllocatedMemory,
+  pub transform_params: AllocU8::AllocatedMemory,
   // less used attributes are in the end of this struct */
   // States inside function calls
   pub substate_metablock_header: BrotliRunningMetablockHeaderState,
@@ -273,15 +352,43 @@ pub struct BrotliState<AllocU8: alloc::Allocator<u8>,
   pub context_map: AllocU8::AllocatedMemory,
   pub context_modes: AllocU8::AllocatedMemory,
   pub trivial_literal_contexts: [u32; 8],
+
+  // For BrotliDecompressStreamToSink
+  pub pipeline_emitted: usize, // absolute output position handed to the sink so far
+  pub pipeline_defer_copies: bool, // current metablock leaves back-references to the sink
+  pub pipeline_history_stale: bool, // ringbuffer misses bytes the sink reconstructed
+
+  // While set, the caller's output slice holds the window in place of the ringbuffer
+  pub flat_window: bool,
+  pub flat_window_offset: usize, // output offset of the first byte of the stream
+
+  pub skip_output_remaining: usize, // decoded bytes still to be dropped instead of written
+
+  pub work_budget: Option<WorkBudget>, // only during BrotliDecompressStreamWithBudget
+  pub work_spent: usize, // in the units of work_budget, since the current call began
+
+  pub caps: DecodeCaps, // see set_caps
+  pub metablocks: u64, // in the stream so far, counted against caps
+  pub metadata_bytes: u64, // likewise
+  pub metablock_output: u64, // sum of the lengths of the metablocks so far
+
+  pub stats: Option<DecodeStats>, // collected only once enable_stats is called
+
+  // For BrotliDecompressStreamWithProvenance
+  pub total_in: u64, // input bytes consumed by earlier calls
+  pub input_base: i64, // stream offset of the first byte of the input the bit reader reads
+  pub provenance_pos: u64, // output bytes described so far
+  pub provenance_bit_start: u64, // first bit of the next span
+  pub provenance_literals: i32, // literals of the current command not described yet
 }
-macro_rules! make_brotli_state {
- ($alloc_u8 : expr, $alloc_u32 : expr, $alloc_hc : expr, $custom_dict : expr, $custom_dict_len: expr) => (BrotliState::<AllocU8, AllocU32, AllocHC>{
+// The fields that belong to the stream being decoded, with the values it starts from.
+// make_brotli_state! builds a state out of them and reset() assigns them, so a field listed
+// here once starts every stream afresh. Calls `$callback!($($args)* ; field : value, ...)`.
+macro_rules! stream_start_fields {
+ ($callback : ident ! ($($args : tt)*)) => ($callback!($($args)* ;
             state : BrotliRunningState::BROTLI_STATE_UNINITED,
             loop_counter : 0,
             br : BrotliBitReader::default(),
-            alloc_u8 : $alloc_u8,
-            alloc_u32 : $alloc_u32,
-            alloc_hc : $alloc_hc,
             buffer : [0u8; 8],
             buffer_length : 0,
             pos : 0,
@@ -346,15 +453,12 @@ macro_rules! make_brotli_state {
            context_index : 0,
            max_run_length_prefix : 0,
            code : 0,
-           context_map_table : AllocHC::AllocatedMemory::default(),
 
            /* For InverseMoveToFrontTransform */
            mtf_upper_bound : 255,
            mtf_or_error_string : Ok([0; 256]),
 
-           /* For custom dictionaries */
-           custom_dict : $custom_dict,
-           custom_dict_size : $custom_dict_len as i32,
+           compound_copy : None,
 
            /* less used attributes are in the end of this struct */
            /* States inside function calls */
@@ -370,15 +474,68 @@ macro_rules! make_brotli_state {
            is_metadata : 0,
            size_nibbles : 0,
            window_bits : 0,
-           large_window: false,
            should_wrap_ringbuffer: false,
            error_code: BrotliDecoderErrorCode::BROTLI_DECODER_SUCCESS,
            num_literal_htrees : 0,
            context_map : AllocU8::AllocatedMemory::default(),
            context_modes : AllocU8::AllocatedMemory::default(),
            trivial_literal_contexts : [0u32; 8],
-        }
-    );
+           pipeline_emitted : 0,
+           pipeline_defer_copies : false,
+           pipeline_history_stale : false,
+           flat_window : false,
+           flat_window_offset : 0,
+           skip_output_remaining : 0,
+           work_spent : 0,
+           metablocks : 0,
+           metadata_bytes : 0,
+           metablock_output : 0,
+           total_in : 0,
+           input_base : 0,
+           provenance_pos : 0,
+           provenance_bit_start : 0,
+           provenance_literals : 0
+    ));
+}
+macro_rules! brotli_state_literal {
+ ($alloc_u8 : expr, $alloc_u32 : expr, $alloc_hc : expr, $custom_dict : expr, $custom_dict_len: expr ;
+  $($field : ident : $value : expr),*) => (BrotliState::<AllocU8, AllocU32, AllocHC>{
+           alloc_u8 : $alloc_u8,
+           alloc_u32 : $alloc_u32,
+           alloc_hc : $alloc_hc,
+           // always pre-allocated on state creation
+           context_map_table : AllocHC::AllocatedMemory::default(),
+
+           /* For custom dictionaries */
+           custom_dict : $custom_dict,
+           custom_dict_size : $custom_dict_len as i32,
+           custom_dict_missing : false,
+           keep_custom_dict : false,
+           shared_dict : None,
+           compound_dictionary : CompoundDictionary::default(),
+           compound_dict_missing : false,
+           transform_prefix_suffix : AllocU8::AllocatedMemory::default(),
+           transform_triplets : AllocU8::AllocatedMemory::default(),
+           transform_params : AllocU8::AllocatedMemory::default(),
+
+           large_window: false,
+           large_window_allowed: false,
+           max_window_bits : kBrotliLargeMaxWbits,
+           work_budget : None,
+           caps : DecodeCaps::default(),
+           stats : None,
+           $($field : $value),*
+    });
+}
+macro_rules! assign_fields {
+ ($target : expr ; $($field : ident : $value : expr),*) => ({
+    $($target.$field = $value;)*
+ });
+}
+macro_rules! make_brotli_state {
+ ($alloc_u8 : expr, $alloc_u32 : expr, $alloc_hc : expr, $custom_dict : expr, $custom_dict_len: expr) => (
+    stream_start_fields!(brotli_state_literal!($alloc_u8, $alloc_u32, $alloc_hc, $custom_dict, $custom_dict_len))
+ );
 }
 impl <'brotli_state,
       AllocU8 : alloc::Allocator<u8>,
@@ -388,7 +545,7 @@ impl <'brotli_state,
            alloc_u32 : AllocU32,
            alloc_hc : AllocHC) -> Self{
         let mut retval = make_brotli_state!(alloc_u8, alloc_u32, alloc_hc, AllocU8::AllocatedMemory::default(), 0);
-        retval.large_window = true;
+        retval.set_large_window(true);
         retval.context_map_table = retval.alloc_hc.alloc_cell(
           BROTLI_HUFFMAN_MAX_TABLE_SIZE as usize);
         BrotliInitBitReader(&mut retval.br);
@@ -402,20 +559,206 @@ impl <'brotli_state,
         let mut retval = make_brotli_state!(alloc_u8, alloc_u32, alloc_hc, custom_dict, custom_dict_len);
         retval.context_map_table = retval.alloc_hc.alloc_cell(
           BROTLI_HUFFMAN_MAX_TABLE_SIZE as usize);
-        retval.large_window =  true;
+        retval.set_large_window(true);
         BrotliInitBitReader(&mut retval.br);
         retval
     }
+    /// Creates a state that decodes against `dict` without copying it other than into
+    /// the window, so any number of states can share one dictionary.
+    pub fn new_with_shared_dictionary(alloc_u8 : AllocU8,
+           alloc_u32 : AllocU32,
+           alloc_hc : AllocHC,
+           dict: SharedDictionary) -> Self{
+        let mut retval = Self::new(alloc_u8, alloc_u32, alloc_hc);
+        retval.custom_dict_size = dict.len() as i32;
+        retval.shared_dict = Some(dict);
+        retval
+    }
     pub fn new_strict(alloc_u8 : AllocU8,
            alloc_u32 : AllocU32,
            alloc_hc : AllocHC) -> Self{
         let mut retval = make_brotli_state!(alloc_u8, alloc_u32, alloc_hc, AllocU8::AllocatedMemory::default(), 0);
         retval.context_map_table = retval.alloc_hc.alloc_cell(
           BROTLI_HUFFMAN_MAX_TABLE_SIZE as usize);
-        retval.large_window =  false;
+        retval.set_large_window(false);
         BrotliInitBitReader(&mut retval.br);
         retval
     }
+    /// Creates a state set up as `options` asks, with its dictionaries copied into memory
+    /// from `alloc_u8`.
+    pub fn new_with_options(mut alloc_u8 : AllocU8,
+           alloc_u32 : AllocU32,
+           alloc_hc : AllocHC,
+           options : &DecompressorOptions) -> Self{
+        let mut dict_failed = false;
+        let custom_dict = match options.custom_dictionary {
+            Some(dict) if options.shared_dictionary.is_none() => {
+                let mut custom_dict = alloc_u8.alloc_cell(dict.len());
+                if custom_dict.slice().len() == dict.len() {
+                    custom_dict.slice_mut().clone_from_slice(dict);
+                } else {
+                    dict_failed = true;
+                    alloc_u8.free_cell(core::mem::take(&mut custom_dict));
+                }
+                custom_dict
+            }
+            _ => AllocU8::AllocatedMemory::default(),
+        };
+        let mut retval = Self::new_with_custom_dictionary(alloc_u8, alloc_u32, alloc_hc, custom_dict);
+        if dict_failed {
+            // decoding without the dictionary would produce the wrong output, so every
+            // stream fails as if the window it starts could not be allocated
+            retval.custom_dict_missing = true;
+            retval.error_code = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_1;
+        }
+        retval.set_large_window(options.large_window);
+        retval.caps = options.caps;
+        if let Some(dict) = options.shared_dictionary {
+            retval.custom_dict_size = dict.len() as i32;
+            retval.shared_dict = Some(dict.clone());
+        }
+        for dict in options.compound_dictionaries {
+            if !retval.attach_dictionary_copy(dict) {
+                // distances into a dictionary left out would decode as static dictionary
+                // words, so every stream fails instead
+                retval.compound_dict_missing = true;
+                if !retval.custom_dict_missing {
+                    retval.error_code = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_COMPOUND_DICTIONARY;
+                }
+                break;
+            }
+        }
+        retval
+    }
+    /// Returns the state to the start of a new stream so it can decode another one
+    /// without reallocating its fixed tables. Allocators, shared and attached dictionaries,
+    /// custom transforms, window limits, caps and the work budget are kept; the rest is
+    /// as in a new state, and statistics, if enabled, start over. An owned custom
+    /// dictionary only applies to the next stream if `keep_custom_dictionary` was called.
+    pub fn reset(&mut self) {
+        self.BrotliStateCleanupAfterMetablock();
+        self.alloc_u8.free_cell(core::mem::take(&mut self.ringbuffer));
+        self.alloc_hc.free_cell(core::mem::take(&mut self.block_type_length_state.block_type_trees));
+        self.alloc_hc.free_cell(core::mem::take(&mut self.block_type_length_state.block_len_trees));
+        stream_start_fields!(assign_fields!(self));
+        self.large_window = self.large_window_allowed;
+        self.custom_dict_size = match self.shared_dict {
+            Some(ref dict) => dict.len() as i32,
+            None => self.custom_dict.slice().len() as i32,
+        };
+        if self.custom_dict_missing {
+            self.error_code = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_1;
+        } else if self.compound_dict_missing {
+            self.error_code = BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_COMPOUND_DICTIONARY;
+        }
+        if self.stats.is_some() {
+            self.stats = Some(DecodeStats::default());
+        }
+        BrotliInitBitReader(&mut self.br);
+    }
+    /// Attaches a raw dictionary after those attached before it, without copying it; see
+    /// `CompoundDictionary`. Fails, giving `dict` back to `alloc_u8`, once decoding has
+    /// started or when `kMaxCompoundDictionaries` are attached already.
+    pub fn attach_dictionary(&mut self, dict: AllocU8::AllocatedMemory) -> bool {
+        if !self.BrotliStateIsStreamStart() {
+            self.alloc_u8.free_cell(dict);
+            return false;
+        }
+        match self.compound_dictionary.attach(dict) {
+            Ok(()) => true,
+            Err(dict) => {
+                self.alloc_u8.free_cell(dict);
+                false
+            }
+        }
+    }
+    /// Like `attach_dictionary`, but copies `dict` into memory from `alloc_u8` first.
+    pub fn attach_dictionary_copy(&mut self, dict: &[u8]) -> bool {
+        let mut mem = self.alloc_u8.alloc_cell(dict.len());
+        if mem.slice().len() != dict.len() {
+            self.alloc_u8.free_cell(mem);
+            return false;
+        }
+        mem.slice_mut().clone_from_slice(dict);
+        self.attach_dictionary(mem)
+    }
+    /// Drops the next `n` bytes of output: they are still decoded into the window, since
+    /// later bytes may refer back to them, but never copied to the caller. `total_out`
+    /// keeps counting them, so it remains the position in the decompressed stream.
+    pub fn skip_output(&mut self, n: usize) {
+        self.skip_output_remaining = self.skip_output_remaining.saturating_add(n);
+    }
+    /// Replaces the RFC 7932 transforms that dictionary references go through with a
+    /// custom set, laid out as described by `transform::TransformTable`. Returns false
+    /// and keeps the current set if the table is not valid. Call it before decoding starts.
+    pub fn set_custom_transforms(&mut self,
+                                 prefix_suffix: AllocU8::AllocatedMemory,
+                                 triplets: AllocU8::AllocatedMemory,
+                                 params: AllocU8::AllocatedMemory) -> bool {
+        let valid = TransformTable {
+            prefix_suffix: prefix_suffix.slice(),
+            triplets: triplets.slice(),
+            params: params.slice(),
+        }.is_valid();
+        if !valid {
+            self.alloc_u8.free_cell(prefix_suffix);
+            self.alloc_u8.free_cell(triplets);
+            self.alloc_u8.free_cell(params);
+            return false;
+        }
+        self.alloc_u8.free_cell(core::mem::replace(&mut self.transform_prefix_suffix, prefix_suffix));
+        self.alloc_u8.free_cell(core::mem::replace(&mut self.transform_triplets, triplets));
+        self.alloc_u8.free_cell(core::mem::replace(&mut self.transform_params, params));
+        true
+    }
+    /// Sets whether streams may use the large window of Large Window Brotli, for this
+    /// stream and every one after a `reset`.
+    pub fn set_large_window(&mut self, allowed: bool) {
+        self.large_window = allowed;
+        self.large_window_allowed = allowed;
+    }
+    /// Keeps an owned custom dictionary once it is copied into the window, so streams
+    /// after a `reset` decode against it as well. Otherwise it is given back to
+    /// `alloc_u8` as soon as the first stream's window is allocated.
+    pub fn keep_custom_dictionary(&mut self) {
+        self.keep_custom_dict = true;
+    }
+    /// Rejects streams whose window is larger than `1 << bits` with
+    /// `BROTLI_DECODER_ERROR_FORMAT_WINDOW_BITS`, before any memory is allocated for them.
+    pub fn set_max_window_bits(&mut self, bits: u32) {
+        self.max_window_bits = bits;
+    }
+    /// Fails streams that go over `caps` with the `BROTLI_DECODER_ERROR_CAP_*` code of the
+    /// cap, so that pathological input cannot burn time while producing little output.
+    /// Set it before decoding starts; it is kept across `reset`.
+    pub fn set_caps(&mut self, caps: DecodeCaps) {
+        self.caps = caps;
+    }
+    /// Starts counting what the stream is made of, from this point on.
+    pub fn enable_stats(&mut self) {
+        if self.stats.is_none() {
+            self.stats = Some(DecodeStats::default());
+        }
+    }
+    /// What the stream decoded so far is made of, if `enable_stats` was called.
+    pub fn stats(&self) -> Option<&DecodeStats> {
+        self.stats.as_ref()
+    }
+    /// Returns the statistics collected so far and stops collecting them.
+    pub fn take_stats(&mut self) -> Option<DecodeStats> {
+        self.stats.take()
+    }
+    /// The transforms dictionary references go through.
+    pub fn transforms(&self) -> TransformTable<'_> {
+        if self.transform_triplets.slice().is_empty() {
+            return kRfcTransforms;
+        }
+        TransformTable {
+            prefix_suffix: self.transform_prefix_suffix.slice(),
+            triplets: self.transform_triplets.slice(),
+            params: self.transform_params.slice(),
+        }
+    }
     pub fn BrotliStateMetablockBegin(self : &mut Self) {
         self.meta_block_remaining_len = 0;
         self.block_type_length_state.block_length[0] = 1u32 << 24;
@@ -471,6 +814,10 @@ impl <'brotli_state,
                               AllocHC::AllocatedMemory::default()));
       self.alloc_u8.free_cell(core::mem::replace(&mut self.custom_dict,
                               AllocU8::AllocatedMemory::default()));
+      self.compound_dictionary.free(&mut self.alloc_u8);
+      self.alloc_u8.free_cell(core::mem::take(&mut self.transform_prefix_suffix));
+      self.alloc_u8.free_cell(core::mem::take(&mut self.transform_triplets));
+      self.alloc_u8.free_cell(core::mem::take(&mut self.transform_params));
 
       //FIXME??  BROTLI_FREE(s, s->legacy_input_buffer);
       //FIXME??  BROTLI_FREE(s, s->legacy_output_buffer);
@@ -0,0 +1,261 @@
+// A decoder for targets without a heap, with all of its memory fixed at compile time.
+//
+// A StaticDecompressor holds its ringbuffer and Huffman table buffers next to the
+// BrotliState that allocates out of them through a DecoderArena, so it keeps no Allocator
+// type parameters, and it caps the window at what the buffers were sized for. `new` leaves
+// the state unbuilt and everything else zeroed, so a `static` of it lands in .bss. The
+// state holds slices of the buffers beside it, so it is only built once the decoder is
+// pinned, on the first call, and the decoder's calls all go through `Pin`.
+
+use core::marker::PhantomPinned;
+use core::mem::MaybeUninit;
+use core::pin::Pin;
+use core::ptr;
+use arena::{ArenaAllocator, DecoderArena, memory_requirement};
+use decode::{BrotliDecoderHasMoreOutput, BrotliDecoderIsFinished, BrotliDecoderIsUsed,
+             BrotliDecoderTakeOutput, BrotliDecompressStream, BrotliDecompressStreamToSink,
+             BrotliDecompressStreamWithDictionary, BrotliDecompressStreamWithProvenance,
+             BrotliResult};
+use huffman::HuffmanCode;
+use pipeline::CommandSink;
+use provenance::ProvenanceSink;
+use state::{BrotliDecoderErrorCode, BrotliState};
+
+/// Names a window of `1 << BITS` bytes as a type; see `StaticWindow`.
+pub struct WindowBits<const BITS: u32>;
+
+/// The u8 memory a `StaticDecompressor` needs for one largest window, implemented by
+/// `WindowBits` for every window the format has.
+pub trait StaticWindow {
+  type Buffer: AsMut<[u8]>;
+  const EMPTY: Self::Buffer;
+}
+
+macro_rules! static_window {
+  ($($bits: expr),*) => {
+    $(
+      impl StaticWindow for WindowBits<$bits> {
+        type Buffer = [u8; memory_requirement($bits, 0).u8_len];
+        const EMPTY: Self::Buffer = [0; memory_requirement($bits, 0).u8_len];
+      }
+    )*
+  };
+}
+
+static_window!(10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30);
+
+#[allow(non_upper_case_globals)]
+const kStaticU32Len: usize = memory_requirement(0, 0).u32_len;
+#[allow(non_upper_case_globals)]
+const kStaticHuffmanLen: usize = memory_requirement(0, 0).huffman_code_len;
+
+/// The state of a `StaticDecompressor`, allocating out of the buffers of the decoder.
+pub type StaticBrotliState = BrotliState<ArenaAllocator<'static, u8>,
+                                         ArenaAllocator<'static, u32>,
+                                         ArenaAllocator<'static, HuffmanCode>>;
+
+/// A decoder whose state and memory are one struct, sized for windows of up to
+/// `1 << MAX_WINDOW_BITS` bytes. It never allocates: streams with a larger window fail with
+/// `BROTLI_DECODER_ERROR_FORMAT_WINDOW_BITS`, and within it decoding never runs out of
+/// memory. `new` is const and zeroes it, so it can be a `static` in .bss.
+///
+/// Its state holds slices of its own buffers, so the decoder is used pinned: a `static` of
+/// it through `Pin::static_mut`, or elsewhere through `Box::pin` or `core::pin::pin!`. Its
+/// calls are those of `BrotliDecompressStream` and its siblings, on the decoder itself.
+pub struct StaticDecompressor<const MAX_WINDOW_BITS: u32>
+  where WindowBits<MAX_WINDOW_BITS>: StaticWindow
+{
+  u8_buffer: <WindowBits<MAX_WINDOW_BITS> as StaticWindow>::Buffer,
+  u32_buffer: [u32; kStaticU32Len],
+  huffman_buffer: [HuffmanCode; kStaticHuffmanLen],
+  // built over the buffers above by the first call, see built_state
+  state: MaybeUninit<StaticBrotliState>,
+  state_built: bool,
+  _pinned: PhantomPinned,
+}
+
+impl<const MAX_WINDOW_BITS: u32> StaticDecompressor<MAX_WINDOW_BITS>
+  where WindowBits<MAX_WINDOW_BITS>: StaticWindow
+{
+  pub const fn new() -> Self {
+    StaticDecompressor {
+      u8_buffer: <WindowBits<MAX_WINDOW_BITS> as StaticWindow>::EMPTY,
+      u32_buffer: [0; kStaticU32Len],
+      huffman_buffer: [HuffmanCode { value: 0, bits: 0 }; kStaticHuffmanLen],
+      state: MaybeUninit::uninit(),
+      state_built: false,
+      _pinned: PhantomPinned,
+    }
+  }
+
+  // Builds the state on first use. The slices it is handed last as long as the decoder:
+  // the decoder is pinned, so the buffers never move, and drop gives up the state first.
+  fn built_state(self: Pin<&mut Self>) -> &mut StaticBrotliState {
+    let this = unsafe { self.get_unchecked_mut() };
+    if !this.state_built {
+      let u8_buffer: &'static mut [u8] = unsafe { &mut *(this.u8_buffer.as_mut() as *mut [u8]) };
+      let u32_buffer: &'static mut [u32] = unsafe { &mut *(&mut this.u32_buffer[..] as *mut [u32]) };
+      let huffman_buffer: &'static mut [HuffmanCode] =
+        unsafe { &mut *(&mut this.huffman_buffer[..] as *mut [HuffmanCode]) };
+      let arena = match DecoderArena::new(MAX_WINDOW_BITS, 0, u8_buffer, u32_buffer, huffman_buffer) {
+        Some(arena) => arena,
+        None => unreachable!(), // the buffers are sized by memory_requirement
+      };
+      let (alloc_u8, alloc_u32, alloc_hc) = arena.into_allocators();
+      let mut state = BrotliState::new(alloc_u8, alloc_u32, alloc_hc);
+      state.set_max_window_bits(MAX_WINDOW_BITS);
+      this.state = MaybeUninit::new(state);
+      this.state_built = true;
+    }
+    unsafe { &mut *this.state.as_mut_ptr() }
+  }
+
+  /// See `BrotliDecompressStream`.
+  #[allow(clippy::too_many_arguments)]
+  pub fn decompress_stream(self: Pin<&mut Self>,
+                           available_in: &mut usize,
+                           input_offset: &mut usize,
+                           input: &[u8],
+                           available_out: &mut usize,
+                           output_offset: &mut usize,
+                           output: &mut [u8],
+                           total_out: &mut usize)
+                           -> BrotliResult {
+    BrotliDecompressStream(available_in,
+                           input_offset,
+                           input,
+                           available_out,
+                           output_offset,
+                           output,
+                           total_out,
+                           self.built_state())
+  }
+
+  /// See `BrotliDecompressStreamWithDictionary`. The dictionary is borrowed, so it takes
+  /// none of the decoder's memory.
+  #[allow(clippy::too_many_arguments)]
+  pub fn decompress_stream_with_dictionary(self: Pin<&mut Self>,
+                                           available_in: &mut usize,
+                                           input_offset: &mut usize,
+                                           input: &[u8],
+                                           available_out: &mut usize,
+                                           output_offset: &mut usize,
+                                           output: &mut [u8],
+                                           total_out: &mut usize,
+                                           dict: &[u8])
+                                           -> BrotliResult {
+    BrotliDecompressStreamWithDictionary(available_in,
+                                         input_offset,
+                                         input,
+                                         available_out,
+                                         output_offset,
+                                         output,
+                                         total_out,
+                                         self.built_state(),
+                                         dict)
+  }
+
+  /// See `BrotliDecompressStreamToSink`.
+  pub fn decompress_stream_to_sink<S: CommandSink>(self: Pin<&mut Self>,
+                                                   available_in: &mut usize,
+                                                   input_offset: &mut usize,
+                                                   input: &[u8],
+                                                   total_out: &mut usize,
+                                                   sink: &mut S)
+                                                   -> BrotliResult {
+    BrotliDecompressStreamToSink(available_in, input_offset, input, total_out, self.built_state(), sink)
+  }
+
+  /// See `BrotliDecompressStreamWithProvenance`.
+  #[allow(clippy::too_many_arguments)]
+  pub fn decompress_stream_with_provenance<P: ProvenanceSink>(self: Pin<&mut Self>,
+                                                              available_in: &mut usize,
+                                                              input_offset: &mut usize,
+                                                              input: &[u8],
+                                                              available_out: &mut usize,
+                                                              output_offset: &mut usize,
+                                                              output: &mut [u8],
+                                                              total_out: &mut usize,
+                                                              recorder: &mut P)
+                                                              -> BrotliResult {
+    BrotliDecompressStreamWithProvenance(available_in,
+                                         input_offset,
+                                         input,
+                                         available_out,
+                                         output_offset,
+                                         output,
+                                         total_out,
+                                         self.built_state(),
+                                         recorder)
+  }
+
+  /// See `BrotliDecoderTakeOutput`.
+  pub fn take_output(self: Pin<&mut Self>, size: &mut usize) -> &[u8] {
+    BrotliDecoderTakeOutput(self.built_state(), size)
+  }
+
+  pub fn has_more_output(&self) -> bool {
+    self.state().map_or(false, BrotliDecoderHasMoreOutput)
+  }
+
+  pub fn is_used(&self) -> bool {
+    self.state().map_or(false, BrotliDecoderIsUsed)
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.state().map_or(false, BrotliDecoderIsFinished)
+  }
+
+  pub fn error_code(&self) -> BrotliDecoderErrorCode {
+    self.state().map_or(BrotliDecoderErrorCode::BROTLI_DECODER_SUCCESS, |state| state.error_code)
+  }
+
+  /// See `BrotliState::reset`.
+  pub fn reset(self: Pin<&mut Self>) {
+    self.built_state().reset()
+  }
+
+  /// See `BrotliState::skip_output`.
+  pub fn skip_output(self: Pin<&mut Self>, n: usize) {
+    self.built_state().skip_output(n)
+  }
+
+  /// The state, once the first call has built it.
+  pub fn state(&self) -> Option<&StaticBrotliState> {
+    if self.state_built {
+      Some(unsafe { &*self.state.as_ptr() })
+    } else {
+      None
+    }
+  }
+
+  /// The state, built if no call has built it yet, to set it up or decode with the
+  /// functions that take one.
+  ///
+  /// # Safety
+  ///
+  /// The state's memory and allocators belong to the decoder's buffers: they must stay in
+  /// the state, not be moved out of it or replaced.
+  pub unsafe fn state_mut(self: Pin<&mut Self>) -> &mut StaticBrotliState {
+    self.built_state()
+  }
+}
+
+impl<const MAX_WINDOW_BITS: u32> Default for StaticDecompressor<MAX_WINDOW_BITS>
+  where WindowBits<MAX_WINDOW_BITS>: StaticWindow
+{
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<const MAX_WINDOW_BITS: u32> Drop for StaticDecompressor<MAX_WINDOW_BITS>
+  where WindowBits<MAX_WINDOW_BITS>: StaticWindow
+{
+  fn drop(&mut self) {
+    if self.state_built {
+      self.state_built = false;
+      unsafe { ptr::drop_in_place(self.state.as_mut_ptr()) };
+    }
+  }
+}
@@ -0,0 +1,119 @@
+use core::fmt;
+use transform::kMaxTransforms;
+
+/// What a brotli stream is made of, counted by a `BrotliState` once
+/// `BrotliState::enable_stats` is called and read back with `BrotliState::stats`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DecodeStats {
+  pub compressed_metablocks: u64,
+  pub uncompressed_metablocks: u64,
+  pub metadata_metablocks: u64,
+  /// Metablocks of no data, such as the empty last metablock that ends many streams.
+  pub empty_metablocks: u64,
+  /// Bytes inserted as literals by commands.
+  pub literal_bytes: u64,
+  /// Bytes copied from earlier output or the custom dictionary.
+  pub copied_bytes: u64,
+  /// Bytes stored in uncompressed metablocks.
+  pub uncompressed_bytes: u64,
+  /// Bytes produced from static dictionary words, after their transforms.
+  pub dictionary_bytes: u64,
+  /// Static dictionary references, indexed by transform.
+  pub dictionary_references: [u64; kMaxTransforms],
+  /// Backward references that start in the custom dictionary or in an attached one.
+  pub custom_dictionary_references: u64,
+  /// Distances taken from the distance cache, indexed by short code. Commands that reuse
+  /// the last distance without coding one count as short code 0.
+  pub distance_cache_hits: [u64; 16],
+  /// Block switches for literals, commands and distances.
+  pub block_switches: [u64; 3],
+  /// Prefix codes read for literals, commands and distances.
+  pub huffman_trees: [u64; 3],
+  /// Memory allocated for the lookup tables of those prefix codes, over all metablocks.
+  pub huffman_table_bytes: u64,
+  /// Largest table memory of any one metablock.
+  pub peak_huffman_table_bytes: u64,
+  /// Memory of the ringbuffer, with its slack. 0 while the caller's output is the window.
+  pub ring_buffer_bytes: u64,
+  /// Largest memory for the tree groups of any one metablock: their tables and where
+  /// each tree starts.
+  pub peak_tree_group_bytes: u64,
+  /// Largest memory for the context modes and the literal and distance context maps of
+  /// any one metablock.
+  pub peak_context_map_bytes: u64,
+}
+
+impl Default for DecodeStats {
+  fn default() -> Self {
+    DecodeStats {
+      compressed_metablocks: 0,
+      uncompressed_metablocks: 0,
+      metadata_metablocks: 0,
+      empty_metablocks: 0,
+      literal_bytes: 0,
+      copied_bytes: 0,
+      uncompressed_bytes: 0,
+      dictionary_bytes: 0,
+      dictionary_references: [0; kMaxTransforms],
+      custom_dictionary_references: 0,
+      distance_cache_hits: [0; 16],
+      block_switches: [0; 3],
+      huffman_trees: [0; 3],
+      huffman_table_bytes: 0,
+      peak_huffman_table_bytes: 0,
+      ring_buffer_bytes: 0,
+      peak_tree_group_bytes: 0,
+      peak_context_map_bytes: 0,
+    }
+  }
+}
+
+impl DecodeStats {
+  pub fn new() -> Self {
+    DecodeStats::default()
+  }
+
+  /// Static dictionary references through any transform.
+  pub fn total_dictionary_references(&self) -> u64 {
+    self.dictionary_references.iter().sum()
+  }
+
+  /// Decompressed size of the stream so far.
+  pub fn output_bytes(&self) -> u64 {
+    self.literal_bytes + self.copied_bytes + self.uncompressed_bytes + self.dictionary_bytes
+  }
+}
+
+impl fmt::Debug for DecodeStats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    // only the transforms that occur, the full array is mostly zeroes
+    struct UsedTransforms<'a>(&'a [u64]);
+    impl<'a> fmt::Debug for UsedTransforms<'a> {
+      fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map()
+         .entries(self.0.iter().enumerate().filter(|&(_, count)| *count != 0))
+         .finish()
+      }
+    }
+    f.debug_struct("DecodeStats")
+     .field("compressed_metablocks", &self.compressed_metablocks)
+     .field("uncompressed_metablocks", &self.uncompressed_metablocks)
+     .field("metadata_metablocks", &self.metadata_metablocks)
+     .field("empty_metablocks", &self.empty_metablocks)
+     .field("literal_bytes", &self.literal_bytes)
+     .field("copied_bytes", &self.copied_bytes)
+     .field("uncompressed_bytes", &self.uncompressed_bytes)
+     .field("dictionary_bytes", &self.dictionary_bytes)
+     .field("dictionary_references", &UsedTransforms(&self.dictionary_references[..]))
+     .field("custom_dictionary_references", &self.custom_dictionary_references)
+     .field("distance_cache_hits", &self.distance_cache_hits)
+     .field("block_switches", &self.block_switches)
+     .field("huffman_trees", &self.huffman_trees)
+     .field("huffman_table_bytes", &self.huffman_table_bytes)
+     .field("peak_huffman_table_bytes", &self.peak_huffman_table_bytes)
+     .field("ring_buffer_bytes", &self.ring_buffer_bytes)
+     .field("peak_tree_group_bytes", &self.peak_tree_group_bytes)
+     .field("peak_context_map_bytes", &self.peak_context_map_bytes)
+     .finish()
+  }
+}
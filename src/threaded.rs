@@ -0,0 +1,217 @@
+#![cfg(feature="std")]
+use std::io::{self, Error, ErrorKind, Read};
+use std::panic;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::vec::Vec;
+use reader::Decompressor;
+
+// number of output chunks in flight between the worker and the consumer:
+// one being filled by the worker while the other is drained by the caller
+const NUM_CHUNKS: usize = 2;
+
+enum Chunk {
+  Data(Vec<u8>),
+  Err(Error),
+  Eof,
+}
+
+/// Decompresses a brotli stream on a background thread.
+///
+/// The worker reads compressed input and runs the decoder into one output
+/// chunk while the caller drains the other, so at most two chunks of
+/// `chunk_size` bytes are buffered at any time. Errors from the underlying
+/// reader or from invalid data are returned from `read` in stream order, and
+/// a panic on the worker thread is resumed on the calling thread.
+///
+/// Dropping the decompressor stops the worker and joins it; a worker blocked
+/// inside the underlying reader is joined once that read returns.
+pub struct ThreadedDecompressor<R: Read + Send + 'static> {
+  filled: Option<Receiver<Chunk>>,
+  recycle: Option<Sender<Vec<u8>>>,
+  shutdown: Arc<AtomicBool>,
+  worker: Option<JoinHandle<()>>,
+  current: Vec<u8>,
+  current_offset: usize,
+  finished: bool,
+  failure: Option<ErrorKind>,
+  _reader: ::core::marker::PhantomData<R>,
+}
+
+fn fill_chunk<R: Read>(decompressor: &mut Decompressor<R>,
+                       chunk: &mut Vec<u8>)
+                       -> io::Result<bool> {
+  let capacity = chunk.capacity();
+  chunk.resize(capacity, 0);
+  let mut len: usize = 0;
+  let mut eof = false;
+  while len < capacity {
+    match decompressor.read(&mut chunk[len..]) {
+      Ok(0) => {
+        eof = true;
+        break;
+      }
+      Ok(size) => len += size,
+      Err(e) => {
+        if let ErrorKind::Interrupted = e.kind() {
+          continue;
+        }
+        chunk.truncate(len);
+        return Err(e);
+      }
+    }
+  }
+  chunk.truncate(len);
+  Ok(eof)
+}
+
+fn worker_loop<R: Read>(mut decompressor: Decompressor<R>,
+                        filled: SyncSender<Chunk>,
+                        recycle: Receiver<Vec<u8>>,
+                        shutdown: Arc<AtomicBool>) {
+  while !shutdown.load(Ordering::Relaxed) {
+    let mut chunk = match recycle.recv() {
+      Ok(chunk) => chunk,
+      Err(_) => return, // consumer hung up
+    };
+    match fill_chunk(&mut decompressor, &mut chunk) {
+      Ok(eof) => {
+        if !chunk.is_empty() && filled.send(Chunk::Data(chunk)).is_err() {
+          return;
+        }
+        if eof {
+          let _ = filled.send(Chunk::Eof);
+          return;
+        }
+      }
+      Err(e) => {
+        // hand over whatever was decoded before the failure first
+        if !chunk.is_empty() && filled.send(Chunk::Data(chunk)).is_err() {
+          return;
+        }
+        let _ = filled.send(Chunk::Err(e));
+        return;
+      }
+    }
+  }
+}
+
+impl<R: Read + Send + 'static> ThreadedDecompressor<R> {
+  pub fn new(r: R, buffer_size: usize) -> Self {
+    Self::new_with_chunk_size(r, buffer_size, buffer_size)
+  }
+  pub fn new_with_chunk_size(r: R, buffer_size: usize, chunk_size: usize) -> Self {
+    let chunk_size = if chunk_size == 0 { 4096 } else { chunk_size };
+    let (filled_send, filled_recv) = sync_channel::<Chunk>(NUM_CHUNKS + 1);
+    let (recycle_send, recycle_recv) = channel::<Vec<u8>>();
+    for _ in 0..NUM_CHUNKS {
+      recycle_send.send(Vec::with_capacity(chunk_size)).unwrap();
+    }
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let worker_shutdown = shutdown.clone();
+    let worker = thread::spawn(move || {
+      let decompressor = Decompressor::new(r, buffer_size);
+      worker_loop(decompressor, filled_send, recycle_recv, worker_shutdown)
+    });
+    ThreadedDecompressor::<R> {
+      filled: Some(filled_recv),
+      recycle: Some(recycle_send),
+      shutdown,
+      worker: Some(worker),
+      current: Vec::new(),
+      current_offset: 0,
+      finished: false,
+      failure: None,
+      _reader: ::core::marker::PhantomData,
+    }
+  }
+
+  // Blocks until the worker hands over the next chunk, or returns false at end of stream.
+  fn next_chunk(&mut self) -> io::Result<bool> {
+    let spent = ::core::mem::take(&mut self.current);
+    self.current_offset = 0;
+    if spent.capacity() != 0 {
+      if let Some(ref recycle) = self.recycle {
+        let _ = recycle.send(spent);
+      }
+    }
+    let message = match self.filled {
+      Some(ref filled) => filled.recv(),
+      None => return Ok(false),
+    };
+    match message {
+      Ok(Chunk::Data(data)) => {
+        self.current = data;
+        Ok(true)
+      }
+      Ok(Chunk::Eof) => {
+        self.finish();
+        Ok(false)
+      }
+      Ok(Chunk::Err(e)) => {
+        self.finish();
+        self.failure = Some(e.kind());
+        Err(e)
+      }
+      Err(_) => {
+        // the worker went away without a final message: it must have panicked
+        self.filled = None;
+        self.recycle = None;
+        if let Some(worker) = self.worker.take() {
+          if let Err(payload) = worker.join() {
+            panic::resume_unwind(payload);
+          }
+        }
+        self.finished = true;
+        self.failure = Some(ErrorKind::Other);
+        Err(Error::new(ErrorKind::Other, "brotli worker thread exited unexpectedly"))
+      }
+    }
+  }
+
+  fn finish(&mut self) {
+    self.finished = true;
+    self.shutdown.store(true, Ordering::Relaxed);
+    self.filled = None;
+    self.recycle = None;
+    if let Some(worker) = self.worker.take() {
+      let _ = worker.join();
+    }
+  }
+}
+
+impl<R: Read + Send + 'static> Read for ThreadedDecompressor<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    while self.current_offset == self.current.len() {
+      if let Some(kind) = self.failure {
+        return Err(Error::new(kind, "brotli stream already failed"));
+      }
+      if self.finished {
+        return Ok(0);
+      }
+      match self.next_chunk() {
+        Ok(true) => {}
+        Ok(false) => return Ok(0),
+        Err(e) => return Err(e),
+      }
+    }
+    let avail = &self.current[self.current_offset..];
+    let to_copy = ::core::cmp::min(avail.len(), buf.len());
+    buf[..to_copy].clone_from_slice(&avail[..to_copy]);
+    self.current_offset += to_copy;
+    Ok(to_copy)
+  }
+}
+
+impl<R: Read + Send + 'static> Drop for ThreadedDecompressor<R> {
+  fn drop(&mut self) {
+    // dropping both channel ends unblocks a worker waiting on either of them;
+    // a worker panic is not re-raised here since we may already be unwinding
+    self.finish();
+  }
+}
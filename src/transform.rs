@@ -1,26 +1,31 @@
 #![allow(non_upper_case_globals)]
+#[cfg(feature="std")]
+use std::vec::Vec;
+use dictionary::{kBrotliMaxDictionaryWordLength, kBrotliMinDictionaryWordLength, num_words, word};
 
-const kIdentity: u8 = 0;
-const kOmitLast1: u8 = 1;
-const kOmitLast2: u8 = 2;
-const kOmitLast3: u8 = 3;
-const kOmitLast4: u8 = 4;
-const kOmitLast5: u8 = 5;
-const kOmitLast6: u8 = 6;
-const kOmitLast7: u8 = 7;
-const kOmitLast8: u8 = 8;
-const kOmitLast9: u8 = 9;
-const kUppercaseFirst: u8 = 10;
-const kUppercaseAll: u8 = 11;
-const kOmitFirst1: u8 = 12;
-const kOmitFirst2: u8 = 13;
-const kOmitFirst3: u8 = 14;
-const kOmitFirst4: u8 = 15;
-const kOmitFirst5: u8 = 16;
-const kOmitFirst6: u8 = 17;
-const kOmitFirst7: u8 = 18;
-// const  kOmitFirst8     : u8 = 19; // <-- unused (reserved)
-const kOmitFirst9: u8 = 20;
+pub const kIdentity: u8 = 0;
+pub const kOmitLast1: u8 = 1;
+pub const kOmitLast2: u8 = 2;
+pub const kOmitLast3: u8 = 3;
+pub const kOmitLast4: u8 = 4;
+pub const kOmitLast5: u8 = 5;
+pub const kOmitLast6: u8 = 6;
+pub const kOmitLast7: u8 = 7;
+pub const kOmitLast8: u8 = 8;
+pub const kOmitLast9: u8 = 9;
+pub const kUppercaseFirst: u8 = 10;
+pub const kUppercaseAll: u8 = 11;
+pub const kOmitFirst1: u8 = 12;
+pub const kOmitFirst2: u8 = 13;
+pub const kOmitFirst3: u8 = 14;
+pub const kOmitFirst4: u8 = 15;
+pub const kOmitFirst5: u8 = 16;
+pub const kOmitFirst6: u8 = 17;
+pub const kOmitFirst7: u8 = 18;
+pub const kOmitFirst8: u8 = 19;
+pub const kOmitFirst9: u8 = 20;
+pub const kShiftFirst: u8 = 21;
+pub const kShiftAll: u8 = 22;
 
 
 pub struct Transform {
@@ -734,23 +739,75 @@ fn ToUpperCase(p: &mut [u8]) -> i32 {
   3
 }
 
+// Adds the sign-extended parameter to the scalar value of the utf-8 sequence at the start
+// of p, keeping its length, and returns the number of bytes it spans.
+fn Shift(p: &mut [u8], len: i32, parameter: u16) -> i32 {
+  // limited sign extension: the scalar stays below 1 << 24
+  let mut scalar: u32 = (parameter as u32 & 0x7fff) + (0x1000000 - (parameter as u32 & 0x8000));
+  if fast!((p)[0]) < 0x80 {
+    scalar += fast!((p)[0]) as u32;
+    fast_mut!((p)[0]) = (scalar & 0x7f) as u8;
+    1
+  } else if fast!((p)[0]) < 0xc0 {
+    // continuation byte
+    1
+  } else if fast!((p)[0]) < 0xe0 {
+    if len < 2 {
+      return 1;
+    }
+    scalar += (fast!((p)[1]) as u32 & 0x3f) | ((fast!((p)[0]) as u32 & 0x1f) << 6);
+    fast_mut!((p)[0]) = (0xc0 | ((scalar >> 6) & 0x1f)) as u8;
+    fast_mut!((p)[1]) = ((fast!((p)[1]) as u32 & 0xc0) | (scalar & 0x3f)) as u8;
+    2
+  } else if fast!((p)[0]) < 0xf0 {
+    if len < 3 {
+      return len;
+    }
+    scalar += (fast!((p)[2]) as u32 & 0x3f) | ((fast!((p)[1]) as u32 & 0x3f) << 6) |
+              ((fast!((p)[0]) as u32 & 0x0f) << 12);
+    fast_mut!((p)[0]) = (0xe0 | ((scalar >> 12) & 0x0f)) as u8;
+    fast_mut!((p)[1]) = ((fast!((p)[1]) as u32 & 0xc0) | ((scalar >> 6) & 0x3f)) as u8;
+    fast_mut!((p)[2]) = ((fast!((p)[2]) as u32 & 0xc0) | (scalar & 0x3f)) as u8;
+    3
+  } else if fast!((p)[0]) < 0xf8 {
+    if len < 4 {
+      return len;
+    }
+    scalar += (fast!((p)[3]) as u32 & 0x3f) | ((fast!((p)[2]) as u32 & 0x3f) << 6) |
+              ((fast!((p)[1]) as u32 & 0x3f) << 12) | ((fast!((p)[0]) as u32 & 0x07) << 18);
+    fast_mut!((p)[0]) = (0xf0 | ((scalar >> 18) & 0x07)) as u8;
+    fast_mut!((p)[1]) = ((fast!((p)[1]) as u32 & 0xc0) | ((scalar >> 12) & 0x3f)) as u8;
+    fast_mut!((p)[2]) = ((fast!((p)[2]) as u32 & 0xc0) | ((scalar >> 6) & 0x3f)) as u8;
+    fast_mut!((p)[3]) = ((fast!((p)[3]) as u32 & 0xc0) | (scalar & 0x3f)) as u8;
+    4
+  } else {
+    1
+  }
+}
+
 pub fn TransformDictionaryWord(dst: &mut [u8],
-                               mut word: &[u8],
-                               mut len: i32,
+                               word: &[u8],
+                               len: i32,
                                transform: i32)
                                -> i32 {
+  TransformDictionaryWordWithTable(dst, word, len, &kRfcTransforms, transform)
+}
+
+pub fn TransformDictionaryWordWithTable(dst: &mut [u8],
+                                        mut word: &[u8],
+                                        mut len: i32,
+                                        table: &TransformTable,
+                                        transform: i32)
+                                        -> i32 {
   let mut idx: i32 = 0;
   {
-    let prefix =
-      &fast!((kPrefixSuffix)[fast_inner!((kTransforms)[transform as usize]).prefix_id as usize;]);
-    while (fast!((prefix)[idx as usize]) != 0) {
-      fast_mut!((dst)[idx as usize]) = fast!((prefix)[idx as usize]);
-      idx += 1;
-    }
+    let prefix = table.prefix(transform as usize);
+    fast_mut!((dst)[0 ; prefix.len()]).clone_from_slice(prefix);
+    idx += prefix.len() as i32;
   }
   {
-    let t = fast_ref!((kTransforms)[transform as usize]).transform;
-    let mut skip: i32 = if t < kOmitFirst1 {
+    let t = table.transform_type(transform as usize);
+    let mut skip: i32 = if !(kOmitFirst1..=kOmitFirst9).contains(&t) {
       0
     } else {
       t as i32 - (kOmitFirst1 - 1) as i32
@@ -779,17 +836,303 @@ pub fn TransformDictionaryWord(dst: &mut [u8],
         uppercase_offset += step as usize;
         len -= step;
       }
+    } else if t == kShiftFirst && len > 0 {
+      Shift(uppercase, len, table.param(transform as usize));
+    } else if t == kShiftAll {
+      let param = table.param(transform as usize);
+      let mut shift_offset: usize = 0;
+      while (len > 0) {
+        let step = Shift(fast_mut!((uppercase)[shift_offset;]), len, param);
+        shift_offset += step as usize;
+        len -= step;
+      }
     }
   }
   {
-    let suffix =
-      &fast!((kPrefixSuffix)[fast_inner!((kTransforms)[transform as usize]).suffix_id as usize ; ]);
-    let mut i: usize = 0;
-    while (fast!((suffix)[i as usize]) != 0) {
-      fast_mut!((dst)[idx as usize]) = fast!((suffix)[i]);
-      idx += 1;
-      i += 1;
+    let suffix = table.suffix(transform as usize);
+    fast_mut!((dst)[idx as usize ; idx as usize + suffix.len()]).clone_from_slice(suffix);
+    idx + suffix.len() as i32
+  }
+}
+
+// the triplets of kTransforms, in the layout of TransformTable
+const fn RfcTransformTriplets() -> [u8; 3 * kNumTransforms as usize] {
+  let mut triplets = [0u8; 3 * kNumTransforms as usize];
+  let mut i = 0;
+  while i < kNumTransforms as usize {
+    triplets[3 * i] = kTransforms[i].prefix_id;
+    triplets[3 * i + 1] = kTransforms[i].transform;
+    triplets[3 * i + 2] = kTransforms[i].suffix_id;
+    i += 1;
+  }
+  triplets
+}
+
+const kRfcTransformTriplets: [u8; 3 * kNumTransforms as usize] = RfcTransformTriplets();
+
+/// Room beyond the length of the word that `apply_into` needs in its destination. It
+/// bounds the prefix and suffix of every transform a `TransformTable` may hold.
+pub const kMaxTransformOverhead: usize = 40;
+
+/// Most transforms a `TransformTable` may hold, as many as the shared-dictionary format
+/// allows.
+pub const kMaxTransforms: usize = 256;
+
+// ToUpperCase may write this far past the last byte of a word
+const kUppercaseOverrun: usize = 2;
+
+/// A set of transforms for dictionary references, laid out like the transforms of the
+/// shared-dictionary format: `triplets` holds a prefix offset, a transform type and a
+/// suffix offset per transform, the offsets pointing at NUL-terminated strings in
+/// `prefix_suffix`, and `params` holds a little-endian 16-bit parameter per transform for
+/// the shift types, or is empty when no transform shifts.
+#[derive(Clone, Copy, Debug)]
+pub struct TransformTable<'a> {
+  pub prefix_suffix: &'a [u8],
+  pub triplets: &'a [u8],
+  pub params: &'a [u8],
+}
+
+/// The 121 transforms of RFC 7932 appendix B.
+pub const kRfcTransforms: TransformTable<'static> = TransformTable {
+  prefix_suffix: &kPrefixSuffix,
+  triplets: &kRfcTransformTriplets,
+  params: &[],
+};
+
+impl<'a> TransformTable<'a> {
+  pub fn num_transforms(&self) -> usize {
+    self.triplets.len() / 3
+  }
+
+  fn affix(&self, offset: u8) -> &'a [u8] {
+    let affix = &self.prefix_suffix[offset as usize..];
+    let len = affix.iter().position(|b| *b == 0).unwrap_or(affix.len());
+    &affix[..len]
+  }
+
+  pub fn prefix(&self, transform_id: usize) -> &'a [u8] {
+    self.affix(self.triplets[3 * transform_id])
+  }
+
+  pub fn transform_type(&self, transform_id: usize) -> u8 {
+    self.triplets[3 * transform_id + 1]
+  }
+
+  pub fn suffix(&self, transform_id: usize) -> &'a [u8] {
+    self.affix(self.triplets[3 * transform_id + 2])
+  }
+
+  pub fn param(&self, transform_id: usize) -> u16 {
+    if self.params.is_empty() {
+      return 0;
+    }
+    self.params[2 * transform_id] as u16 | (self.params[2 * transform_id + 1] as u16) << 8
+  }
+
+  /// Checks that the table describes 1 to `kMaxTransforms` transforms, that every offset points at
+  /// a NUL-terminated string, that every type is known, that shift transforms have
+  /// parameters and that no prefix and suffix together exceed the decoder's slack.
+  pub fn is_valid(&self) -> bool {
+    let num_transforms = self.num_transforms();
+    if num_transforms == 0 || num_transforms > kMaxTransforms || 3 * num_transforms != self.triplets.len() {
+      return false;
+    }
+    if !self.params.is_empty() && self.params.len() != 2 * num_transforms {
+      return false;
+    }
+    let terminated = |offset: u8| self.prefix_suffix[offset as usize..].contains(&0);
+    for transform_id in 0..num_transforms {
+      let prefix_id = self.triplets[3 * transform_id];
+      let suffix_id = self.triplets[3 * transform_id + 2];
+      if prefix_id as usize >= self.prefix_suffix.len() || suffix_id as usize >= self.prefix_suffix.len() ||
+         !terminated(prefix_id) || !terminated(suffix_id) {
+        return false;
+      }
+      let t = self.transform_type(transform_id);
+      if t > kShiftAll || (t >= kShiftFirst && self.params.is_empty()) {
+        return false;
+      }
+      if self.prefix(transform_id).len() + self.suffix(transform_id).len() + kUppercaseOverrun >
+         kMaxTransformOverhead {
+        return false;
+      }
+    }
+    true
+  }
+
+  /// Applies transform `transform_id` to `word`, writing the result to the start of `dst`
+  /// and returning its length.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `transform_id >= self.num_transforms()` or if `dst` is shorter than
+  /// `word.len() + kMaxTransformOverhead`.
+  pub fn apply_into(&self, transform_id: usize, word: &[u8], dst: &mut [u8]) -> usize {
+    assert!(transform_id < self.num_transforms(), "no transform {}", transform_id);
+    assert!(dst.len() >= word.len() + kMaxTransformOverhead, "transform destination too short");
+    TransformDictionaryWordWithTable(dst, word, word.len() as i32, self, transform_id as i32) as usize
+  }
+
+  /// Applies transform `transform_id` to `word`.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `transform_id >= self.num_transforms()`.
+  #[cfg(feature="std")]
+  pub fn apply(&self, transform_id: usize, word: &[u8]) -> Vec<u8> {
+    let mut dst = vec![0u8; word.len() + kMaxTransformOverhead];
+    let len = self.apply_into(transform_id, word, &mut dst[..]);
+    dst.truncate(len);
+    dst
+  }
+
+  /// Finds every `(len, idx, transform)` whose dictionary reference decodes to
+  /// `transformed` under this table, ordered by transform, then word length, then index.
+  pub fn reverse_lookup<'b>(&self, transformed: &'b [u8]) -> ReverseLookup<'a, 'b> {
+    ReverseLookup {
+      table: *self,
+      transformed,
+      next_transform: 0,
+      transform: 0,
+      kept: (0, 0),
+      omit_first: 0,
+      loose_match: false,
+      len: 0,
+      max_len: 0,
+      idx: 0,
+    }
+  }
+
+  // The range of word lengths that transform t could turn into transformed, judging by
+  // its prefix, suffix and the number of bytes it omits from the word, and the bytes of
+  // transformed that come from the word.
+  fn candidate_lengths(&self, t: usize, transformed: &[u8]) -> Option<(usize, usize, (usize, usize))> {
+    let prefix = self.prefix(t);
+    let suffix = self.suffix(t);
+    if transformed.len() < prefix.len() + suffix.len() || !transformed.starts_with(prefix) ||
+       !transformed.ends_with(suffix) {
+      return None;
+    }
+    let kept_range = (prefix.len(), transformed.len() - suffix.len());
+    let kept = kept_range.1 - kept_range.0;
+    let omitted = omitted_bytes(self.transform_type(t));
+    if kept != 0 {
+      return Some((kept + omitted, kept + omitted, kept_range));
+    }
+    // words no longer than what the transform omits all vanish
+    if omitted < kBrotliMinDictionaryWordLength as usize {
+      return None;
+    }
+    Some((kBrotliMinDictionaryWordLength as usize, omitted, kept_range))
+  }
+}
+
+// how many bytes of the word a transform type drops
+fn omitted_bytes(t: u8) -> usize {
+  if t <= kOmitLast9 {
+    t as usize
+  } else if (kOmitFirst1..=kOmitFirst9).contains(&t) {
+    (t - (kOmitFirst1 - 1)) as usize
+  } else {
+    0
+  }
+}
+
+/// Applies transform `transform_id` of RFC 7932 appendix B to `word`, writing the result
+/// to the start of `dst` and returning its length.
+///
+/// # Panics
+///
+/// Panics if `transform_id >= kNumTransforms` or if `dst` is shorter than
+/// `word.len() + kMaxTransformOverhead`.
+pub fn apply_into(transform_id: usize, word: &[u8], dst: &mut [u8]) -> usize {
+  kRfcTransforms.apply_into(transform_id, word, dst)
+}
+
+/// Applies transform `transform_id` of RFC 7932 appendix B to `word`.
+///
+/// # Panics
+///
+/// Panics if `transform_id >= kNumTransforms`.
+#[cfg(feature="std")]
+pub fn apply(transform_id: usize, word: &[u8]) -> Vec<u8> {
+  kRfcTransforms.apply(transform_id, word)
+}
+
+/// A static dictionary word and the transform that turns it into the bytes of a
+/// dictionary reference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DictionaryReference {
+  pub len: usize,
+  pub idx: usize,
+  pub transform: usize,
+}
+
+/// Iterator over the dictionary references that produce a byte string, as returned by
+/// `reverse_lookup`.
+#[derive(Clone, Debug)]
+pub struct ReverseLookup<'a, 'b> {
+  table: TransformTable<'a>,
+  transformed: &'b [u8],
+  next_transform: usize,
+  transform: usize,
+  kept: (usize, usize), // range of transformed that comes from the word
+  omit_first: usize,
+  loose_match: bool, // whether the kept bytes may differ from the word by case alone
+  len: usize,
+  max_len: usize,
+  idx: usize,
+}
+
+/// Finds every `(len, idx, transform)` whose dictionary reference decodes to
+/// `transformed` under the RFC 7932 transforms, ordered by transform, then word length,
+/// then index.
+pub fn reverse_lookup<'b>(transformed: &'b [u8]) -> ReverseLookup<'static, 'b> {
+  kRfcTransforms.reverse_lookup(transformed)
+}
+
+impl<'a, 'b> Iterator for ReverseLookup<'a, 'b> {
+  type Item = DictionaryReference;
+  fn next(&mut self) -> Option<DictionaryReference> {
+    let mut scratch = [0u8; kBrotliMaxDictionaryWordLength as usize + kMaxTransformOverhead];
+    loop {
+      if self.idx < num_words(self.len) {
+        let idx = self.idx;
+        self.idx += 1;
+        let word = word(self.len, idx);
+        let skip = core::cmp::min(self.omit_first, self.len);
+        let kept = &self.transformed[self.kept.0..self.kept.1];
+        // uppercasing flips at most bits 0x20 and 0x05, so other differences rule the word out
+        if self.loose_match && word[skip..].iter().zip(kept.iter()).any(|(a, b)| (a ^ b) & !0x25 != 0) {
+          continue;
+        }
+        let size = self.table.apply_into(self.transform, word, &mut scratch[..]);
+        if scratch[..size] == *self.transformed {
+          return Some(DictionaryReference {
+            len: self.len,
+            idx,
+            transform: self.transform,
+          });
+        }
+      } else if self.len < self.max_len {
+        self.len += 1;
+        self.idx = 0;
+      } else if self.next_transform < self.table.num_transforms() {
+        self.transform = self.next_transform;
+        self.next_transform += 1;
+        let (len, max_len, kept) = self.table.candidate_lengths(self.transform, self.transformed)
+          .unwrap_or((0, 0, (0, 0)));
+        let t = self.table.transform_type(self.transform);
+        self.omit_first = if (kOmitFirst1..=kOmitFirst9).contains(&t) { omitted_bytes(t) } else { 0 };
+        self.loose_match = t < kShiftFirst;
+        self.kept = kept;
+        self.len = len;
+        self.max_len = max_len;
+        self.idx = 0;
+      } else {
+        return None;
+      }
     }
-    idx
   }
 }
@@ -6,11 +6,12 @@ pub use alloc_stdlib::StandardAlloc;
 pub use alloc_stdlib::HeapAllocUninitialized;
 pub use huffman::{HuffmanCode, HuffmanTreeGroup};
 pub use state::BrotliState;
+use options::DecompressorOptions;
 // use io_wrappers::write_all;
 pub use io_wrappers::{CustomWrite};
 #[cfg(feature="std")]
 pub use io_wrappers::{IntoIoWriter, IoWriterWrapper};
-pub use super::decode::{BrotliDecompressStream, BrotliResult};
+pub use super::decode::{BrotliDecompressStream, BrotliDecompressStreamWithDictionary, BrotliResult};
 pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 
 #[cfg(feature="std")]
@@ -18,10 +19,12 @@ pub struct DecompressorWriterCustomAlloc<W: Write,
      BufferType : SliceWrapperMut<u8>,
      AllocU8 : Allocator<u8>,
      AllocU32 : Allocator<u32>,
-     AllocHC : Allocator<HuffmanCode> >(DecompressorWriterCustomIo<io::Error,
+     AllocHC : Allocator<HuffmanCode>,
+     Dict : AsRef<[u8]> = &'static [u8]>(DecompressorWriterCustomIo<io::Error,
                                                              IntoIoWriter<W>,
                                                              BufferType,
-                                                             AllocU8, AllocU32, AllocHC>);
+                                                             AllocU8, AllocU32, AllocHC,
+                                                             Dict>);
 
 
 #[cfg(feature="std")]
@@ -51,6 +54,46 @@ impl<W: Write,
                                                                   Error::new(ErrorKind::InvalidData,
                                                                              "Invalid Data")))
     }
+    pub fn with_options(w: W, buffer : BufferType,
+               alloc_u8 : AllocU8, alloc_u32 : AllocU32, alloc_hc : AllocHC,
+               options: &DecompressorOptions) -> Self {
+        DecompressorWriterCustomAlloc::<W, BufferType, AllocU8, AllocU32, AllocHC>(
+          DecompressorWriterCustomIo::<Error,
+                                 IntoIoWriter<W>,
+                                 BufferType,
+                                 AllocU8, AllocU32, AllocHC>::with_options(IntoIoWriter::<W>(w),
+                                                                  buffer,
+                                                                  alloc_u8, alloc_u32, alloc_hc,
+                                                                  options,
+                                                                  Error::new(ErrorKind::InvalidData,
+                                                                             "Invalid Data")))
+    }
+}
+
+#[cfg(feature="std")]
+impl<W: Write,
+     BufferType : SliceWrapperMut<u8>,
+     AllocU8,
+     AllocU32,
+     AllocHC,
+     Dict : AsRef<[u8]>> DecompressorWriterCustomAlloc<W, BufferType, AllocU8, AllocU32, AllocHC, Dict>
+ where AllocU8 : Allocator<u8>, AllocU32 : Allocator<u32>, AllocHC : Allocator<HuffmanCode>
+    {
+    /// Decodes as `options` asks, against `dict` read in place; see
+    /// `DecompressorWriterCustomIo::with_dictionary`.
+    pub fn with_dictionary(w: W, buffer : BufferType,
+               alloc_u8 : AllocU8, alloc_u32 : AllocU32, alloc_hc : AllocHC,
+               dict: Dict,
+               options: &DecompressorOptions) -> Self {
+        DecompressorWriterCustomAlloc(
+          DecompressorWriterCustomIo::with_dictionary(IntoIoWriter::<W>(w),
+                                                      buffer,
+                                                      alloc_u8, alloc_u32, alloc_hc,
+                                                      dict,
+                                                      options,
+                                                      Error::new(ErrorKind::InvalidData,
+                                                                 "Invalid Data")))
+    }
 
     pub fn get_ref(&self) -> &W {
         &self.0.get_ref().0
@@ -64,11 +107,13 @@ impl<W: Write,
      BufferType : SliceWrapperMut<u8>,
      AllocU8 : Allocator<u8>,
      AllocU32 : Allocator<u32>,
-     AllocHC : Allocator<HuffmanCode> > Write for DecompressorWriterCustomAlloc<W,
-                                                                         BufferType,
-                                                                         AllocU8,
-                                                                         AllocU32,
-                                                                         AllocHC> {
+     AllocHC : Allocator<HuffmanCode>,
+     Dict : AsRef<[u8]> > Write for DecompressorWriterCustomAlloc<W,
+                                                                  BufferType,
+                                                                  AllocU8,
+                                                                  AllocU32,
+                                                                  AllocHC,
+                                                                  Dict> {
   	fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
        self.0.write(buf)
     }
@@ -79,12 +124,13 @@ impl<W: Write,
 
 
 #[cfg(not(any(feature="unsafe", not(feature="std"))))]
-pub struct DecompressorWriter<W: Write>(DecompressorWriterCustomAlloc<W,
+pub struct DecompressorWriter<W: Write, Dict: AsRef<[u8]> = &'static [u8]>(DecompressorWriterCustomAlloc<W,
                                                          <StandardAlloc
                                                           as Allocator<u8>>::AllocatedMemory,
                                                          StandardAlloc,
                                                          StandardAlloc,
-                                                         StandardAlloc>);
+                                                         StandardAlloc,
+                                                         Dict>);
 
 
 #[cfg(not(any(feature="unsafe", not(feature="std"))))]
@@ -107,6 +153,40 @@ impl<W: Write> DecompressorWriter<W> {
                                                                               StandardAlloc::default(),
                                                                               dict))
   }
+  /// Decodes as `options` asks, writing through a buffer of `options.buffer_size`.
+  pub fn with_options(w: W, options: &DecompressorOptions) -> Self {
+    let mut alloc = StandardAlloc::default();
+    let buffer = <StandardAlloc as Allocator<u8>>::alloc_cell(&mut alloc, options.buffer_size);
+    DecompressorWriter::<W>(DecompressorWriterCustomAlloc::<W,
+                                                <StandardAlloc
+                                                 as Allocator<u8>>::AllocatedMemory,
+                                                StandardAlloc,
+                                                StandardAlloc,
+                                                StandardAlloc>::with_options(w,
+                                                                             buffer,
+                                                                             alloc,
+                                                                             StandardAlloc::default(),
+                                                                             StandardAlloc::default(),
+                                                                             options))
+  }
+}
+
+#[cfg(not(any(feature="unsafe", not(feature="std"))))]
+impl<W: Write, Dict: AsRef<[u8]>> DecompressorWriter<W, Dict> {
+  /// Decodes as `options` asks, against `dict` rather than any dictionary in `options`.
+  /// `dict` is read in place for the whole stream, so a `DecompressorWriter<W, &'a [u8]>`
+  /// borrows its dictionary and a `DecompressorWriter<W, Arc<[u8]>>` shares one.
+  pub fn with_dictionary(w: W, dict: Dict, options: &DecompressorOptions) -> Self {
+    let mut alloc = StandardAlloc::default();
+    let buffer = <StandardAlloc as Allocator<u8>>::alloc_cell(&mut alloc, options.buffer_size);
+    DecompressorWriter(DecompressorWriterCustomAlloc::with_dictionary(w,
+                                                                      buffer,
+                                                                      alloc,
+                                                                      StandardAlloc::default(),
+                                                                      StandardAlloc::default(),
+                                                                      dict,
+                                                                      options))
+  }
 
   pub fn get_ref(&self) -> &W {
       self.0.get_ref()
@@ -118,12 +198,13 @@ impl<W: Write> DecompressorWriter<W> {
 
 
 #[cfg(all(feature="unsafe", feature="std"))]
-pub struct DecompressorWriter<W: Write>(DecompressorWriterCustomAlloc<W,
+pub struct DecompressorWriter<W: Write, Dict: AsRef<[u8]> = &'static [u8]>(DecompressorWriterCustomAlloc<W,
                                                          <HeapAllocUninitialized<u8>
                                                           as Allocator<u8>>::AllocatedMemory,
                                                          HeapAllocUninitialized<u8>,
                                                          HeapAllocUninitialized<u32>,
-                                                         HeapAllocUninitialized<HuffmanCode> >);
+                                                         HeapAllocUninitialized<HuffmanCode>,
+                                                         Dict>);
 
 
 #[cfg(all(feature="unsafe", feature="std"))]
@@ -145,6 +226,35 @@ impl<W: Write> DecompressorWriter<W> {
                                                 HeapAllocUninitialized<HuffmanCode> >
       ::new_with_custom_dictionary(w, buffer, alloc_u8, alloc_u32, alloc_hc, dict))
   }
+  /// Decodes as `options` asks, writing through a buffer of `options.buffer_size`.
+  pub fn with_options(w: W, options: &DecompressorOptions) -> Self {
+    let mut alloc_u8 = unsafe { HeapAllocUninitialized::<u8>::new() };
+    let buffer = alloc_u8.alloc_cell(options.buffer_size);
+    let alloc_u32 = unsafe { HeapAllocUninitialized::<u32>::new() };
+    let alloc_hc = unsafe { HeapAllocUninitialized::<HuffmanCode>::new() };
+    DecompressorWriter::<W>(DecompressorWriterCustomAlloc::<W,
+                                                <HeapAllocUninitialized<u8>
+                                                 as Allocator<u8>>::AllocatedMemory,
+                                                HeapAllocUninitialized<u8>,
+                                                HeapAllocUninitialized<u32>,
+                                                HeapAllocUninitialized<HuffmanCode> >
+      ::with_options(w, buffer, alloc_u8, alloc_u32, alloc_hc, options))
+  }
+}
+
+#[cfg(all(feature="unsafe", feature="std"))]
+impl<W: Write, Dict: AsRef<[u8]>> DecompressorWriter<W, Dict> {
+  /// Decodes as `options` asks, against `dict` rather than any dictionary in `options`.
+  /// `dict` is read in place for the whole stream, so a `DecompressorWriter<W, &'a [u8]>`
+  /// borrows its dictionary and a `DecompressorWriter<W, Arc<[u8]>>` shares one.
+  pub fn with_dictionary(w: W, dict: Dict, options: &DecompressorOptions) -> Self {
+    let mut alloc_u8 = unsafe { HeapAllocUninitialized::<u8>::new() };
+    let buffer = alloc_u8.alloc_cell(options.buffer_size);
+    let alloc_u32 = unsafe { HeapAllocUninitialized::<u32>::new() };
+    let alloc_hc = unsafe { HeapAllocUninitialized::<HuffmanCode>::new() };
+    DecompressorWriter(DecompressorWriterCustomAlloc::with_dictionary(w, buffer, alloc_u8, alloc_u32, alloc_hc,
+                                                                      dict, options))
+  }
 
   pub fn get_ref(&self) -> &W {
       self.0.get_ref()
@@ -156,7 +266,7 @@ impl<W: Write> DecompressorWriter<W> {
 
 
 #[cfg(feature="std")]
-impl<W: Write> Write for DecompressorWriter<W> {
+impl<W: Write, Dict: AsRef<[u8]>> Write for DecompressorWriter<W, Dict> {
   	fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
        self.0.write(buf)
     }
@@ -170,12 +280,17 @@ pub struct DecompressorWriterCustomIo<ErrType,
                                 BufferType: SliceWrapperMut<u8>,
                                 AllocU8: Allocator<u8>,
                                 AllocU32: Allocator<u32>,
-                                AllocHC: Allocator<HuffmanCode>>
+                                AllocHC: Allocator<HuffmanCode>,
+                                Dict: AsRef<[u8]> = &'static [u8]>
 {
   output_buffer: BufferType,
   total_out: usize,
   output: W,
   error_if_invalid_data: Option<ErrType>,
+  max_output_size: usize,
+  strict: bool,
+  // decoded against in place of any dictionary in the state; empty for none
+  dict: Dict,
   state: BrotliState<AllocU8, AllocU32, AllocHC>,
 }
 
@@ -219,29 +334,105 @@ impl<ErrType,
                                      alloc_hc,
                                      dict),
             error_if_invalid_data : Some(invalid_data_error_type),
+            max_output_size : usize::MAX,
+            strict : false,
+            dict : &[],
+        }
+    }
+    /// Decodes as `options` asks. The buffer is the one given here, whatever
+    /// `options.buffer_size` says.
+    pub fn with_options(w: W, buffer : BufferType,
+               alloc_u8 : AllocU8, alloc_u32 : AllocU32, alloc_hc : AllocHC,
+               options: &DecompressorOptions,
+               invalid_data_error_type : ErrType) -> Self {
+        DecompressorWriterCustomIo::<ErrType, W, BufferType, AllocU8, AllocU32, AllocHC>{
+            output_buffer : buffer,
+            total_out : 0,
+            output: w,
+            state : BrotliState::new_with_options(alloc_u8,
+                                     alloc_u32,
+                                     alloc_hc,
+                                     options),
+            error_if_invalid_data : Some(invalid_data_error_type),
+            max_output_size : options.limits.max_output_size,
+            strict : options.strict,
+            dict : &[],
+        }
+    }
+}
+
+impl<ErrType,
+     W: CustomWrite<ErrType>,
+     BufferType : SliceWrapperMut<u8>,
+     AllocU8,
+     AllocU32,
+     AllocHC,
+     Dict : AsRef<[u8]>> DecompressorWriterCustomIo<ErrType, W, BufferType, AllocU8, AllocU32, AllocHC, Dict>
+ where AllocU8 : Allocator<u8>, AllocU32 : Allocator<u32>, AllocHC : Allocator<HuffmanCode>
+{
+    /// Decodes as `options` asks, against `dict` rather than any dictionary in
+    /// `options`. The decoder reads `dict` in place for the whole stream instead of
+    /// copying it, so it can be borrowed as `&'a [u8]` or shared as an `Arc<[u8]>`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_dictionary(w: W, buffer : BufferType,
+               alloc_u8 : AllocU8, alloc_u32 : AllocU32, alloc_hc : AllocHC,
+               dict: Dict,
+               options: &DecompressorOptions,
+               invalid_data_error_type : ErrType) -> Self {
+        let options = DecompressorOptions {
+          custom_dictionary: None,
+          shared_dictionary: None,
+          ..*options
+        };
+        DecompressorWriterCustomIo {
+            output_buffer : buffer,
+            total_out : 0,
+            output: w,
+            state : BrotliState::new_with_options(alloc_u8,
+                                     alloc_u32,
+                                     alloc_hc,
+                                     &options),
+            error_if_invalid_data : Some(invalid_data_error_type),
+            max_output_size : options.limits.max_output_size,
+            strict : options.strict,
+            dict,
+        }
+    }
+    // Reports the invalid data error; writes after that are dropped.
+    fn invalid_data(&mut self) -> Result<usize, ErrType> {
+        match self.error_if_invalid_data.take() {
+            Some(e) => Err(e),
+            None => Ok(0),
         }
     }
     fn close(&mut self) -> Result<(), ErrType>{
         loop {
             let mut avail_in : usize = 0;
             let mut input_offset : usize = 0;
-            let mut avail_out : usize = self.output_buffer.slice_mut().len();
+            // the decoder may only fill what is left of max_output_size
+            let out_len = core::cmp::min(self.output_buffer.slice_mut().len(),
+                                         self.max_output_size - self.total_out);
+            let mut avail_out : usize = out_len;
             let mut output_offset : usize = 0;
-            let ret = BrotliDecompressStream(
+            let ret = BrotliDecompressStreamWithDictionary(
                 &mut avail_in,
                 &mut input_offset,
                 &[],
                 &mut avail_out,
                 &mut output_offset,
-                self.output_buffer.slice_mut(),                
+                &mut self.output_buffer.slice_mut()[..out_len],
                 &mut self.total_out,
-                &mut self.state);
+                &mut self.state,
+                self.dict.as_ref());
           match write_all(&mut self.output, &self.output_buffer.slice_mut()[..output_offset]) {
             Ok(_) => {},
             Err(e) => return Err(e),
            }
            match ret {
            BrotliResult::NeedsMoreInput => return Err(self.error_if_invalid_data.take().unwrap()),
+           BrotliResult::NeedsMoreOutput if self.total_out == self.max_output_size && output_offset == 0 => {
+             return self.invalid_data().map(|_| ());
+           },
            BrotliResult::NeedsMoreOutput => {},
            BrotliResult::ResultSuccess => return Ok(()),
            BrotliResult::ResultFailure => return Err(self.error_if_invalid_data.take().unwrap()),
@@ -261,12 +452,14 @@ impl<ErrType,
      BufferType : SliceWrapperMut<u8>,
      AllocU8 : Allocator<u8>,
      AllocU32 : Allocator<u32>,
-     AllocHC : Allocator<HuffmanCode> > Drop for DecompressorWriterCustomIo<ErrType,
-                                                                                     W,
-                                                                                     BufferType,
-                                                                                     AllocU8,
-                                                                                     AllocU32,
-                                                                                     AllocHC> {
+     AllocHC : Allocator<HuffmanCode>,
+     Dict : AsRef<[u8]> > Drop for DecompressorWriterCustomIo<ErrType,
+                                                                       W,
+                                                                       BufferType,
+                                                                       AllocU8,
+                                                                       AllocU32,
+                                                                       AllocHC,
+                                                                       Dict> {
   fn drop(&mut self) {
     match self.close() {
           Ok(_) => {},
@@ -279,34 +472,48 @@ impl<ErrType,
      BufferType : SliceWrapperMut<u8>,
      AllocU8 : Allocator<u8>,
      AllocU32 : Allocator<u32>,
-     AllocHC : Allocator<HuffmanCode> > CustomWrite<ErrType> for DecompressorWriterCustomIo<ErrType,
-                                                                                     W,
-                                                                                     BufferType,
-                                                                                     AllocU8,
-                                                                                     AllocU32,
-                                                                                     AllocHC> {
+     AllocHC : Allocator<HuffmanCode>,
+     Dict : AsRef<[u8]> > CustomWrite<ErrType> for DecompressorWriterCustomIo<ErrType,
+                                                                       W,
+                                                                       BufferType,
+                                                                       AllocU8,
+                                                                       AllocU32,
+                                                                       AllocHC,
+                                                                       Dict> {
 	fn write(&mut self, buf: &[u8]) -> Result<usize, ErrType > {
         let mut avail_in = buf.len();
         let mut input_offset : usize = 0;
         loop {
             let mut output_offset = 0;
-            let mut avail_out = self.output_buffer.slice_mut().len();
-            let op_result = BrotliDecompressStream(&mut avail_in,
+            // the decoder may only fill what is left of max_output_size
+            let out_len = core::cmp::min(self.output_buffer.slice_mut().len(),
+                                         self.max_output_size - self.total_out);
+            let mut avail_out = out_len;
+            let op_result = BrotliDecompressStreamWithDictionary(&mut avail_in,
                                      &mut input_offset,
                                      &buf[..],
                                      &mut avail_out,
                                      &mut output_offset,
-                                     self.output_buffer.slice_mut(),
+                                     &mut self.output_buffer.slice_mut()[..out_len],
                                      &mut self.total_out,
-                                     &mut self.state);
+                                     &mut self.state,
+                                     self.dict.as_ref());
          match write_all(&mut self.output, &self.output_buffer.slice_mut()[..output_offset]) {
           Ok(_) => {},
           Err(e) => return Err(e),
          }
          match op_result {
           BrotliResult::NeedsMoreInput => assert_eq!(avail_in, 0),
+          BrotliResult::NeedsMoreOutput if self.total_out == self.max_output_size && output_offset == 0 => {
+            return self.invalid_data();
+          },
           BrotliResult::NeedsMoreOutput => continue,
-          BrotliResult::ResultSuccess => return Ok((buf.len())),
+          BrotliResult::ResultSuccess => {
+            if self.strict && input_offset != buf.len() {
+              return self.invalid_data();
+            }
+            return Ok((buf.len()))
+          },
           BrotliResult::ResultFailure => return Err(self.error_if_invalid_data.take().unwrap()),
         }
         if avail_in == 0 {